@@ -0,0 +1,17 @@
+-- Create usernames table (BIP353 user@domain names)
+CREATE TABLE usernames (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    username VARCHAR(63) UNIQUE NOT NULL,
+    offer TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+-- Create index on user_id for quota checks and listing
+CREATE INDEX idx_usernames_user_id ON usernames(user_id);
+
+CREATE TRIGGER set_timestamp
+    BEFORE UPDATE ON usernames
+    FOR EACH ROW
+    EXECUTE PROCEDURE trigger_set_timestamp();
@@ -0,0 +1,29 @@
+-- Audit trail of every owner and offer change of a username
+CREATE TABLE username_history (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    username VARCHAR(63) NOT NULL,
+    event VARCHAR(32) NOT NULL,
+    from_user_id UUID REFERENCES users(id) ON DELETE SET NULL,
+    to_user_id UUID REFERENCES users(id) ON DELETE SET NULL,
+    offer TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+-- Support looks names up, cooldown checks look for the latest release
+CREATE INDEX idx_username_history_username ON username_history(username, created_at);
+
+-- Pending transfers awaiting the recipient's confirmation
+CREATE TABLE username_transfers (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    username_id UUID NOT NULL REFERENCES usernames(id) ON DELETE CASCADE,
+    from_user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    to_user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    status VARCHAR(16) NOT NULL DEFAULT 'pending',
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    completed_at TIMESTAMP WITH TIME ZONE
+);
+
+-- At most one open transfer per username
+CREATE UNIQUE INDEX idx_username_transfers_pending ON username_transfers(username_id) WHERE status = 'pending';
+CREATE INDEX idx_username_transfers_to_user_id ON username_transfers(to_user_id);
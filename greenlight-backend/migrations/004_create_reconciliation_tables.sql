@@ -0,0 +1,23 @@
+-- Runs of the username/offer/DNS reconciliation job
+CREATE TABLE reconciliation_runs (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    started_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    finished_at TIMESTAMP WITH TIME ZONE,
+    usernames_checked INTEGER NOT NULL DEFAULT 0,
+    drift_found INTEGER NOT NULL DEFAULT 0,
+    repaired INTEGER NOT NULL DEFAULT 0
+);
+
+-- Drift detected for a single username during a run
+CREATE TABLE reconciliation_findings (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    run_id UUID NOT NULL REFERENCES reconciliation_runs(id) ON DELETE CASCADE,
+    username VARCHAR(63) NOT NULL,
+    user_id UUID REFERENCES users(id) ON DELETE SET NULL,
+    issue VARCHAR(32) NOT NULL,
+    detail TEXT,
+    repaired BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_reconciliation_findings_run_id ON reconciliation_findings(run_id);
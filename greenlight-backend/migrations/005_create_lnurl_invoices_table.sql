@@ -0,0 +1,13 @@
+-- Invoices issued through the LNURL-pay / Lightning Address callback
+CREATE TABLE lnurl_invoices (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    username VARCHAR(63) NOT NULL,
+    amount_msat BIGINT NOT NULL,
+    payment_hash VARCHAR(64) NOT NULL,
+    bolt11 TEXT NOT NULL,
+    comment TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_lnurl_invoices_user_id ON lnurl_invoices(user_id, created_at);
@@ -0,0 +1,2 @@
+-- Relay hints published in the user's NIP-05 document
+ALTER TABLE users ADD COLUMN nostr_relays TEXT[] NOT NULL DEFAULT '{}';
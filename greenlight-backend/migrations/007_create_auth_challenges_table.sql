@@ -0,0 +1,11 @@
+-- One-time nonces for signature login with the user's Nostr key
+CREATE TABLE auth_challenges (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    public_key VARCHAR(64) NOT NULL,
+    nonce VARCHAR(64) NOT NULL UNIQUE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    used_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX idx_auth_challenges_expires_at ON auth_challenges(expires_at);
@@ -0,0 +1,9 @@
+-- Failed login counters shared by all replicas (LOGIN_THROTTLE_STORE=postgres)
+CREATE TABLE login_throttle (
+    key VARCHAR(320) PRIMARY KEY,
+    failures INTEGER NOT NULL DEFAULT 0,
+    last_failure_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    locked_until TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX idx_login_throttle_last_failure_at ON login_throttle(last_failure_at);
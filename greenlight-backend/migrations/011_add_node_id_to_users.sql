@@ -0,0 +1,5 @@
+-- Node id derived from the user's seed, recorded at node registration so that
+-- account recovery can check a mnemonic belongs to the account's node
+ALTER TABLE users ADD COLUMN node_id VARCHAR(66);
+
+CREATE INDEX idx_users_node_id ON users(node_id);
@@ -0,0 +1,25 @@
+-- TOTP second factor, one authenticator per user
+CREATE TABLE user_totp (
+    user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    -- Shared secret encrypted with SECRETS_ENCRYPTION_KEY
+    encrypted_secret TEXT NOT NULL,
+    -- NULL until the user confirms enrollment with a first code
+    enabled_at TIMESTAMP WITH TIME ZONE,
+    -- Time step of the last accepted code, so a code can't be replayed
+    last_used_step BIGINT,
+    -- Operations that need a fresh code on top of the session
+    protected_operations TEXT[] NOT NULL DEFAULT '{payments,withdrawals,username_transfer,api_key_creation}',
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+-- Single-use backup codes, stored as sha256 hashes
+CREATE TABLE totp_backup_codes (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    code_hash VARCHAR(64) NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    used_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX idx_totp_backup_codes_user_id ON totp_backup_codes(user_id);
@@ -0,0 +1,65 @@
+-- Per-user guardrails checked before any outgoing payment; no row means no limits
+CREATE TABLE spending_policies (
+    user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    -- Limits in satoshis over rolling 24 hour and 7 day windows; NULL is unlimited
+    daily_limit_sat BIGINT,
+    weekly_limit_sat BIGINT,
+    max_payment_sat BIGINT,
+    -- Lightning routing fee cap per payment
+    max_fee_sat BIGINT,
+    -- On-chain feerate cap for withdrawals
+    max_feerate_sat_vb BIGINT,
+    -- Only pay destinations on the allowlist
+    allowlist_enabled BOOLEAN NOT NULL DEFAULT FALSE,
+    -- Hours before a newly added destination can be paid
+    cooling_off_hours BIGINT NOT NULL DEFAULT 24,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+-- Allowlisted destinations: Lightning node ids, BIP353 names and on-chain addresses
+CREATE TABLE spending_destinations (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    kind VARCHAR(16) NOT NULL,
+    value TEXT NOT NULL,
+    label VARCHAR(64),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    usable_after TIMESTAMP WITH TIME ZONE NOT NULL,
+    removed_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE UNIQUE INDEX idx_spending_destinations_active
+    ON spending_destinations(user_id, kind, value) WHERE removed_at IS NULL;
+
+-- Every payment and withdrawal attempt, including the ones the policy denied
+CREATE TABLE outgoing_payments (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    -- lightning or onchain
+    kind VARCHAR(16) NOT NULL,
+    destination TEXT NOT NULL,
+    amount_msat BIGINT NOT NULL,
+    -- Maximum fee while pending, the fee actually paid once complete
+    fee_msat BIGINT NOT NULL DEFAULT 0,
+    -- pending, complete, failed or denied
+    status VARCHAR(16) NOT NULL,
+    denial_reason VARCHAR(32),
+    payment_hash VARCHAR(64),
+    txid VARCHAR(64),
+    error TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    completed_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX idx_outgoing_payments_user_created ON outgoing_payments(user_id, created_at);
+
+-- Who changed the policy or allowlist, and how
+CREATE TABLE spending_policy_audit (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    action VARCHAR(32) NOT NULL,
+    details JSONB NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_spending_policy_audit_user_id ON spending_policy_audit(user_id, created_at);
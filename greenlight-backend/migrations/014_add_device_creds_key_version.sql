@@ -0,0 +1,10 @@
+-- Device credentials are envelope-encrypted under the server key
+-- (SECRETS_ENCRYPTION_KEY, see services/credential_vault.rs). The version
+-- says which key wraps a row. The key never reaches the database, so rows
+-- are converted by the server rather than here: NULL marks a row still in
+-- a legacy format. Plain base64 rows are converted at startup, and
+-- password-encrypted ones when the user next logs in.
+ALTER TABLE users ADD COLUMN device_creds_key_version INTEGER;
+
+CREATE INDEX idx_users_legacy_device_creds ON users(id)
+    WHERE encrypted_device_creds IS NOT NULL AND device_creds_key_version IS NULL;
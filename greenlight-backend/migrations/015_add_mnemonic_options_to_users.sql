@@ -0,0 +1,10 @@
+-- BIP39 parameters of each account's mnemonic, so that node registration
+-- and account recovery derive the same seed as signup did. The passphrase
+-- itself is never stored, only whether there is one; the BIP32 master key
+-- fingerprint catches a wrong passphrase before a node is registered for
+-- the wrong seed. Existing accounts have 24 English words and no passphrase.
+ALTER TABLE users
+    ADD COLUMN mnemonic_word_count SMALLINT NOT NULL DEFAULT 24,
+    ADD COLUMN mnemonic_language VARCHAR(32) NOT NULL DEFAULT 'english',
+    ADD COLUMN bip39_passphrase BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN seed_fingerprint VARCHAR(8);
@@ -0,0 +1,3 @@
+-- Offer for payment URIs of accounts that have no username to borrow one
+-- from. Created once on first use instead of on every request.
+ALTER TABLE users ADD COLUMN payment_offer TEXT;
@@ -0,0 +1,5 @@
+-- Changes that loosen a policy wait out its cooling-off period so a stolen
+-- session can't lift the limits and spend in one go. The requested policy is
+-- kept here and takes over once pending_effective_at has passed.
+ALTER TABLE spending_policies ADD COLUMN pending_changes JSONB;
+ALTER TABLE spending_policies ADD COLUMN pending_effective_at TIMESTAMP WITH TIME ZONE;
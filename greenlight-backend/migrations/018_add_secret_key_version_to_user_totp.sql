@@ -0,0 +1,4 @@
+-- TOTP secrets are sealed by the credential vault under a data key wrapped
+-- by CREDENTIALS_KEY, like device credentials. NULL marks secrets encrypted
+-- directly with SECRETS_ENCRYPTION_KEY, converted at startup.
+ALTER TABLE user_totp ADD COLUMN secret_key_version INTEGER;
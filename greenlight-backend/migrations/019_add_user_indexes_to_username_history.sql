@@ -0,0 +1,3 @@
+-- Support looks up the names an account held or received
+CREATE INDEX idx_username_history_from_user_id ON username_history(from_user_id, created_at);
+CREATE INDEX idx_username_history_to_user_id ON username_history(to_user_id, created_at);
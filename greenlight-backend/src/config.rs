@@ -1,15 +1,79 @@
 use std::env;
+use std::str::FromStr;
 use anyhow::Result;
+use bitcoin::Network;
+use crate::middleware::client_ip::is_valid_ip_range;
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub database_url: String,
     pub jwt_secret: String,
+    pub jwt_algorithm: String,
+    pub jwt_signing_kid: String,
+    pub jwt_signing_key_path: Option<String>,
+    pub jwt_public_keys: Vec<(String, String)>,
+    pub jwt_issuer: String,
+    pub jwt_audience: String,
+    pub jwt_expiry_minutes: i64,
     pub server_host: String,
     pub server_port: u16,
     pub gl_cert_path: String,
     pub gl_key_path: String,
-    pub gl_network: String,
+    pub gl_network: Network,
+    pub domain: String,
+    pub dns_provider: String,
+    pub dns_server: String,
+    pub dns_zone: String,
+    pub dns_tsig_key_name: String,
+    pub dns_tsig_secret: String,
+    pub dns_tsig_algorithm: String,
+    pub dns_record_ttl: u32,
+    pub username_min_length: usize,
+    pub username_max_length: usize,
+    pub username_reserved: Vec<String>,
+    pub username_blocklist_path: Option<String>,
+    pub username_max_per_user: i64,
+    pub username_release_cooldown_days: i64,
+    pub username_transfer_expiry_hours: i64,
+    pub dns_resolver: String,
+    pub reconcile_interval_secs: u64,
+    pub reconcile_auto_repair: bool,
+    pub admin_api_key: Option<String>,
+    pub public_url: String,
+    pub lnurl_min_sendable_msat: u64,
+    pub lnurl_max_sendable_msat: u64,
+    pub lnurl_comment_allowed: usize,
+    pub refresh_token_ttl_days: i64,
+    pub auth_challenge_ttl_secs: i64,
+    pub nip98_time_window_secs: i64,
+    pub trust_proxy_headers: bool,
+    pub trusted_proxies: Vec<String>,
+    pub login_throttle_store: String,
+    pub login_free_attempts: u32,
+    pub login_lockout_threshold: u32,
+    pub login_backoff_base_secs: i64,
+    pub login_backoff_max_secs: i64,
+    pub login_lockout_secs: i64,
+    pub login_failure_window_secs: i64,
+    pub login_ip_multiplier: u32,
+    pub secrets_encryption_key: Option<String>,
+    pub secrets_encryption_key_version: i32,
+    pub credentials_key: Option<String>,
+    pub credentials_previous_keys: Vec<(i32, String)>,
+    pub jwt_signing_key: Option<String>,
+    pub pkcs11_module: Option<String>,
+    pub pkcs11_slot: Option<u64>,
+    pub pkcs11_pin: Option<String>,
+    pub vault_addr: String,
+    pub vault_token: Option<String>,
+    pub vault_namespace: Option<String>,
+    pub vault_transit_mount: String,
+    pub seed_kdf_memory_kib: u32,
+    pub seed_kdf_iterations: u32,
+    pub seed_kdf_parallelism: u32,
+    pub seed_kdf_previous_params: Vec<(u32, u32, u32)>,
+    pub totp_issuer: String,
+    pub totp_allowed_skew_steps: i64,
 }
 
 impl Config {
@@ -25,6 +89,30 @@ impl Config {
                 "default-secret-change-in-production".to_string()
             });
 
+        // Access token signing; asymmetric algorithms sign with the private key
+        // of JWT_SIGNING_KID and verify with every `kid:path` in JWT_PUBLIC_KEYS
+        let jwt_algorithm = env::var("JWT_ALGORITHM").unwrap_or_else(|_| "HS256".to_string());
+        let jwt_signing_kid = env::var("JWT_SIGNING_KID").unwrap_or_else(|_| "default".to_string());
+        let jwt_signing_key_path = env::var("JWT_SIGNING_KEY_PATH").ok().filter(|path| !path.is_empty());
+        let jwt_public_keys = env::var("JWT_PUBLIC_KEYS")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|entry| entry.trim().split_once(':'))
+            .map(|(kid, path)| (kid.trim().to_string(), path.trim().to_string()))
+            .collect();
+        let jwt_audience = env::var("JWT_AUDIENCE").unwrap_or_else(|_| "greenlight-backend".to_string());
+        // JWT_EXPIRY_MINUTES wins over the coarser JWT_EXPIRY_HOURS
+        let jwt_expiry_minutes = env::var("JWT_EXPIRY_MINUTES")
+            .ok()
+            .and_then(|minutes| minutes.parse::<i64>().ok())
+            .or_else(|| {
+                env::var("JWT_EXPIRY_HOURS")
+                    .ok()
+                    .and_then(|hours| hours.parse::<i64>().ok())
+                    .map(|hours| hours * 60)
+            })
+            .unwrap_or(15);
+
         let server_host = env::var("HOST").unwrap_or_else(|_| "0.0.0.0".to_string());
         let server_port = env::var("PORT")
             .unwrap_or_else(|_| "8080".to_string())
@@ -37,15 +125,295 @@ impl Config {
             .unwrap_or_else(|_| "./client-key.pem".to_string());
         let gl_network = env::var("GL_NETWORK")
             .unwrap_or_else(|_| "bitcoin".to_string());
+        let gl_network = match Network::from_str(gl_network.trim()) {
+            Ok(network) => network,
+            Err(_) => anyhow::bail!("Invalid GL_NETWORK: {} (expected bitcoin, testnet, signet or regtest)", gl_network),
+        };
+
+        // BIP353 DNS publishing
+        let domain = env::var("DOMAIN").unwrap_or_else(|_| "example.com".to_string());
+        let dns_provider = env::var("DNS_PROVIDER").unwrap_or_else(|_| "none".to_string());
+        let dns_server = env::var("DNS_SERVER").unwrap_or_else(|_| "127.0.0.1:53".to_string());
+        let dns_zone = env::var("DNS_ZONE").unwrap_or_else(|_| domain.clone());
+        let dns_tsig_key_name = env::var("DNS_TSIG_KEY_NAME").unwrap_or_default();
+        let dns_tsig_secret = env::var("DNS_TSIG_SECRET").unwrap_or_default();
+        let dns_tsig_algorithm = env::var("DNS_TSIG_ALGORITHM")
+            .unwrap_or_else(|_| "hmac-sha256".to_string());
+        let dns_record_ttl = env::var("DNS_RECORD_TTL")
+            .unwrap_or_else(|_| "300".to_string())
+            .parse::<u32>()
+            .unwrap_or(300);
+
+        // Username policy
+        let username_min_length = env::var("USERNAME_MIN_LENGTH")
+            .unwrap_or_else(|_| "3".to_string())
+            .parse::<usize>()
+            .unwrap_or(3);
+        let username_max_length = env::var("USERNAME_MAX_LENGTH")
+            .unwrap_or_else(|_| "30".to_string())
+            .parse::<usize>()
+            .unwrap_or(30);
+        let username_reserved = env::var("USERNAME_RESERVED")
+            .map(|names| {
+                names
+                    .split(',')
+                    .map(|name| name.trim().to_string())
+                    .filter(|name| !name.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let username_blocklist_path = env::var("USERNAME_BLOCKLIST_PATH").ok();
+        let username_max_per_user = env::var("USERNAME_MAX_PER_USER")
+            .unwrap_or_else(|_| "3".to_string())
+            .parse::<i64>()
+            .unwrap_or(3);
+
+        let username_release_cooldown_days = env::var("USERNAME_RELEASE_COOLDOWN_DAYS")
+            .unwrap_or_else(|_| "30".to_string())
+            .parse::<i64>()
+            .unwrap_or(30);
+        let username_transfer_expiry_hours = env::var("USERNAME_TRANSFER_EXPIRY_HOURS")
+            .unwrap_or_else(|_| "72".to_string())
+            .parse::<i64>()
+            .unwrap_or(72);
+
+        // Reconciliation between usernames, offers and published DNS
+        let dns_resolver = env::var("DNS_RESOLVER").unwrap_or_else(|_| "1.1.1.1:53".to_string());
+        let reconcile_interval_secs = env::var("RECONCILE_INTERVAL_SECS")
+            .unwrap_or_else(|_| "3600".to_string())
+            .parse::<u64>()
+            .unwrap_or(3600);
+        let reconcile_auto_repair = env::var("RECONCILE_AUTO_REPAIR")
+            .map(|value| value == "true" || value == "1")
+            .unwrap_or(false);
+
+        let admin_api_key = env::var("ADMIN_API_KEY").ok().filter(|key| !key.is_empty());
+
+        // Lightning Address / LNURL-pay
+        let public_url = env::var("PUBLIC_URL").unwrap_or_else(|_| format!("https://{}", domain));
+        let jwt_issuer = env::var("JWT_ISSUER").unwrap_or_else(|_| public_url.clone());
+        let lnurl_min_sendable_msat = env::var("LNURL_MIN_SENDABLE_MSAT")
+            .unwrap_or_else(|_| "1000".to_string())
+            .parse::<u64>()
+            .unwrap_or(1000);
+        let lnurl_max_sendable_msat = env::var("LNURL_MAX_SENDABLE_MSAT")
+            .unwrap_or_else(|_| "1000000000".to_string())
+            .parse::<u64>()
+            .unwrap_or(1_000_000_000);
+        let lnurl_comment_allowed = env::var("LNURL_COMMENT_ALLOWED")
+            .unwrap_or_else(|_| "255".to_string())
+            .parse::<usize>()
+            .unwrap_or(255);
+
+        // Sessions: idle sessions expire this long after their last refresh
+        let refresh_token_ttl_days = env::var("REFRESH_TOKEN_TTL_DAYS")
+            .unwrap_or_else(|_| "30".to_string())
+            .parse::<i64>()
+            .unwrap_or(30);
+
+        // Signature login with the user's Nostr key
+        let auth_challenge_ttl_secs = env::var("AUTH_CHALLENGE_TTL_SECS")
+            .unwrap_or_else(|_| "300".to_string())
+            .parse::<i64>()
+            .unwrap_or(300);
+
+        // NIP-98 HTTP auth: maximum clock difference for a signed request
+        let nip98_time_window_secs = env::var("NIP98_TIME_WINDOW_SECS")
+            .unwrap_or_else(|_| "60".to_string())
+            .parse::<i64>()
+            .unwrap_or(60);
+
+        // Take the client address from X-Forwarded-For (only behind a reverse proxy)
+        let trust_proxy_headers = env::var("TRUST_PROXY_HEADERS")
+            .map(|value| value == "true" || value == "1")
+            .unwrap_or(false);
+        // Proxy addresses or CIDR ranges to skip when reading X-Forwarded-For
+        // from the right; empty means a single proxy hop
+        let trusted_proxies: Vec<String> = env::var("TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(|proxy| proxy.trim().to_string())
+            .filter(|proxy| !proxy.is_empty())
+            .collect();
+        if let Some(proxy) = trusted_proxies.iter().find(|proxy| !is_valid_ip_range(proxy)) {
+            anyhow::bail!("Invalid TRUSTED_PROXIES entry: {}", proxy);
+        }
+
+        // Login throttling: exponential backoff after LOGIN_FREE_ATTEMPTS failures,
+        // lockout after LOGIN_LOCKOUT_THRESHOLD; IPs get LOGIN_IP_MULTIPLIER times more
+        let login_throttle_store = env::var("LOGIN_THROTTLE_STORE").unwrap_or_else(|_| "memory".to_string());
+        let login_free_attempts = env::var("LOGIN_FREE_ATTEMPTS")
+            .unwrap_or_else(|_| "3".to_string())
+            .parse::<u32>()
+            .unwrap_or(3);
+        let login_lockout_threshold = env::var("LOGIN_LOCKOUT_THRESHOLD")
+            .unwrap_or_else(|_| "10".to_string())
+            .parse::<u32>()
+            .unwrap_or(10);
+        let login_backoff_base_secs = env::var("LOGIN_BACKOFF_BASE_SECS")
+            .unwrap_or_else(|_| "1".to_string())
+            .parse::<i64>()
+            .unwrap_or(1);
+        let login_backoff_max_secs = env::var("LOGIN_BACKOFF_MAX_SECS")
+            .unwrap_or_else(|_| "300".to_string())
+            .parse::<i64>()
+            .unwrap_or(300);
+        let login_lockout_secs = env::var("LOGIN_LOCKOUT_SECS")
+            .unwrap_or_else(|_| "900".to_string())
+            .parse::<i64>()
+            .unwrap_or(900);
+        let login_failure_window_secs = env::var("LOGIN_FAILURE_WINDOW_SECS")
+            .unwrap_or_else(|_| "3600".to_string())
+            .parse::<i64>()
+            .unwrap_or(3600);
+        let login_ip_multiplier = env::var("LOGIN_IP_MULTIPLIER")
+            .unwrap_or_else(|_| "5".to_string())
+            .parse::<u32>()
+            .unwrap_or(5);
+
+        // Server-side key for secrets stored in the database (device credentials, TOTP secrets)
+        let secrets_encryption_key = env::var("SECRETS_ENCRYPTION_KEY").ok().filter(|key| !key.is_empty());
+        let secrets_encryption_key_version = env::var("SECRETS_ENCRYPTION_KEY_VERSION")
+            .unwrap_or_else(|_| "1".to_string())
+            .parse::<i32>()
+            .unwrap_or(1);
+
+        // Master keys as `<provider>:<name>` (env, file, pkcs11, vault). By
+        // default credentials use SECRETS_ENCRYPTION_KEY and tokens JWT_SECRET
+        // or JWT_SIGNING_KEY_PATH.
+        let credentials_key = env::var("CREDENTIALS_KEY").ok().filter(|key| !key.is_empty());
+        // Retired credential keys as `<version>=<provider>:<name>`, still used
+        // to open rows sealed under them until they are re-encrypted
+        let mut credentials_previous_keys = Vec::new();
+        for entry in env::var("CREDENTIALS_PREVIOUS_KEYS").unwrap_or_default().split(',').map(str::trim) {
+            if entry.is_empty() {
+                continue;
+            }
+            let (version, spec) = entry
+                .split_once('=')
+                .and_then(|(version, spec)| Some((version.trim().parse::<i32>().ok()?, spec.trim().to_string())))
+                .ok_or_else(|| anyhow::anyhow!("Invalid CREDENTIALS_PREVIOUS_KEYS entry: {}", entry))?;
+            if version == secrets_encryption_key_version
+                || credentials_previous_keys.iter().any(|(existing, _)| *existing == version)
+            {
+                anyhow::bail!("CREDENTIALS_PREVIOUS_KEYS lists key version {} twice or as the current version", version);
+            }
+            credentials_previous_keys.push((version, spec));
+        }
+        let jwt_signing_key = env::var("JWT_SIGNING_KEY").ok().filter(|key| !key.is_empty());
+        let pkcs11_module = env::var("PKCS11_MODULE").ok().filter(|module| !module.is_empty());
+        let pkcs11_slot = env::var("PKCS11_SLOT").ok().and_then(|slot| slot.parse::<u64>().ok());
+        let pkcs11_pin = env::var("PKCS11_PIN").ok().filter(|pin| !pin.is_empty());
+        let vault_addr = env::var("VAULT_ADDR").unwrap_or_else(|_| "http://127.0.0.1:8200".to_string());
+        let vault_token = env::var("VAULT_TOKEN").ok().filter(|token| !token.is_empty());
+        let vault_namespace = env::var("VAULT_NAMESPACE").ok().filter(|namespace| !namespace.is_empty());
+        let vault_transit_mount = env::var("VAULT_TRANSIT_MOUNT").unwrap_or_else(|_| "transit".to_string());
+
+        // Argon2id costs for password-encrypted seeds (memory in KiB)
+        let seed_kdf_memory_kib = env::var("SEED_KDF_MEMORY_KIB")
+            .unwrap_or_else(|_| "65536".to_string())
+            .parse::<u32>()
+            .unwrap_or(65536);
+        let seed_kdf_iterations = env::var("SEED_KDF_ITERATIONS")
+            .unwrap_or_else(|_| "3".to_string())
+            .parse::<u32>()
+            .unwrap_or(3);
+        let seed_kdf_parallelism = env::var("SEED_KDF_PARALLELISM")
+            .unwrap_or_else(|_| "1".to_string())
+            .parse::<u32>()
+            .unwrap_or(1);
+        // Costs used before the current ones, as `memory:iterations:parallelism`;
+        // envelopes with any other parameters are refused
+        let mut seed_kdf_previous_params = Vec::new();
+        for entry in env::var("SEED_KDF_PREVIOUS_PARAMS").unwrap_or_default().split(',').map(str::trim) {
+            if entry.is_empty() {
+                continue;
+            }
+            let costs: Vec<u32> = entry.split(':').filter_map(|cost| cost.trim().parse().ok()).collect();
+            match costs.as_slice() {
+                [memory_kib, iterations, parallelism] if entry.split(':').count() == 3 => {
+                    seed_kdf_previous_params.push((*memory_kib, *iterations, *parallelism));
+                }
+                _ => anyhow::bail!("Invalid SEED_KDF_PREVIOUS_PARAMS entry: {}", entry),
+            }
+        }
+
+        // TOTP two-factor authentication
+        let totp_issuer = env::var("TOTP_ISSUER").unwrap_or_else(|_| domain.clone());
+        let totp_allowed_skew_steps = env::var("TOTP_ALLOWED_SKEW_STEPS")
+            .unwrap_or_else(|_| "1".to_string())
+            .parse::<i64>()
+            .unwrap_or(1);
 
         Ok(Config {
             database_url,
             jwt_secret,
+            jwt_algorithm,
+            jwt_signing_kid,
+            jwt_signing_key_path,
+            jwt_public_keys,
+            jwt_issuer,
+            jwt_audience,
+            jwt_expiry_minutes,
             server_host,
             server_port,
             gl_cert_path,
             gl_key_path,
             gl_network,
+            domain,
+            dns_provider,
+            dns_server,
+            dns_zone,
+            dns_tsig_key_name,
+            dns_tsig_secret,
+            dns_tsig_algorithm,
+            dns_record_ttl,
+            username_min_length,
+            username_max_length,
+            username_reserved,
+            username_blocklist_path,
+            username_max_per_user,
+            username_release_cooldown_days,
+            username_transfer_expiry_hours,
+            dns_resolver,
+            reconcile_interval_secs,
+            reconcile_auto_repair,
+            admin_api_key,
+            public_url,
+            lnurl_min_sendable_msat,
+            lnurl_max_sendable_msat,
+            lnurl_comment_allowed,
+            refresh_token_ttl_days,
+            auth_challenge_ttl_secs,
+            nip98_time_window_secs,
+            trust_proxy_headers,
+            trusted_proxies,
+            login_throttle_store,
+            login_free_attempts,
+            login_lockout_threshold,
+            login_backoff_base_secs,
+            login_backoff_max_secs,
+            login_lockout_secs,
+            login_failure_window_secs,
+            login_ip_multiplier,
+            secrets_encryption_key,
+            secrets_encryption_key_version,
+            credentials_key,
+            credentials_previous_keys,
+            jwt_signing_key,
+            pkcs11_module,
+            pkcs11_slot,
+            pkcs11_pin,
+            vault_addr,
+            vault_token,
+            vault_namespace,
+            vault_transit_mount,
+            seed_kdf_memory_kib,
+            seed_kdf_iterations,
+            seed_kdf_parallelism,
+            seed_kdf_previous_params,
+            totp_issuer,
+            totp_allowed_skew_steps,
         })
     }
 }
@@ -1,7 +1,8 @@
 use serde::Serialize;
-use axum::http::StatusCode;
+use axum::http::{header, StatusCode};
 use axum::response::{IntoResponse, Response};
 use axum::Json;
+use crate::services::spending_policy::PolicyDenial;
 
 #[derive(Debug, thiserror::Error)]
 pub enum AppError {
@@ -20,6 +21,9 @@ pub enum AppError {
     #[error("Greenlight error: {0}")]
     Greenlight(String),
     
+    #[error("DNS error: {0}")]
+    Dns(String),
+    
     #[error("Cryptography error: {0}")]
     Cryptography(String),
     
@@ -31,28 +35,83 @@ pub enum AppError {
     
     #[error("Bad request: {0}")]
     BadRequest(String),
+
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
+    #[error("Too many attempts, retry in {0} seconds")]
+    RateLimited(u64),
+
+    #[error("Two-factor code required")]
+    SecondFactorRequired,
+
+    #[error("Denied by spending policy: {}", .0.message)]
+    PolicyDenied(PolicyDenial),
 }
 
 #[derive(Serialize)]
 pub struct ErrorResponse {
     pub status: String,
     pub message: String,
+    /// Structured reason when the spending policy refused a payment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub denial: Option<PolicyDenial>,
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
+        if let AppError::RateLimited(retry_after) = self {
+            let error_response = ErrorResponse {
+                status: "error".to_string(),
+                message: self.to_string(),
+                denial: None,
+            };
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                Json(error_response),
+            )
+                .into_response();
+        }
+
+        // Tells clients to prompt for an authenticator code and retry
+        if let AppError::SecondFactorRequired = self {
+            let error_response = ErrorResponse {
+                status: "error".to_string(),
+                message: self.to_string(),
+                denial: None,
+            };
+            return (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "TOTP")],
+                Json(error_response),
+            )
+                .into_response();
+        }
+
+        if let AppError::PolicyDenied(denial) = self {
+            let error_response = ErrorResponse {
+                status: "error".to_string(),
+                message: denial.message.clone(),
+                denial: Some(denial),
+            };
+            return (StatusCode::FORBIDDEN, Json(error_response)).into_response();
+        }
+
         let (status, message) = match self {
             AppError::Authentication(_) => (StatusCode::UNAUTHORIZED, self.to_string()),
             AppError::Authorization(_) => (StatusCode::FORBIDDEN, self.to_string()),
             AppError::Validation(_) => (StatusCode::BAD_REQUEST, self.to_string()),
             AppError::NotFound(_) => (StatusCode::NOT_FOUND, self.to_string()),
             AppError::BadRequest(_) => (StatusCode::BAD_REQUEST, self.to_string()),
+            AppError::Conflict(_) => (StatusCode::CONFLICT, self.to_string()),
             _ => (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error".to_string()),
         };
 
         let error_response = ErrorResponse {
             status: "error".to_string(),
             message,
+            denial: None,
         };
 
         (status, Json(error_response)).into_response()
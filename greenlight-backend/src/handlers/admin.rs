@@ -0,0 +1,93 @@
+use axum::{
+    extract::{Query, State},
+    response::Json,
+};
+use serde::Serialize;
+use crate::{AppState, error::{AppError, Result}};
+use crate::models::{ReconciliationRepository, UserRepository, UsernameHistory, UsernameHistoryQuery, UsernameRepository};
+use crate::services::{Binding, CryptoService, SecretPurpose};
+use crate::services::reconciliation::ReconciliationReport;
+
+#[derive(Serialize)]
+pub struct ReencryptionReport {
+    pub device_credentials_reencrypted: usize,
+    pub totp_secrets_reencrypted: usize,
+    /// Secrets that could not be decrypted with the configured keys
+    pub failed: usize,
+    /// Password-encrypted seeds in an older format; they are re-encrypted
+    /// at the owner's next password login
+    pub seeds_pending_login: usize,
+}
+
+/// GET /admin/reconciliation - Latest username/offer/DNS reconciliation report
+pub async fn get_reconciliation_report(
+    State(state): State<AppState>,
+) -> Result<Json<ReconciliationReport>> {
+    let reconciliation_repo = ReconciliationRepository::new(state.db_pool.clone());
+
+    let run = reconciliation_repo.latest_run().await?
+        .ok_or_else(|| AppError::NotFound("No reconciliation run has completed yet".to_string()))?;
+    let findings = reconciliation_repo.findings_for_run(run.id).await?;
+
+    Ok(Json(ReconciliationReport { run, findings }))
+}
+
+/// POST /admin/reconciliation/run - Run reconciliation now and return its report
+pub async fn run_reconciliation(
+    State(state): State<AppState>,
+) -> Result<Json<ReconciliationReport>> {
+    Ok(Json(state.reconciliation_service.run().await?))
+}
+
+/// POST /admin/secrets/reencrypt - Rewrite server-encrypted secrets in the
+/// current CryptoService format and count the seeds still waiting for a login
+pub async fn reencrypt_secrets(
+    State(state): State<AppState>,
+) -> Result<Json<ReencryptionReport>> {
+    let (device_credentials_reencrypted, device_credentials_failed) =
+        state.credential_vault.reencrypt_rows(&state.db_pool).await?;
+    let (totp_secrets_reencrypted, totp_secrets_failed) = state.totp_service.reencrypt_secrets().await?;
+
+    let user_repo = UserRepository::new(state.db_pool.clone());
+    let seeds_pending_login = user_repo
+        .find_encrypted_seeds()
+        .await?
+        .iter()
+        .filter(|(user_id, encrypted_seed)| {
+            CryptoService::needs_reencryption(encrypted_seed, Some(&Binding::new(*user_id, SecretPurpose::Seed)))
+        })
+        .count();
+
+    tracing::info!(
+        "Re-encrypted {} device credentials and {} TOTP secrets, {} seeds pending login",
+        device_credentials_reencrypted,
+        totp_secrets_reencrypted,
+        seeds_pending_login
+    );
+
+    Ok(Json(ReencryptionReport {
+        device_credentials_reencrypted,
+        totp_secrets_reencrypted,
+        failed: device_credentials_failed + totp_secrets_failed,
+        seeds_pending_login,
+    }))
+}
+
+/// GET /admin/usernames/history?username=&user_id= - Owner and offer changes
+/// of a name, or of every name an account gave up or received
+pub async fn get_username_history(
+    State(state): State<AppState>,
+    Query(query): Query<UsernameHistoryQuery>,
+) -> Result<Json<Vec<UsernameHistory>>> {
+    if query.username.is_none() && query.user_id.is_none() {
+        return Err(AppError::BadRequest("username or user_id is required".to_string()));
+    }
+
+    // Names claimed under an older policy may not pass it any more
+    let username = query.username.as_deref().map(|name| {
+        state.username_policy.normalize(name).unwrap_or_else(|_| name.trim().to_lowercase())
+    });
+
+    let username_repo = UsernameRepository::new(state.db_pool.clone());
+    Ok(Json(username_repo.search_history(username.as_deref(), query.user_id).await?))
+}
@@ -0,0 +1,119 @@
+use axum::{
+    extract::{State, Extension, Path},
+    http::{HeaderMap, StatusCode},
+    response::Json,
+};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{Duration, Utc};
+use rand::RngCore;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use crate::{AppState, error::{AppError, Result}};
+use crate::middleware::auth::TOTP_CODE_HEADER;
+use crate::middleware::client_ip::is_valid_ip_range;
+use crate::models::{ApiKey, ApiKeyRepository, ApiScope, CreateApiKeyRequest, ProtectedOperation};
+
+/// Marks bearer tokens that are API keys rather than JWTs
+pub const API_KEY_PREFIX: &str = "glk_";
+
+const MAX_ALLOWED_IPS: usize = 20;
+
+#[derive(Serialize)]
+pub struct CreateApiKeyResponse {
+    /// The full key; it is only ever shown here
+    pub key: String,
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+}
+
+/// POST /api-keys - Create an API key; the secret is returned once. Keys
+/// are never asked for a second factor, so creating one needs a code if the
+/// user protects key creation or anything the key's scopes can do.
+pub async fn create_api_key(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    headers: HeaderMap,
+    Json(request): Json<CreateApiKeyRequest>,
+) -> Result<(StatusCode, Json<CreateApiKeyResponse>)> {
+    let name = request.name.trim();
+    if name.is_empty() || name.chars().count() > 64 {
+        return Err(AppError::Validation("name must be between 1 and 64 characters".to_string()));
+    }
+
+    if request.scopes.is_empty() {
+        return Err(AppError::Validation("At least one scope is required".to_string()));
+    }
+    let mut scopes = Vec::new();
+    let mut operations = vec![ProtectedOperation::ApiKeyCreation];
+    for scope in &request.scopes {
+        let scope = ApiScope::parse(scope).ok_or_else(|| {
+            let known: Vec<&str> = ApiScope::ALL.iter().map(ApiScope::as_str).collect();
+            AppError::Validation(format!("Unknown scope '{}', expected one of {}", scope, known.join(", ")))
+        })?;
+        if !scopes.contains(&scope.as_str().to_string()) {
+            scopes.push(scope.as_str().to_string());
+            operations.extend_from_slice(scope.protected_operations());
+        }
+    }
+
+    let allowed_ips: Vec<String> = request.allowed_ips.unwrap_or_default()
+        .into_iter()
+        .map(|entry| entry.trim().to_string())
+        .collect();
+    if allowed_ips.len() > MAX_ALLOWED_IPS {
+        return Err(AppError::Validation(format!("At most {} allowed IPs", MAX_ALLOWED_IPS)));
+    }
+    if let Some(entry) = allowed_ips.iter().find(|entry| !is_valid_ip_range(entry)) {
+        return Err(AppError::Validation(format!("Invalid IP address or CIDR range: {}", entry)));
+    }
+
+    let expires_at = match request.expires_in_days {
+        Some(days) if !(1..=3650).contains(&days) => {
+            return Err(AppError::Validation("expires_in_days must be between 1 and 3650".to_string()));
+        }
+        Some(days) => Some(Utc::now() + Duration::days(days)),
+        None => None,
+    };
+
+    let code = headers.get(TOTP_CODE_HEADER).and_then(|header| header.to_str().ok());
+    state.totp_service.check_operations(user_id, &operations, code).await?;
+
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    let key = format!("{}{}", API_KEY_PREFIX, general_purpose::URL_SAFE_NO_PAD.encode(secret));
+
+    let api_key_repo = ApiKeyRepository::new(state.db_pool.clone());
+    let api_key = api_key_repo
+        .create_api_key(user_id, name, &key[..12], &hash_api_key(&key), &scopes, &allowed_ips, expires_at)
+        .await?;
+
+    Ok((StatusCode::CREATED, Json(CreateApiKeyResponse { key, api_key })))
+}
+
+/// GET /api-keys - The authenticated user's active API keys
+pub async fn list_api_keys(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<Vec<ApiKey>>> {
+    let api_key_repo = ApiKeyRepository::new(state.db_pool.clone());
+    Ok(Json(api_key_repo.find_by_user(user_id).await?))
+}
+
+/// DELETE /api-keys/:id - Revoke an API key
+pub async fn revoke_api_key(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode> {
+    let api_key_repo = ApiKeyRepository::new(state.db_pool.clone());
+    if !api_key_repo.revoke_api_key(id, user_id).await? {
+        return Err(AppError::NotFound("API key not found".to_string()));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
@@ -1,28 +1,79 @@
 use axum::{
-    extract::State,
-    http::StatusCode,
+    extract::{ConnectInfo, State, Extension},
+    http::{header::USER_AGENT, HeaderMap, StatusCode},
     response::Json,
 };
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{Duration, Utc};
+use rand::RngCore;
 use serde::Serialize;
-use crate::{AppState, error::Result};
-use crate::models::{CreateUserRequest, LoginRequest, UserRepository};
-use crate::services::CryptoService;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use uuid::Uuid;
+use crate::{AppState, error::{AppError, Result}};
+use crate::handlers::node::{encrypt_for_client, user_seed};
+use crate::middleware::client_ip::client_ip;
+use crate::models::{
+    AuthChallengeRepository, ChallengeRequest, ChangePasswordRequest, CreateUserRequest, CurrentSession,
+    LoginRequest, RecoverAccountRequest, RefreshTokenRequest, RevokeReason, Session, SessionRepository,
+    SignatureLoginRequest, User, UserRepository,
+};
+use crate::services::{Binding, CryptoService, GreenlightService, MnemonicOptions, SecretBytes, SecretPurpose, SecretString};
+use crate::services::greenlight::DeviceCredentials;
+use crate::services::nostr::{self, KIND_CLIENT_AUTH};
 
 #[derive(Serialize)]
 pub struct SignupResponse {
     #[serde(rename = "encryptedSeed")]
     pub encrypted_seed: String,
+    /// Node credentials encrypted with the password, when the signup
+    /// imported a seed and its node was set up
+    #[serde(rename = "encryptedDeviceCreds", skip_serializing_if = "Option::is_none")]
+    pub encrypted_device_creds: Option<String>,
     pub token: String,
+    pub refresh_token: String,
+    /// Access token lifetime in seconds
+    pub expires_in: i64,
 }
 
 #[derive(Serialize)]
 pub struct LoginResponse {
     pub token: String,
+    pub refresh_token: String,
+    /// Access token lifetime in seconds
+    pub expires_in: i64,
+}
+
+#[derive(Serialize)]
+pub struct ChangePasswordResponse {
+    /// The seed re-encrypted under the new password; replaces the client's copy
+    #[serde(rename = "encryptedSeed")]
+    pub encrypted_seed: Option<String>,
+    pub revoked_sessions: u64,
+}
+
+#[derive(Serialize)]
+pub struct RecoverAccountResponse {
+    /// The seed encrypted under the new password
+    #[serde(rename = "encryptedSeed")]
+    pub encrypted_seed: String,
+    #[serde(flatten)]
+    pub session: LoginResponse,
+}
+
+#[derive(Serialize)]
+pub struct ChallengeResponse {
+    pub nonce: String,
+    /// Text whose sha256 is signed for a raw BIP340 signature login
+    pub message: String,
+    pub expires_at: chrono::DateTime<Utc>,
 }
 
 /// POST /api/v1/users/signup
 pub async fn signup(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Json(request): Json<CreateUserRequest>,
 ) -> Result<(StatusCode, Json<SignupResponse>)> {
     let user_repo = UserRepository::new(state.db_pool.clone());
@@ -35,58 +86,628 @@ pub async fn signup(
     }
 
     // Validate inputs
-    if request.public_key.is_empty() || request.password.len() < 8 {
+    if request.public_key.is_empty() || request.password.expose().len() < 8 {
         return Err(crate::error::AppError::Validation(
             "Public key cannot be empty and password must be at least 8 characters".to_string()
         ));
     }
 
-    // Generate BIP39 mnemonic
-    let mnemonic = CryptoService::generate_mnemonic()?;
-    
-    // Encrypt the mnemonic with the user's password
-    let encrypted_seed = CryptoService::encrypt(&mnemonic, &request.password)?;
+    let passphrase = request.passphrase.as_ref().filter(|passphrase| !passphrase.expose().is_empty());
+    if passphrase.is_some_and(|passphrase| passphrase.expose() == request.password.expose()) {
+        return Err(AppError::Validation("BIP39 passphrase must differ from the password".to_string()));
+    }
+
+    // The client's own mnemonic, or a new one. The BIP39 options are
+    // recorded so the seed is always derived the same way.
+    let user_id = Uuid::new_v4();
+    let imported = imported_mnemonic(&request).await?;
+    let importing = imported.is_some();
+    let mnemonic_options = signup_mnemonic_options(&request, imported.as_ref(), passphrase.is_some())?;
+    let mnemonic = match imported {
+        Some(mnemonic) => mnemonic,
+        None => CryptoService::generate_mnemonic(&mnemonic_options)?,
+    };
+    let seed = CryptoService::mnemonic_to_seed(&mnemonic, mnemonic_options.language, passphrase)?;
+
+    // Encrypt the mnemonic with the user's password, bound to the new account
+    let encrypted_seed = CryptoService::encrypt(
+        mnemonic.expose(),
+        &request.password,
+        Some(&Binding::new(user_id, SecretPurpose::Seed)),
+    ).await?;
     
     // Hash the password
-    let password_hash = CryptoService::hash_password(&request.password)?;
+    let password_hash = CryptoService::hash_password(&request.password).await?;
     
-    // Create user in database
+    // An imported seed gets its node before the account is written, so no
+    // transaction is held open across Greenlight calls: the node another app
+    // created for it if there is one, otherwise a new one. If the account
+    // then can't be saved, a retry recovers the node instead.
+    let imported_node = if importing {
+        let (node_id, device_creds) = import_node(&state, &user_repo, user_id, &seed).await?;
+        let sealed_creds = state.credential_vault.seal(user_id, &device_creds.creds).await?;
+        Some((node_id, sealed_creds, device_creds))
+    } else {
+        None
+    };
+
+    let mut tx = user_repo.begin().await?;
     let user = user_repo.create_user(
+        &mut tx,
+        user_id,
         &request.public_key,
         &password_hash,
         &encrypted_seed,
+        &mnemonic_options,
+        &CryptoService::seed_fingerprint(&seed)?,
     ).await?;
+    if let Some((node_id, sealed_creds, _)) = &imported_node {
+        user_repo
+            .attach_node(&mut tx, user.id, node_id, sealed_creds, state.credential_vault.key_version())
+            .await?;
+    }
+    tx.commit().await?;
+
+    let encrypted_device_creds = match imported_node.map(|(_, _, device_creds)| device_creds) {
+        Some(device_creds) => Some(encrypt_for_client(user.id, &device_creds, &request.password).await?),
+        None => None,
+    };
     
-    // Generate JWT token
-    let token = state.jwt_service.generate_token(user.id)?;
+    // Start a session and issue its tokens
+    let tokens = start_session(&state, user.id, &headers).await?;
     
     Ok((
         StatusCode::CREATED,
         Json(SignupResponse {
             encrypted_seed,
-            token,
+            encrypted_device_creds,
+            token: tokens.token,
+            refresh_token: tokens.refresh_token,
+            expires_in: tokens.expires_in,
         })
     ))
 }
 
+/// The mnemonic a signup imports, given either in the clear or encrypted
+/// with the password, normalized to single spaces
+async fn imported_mnemonic(request: &CreateUserRequest) -> Result<Option<SecretString>> {
+    let mnemonic = match (&request.mnemonic, &request.encrypted_seed) {
+        (Some(_), Some(_)) => {
+            return Err(AppError::Validation("Send either mnemonic or encryptedSeed, not both".to_string()));
+        }
+        (Some(mnemonic), None) => mnemonic.clone(),
+        // Clients can't bind it to an account that doesn't exist yet, so
+        // only the server's own KDF parameters are accepted for it
+        (None, Some(encrypted_seed)) => CryptoService::decrypt_unbound(encrypted_seed, &request.password).await?,
+        (None, None) => return Ok(None),
+    };
+
+    Ok(Some(SecretString::new(
+        mnemonic.expose().split_whitespace().collect::<Vec<_>>().join(" "),
+    )))
+}
+
+/// BIP39 options for a signup: those of the imported mnemonic, whose
+/// language is detected unless given, or those requested for a new one
+fn signup_mnemonic_options(
+    request: &CreateUserRequest,
+    imported: Option<&SecretString>,
+    passphrase: bool,
+) -> Result<MnemonicOptions> {
+    let language = match request.language.as_deref() {
+        Some(name) => Some(MnemonicOptions::parse_language(name).ok_or_else(|| {
+            AppError::Validation(format!(
+                "Unknown mnemonic language, expected one of: {}",
+                MnemonicOptions::language_names().join(", ")
+            ))
+        })?),
+        None => None,
+    };
+
+    let (word_count, language) = match imported {
+        Some(mnemonic) => {
+            let language = language
+                .or_else(|| CryptoService::detect_mnemonic_language(mnemonic))
+                .ok_or_else(|| AppError::Validation("Mnemonic is not in any BIP39 wordlist".to_string()))?;
+            if !CryptoService::validate_mnemonic(mnemonic, language)? {
+                return Err(AppError::Validation("Invalid mnemonic".to_string()));
+            }
+            let word_count = mnemonic.expose().split_whitespace().count();
+            if request.word_count.is_some_and(|requested| requested != word_count) {
+                return Err(AppError::Validation(format!("Mnemonic has {} words", word_count)));
+            }
+            (word_count, language)
+        }
+        None => {
+            let word_count = request.word_count.unwrap_or(MnemonicOptions::default().word_count);
+            if !MnemonicOptions::WORD_COUNTS.contains(&word_count) {
+                return Err(AppError::Validation("Mnemonic must have 12 or 24 words".to_string()));
+            }
+            (word_count, language.unwrap_or(MnemonicOptions::default().language))
+        }
+    };
+
+    Ok(MnemonicOptions { word_count, language, passphrase })
+}
+
+/// Node id and credentials for an imported seed. The node is recovered if
+/// Greenlight already has one for the seed, and registered otherwise. A
+/// node that belongs to another account here is refused.
+async fn import_node(
+    state: &AppState,
+    user_repo: &UserRepository,
+    user_id: Uuid,
+    seed: &SecretBytes,
+) -> Result<(String, DeviceCredentials)> {
+    let greenlight_service = GreenlightService::new(state.config.clone());
+    let node_id = greenlight_service.node_id(seed)?;
+    if user_repo.node_id_exists(&node_id).await? {
+        return Err(AppError::BadRequest("This seed's node already belongs to an account".to_string()));
+    }
+
+    let device_creds = match greenlight_service.recover_node(seed).await {
+        Ok(device_creds) => {
+            tracing::info!("Recovered existing node {} for imported seed of user {}", node_id, user_id);
+            device_creds
+        }
+        Err(AppError::NotFound(_)) => {
+            tracing::debug!("No node to recover for user {}, registering one", user_id);
+            greenlight_service.register_node(seed).await?
+        }
+        Err(e) => return Err(e),
+    };
+
+    Ok((node_id, device_creds))
+}
+
 /// POST /api/v1/users/login
 pub async fn login(
     State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(request): Json<LoginRequest>,
 ) -> Result<Json<LoginResponse>> {
     let user_repo = UserRepository::new(state.db_pool.clone());
+    let ip = client_ip(&headers, Some(peer), &state.config);
+
+    // Refuse throttled attempts before doing any password hashing. The
+    // attempt counts as failed until it succeeds, including a wrong second
+    // factor, so parallel guesses can't overrun the limit.
+    state.login_throttle.reserve(&request.public_key, ip).await?;
 
     // Find user by public key
     let user = user_repo.find_by_public_key(&request.public_key).await?
         .ok_or_else(|| crate::error::AppError::Authentication("Invalid credentials".to_string()))?;
 
     // Verify password
-    if !CryptoService::verify_password(&request.password, &user.password_hash)? {
+    if !CryptoService::verify_password(&request.password, &user.password_hash).await? {
         return Err(crate::error::AppError::Authentication("Invalid credentials".to_string()));
     }
 
-    // Generate JWT token
-    let token = state.jwt_service.generate_token(user.id)?;
+    state.totp_service.check_login(user.id, request.totp_code.as_deref()).await?;
+
+    state.login_throttle.record_success(&request.public_key, ip).await?;
+
+    // Secrets in older formats may need the password to convert
+    if let Err(e) = state.credential_vault.convert_with_password(&state.db_pool, &user, &request.password).await {
+        tracing::warn!("Could not convert device credentials of user {}: {}", user.id, e);
+    }
+    if let Err(e) = reencrypt_seed(&user_repo, &user, &request.password).await {
+        tracing::warn!("Could not re-encrypt the seed of user {}: {}", user.id, e);
+    }
+
+    // Start a session and issue its tokens
+    Ok(Json(start_session(&state, user.id, &headers).await?))
+}
+
+/// POST /auth/challenge - Issue a one-time nonce for signature login
+pub async fn challenge(
+    State(state): State<AppState>,
+    Json(request): Json<ChallengeRequest>,
+) -> Result<Json<ChallengeResponse>> {
+    let public_key = nostr::normalize_pubkey(&request.public_key)
+        .ok_or_else(|| AppError::Validation("public_key must be a 64-character hex Nostr key".to_string()))?;
+
+    let mut nonce = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let nonce = hex::encode(nonce);
+
+    // Issued whether or not the key is registered, so this doesn't reveal accounts
+    let challenge_repo = AuthChallengeRepository::new(state.db_pool.clone());
+    let challenge = challenge_repo
+        .create_challenge(&public_key, &nonce, Duration::seconds(state.config.auth_challenge_ttl_secs))
+        .await?;
+
+    Ok(Json(ChallengeResponse {
+        message: challenge_message(&state, &challenge.nonce),
+        nonce: challenge.nonce,
+        expires_at: challenge.expires_at,
+    }))
+}
+
+/// POST /auth/login/signature - Log in with a BIP340 signature or a signed
+/// Nostr event (kind 22242, `challenge` tag) over an issued nonce
+pub async fn login_with_signature(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(request): Json<SignatureLoginRequest>,
+) -> Result<Json<LoginResponse>> {
+    let (public_key, nonce) = match (request.event, request.signature) {
+        (Some(event), None) => {
+            if event.kind != KIND_CLIENT_AUTH {
+                return Err(AppError::Authentication(format!("Event must be of kind {}", KIND_CLIENT_AUTH)));
+            }
+            if (Utc::now().timestamp() - event.created_at).abs() > state.config.auth_challenge_ttl_secs {
+                return Err(AppError::Authentication("Event is too old or too far in the future".to_string()));
+            }
+            event.verify()?;
+
+            let nonce = event.tag("challenge")
+                .ok_or_else(|| AppError::Authentication("Event has no challenge tag".to_string()))?
+                .to_string();
+            (event.pubkey.to_lowercase(), nonce)
+        }
+        (None, Some(signature)) => {
+            let public_key = request.public_key.as_deref()
+                .and_then(nostr::normalize_pubkey)
+                .ok_or_else(|| AppError::Validation("public_key must be a 64-character hex Nostr key".to_string()))?;
+            let nonce = request.nonce
+                .ok_or_else(|| AppError::Validation("nonce is required".to_string()))?;
+
+            let digest: [u8; 32] = Sha256::digest(challenge_message(&state, &nonce).as_bytes()).into();
+            nostr::verify_signature(&public_key, &digest, &signature)?;
+            (public_key, nonce)
+        }
+        _ => {
+            return Err(AppError::Validation("Provide either a signature or a signed event".to_string()));
+        }
+    };
+
+    // Only someone holding the key gets this far, so the throttle guards the
+    // second factor without letting others lock the account
+    let ip = client_ip(&headers, Some(peer), &state.config);
+    state.login_throttle.reserve(&public_key, ip).await?;
+
+    let user_repo = UserRepository::new(state.db_pool.clone());
+    let user = user_repo.find_by_public_key(&public_key).await?
+        .ok_or_else(|| AppError::Authentication("Invalid credentials".to_string()))?;
+
+    // Checked before the nonce is spent, so a client asked for a code can
+    // retry with the same challenge
+    state.totp_service.check_login(user.id, request.totp_code.as_deref()).await?;
+
+    // Only consumed once the signature checks out, so a leaked nonce can't be burnt
+    let challenge_repo = AuthChallengeRepository::new(state.db_pool.clone());
+    if challenge_repo.consume_challenge(&public_key, &nonce).await?.is_none() {
+        return Err(AppError::Authentication("Unknown, used or expired challenge".to_string()));
+    }
+    state.login_throttle.record_success(&public_key, ip).await?;
+
+    Ok(Json(start_session(&state, user.id, &headers).await?))
+}
+
+fn challenge_message(state: &AppState, nonce: &str) -> String {
+    format!("{} login challenge: {}", state.config.domain, nonce)
+}
+
+/// POST /auth/refresh - Exchange a refresh token for a new access and refresh token
+pub async fn refresh(
+    State(state): State<AppState>,
+    Json(request): Json<RefreshTokenRequest>,
+) -> Result<Json<LoginResponse>> {
+    let session_repo = SessionRepository::new(state.db_pool.clone());
+
+    let mut tx = session_repo.begin().await?;
+    let record = session_repo
+        .find_refresh_token_for_update(&mut tx, &hash_refresh_token(&request.refresh_token))
+        .await?
+        .ok_or_else(|| AppError::Authentication("Invalid refresh token".to_string()))?;
+
+    if record.session_revoked_at.is_some() || record.session_expires_at <= Utc::now() {
+        return Err(AppError::Authentication("Session has been revoked or has expired".to_string()));
+    }
+
+    // A rotated-out token coming back means it was copied; end the session for
+    // both the legitimate client and whoever holds the copy
+    if record.used_at.is_some() {
+        drop(tx);
+        tracing::warn!("Refresh token reuse detected, revoking session {}", record.session_id);
+        session_repo.revoke_session(record.session_id, RevokeReason::RefreshTokenReuse).await?;
+        return Err(AppError::Authentication("Refresh token has already been used".to_string()));
+    }
+
+    let refresh_token = generate_refresh_token();
+    session_repo.mark_refresh_token_used(&mut tx, record.id).await?;
+    session_repo
+        .create_refresh_token(&mut tx, record.session_id, &hash_refresh_token(&refresh_token))
+        .await?;
+    session_repo
+        .touch_session(&mut tx, record.session_id, Utc::now() + Duration::days(state.config.refresh_token_ttl_days))
+        .await?;
+    tx.commit().await?;
+
+    Ok(Json(LoginResponse {
+        token: state.jwt_service.generate_token(record.user_id, record.session_id).await?,
+        refresh_token,
+        expires_in: state.jwt_service.expires_in(),
+    }))
+}
+
+/// POST /auth/logout - Revoke the session a refresh token belongs to
+pub async fn logout(
+    State(state): State<AppState>,
+    Json(request): Json<RefreshTokenRequest>,
+) -> Result<StatusCode> {
+    let session_repo = SessionRepository::new(state.db_pool.clone());
+
+    let mut tx = session_repo.begin().await?;
+    let record = session_repo
+        .find_refresh_token_for_update(&mut tx, &hash_refresh_token(&request.refresh_token))
+        .await?
+        .ok_or_else(|| AppError::Authentication("Invalid refresh token".to_string()))?;
+    drop(tx);
+
+    session_repo.revoke_session(record.session_id, RevokeReason::Logout).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /auth/logout/all - Revoke every session of the authenticated user
+pub async fn logout_all(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<Value>> {
+    let session_repo = SessionRepository::new(state.db_pool.clone());
+    let revoked = session_repo.revoke_all_for_user(user_id, RevokeReason::LogoutAll).await?;
+
+    Ok(Json(json!({ "revoked": revoked })))
+}
+
+/// GET /auth/sessions - Open sessions of the authenticated user
+pub async fn list_sessions(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<Vec<Session>>> {
+    let session_repo = SessionRepository::new(state.db_pool.clone());
+    Ok(Json(session_repo.find_by_user(user_id).await?))
+}
+
+/// POST /auth/password - Change the password, re-encrypting every secret
+/// bound to it and logging out all other sessions
+pub async fn change_password(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    current_session: Option<Extension<CurrentSession>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(request): Json<ChangePasswordRequest>,
+) -> Result<Json<ChangePasswordResponse>> {
+    if request.new_password.expose().len() < 8 {
+        return Err(AppError::Validation("Password must be at least 8 characters".to_string()));
+    }
+    if request.new_password.expose() == request.current_password.expose() {
+        return Err(AppError::Validation("New password must differ from the current one".to_string()));
+    }
+
+    let user_repo = UserRepository::new(state.db_pool.clone());
+    let user = user_repo.find_by_id(user_id).await?
+        .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+
+    // A stolen session must not be a free password oracle
+    let ip = client_ip(&headers, Some(peer), &state.config);
+    state.login_throttle.reserve(&user.public_key, ip).await?;
+    if !CryptoService::verify_password(&request.current_password, &user.password_hash).await? {
+        return Err(AppError::Authentication("Current password is incorrect".to_string()));
+    }
+    state.login_throttle.record_success(&user.public_key, ip).await?;
+
+    let seed_binding = Binding::new(user_id, SecretPurpose::Seed);
+    let encrypted_seed = match &user.encrypted_seed {
+        Some(encrypted_seed) => {
+            let mnemonic = CryptoService::decrypt(encrypted_seed, &request.current_password, Some(&seed_binding)).await?;
+            Some(CryptoService::encrypt(mnemonic.expose(), &request.new_password, Some(&seed_binding)).await?)
+        }
+        None => None,
+    };
+
+    // Device credentials are under the server key, unless they still are in
+    // the legacy password-encrypted format, which would be lost with the
+    // password; they are converted in the same update as the password
+    let converted_device_creds = state.credential_vault
+        .seal_legacy_with_password(&user, &request.current_password)
+        .await?;
+
+    let password_hash = CryptoService::hash_password(&request.new_password).await?;
+    let session_repo = SessionRepository::new(state.db_pool.clone());
+
+    let mut tx = user_repo.begin().await?;
+    let updated = user_repo
+        .update_password_secrets(
+            &mut tx,
+            user_id,
+            &user.password_hash,
+            &password_hash,
+            encrypted_seed.as_deref(),
+            converted_device_creds.as_deref().zip(user.encrypted_device_creds.as_deref()).map(|(encrypted, legacy)| {
+                (legacy, encrypted, state.credential_vault.key_version())
+            }),
+        )
+        .await?;
+    if !updated {
+        return Err(AppError::BadRequest("Password was changed concurrently, try again".to_string()));
+    }
+    let revoked_sessions = session_repo
+        .revoke_other_sessions(
+            &mut tx,
+            user_id,
+            current_session.map(|Extension(CurrentSession(session_id))| session_id),
+            RevokeReason::PasswordChanged,
+        )
+        .await?;
+    tx.commit().await?;
+
+    Ok(Json(ChangePasswordResponse {
+        encrypted_seed,
+        revoked_sessions,
+    }))
+}
+
+/// POST /auth/recover - Regain an account with its BIP39 mnemonic after
+/// losing the password. The mnemonic must derive the account's node id; the
+/// node is then recovered for fresh device credentials and every session
+/// is logged out.
+pub async fn recover_account(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(request): Json<RecoverAccountRequest>,
+) -> Result<Json<RecoverAccountResponse>> {
+    if request.new_password.expose().len() < 8 {
+        return Err(AppError::Validation("Password must be at least 8 characters".to_string()));
+    }
+
+    let ip = client_ip(&headers, Some(peer), &state.config);
+    state.login_throttle.reserve(&request.public_key, ip).await?;
+
+    let user_repo = UserRepository::new(state.db_pool.clone());
+    let user = user_repo.find_by_public_key(&request.public_key).await?
+        .ok_or_else(|| AppError::Authentication("Mnemonic does not match this account".to_string()))?;
+
+    let mnemonic = SecretString::new(request.mnemonic.expose().split_whitespace().collect::<Vec<_>>().join(" "));
+    let seed = match user_seed(&user, &mnemonic, request.passphrase.as_ref()) {
+        Ok(seed) => seed,
+        Err(AppError::Authentication(_)) => {
+            return Err(AppError::Authentication("Mnemonic does not match this account".to_string()));
+        }
+        Err(e) => return Err(e),
+    };
+
+    let greenlight_service = GreenlightService::new(state.config.clone());
+    let expected_node_id = registered_node_id(&state, &greenlight_service, &user).await?
+        .ok_or_else(|| AppError::BadRequest("Account has no registered node to recover".to_string()))?;
+
+    let node_id = greenlight_service.node_id(&seed)?;
+    if node_id != expected_node_id {
+        return Err(AppError::Authentication("Mnemonic does not match this account".to_string()));
+    }
+    state.login_throttle.record_success(&request.public_key, ip).await?;
+    user_repo.record_seed_fingerprint(user.id, &CryptoService::seed_fingerprint(&seed)?).await?;
+
+    // Fresh credentials; whatever was stored may be bound to the lost password
+    let device_creds = greenlight_service.recover_node(&seed).await?;
+    let encrypted_device_creds = state.credential_vault.seal(user.id, &device_creds.creds).await?;
+    let encrypted_seed = CryptoService::encrypt(
+        mnemonic.expose(),
+        &request.new_password,
+        Some(&Binding::new(user.id, SecretPurpose::Seed)),
+    ).await?;
+    let password_hash = CryptoService::hash_password(&request.new_password).await?;
+
+    let session_repo = SessionRepository::new(state.db_pool.clone());
+    let mut tx = user_repo.begin().await?;
+    user_repo
+        .recover_account(
+            &mut tx,
+            user.id,
+            &password_hash,
+            &encrypted_seed,
+            &encrypted_device_creds,
+            state.credential_vault.key_version(),
+            &node_id,
+        )
+        .await?;
+    session_repo
+        .revoke_other_sessions(&mut tx, user.id, None, RevokeReason::AccountRecovered)
+        .await?;
+    tx.commit().await?;
+
+    tracing::info!("Account {} recovered with its mnemonic", user.id);
+
+    Ok(Json(RecoverAccountResponse {
+        encrypted_seed,
+        session: start_session(&state, user.id, &headers).await?,
+    }))
+}
+
+/// The node id recorded for the account, or for accounts registered before
+/// node ids were recorded, the id reported by the node itself
+async fn registered_node_id(
+    state: &AppState,
+    greenlight_service: &GreenlightService,
+    user: &User,
+) -> Result<Option<String>> {
+    let user_repo = UserRepository::new(state.db_pool.clone());
+    if let Some(node_id) = user_repo.get_node_id(user.id).await? {
+        return Ok(Some(node_id));
+    }
+
+    // Legacy password-encrypted credentials are not readable without the password
+    let device_creds = match state.credential_vault.open_for(user).await {
+        Ok(device_creds) => device_creds,
+        Err(_) => return Ok(None),
+    };
+
+    match greenlight_service.get_node_info(&device_creds).await {
+        Ok(info) => Ok(info.get("node_id").and_then(|id| id.as_str()).map(str::to_string)),
+        Err(e) => {
+            tracing::warn!("Could not read node id for user {}: {}", user.id, e);
+            Ok(None)
+        }
+    }
+}
+
+/// Rewrite the stored seed in the current envelope format and KDF, bound
+/// to the account, while the password is at hand
+pub async fn reencrypt_seed(user_repo: &UserRepository, user: &User, password: &SecretString) -> Result<()> {
+    let Some(encrypted_seed) = user.encrypted_seed.as_deref() else {
+        return Ok(());
+    };
+
+    let binding = Binding::new(user.id, SecretPurpose::Seed);
+    if let Some(reencrypted) = CryptoService::reencrypt(encrypted_seed, password, Some(&binding)).await? {
+        if user_repo.replace_encrypted_seed(user.id, encrypted_seed, &reencrypted).await? {
+            tracing::info!("Re-encrypted the seed of user {}", user.id);
+        }
+    }
+
+    Ok(())
+}
+
+/// GET /.well-known/jwks.json - Public keys for verifying access tokens
+pub async fn jwks(State(state): State<AppState>) -> Json<Value> {
+    Json(state.jwt_service.jwks())
+}
+
+/// Create a session with its first refresh token and an access token for it
+async fn start_session(state: &AppState, user_id: Uuid, headers: &HeaderMap) -> Result<LoginResponse> {
+    let session_repo = SessionRepository::new(state.db_pool.clone());
+    let user_agent = headers.get(USER_AGENT).and_then(|value| value.to_str().ok());
+    let refresh_token = generate_refresh_token();
+
+    let mut tx = session_repo.begin().await?;
+    let session = session_repo
+        .create_session(&mut tx, user_id, user_agent, Utc::now() + Duration::days(state.config.refresh_token_ttl_days))
+        .await?;
+    session_repo
+        .create_refresh_token(&mut tx, session.id, &hash_refresh_token(&refresh_token))
+        .await?;
+    tx.commit().await?;
+
+    Ok(LoginResponse {
+        token: state.jwt_service.generate_token(user_id, session.id).await?,
+        refresh_token,
+        expires_in: state.jwt_service.expires_in(),
+    })
+}
+
+fn generate_refresh_token() -> String {
+    let mut token = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut token);
+    general_purpose::URL_SAFE_NO_PAD.encode(token)
+}
 
-    Ok(Json(LoginResponse { token }))
+/// Only hashes are stored, so a database leak doesn't hand out sessions
+fn hash_refresh_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
 }
@@ -0,0 +1,170 @@
+use axum::{
+    extract::{State, Extension, Path, Query},
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use serde::Serialize;
+use serde_json::json;
+use uuid::Uuid;
+use crate::{AppState, error::AppError};
+use crate::models::{LnurlCallbackQuery, LnurlInvoice, LnurlInvoiceRepository, Username, UsernameRepository, UserRepository};
+use crate::services::GreenlightService;
+
+/// Errors on the public LNURL routes use the LUD-06 `{"status": "ERROR", "reason"}`
+/// shape that wallets understand, instead of our usual error body.
+pub struct LnurlError(AppError);
+
+impl From<AppError> for LnurlError {
+    fn from(error: AppError) -> Self {
+        Self(error)
+    }
+}
+
+impl IntoResponse for LnurlError {
+    fn into_response(self) -> Response {
+        let (status, reason) = match self.0 {
+            AppError::Validation(_) | AppError::BadRequest(_) => (StatusCode::BAD_REQUEST, self.0.to_string()),
+            AppError::NotFound(_) => (StatusCode::NOT_FOUND, self.0.to_string()),
+            e => {
+                tracing::error!("LNURL request failed: {}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, "Unable to create invoice".to_string())
+            }
+        };
+
+        (status, Json(json!({ "status": "ERROR", "reason": reason }))).into_response()
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PayRequestResponse {
+    pub tag: &'static str,
+    pub callback: String,
+    pub min_sendable: u64,
+    pub max_sendable: u64,
+    pub metadata: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment_allowed: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct CallbackResponse {
+    pub pr: String,
+    pub routes: Vec<serde_json::Value>,
+}
+
+/// GET /.well-known/lnurlp/:username - Lightning Address (LUD-16) pay request
+pub async fn pay_request(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<PayRequestResponse>, LnurlError> {
+    let username = find_username(&state, &name).await?;
+
+    Ok(Json(PayRequestResponse {
+        tag: "payRequest",
+        callback: format!(
+            "{}/lnurlp/{}/callback",
+            state.config.public_url.trim_end_matches('/'),
+            username.username
+        ),
+        min_sendable: state.config.lnurl_min_sendable_msat,
+        max_sendable: state.config.lnurl_max_sendable_msat,
+        metadata: metadata(&state, &username),
+        comment_allowed: (state.config.lnurl_comment_allowed > 0).then_some(state.config.lnurl_comment_allowed),
+    }))
+}
+
+/// GET /lnurlp/:username/callback?amount=&comment= - Issue a BOLT11 invoice on the owner's node
+pub async fn pay_callback(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<LnurlCallbackQuery>,
+) -> Result<Json<CallbackResponse>, LnurlError> {
+    let username = find_username(&state, &name).await?;
+
+    if query.amount < state.config.lnurl_min_sendable_msat || query.amount > state.config.lnurl_max_sendable_msat {
+        return Err(AppError::Validation(format!(
+            "Amount must be between {} and {} msat",
+            state.config.lnurl_min_sendable_msat, state.config.lnurl_max_sendable_msat
+        )).into());
+    }
+
+    let comment = query.comment.filter(|comment| !comment.is_empty());
+    if let Some(comment) = &comment {
+        if comment.chars().count() > state.config.lnurl_comment_allowed {
+            return Err(AppError::Validation(format!(
+                "Comment must be at most {} characters",
+                state.config.lnurl_comment_allowed
+            )).into());
+        }
+    }
+
+    let user_repo = UserRepository::new(state.db_pool.clone());
+    let user = user_repo.find_by_id(username.user_id).await?
+        .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+
+    if user.encrypted_device_creds.is_none() {
+        return Err(AppError::BadRequest("Recipient has no node to receive payments".to_string()).into());
+    }
+    let device_creds = state.credential_vault.open_for(&user).await?;
+
+    // The invoice commits to sha256(metadata) as its description hash
+    let greenlight_service = GreenlightService::new(state.config.clone());
+    let invoice = greenlight_service
+        .create_invoice(
+            &device_creds,
+            Some(query.amount),
+            &metadata(&state, &username),
+            &format!("lnurl-{}-{}", username.username, Uuid::new_v4()),
+            true,
+        )
+        .await?;
+
+    let lnurl_repo = LnurlInvoiceRepository::new(state.db_pool.clone());
+    lnurl_repo
+        .create_invoice(
+            user.id,
+            &username.username,
+            query.amount as i64,
+            &invoice.payment_hash,
+            &invoice.bolt11,
+            comment.as_deref(),
+        )
+        .await?;
+
+    Ok(Json(CallbackResponse {
+        pr: invoice.bolt11,
+        routes: Vec::new(),
+    }))
+}
+
+/// GET /lnurl/invoices - Invoices issued for the authenticated user's Lightning Addresses
+pub async fn list_invoices(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> crate::error::Result<Json<Vec<LnurlInvoice>>> {
+    let lnurl_repo = LnurlInvoiceRepository::new(state.db_pool.clone());
+    Ok(Json(lnurl_repo.find_by_user(user_id).await?))
+}
+
+async fn find_username(state: &AppState, name: &str) -> Result<Username, LnurlError> {
+    let label = state.username_policy.normalize(name)
+        .map_err(|_| AppError::NotFound("Unknown Lightning Address".to_string()))?;
+
+    let username_repo = UsernameRepository::new(state.db_pool.clone());
+    let username = username_repo.find_by_username(&label).await?
+        .ok_or_else(|| AppError::NotFound("Unknown Lightning Address".to_string()))?;
+
+    Ok(username)
+}
+
+/// LUD-06 metadata; the exact string is hashed into the invoice, so it must be
+/// produced identically for the pay request and the callback
+fn metadata(state: &AppState, username: &Username) -> String {
+    let address = format!("{}@{}", username.username, state.config.domain);
+    json!([
+        ["text/plain", format!("Payment to {}", address)],
+        ["text/identifier", address],
+    ])
+    .to_string()
+}
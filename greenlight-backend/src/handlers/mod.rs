@@ -1,3 +1,11 @@
+pub mod admin;
+pub mod api_keys;
 pub mod auth;
+pub mod lnurl;
 pub mod node;
+pub mod nostr;
+pub mod payments;
+pub mod spending;
+pub mod totp;
+pub mod username;
 pub mod websocket;
@@ -1,26 +1,33 @@
 use axum::{
-    extract::{State, Extension},
-    response::Json,
+    extract::{State, Extension, Query},
+    http::header,
+    response::{IntoResponse, Json, Response},
 };
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
-use base64::{engine::general_purpose, Engine as _};
 use crate::{AppState, error::Result};
-use crate::models::UserRepository;
-use crate::services::{CryptoService, GreenlightService};
+use crate::models::{User, UsernameRepository, UserRepository};
+use crate::handlers::auth::reencrypt_seed;
+use crate::services::{payment_uri, Binding, CryptoService, GreenlightService, SecretBytes, SecretPurpose, SecretString};
+use crate::services::greenlight::DeviceCredentials;
+use crate::services::payment_uri::PaymentUri;
 
 #[derive(Deserialize)]
 pub struct NodeRegisterRequest {
     #[serde(rename = "encryptedSeed")]
     pub encrypted_seed: String,
-    pub password: String,
+    pub password: SecretString,
+    /// Required if the account's seed was created with a BIP39 passphrase
+    pub passphrase: Option<SecretString>,
 }
 
 #[derive(Deserialize)]
 pub struct NodeRecoverRequest {
     #[serde(rename = "encryptedSeed")]
     pub encrypted_seed: String,
-    pub password: String,
+    pub password: SecretString,
+    /// Required if the account's seed was created with a BIP39 passphrase
+    pub passphrase: Option<SecretString>,
 }
 
 #[derive(Serialize)]
@@ -35,6 +42,25 @@ pub struct CreateOfferRequest {
     pub description: String,
 }
 
+#[derive(Deserialize)]
+pub struct PaymentUriQuery {
+    pub amount_sat: Option<u64>,
+    pub label: Option<String>,
+    pub message: Option<String>,
+    /// `json` (default), `svg` or `png`
+    pub format: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PaymentUriResponse {
+    pub uri: String,
+    pub address: String,
+    pub bolt11: String,
+    pub bolt12: String,
+    pub payment_hash: String,
+    pub amount_sat: Option<u64>,
+}
+
 /// POST /api/v1/node/register
 pub async fn register_node(
     State(state): State<AppState>,
@@ -56,30 +82,74 @@ pub async fn register_node(
     }
 
     // Decrypt the seed
-    let mnemonic = CryptoService::decrypt(&request.encrypted_seed, &request.password)?;
+    let mnemonic = CryptoService::decrypt(
+        &request.encrypted_seed,
+        &request.password,
+        Some(&Binding::new(user_id, SecretPurpose::Seed)),
+    ).await?;
     
-    // Validate the mnemonic
-    if !CryptoService::validate_mnemonic(&mnemonic)? {
-        return Err(crate::error::AppError::Validation("Invalid mnemonic".to_string()));
-    }
+    // Convert mnemonic to seed as it was created at signup
+    let seed = user_seed(&user, &mnemonic, request.passphrase.as_ref())?;
+    user_repo.record_seed_fingerprint(user_id, &CryptoService::seed_fingerprint(&seed)?).await?;
 
-    // Convert mnemonic to seed
-    let seed = CryptoService::mnemonic_to_seed(&mnemonic)?;
+    // The password is known to be right, so the stored seed can be upgraded
+    if let Err(e) = reencrypt_seed(&user_repo, &user, &request.password).await {
+        tracing::warn!("Could not re-encrypt the seed of user {}: {}", user.id, e);
+    }
 
     // Register node with Greenlight
     let device_creds = greenlight_service.register_node(&seed).await?;
 
-    // Store device credentials as base64 for simplicity (in production, encrypt properly)
-    let creds_base64 = general_purpose::STANDARD.encode(&device_creds.creds);
+    // Remember which node the seed controls, for mnemonic account recovery
+    user_repo.update_node_id(user_id, &greenlight_service.node_id(&seed)?).await?;
 
-    // Store credentials in database
-    user_repo.update_device_credentials(user_id, &creds_base64).await?;
+    // Store credentials under the server key, and return a copy the client can open with the password
+    let sealed_creds = state.credential_vault.seal(user_id, &device_creds.creds).await?;
+    user_repo.update_device_credentials(user_id, &sealed_creds, state.credential_vault.key_version()).await?;
 
     Ok(Json(NodeCredentialsResponse {
-        encrypted_device_creds: creds_base64,
+        encrypted_device_creds: encrypt_for_client(user_id, &device_creds, &request.password).await?,
     }))
 }
 
+/// Derive `user`'s seed from their mnemonic with the BIP39 options recorded
+/// at signup. A recorded fingerprint must match, so that a mistyped
+/// passphrase can't register or recover a node for some other seed.
+pub fn user_seed(user: &User, mnemonic: &SecretString, passphrase: Option<&SecretString>) -> Result<SecretBytes> {
+    let options = user.mnemonic_options()?;
+    let passphrase = passphrase.filter(|passphrase| !passphrase.expose().is_empty());
+    match (options.passphrase, passphrase.is_some()) {
+        (true, false) => {
+            return Err(crate::error::AppError::Validation("This account's seed needs its BIP39 passphrase".to_string()));
+        }
+        (false, true) => {
+            return Err(crate::error::AppError::Validation("This account's seed has no BIP39 passphrase".to_string()));
+        }
+        _ => {}
+    }
+
+    // Validate the mnemonic
+    if !CryptoService::validate_mnemonic(mnemonic, options.language)? {
+        return Err(crate::error::AppError::Validation("Invalid mnemonic".to_string()));
+    }
+    let seed = CryptoService::mnemonic_to_seed(mnemonic, options.language, passphrase)?;
+
+    match &user.seed_fingerprint {
+        Some(fingerprint) if *fingerprint != CryptoService::seed_fingerprint(&seed)? => Err(
+            crate::error::AppError::Authentication("Mnemonic and passphrase do not derive this account's seed".to_string()),
+        ),
+        _ => Ok(seed),
+    }
+}
+
+/// The credentials as returned to the client: JSON encrypted with the user's password
+pub async fn encrypt_for_client(user_id: Uuid, device_creds: &DeviceCredentials, password: &SecretString) -> Result<String> {
+    let creds_json = serde_json::to_string(device_creds)
+        .map_err(|e| crate::error::AppError::Internal(format!("Failed to serialize credentials: {}", e)))?;
+
+    CryptoService::encrypt(&creds_json, password, Some(&Binding::new(user_id, SecretPurpose::DeviceCredentials))).await
+}
+
 /// POST /api/v1/node/recover
 pub async fn recover_node(
     State(state): State<AppState>,
@@ -89,31 +159,32 @@ pub async fn recover_node(
     let user_repo = UserRepository::new(state.db_pool.clone());
     let greenlight_service = GreenlightService::new(state.config.clone());
 
+    let user = user_repo.find_by_id(user_id).await?
+        .ok_or_else(|| crate::error::AppError::NotFound("User not found".to_string()))?;
+
     // Decrypt the seed
-    let mnemonic = CryptoService::decrypt(&request.encrypted_seed, &request.password)?;
-    
-    // Validate the mnemonic
-    if !CryptoService::validate_mnemonic(&mnemonic)? {
-        return Err(crate::error::AppError::Validation("Invalid mnemonic".to_string()));
-    }
+    let mnemonic = CryptoService::decrypt(
+        &request.encrypted_seed,
+        &request.password,
+        Some(&Binding::new(user_id, SecretPurpose::Seed)),
+    ).await?;
 
-    // Convert mnemonic to seed
-    let seed = CryptoService::mnemonic_to_seed(&mnemonic)?;
+    // Convert mnemonic to seed as it was created at signup
+    let seed = user_seed(&user, &mnemonic, request.passphrase.as_ref())?;
+    user_repo.record_seed_fingerprint(user_id, &CryptoService::seed_fingerprint(&seed)?).await?;
 
     // Recover node with Greenlight
     let device_creds = greenlight_service.recover_node(&seed).await?;
 
-    // Encrypt device credentials with user's password
-    let creds_json = serde_json::to_string(&device_creds)
-        .map_err(|e| crate::error::AppError::Internal(format!("Failed to serialize credentials: {}", e)))?;
-    
-    let encrypted_device_creds = CryptoService::encrypt(&creds_json, &request.password)?;
+    // Remember which node the seed controls, for mnemonic account recovery
+    user_repo.update_node_id(user_id, &greenlight_service.node_id(&seed)?).await?;
 
-    // Update credentials in database
-    user_repo.update_device_credentials(user_id, &encrypted_device_creds).await?;
+    // Store credentials under the server key, and return a copy the client can open with the password
+    let sealed_creds = state.credential_vault.seal(user_id, &device_creds.creds).await?;
+    user_repo.update_device_credentials(user_id, &sealed_creds, state.credential_vault.key_version()).await?;
 
     Ok(Json(NodeCredentialsResponse {
-        encrypted_device_creds,
+        encrypted_device_creds: encrypt_for_client(user_id, &device_creds, &request.password).await?,
     }))
 }
 
@@ -122,17 +193,7 @@ pub async fn get_node_info(
     State(state): State<AppState>,
     Extension(user_id): Extension<Uuid>,
 ) -> Result<Json<serde_json::Value>> {
-    let user_repo = UserRepository::new(state.db_pool.clone());
-    let user = user_repo.find_by_id(user_id).await?
-        .ok_or_else(|| crate::error::AppError::NotFound("User not found".to_string()))?;
-
-    let encrypted_device_creds = user.encrypted_device_creds
-        .ok_or_else(|| crate::error::AppError::BadRequest("No node registered for this user".to_string()))?;
-
-    // For now, we'll assume the device creds are stored in a simple format
-    // In production, you'd decrypt these with the user's password
-    let device_creds = general_purpose::STANDARD.decode(&encrypted_device_creds)
-        .map_err(|e| crate::error::AppError::Internal(format!("Failed to decode device credentials: {}", e)))?;
+    let device_creds = user_device_creds(&state, user_id).await?;
 
     let greenlight_service = GreenlightService::new(state.config.clone());
     let node_info = greenlight_service.get_node_info(&device_creds).await?;
@@ -140,20 +201,21 @@ pub async fn get_node_info(
     Ok(Json(node_info))
 }
 
-/// GET /node/balance - Get real node balance
-pub async fn get_balance(
-    State(state): State<AppState>,
-    Extension(user_id): Extension<Uuid>,
-) -> Result<Json<serde_json::Value>> {
+/// Device credentials of `user_id`'s node, as the node handlers read them
+pub async fn user_device_creds(state: &AppState, user_id: Uuid) -> Result<Vec<u8>> {
     let user_repo = UserRepository::new(state.db_pool.clone());
     let user = user_repo.find_by_id(user_id).await?
         .ok_or_else(|| crate::error::AppError::NotFound("User not found".to_string()))?;
 
-    let encrypted_device_creds = user.encrypted_device_creds
-        .ok_or_else(|| crate::error::AppError::BadRequest("No node registered for this user".to_string()))?;
+    state.credential_vault.open_for(&user).await
+}
 
-    let device_creds = general_purpose::STANDARD.decode(&encrypted_device_creds)
-        .map_err(|e| crate::error::AppError::Internal(format!("Failed to decode device credentials: {}", e)))?;
+/// GET /node/balance - Get real node balance
+pub async fn get_balance(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<serde_json::Value>> {
+    let device_creds = user_device_creds(&state, user_id).await?;
 
     let greenlight_service = GreenlightService::new(state.config.clone());
     let balance = greenlight_service.get_balance(&device_creds).await?;
@@ -167,15 +229,7 @@ pub async fn create_offer(
     Extension(user_id): Extension<Uuid>,
     Json(request): Json<serde_json::Value>,
 ) -> Result<Json<serde_json::Value>> {
-    let user_repo = UserRepository::new(state.db_pool.clone());
-    let user = user_repo.find_by_id(user_id).await?
-        .ok_or_else(|| crate::error::AppError::NotFound("User not found".to_string()))?;
-
-    let encrypted_device_creds = user.encrypted_device_creds
-        .ok_or_else(|| crate::error::AppError::BadRequest("No node registered for this user".to_string()))?;
-
-    let device_creds = general_purpose::STANDARD.decode(&encrypted_device_creds)
-        .map_err(|e| crate::error::AppError::Internal(format!("Failed to decode device credentials: {}", e)))?;
+    let device_creds = user_device_creds(&state, user_id).await?;
 
     let amount_msat = request.get("amount_msat").and_then(|v| v.as_u64());
     let description = request.get("description").and_then(|v| v.as_str()).unwrap_or("Bolt12 offer").to_string();
@@ -190,3 +244,88 @@ pub async fn create_offer(
 
     Ok(Json(offer))
 }
+
+/// GET /node/payment-uri - BIP21 URI with on-chain, BOLT11 and BOLT12 payment options, optionally as a QR code
+pub async fn get_payment_uri(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Query(query): Query<PaymentUriQuery>,
+) -> Result<Response> {
+    let format = query.format.as_deref().unwrap_or("json");
+    if !matches!(format, "json" | "svg" | "png") {
+        return Err(crate::error::AppError::Validation("format must be json, svg or png".to_string()));
+    }
+
+    // 21M BTC
+    if query.amount_sat.is_some_and(|amount_sat| amount_sat == 0 || amount_sat > 2_100_000_000_000_000) {
+        return Err(crate::error::AppError::Validation("amount_sat must be between 1 and 2100000000000000".to_string()));
+    }
+
+    let label = query.label.filter(|label| !label.is_empty());
+    let message = query.message.filter(|message| !message.is_empty());
+    if label.iter().chain(message.iter()).any(|text| text.chars().count() > 256) {
+        return Err(crate::error::AppError::Validation("label and message must be at most 256 characters".to_string()));
+    }
+
+    let device_creds = user_device_creds(&state, user_id).await?;
+
+    let greenlight_service = GreenlightService::new(state.config.clone());
+    let address = greenlight_service.new_address(&device_creds).await?;
+
+    let description = message.clone().or_else(|| label.clone()).unwrap_or_else(|| "Payment".to_string());
+    let invoice = greenlight_service
+        .create_invoice(
+            &device_creds,
+            query.amount_sat.map(|amount_sat| amount_sat * 1000),
+            &description,
+            &format!("payment-uri-{}", Uuid::new_v4()),
+            false,
+        )
+        .await?;
+
+    // Reuse the offer behind the user's primary username. Accounts without one
+    // get an offer for any amount, created on first use and kept from then on.
+    let username_repo = UsernameRepository::new(state.db_pool.clone());
+    let user_repo = UserRepository::new(state.db_pool.clone());
+    let bolt12 = match username_repo.find_primary(user_id).await? {
+        Some(username) => username.offer,
+        None => match user_repo.get_payment_offer(user_id).await? {
+            Some(offer) => offer,
+            None => {
+                let offer = greenlight_service
+                    .create_offer(&device_creds, CreateOfferRequest { amount_msat: None, description: "Payment".to_string() })
+                    .await?;
+                let offer = offer.get("bolt12")
+                    .and_then(|bolt12| bolt12.as_str())
+                    .ok_or_else(|| crate::error::AppError::Greenlight("Node returned no offer".to_string()))?;
+                user_repo.set_payment_offer(user_id, offer).await?
+            }
+        },
+    };
+
+    let uri = PaymentUri {
+        address: address.clone(),
+        amount_sat: query.amount_sat,
+        label,
+        message,
+        bolt11: Some(invoice.bolt11.clone()),
+        bolt12: Some(bolt12.clone()),
+    }
+    .to_uri();
+
+    let response = match format {
+        "svg" => ([(header::CONTENT_TYPE, "image/svg+xml")], payment_uri::render_svg(&uri)?).into_response(),
+        "png" => ([(header::CONTENT_TYPE, "image/png")], payment_uri::render_png(&uri, 4)?).into_response(),
+        _ => Json(PaymentUriResponse {
+            uri,
+            address,
+            bolt11: invoice.bolt11,
+            bolt12,
+            payment_hash: invoice.payment_hash,
+            amount_sat: query.amount_sat,
+        })
+        .into_response(),
+    };
+
+    Ok(response)
+}
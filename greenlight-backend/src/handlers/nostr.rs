@@ -0,0 +1,97 @@
+use axum::{
+    extract::{State, Extension, Query},
+    http::header::ACCESS_CONTROL_ALLOW_ORIGIN,
+    response::{IntoResponse, Json},
+};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use uuid::Uuid;
+use crate::{AppState, error::{AppError, Result}};
+use crate::models::{NostrRelaysRequest, UsernameRepository, UserRepository};
+
+const MAX_RELAYS: usize = 10;
+
+#[derive(Deserialize)]
+pub struct Nip05Query {
+    pub name: Option<String>,
+}
+
+/// GET /.well-known/nostr.json?name= - NIP-05 identity for `name@domain`
+pub async fn nip05(
+    State(state): State<AppState>,
+    Query(query): Query<Nip05Query>,
+) -> Result<impl IntoResponse> {
+    let mut names = Map::new();
+    let mut relays = Map::new();
+
+    let label = query.name.as_deref().and_then(|name| state.username_policy.normalize(name).ok());
+    if let Some(label) = label {
+        let username_repo = UsernameRepository::new(state.db_pool.clone());
+        let user_repo = UserRepository::new(state.db_pool.clone());
+
+        if let Some(username) = username_repo.find_by_username(&label).await? {
+            let user = user_repo.find_by_id(username.user_id).await?
+                .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+
+            // Only keys that are valid Nostr pubkeys can be vouched for
+            let pubkey = user.public_key.to_lowercase();
+            if pubkey.len() == 64 && pubkey.chars().all(|c| c.is_ascii_hexdigit()) {
+                let user_relays = user_repo.get_nostr_relays(user.id).await?;
+                if !user_relays.is_empty() {
+                    relays.insert(pubkey.clone(), json!(user_relays));
+                }
+                names.insert(username.username, Value::String(pubkey));
+            }
+        }
+    }
+
+    let mut document = json!({ "names": names });
+    if !relays.is_empty() {
+        document["relays"] = Value::Object(relays);
+    }
+
+    // NIP-05 documents are fetched by web clients on any origin; keep this
+    // route readable cross-origin regardless of the API-wide CORS policy
+    Ok(([(ACCESS_CONTROL_ALLOW_ORIGIN, "*")], Json(document)))
+}
+
+/// GET /nostr/relays - Relay hints published with the user's NIP-05 identity
+pub async fn get_relays(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<NostrRelaysRequest>> {
+    let user_repo = UserRepository::new(state.db_pool.clone());
+    let relays = user_repo.get_nostr_relays(user_id).await?;
+
+    Ok(Json(NostrRelaysRequest { relays }))
+}
+
+/// PUT /nostr/relays - Replace the user's relay hints
+pub async fn update_relays(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Json(request): Json<NostrRelaysRequest>,
+) -> Result<Json<NostrRelaysRequest>> {
+    if request.relays.len() > MAX_RELAYS {
+        return Err(AppError::Validation(format!("At most {} relays are allowed", MAX_RELAYS)));
+    }
+
+    let mut relays: Vec<String> = Vec::with_capacity(request.relays.len());
+    for relay in request.relays {
+        let relay = relay.trim().to_string();
+        let valid = (relay.starts_with("wss://") || relay.starts_with("ws://"))
+            && relay.len() <= 255
+            && !relay.contains(char::is_whitespace);
+        if !valid {
+            return Err(AppError::Validation(format!("Invalid relay URL: {}", relay)));
+        }
+        if !relays.contains(&relay) {
+            relays.push(relay);
+        }
+    }
+
+    let user_repo = UserRepository::new(state.db_pool.clone());
+    user_repo.update_nostr_relays(user_id, &relays).await?;
+
+    Ok(Json(NostrRelaysRequest { relays }))
+}
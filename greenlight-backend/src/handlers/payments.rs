@@ -0,0 +1,235 @@
+use axum::{
+    extract::{State, Extension, Query},
+    response::Json,
+};
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+use uuid::Uuid;
+use crate::{AppState, error::{AppError, Result}};
+use crate::handlers::node::user_device_creds;
+use crate::models::{DestinationKind, OutgoingPayment, PaymentKind, SpendingPolicy, SpendingRepository};
+use crate::services::dns::{self, parse_bip353_name};
+use crate::services::greenlight::PaymentRequestKind;
+use crate::services::spending_policy::{self, SpendRequest};
+use crate::services::GreenlightService;
+
+const MAX_PAYMENTS_LISTED: i64 = 500;
+
+#[derive(Deserialize)]
+pub struct PayRequest {
+    /// BOLT11 invoice, BOLT12 offer or BIP353 name (`₿user@domain`)
+    pub destination: String,
+    /// Required for invoices and offers without an amount
+    pub amount_msat: Option<u64>,
+    /// Routing fee cap; defaults to the policy's cap
+    pub max_fee_msat: Option<u64>,
+    /// Note sent to the recipient of a BOLT12 payment
+    pub payer_note: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct WithdrawRequest {
+    pub address: String,
+    pub amount_sat: u64,
+    /// Defaults to the policy's cap if there is one, otherwise the node's estimate
+    pub feerate_sat_vb: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct ListPaymentsQuery {
+    pub limit: Option<i64>,
+}
+
+/// POST /node/pay - Pay an invoice, offer or BIP353 name, subject to the
+/// user's spending policy
+pub async fn pay(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Json(request): Json<PayRequest>,
+) -> Result<Json<OutgoingPayment>> {
+    let device_creds = user_device_creds(&state, user_id).await?;
+    let greenlight_service = GreenlightService::new(state.config.clone());
+
+    let mut identities = Vec::new();
+    let payment_request = if request.destination.contains('@') {
+        let (user, domain) = parse_bip353_name(&request.destination)
+            .ok_or_else(|| AppError::Validation("Invalid BIP353 name".to_string()))?;
+        let resolved = dns::resolve_bip353_offer(&state.config.dns_resolver, &request.destination).await?;
+        // An unvalidated answer could have been forged, so the name alone
+        // must not satisfy the allowlist; the offer's node id still can
+        if resolved.authenticated {
+            identities.push((DestinationKind::Bip353, format!("{}@{}", user, domain)));
+        } else {
+            tracing::warn!("BIP353 answer for {} was not DNSSEC validated", request.destination);
+        }
+        resolved.offer
+    } else {
+        request.destination.trim().to_string()
+    };
+
+    let decoded = greenlight_service.decode_payment_request(&device_creds, &payment_request).await?;
+    identities.push((DestinationKind::NodeId, decoded.node_id.clone()));
+
+    let amount_msat = match (decoded.amount_msat, request.amount_msat) {
+        (Some(invoice_msat), Some(requested_msat)) if invoice_msat != requested_msat => {
+            return Err(AppError::Validation("amount_msat does not match the amount requested by the recipient".to_string()));
+        }
+        (Some(amount_msat), _) | (None, Some(amount_msat)) => amount_msat,
+        (None, None) => {
+            return Err(AppError::Validation("amount_msat is required when the recipient sets no amount".to_string()));
+        }
+    };
+    if amount_msat == 0 {
+        return Err(AppError::Validation("amount_msat must be positive".to_string()));
+    }
+
+    let (payment, spend) = authorize_spend(
+        &state,
+        user_id,
+        &request.destination,
+        SpendRequest {
+            kind: PaymentKind::Lightning,
+            amount_msat,
+            max_fee_msat: request.max_fee_msat,
+            feerate_sat_vb: None,
+            identities,
+        },
+    )
+    .await?;
+
+    let result = match decoded.kind {
+        PaymentRequestKind::Bolt12Offer => {
+            match greenlight_service
+                .fetch_invoice(&device_creds, &payment_request, Some(amount_msat), request.payer_note.as_deref())
+                .await
+            {
+                Ok(invoice) => greenlight_service.pay(&device_creds, &invoice, None, spend.max_fee_msat).await,
+                Err(e) => Err(e),
+            }
+        }
+        PaymentRequestKind::Bolt11 => {
+            let amount_msat = decoded.amount_msat.is_none().then_some(amount_msat);
+            greenlight_service.pay(&device_creds, &payment_request, amount_msat, spend.max_fee_msat).await
+        }
+    };
+
+    let spending_repo = SpendingRepository::new(state.db_pool.clone());
+    match result {
+        Ok(node_payment) => {
+            let fee_msat = node_payment.amount_sent_msat.saturating_sub(node_payment.amount_msat) as i64;
+            Ok(Json(spending_repo
+                .complete_payment(payment.id, fee_msat, Some(&node_payment.payment_hash), None)
+                .await?))
+        }
+        Err(e) => {
+            spending_repo.fail_payment(payment.id, &e.to_string()).await?;
+            Err(e)
+        }
+    }
+}
+
+/// POST /node/withdraw - Send funds on-chain, subject to the user's spending policy
+pub async fn withdraw(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Json(request): Json<WithdrawRequest>,
+) -> Result<Json<OutgoingPayment>> {
+    let address = spending_policy::normalize_destination(DestinationKind::Address, &request.address, state.config.gl_network)
+        .ok_or_else(|| AppError::Validation(format!("Invalid address for {}", state.config.gl_network)))?;
+    if request.amount_sat == 0 {
+        return Err(AppError::Validation("amount_sat must be positive".to_string()));
+    }
+    let amount_msat = request.amount_sat
+        .checked_mul(1000)
+        .ok_or_else(|| AppError::Validation("amount_sat is too large".to_string()))?;
+
+    let device_creds = user_device_creds(&state, user_id).await?;
+
+    let (payment, spend) = authorize_spend(
+        &state,
+        user_id,
+        &address,
+        SpendRequest {
+            kind: PaymentKind::Onchain,
+            amount_msat,
+            max_fee_msat: None,
+            feerate_sat_vb: request.feerate_sat_vb,
+            identities: vec![(DestinationKind::Address, address.clone())],
+        },
+    )
+    .await?;
+
+    let greenlight_service = GreenlightService::new(state.config.clone());
+    let spending_repo = SpendingRepository::new(state.db_pool.clone());
+    match greenlight_service
+        .withdraw(&device_creds, &address, request.amount_sat, spend.feerate_sat_vb)
+        .await
+    {
+        Ok(txid) => Ok(Json(spending_repo.complete_payment(payment.id, 0, None, Some(&txid)).await?)),
+        Err(e) => {
+            spending_repo.fail_payment(payment.id, &e.to_string()).await?;
+            Err(e)
+        }
+    }
+}
+
+/// GET /node/payments - Recent payment and withdrawal attempts, newest first
+pub async fn list_payments(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Query(query): Query<ListPaymentsQuery>,
+) -> Result<Json<Vec<OutgoingPayment>>> {
+    let limit = query.limit.unwrap_or(100).clamp(1, MAX_PAYMENTS_LISTED);
+    let spending_repo = SpendingRepository::new(state.db_pool.clone());
+    Ok(Json(spending_repo.find_payments(user_id, limit).await?))
+}
+
+/// Evaluate a payment against the user's policy and, if allowed, record it
+/// as pending so it counts towards the limits while in flight. Fee caps the
+/// request leaves open are filled in from the policy. Denials are recorded too.
+async fn authorize_spend(
+    state: &AppState,
+    user_id: Uuid,
+    destination: &str,
+    mut spend: SpendRequest,
+) -> Result<(OutgoingPayment, SpendRequest)> {
+    let spending_repo = SpendingRepository::new(state.db_pool.clone());
+
+    // Holding the policy row serializes one user's payments, so two can't
+    // both fit under the same remaining limit
+    let mut tx = spending_repo.begin().await?;
+    let policy = spending_repo.lock_policy(&mut tx, user_id).await?
+        .unwrap_or_else(|| SpendingPolicy::unrestricted(user_id));
+
+    spend.max_fee_msat = spend.max_fee_msat.or(policy.max_fee_sat.map(|sat| (sat as u64).saturating_mul(1000)));
+    if spend.kind == PaymentKind::Onchain {
+        spend.feerate_sat_vb = spend.feerate_sat_vb.or(policy.max_feerate_sat_vb.map(|rate| rate as u64));
+    }
+
+    let now = Utc::now();
+    let spent_day_msat = spending_repo.spent_since_msat(&mut tx, user_id, now - Duration::days(1)).await?;
+    let spent_week_msat = spending_repo.spent_since_msat(&mut tx, user_id, now - Duration::days(7)).await?;
+    let allowlisted = spending_repo.find_matching_destinations(&mut tx, user_id, &spend.identities).await?;
+
+    let amount_msat = spending_policy::to_i64(spend.amount_msat, "amount_msat")?;
+    let fee_msat = spending_policy::to_i64(spend.max_fee_msat.unwrap_or(0), "max_fee_msat")?;
+
+    match spending_policy::evaluate(&policy, &spend, spent_day_msat, spent_week_msat, &allowlisted, now) {
+        Ok(()) => {
+            let payment = spending_repo
+                .create_payment(&mut tx, user_id, spend.kind, destination, amount_msat, fee_msat, None)
+                .await?;
+            tx.commit().await?;
+            Ok((payment, spend))
+        }
+        Err(AppError::PolicyDenied(denial)) => {
+            spending_repo
+                .create_payment(&mut tx, user_id, spend.kind, destination, amount_msat, 0, Some(denial.reason.as_str()))
+                .await?;
+            tx.commit().await?;
+            tracing::info!("Payment by user {} denied by policy: {}", user_id, denial.reason.as_str());
+            Err(AppError::PolicyDenied(denial))
+        }
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,153 @@
+use axum::{
+    extract::{State, Extension, Path},
+    http::StatusCode,
+    response::Json,
+};
+use chrono::{Duration, Utc};
+use serde_json::json;
+use uuid::Uuid;
+use crate::{AppState, error::{AppError, Result}};
+use crate::models::{
+    AddDestinationRequest, DestinationKind, DEFAULT_COOLING_OFF_HOURS, PolicyAuditAction, PolicyAuditEntry, SpendingDestination,
+    SpendingPolicy, SpendingRepository, UpdateSpendingPolicyRequest,
+};
+use crate::services::spending_policy::{normalize_destination, stricter_policy};
+
+const MAX_COOLING_OFF_HOURS: i64 = 720;
+const MAX_DESTINATIONS: usize = 200;
+
+/// GET /spending/policy - The policy applied to outgoing payments
+pub async fn get_policy(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<SpendingPolicy>> {
+    let spending_repo = SpendingRepository::new(state.db_pool.clone());
+    let policy = spending_repo.find_policy(user_id).await?
+        .unwrap_or_else(|| SpendingPolicy::unrestricted(user_id));
+
+    Ok(Json(policy))
+}
+
+/// PUT /spending/policy - Replace the policy; the change is recorded in the audit history.
+/// Tightening applies at once, while anything that loosens the policy waits
+/// out the current cooling-off period and shows up as `pending_changes`.
+pub async fn update_policy(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Json(mut request): Json<UpdateSpendingPolicyRequest>,
+) -> Result<Json<SpendingPolicy>> {
+    let limits = [
+        ("daily_limit_sat", request.daily_limit_sat),
+        ("weekly_limit_sat", request.weekly_limit_sat),
+        ("max_payment_sat", request.max_payment_sat),
+        ("max_fee_sat", request.max_fee_sat),
+        ("max_feerate_sat_vb", request.max_feerate_sat_vb),
+    ];
+    if let Some((name, _)) = limits.iter().find(|(_, limit)| limit.is_some_and(|limit| limit < 0)) {
+        return Err(AppError::Validation(format!("{} must not be negative", name)));
+    }
+
+    let cooling_off_hours = request.cooling_off_hours.unwrap_or(DEFAULT_COOLING_OFF_HOURS);
+    if !(0..=MAX_COOLING_OFF_HOURS).contains(&cooling_off_hours) {
+        return Err(AppError::Validation(format!("cooling_off_hours must be between 0 and {}", MAX_COOLING_OFF_HOURS)));
+    }
+    request.cooling_off_hours = Some(cooling_off_hours);
+
+    let spending_repo = SpendingRepository::new(state.db_pool.clone());
+    let mut tx = spending_repo.begin().await?;
+    let previous = spending_repo.lock_policy(&mut tx, user_id).await?;
+    let current = previous.clone().unwrap_or_else(|| SpendingPolicy::unrestricted(user_id));
+
+    let immediate = stricter_policy(&current, &request);
+    let pending = (immediate != request).then(|| (&request, Utc::now() + Duration::hours(current.cooling_off_hours)));
+    let policy = spending_repo.upsert_policy(&mut tx, user_id, &immediate, pending).await?;
+    spending_repo
+        .record_audit(
+            &mut tx,
+            user_id,
+            PolicyAuditAction::PolicyUpdated,
+            json!({ "before": previous, "after": policy }),
+        )
+        .await?;
+    tx.commit().await?;
+
+    Ok(Json(policy))
+}
+
+/// GET /spending/policy/history - Policy and allowlist changes, newest first
+pub async fn get_policy_history(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<Vec<PolicyAuditEntry>>> {
+    let spending_repo = SpendingRepository::new(state.db_pool.clone());
+    Ok(Json(spending_repo.find_audit(user_id).await?))
+}
+
+/// GET /spending/destinations - The allowlist
+pub async fn list_destinations(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<Vec<SpendingDestination>>> {
+    let spending_repo = SpendingRepository::new(state.db_pool.clone());
+    Ok(Json(spending_repo.find_destinations(user_id).await?))
+}
+
+/// POST /spending/destinations - Allowlist a destination; it becomes payable
+/// after the policy's cooling-off period
+pub async fn add_destination(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Json(request): Json<AddDestinationRequest>,
+) -> Result<(StatusCode, Json<SpendingDestination>)> {
+    let kind = DestinationKind::parse(&request.kind).ok_or_else(|| {
+        let known: Vec<&str> = DestinationKind::ALL.iter().map(DestinationKind::as_str).collect();
+        AppError::Validation(format!("Unknown kind '{}', expected one of {}", request.kind, known.join(", ")))
+    })?;
+    let value = normalize_destination(kind, &request.value, state.config.gl_network)
+        .ok_or_else(|| AppError::Validation(format!("Invalid {}: {}", kind.as_str(), request.value)))?;
+    let label = request.label.as_deref().map(str::trim).filter(|label| !label.is_empty());
+    if label.is_some_and(|label| label.chars().count() > 64) {
+        return Err(AppError::Validation("label must be at most 64 characters".to_string()));
+    }
+
+    let spending_repo = SpendingRepository::new(state.db_pool.clone());
+    if spending_repo.destination_exists(user_id, kind, &value).await? {
+        return Err(AppError::BadRequest("Destination is already on the allowlist".to_string()));
+    }
+    if spending_repo.find_destinations(user_id).await?.len() >= MAX_DESTINATIONS {
+        return Err(AppError::BadRequest(format!("At most {} destinations can be allowlisted", MAX_DESTINATIONS)));
+    }
+
+    let mut tx = spending_repo.begin().await?;
+    let cooling_off_hours = spending_repo.lock_policy(&mut tx, user_id).await?
+        .map(|policy| policy.cooling_off_hours)
+        .unwrap_or(DEFAULT_COOLING_OFF_HOURS);
+    let destination = spending_repo
+        .add_destination(&mut tx, user_id, kind, &value, label, Utc::now() + Duration::hours(cooling_off_hours))
+        .await?;
+    spending_repo
+        .record_audit(&mut tx, user_id, PolicyAuditAction::DestinationAdded, json!(destination))
+        .await?;
+    tx.commit().await?;
+
+    Ok((StatusCode::CREATED, Json(destination)))
+}
+
+/// DELETE /spending/destinations/:id - Remove a destination from the allowlist
+pub async fn remove_destination(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode> {
+    let spending_repo = SpendingRepository::new(state.db_pool.clone());
+
+    let mut tx = spending_repo.begin().await?;
+    let destination = spending_repo.remove_destination(&mut tx, id, user_id).await?
+        .ok_or_else(|| AppError::NotFound("Destination not found".to_string()))?;
+    spending_repo
+        .record_audit(&mut tx, user_id, PolicyAuditAction::DestinationRemoved, json!(destination))
+        .await?;
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,114 @@
+use axum::{
+    extract::{State, Extension},
+    http::StatusCode,
+    response::Json,
+};
+use serde::Serialize;
+use uuid::Uuid;
+use crate::{AppState, error::{AppError, Result}};
+use crate::models::{ProtectedOperation, TotpCodeRequest, TotpPolicyRequest, TotpStatus, UserRepository, UsernameRepository};
+use crate::services::payment_uri;
+
+#[derive(Serialize)]
+pub struct EnrollTotpResponse {
+    /// Base32 secret for entering into an authenticator app by hand
+    pub secret: String,
+    pub provisioning_uri: String,
+    /// The provisioning URI as an SVG QR code
+    pub qr_svg: String,
+}
+
+#[derive(Serialize)]
+pub struct BackupCodesResponse {
+    /// Single-use codes for when the authenticator is lost; only shown here
+    pub backup_codes: Vec<String>,
+}
+
+/// GET /auth/totp - Whether two-factor authentication is on and what it protects
+pub async fn get_status(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<TotpStatus>> {
+    Ok(Json(state.totp_service.status(user_id).await?))
+}
+
+/// POST /auth/totp/enroll - Create a secret to scan; it takes effect once confirmed
+pub async fn enroll(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<EnrollTotpResponse>> {
+    let user_repo = UserRepository::new(state.db_pool.clone());
+    let user = user_repo.find_by_id(user_id).await?
+        .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+
+    // Label the entry in the authenticator app with the user's address if they have one
+    let username_repo = UsernameRepository::new(state.db_pool.clone());
+    let account_name = match username_repo.find_primary(user_id).await? {
+        Some(username) => format!("{}@{}", username.username, state.config.domain),
+        None => user.public_key.chars().take(16).collect(),
+    };
+
+    let enrollment = state.totp_service.begin_enrollment(user_id, &account_name).await?;
+
+    Ok(Json(EnrollTotpResponse {
+        qr_svg: payment_uri::render_svg(&enrollment.provisioning_uri)?,
+        secret: enrollment.secret,
+        provisioning_uri: enrollment.provisioning_uri,
+    }))
+}
+
+/// POST /auth/totp/confirm - Enable two-factor authentication with a first code
+pub async fn confirm(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Json(request): Json<TotpCodeRequest>,
+) -> Result<(StatusCode, Json<BackupCodesResponse>)> {
+    let backup_codes = state.totp_service.confirm_enrollment(user_id, &request.code).await?;
+    tracing::info!("Two-factor authentication enabled for user {}", user_id);
+
+    Ok((StatusCode::CREATED, Json(BackupCodesResponse { backup_codes })))
+}
+
+/// DELETE /auth/totp - Disable two-factor authentication
+pub async fn disable(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Json(request): Json<TotpCodeRequest>,
+) -> Result<StatusCode> {
+    state.totp_service.disable(user_id, &request.code).await?;
+    tracing::info!("Two-factor authentication disabled for user {}", user_id);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /auth/totp/backup-codes - Replace all backup codes
+pub async fn regenerate_backup_codes(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Json(request): Json<TotpCodeRequest>,
+) -> Result<Json<BackupCodesResponse>> {
+    let backup_codes = state.totp_service.regenerate_backup_codes(user_id, &request.code).await?;
+    Ok(Json(BackupCodesResponse { backup_codes }))
+}
+
+/// PUT /auth/totp/policy - Choose the operations that need a code
+pub async fn update_policy(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Json(request): Json<TotpPolicyRequest>,
+) -> Result<Json<TotpStatus>> {
+    let mut operations = Vec::new();
+    for operation in &request.protected_operations {
+        let operation = ProtectedOperation::parse(operation).ok_or_else(|| {
+            let known: Vec<&str> = ProtectedOperation::ALL.iter().map(ProtectedOperation::as_str).collect();
+            AppError::Validation(format!("Unknown operation '{}', expected one of {}", operation, known.join(", ")))
+        })?;
+        if !operations.contains(&operation) {
+            operations.push(operation);
+        }
+    }
+
+    state.totp_service.update_protected_operations(user_id, &operations, &request.code).await?;
+
+    Ok(Json(state.totp_service.status(user_id).await?))
+}
@@ -0,0 +1,435 @@
+use axum::{
+    extract::{State, Extension, Path, Query},
+    http::StatusCode,
+    response::Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use sqlx::{Postgres, Transaction};
+use uuid::Uuid;
+use crate::{AppState, error::{AppError, Result}};
+use crate::models::{
+    AcceptTransferRequest, AvailabilityQuery, CreateUsernameRequest, TransferUsernameRequest,
+    UpdateUsernameRequest, Username, UsernameEvent, UsernameHistory, UsernameRepository,
+    UsernameTransfer, UserRepository,
+};
+use crate::services::GreenlightService;
+use crate::services::dns::{bip353_record_name, bip353_record_value};
+
+const MAX_SUGGESTIONS: usize = 5;
+
+#[derive(Serialize)]
+pub struct AvailabilityResponse {
+    pub name: String,
+    pub username: Option<String>,
+    pub available: bool,
+    pub reason: Option<String>,
+    pub suggestions: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct UsernameResponse {
+    pub username: String,
+    pub address: String,
+    pub offer: String,
+}
+
+#[derive(Serialize)]
+pub struct ReleaseResponse {
+    pub username: String,
+    pub offer_disabled: bool,
+    pub available_at: DateTime<Utc>,
+}
+
+impl UsernameResponse {
+    fn new(username: Username, domain: &str) -> Self {
+        Self {
+            address: format!("{}@{}", username.username, domain),
+            username: username.username,
+            offer: username.offer,
+        }
+    }
+}
+
+/// GET /usernames/availability?name= - Check a name against the policy and existing claims
+pub async fn check_availability(
+    State(state): State<AppState>,
+    Query(query): Query<AvailabilityQuery>,
+) -> Result<Json<AvailabilityResponse>> {
+    let username_repo = UsernameRepository::new(state.db_pool.clone());
+
+    let (username, reason) = match state.username_policy.check(&query.name) {
+        Ok(label) => {
+            let taken = !username_repo.find_taken(std::slice::from_ref(&label)).await?.is_empty();
+            let reason = if taken {
+                Some("Username is already taken".to_string())
+            } else {
+                username_repo
+                    .cooldown_until(&label, release_cooldown(&state))
+                    .await?
+                    .map(|available_at| format!("Username was released recently and can be claimed again after {}", available_at))
+            };
+            (Some(label), reason)
+        }
+        Err(AppError::Validation(message)) => (None, Some(message)),
+        Err(e) => return Err(e),
+    };
+
+    let available = reason.is_none();
+    let suggestions = if available {
+        Vec::new()
+    } else {
+        let candidates = state.username_policy.suggestions(&query.name);
+        let taken = username_repo.find_taken(&candidates).await?;
+        candidates
+            .into_iter()
+            .filter(|candidate| !taken.contains(candidate))
+            .take(MAX_SUGGESTIONS)
+            .collect()
+    };
+
+    Ok(Json(AvailabilityResponse {
+        name: query.name,
+        username,
+        available,
+        reason,
+        suggestions,
+    }))
+}
+
+/// GET /usernames - List the authenticated user's usernames
+pub async fn list_usernames(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<Vec<UsernameResponse>>> {
+    let username_repo = UsernameRepository::new(state.db_pool.clone());
+    let usernames = username_repo.find_by_user(user_id).await?;
+
+    Ok(Json(
+        usernames
+            .into_iter()
+            .map(|username| UsernameResponse::new(username, &state.config.domain))
+            .collect(),
+    ))
+}
+
+/// POST /usernames - Claim a username and publish its BIP353 record
+pub async fn create_username(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Json(request): Json<CreateUsernameRequest>,
+) -> Result<(StatusCode, Json<UsernameResponse>)> {
+    let username_repo = UsernameRepository::new(state.db_pool.clone());
+
+    let label = state.username_policy.check(&request.username)?;
+    validate_offer(&request.offer)?;
+
+    if let Some(available_at) = username_repo.cooldown_until(&label, release_cooldown(&state)).await? {
+        return Err(AppError::BadRequest(format!(
+            "Username was released recently and can be claimed again after {}",
+            available_at
+        )));
+    }
+
+    // Keep the row uncommitted until the record is published so a DNS failure
+    // does not leave a claimed but unresolvable name behind
+    let mut tx = username_repo.begin().await?;
+    check_quota(&state, &username_repo, &mut tx, user_id).await?;
+    let username = username_repo
+        .create_username(&mut tx, user_id, &label, &request.offer)
+        .await
+        .map_err(|e| match e {
+            AppError::Database(sqlx::Error::Database(db)) if db.is_unique_violation() => {
+                AppError::BadRequest("Username is already taken".to_string())
+            }
+            e => e,
+        })?;
+    username_repo
+        .record_history(&mut tx, &username.username, UsernameEvent::Claimed, None, Some(user_id), Some(&username.offer))
+        .await?;
+
+    if let Some(dns_provider) = &state.dns_provider {
+        dns_provider
+            .create_txt_record(
+                &bip353_record_name(&username.username, &state.config.domain),
+                &bip353_record_value(&username.offer),
+            )
+            .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(UsernameResponse::new(username, &state.config.domain)),
+    ))
+}
+
+/// PUT /usernames/:username - Point a username at a new offer
+pub async fn update_username(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(name): Path<String>,
+    Json(request): Json<UpdateUsernameRequest>,
+) -> Result<Json<UsernameResponse>> {
+    let username_repo = UsernameRepository::new(state.db_pool.clone());
+    let username = owned_username(&state, &username_repo, user_id, &name).await?;
+    validate_offer(&request.offer)?;
+
+    let mut tx = username_repo.begin().await?;
+    let username = username_repo.update_offer(&mut tx, username.id, user_id, &request.offer).await?
+        .ok_or_else(changed_owner)?;
+    username_repo
+        .record_history(&mut tx, &username.username, UsernameEvent::OfferChanged, Some(user_id), Some(user_id), Some(&username.offer))
+        .await?;
+
+    publish_record(&state, &username).await?;
+    tx.commit().await?;
+
+    Ok(Json(UsernameResponse::new(username, &state.config.domain)))
+}
+
+/// DELETE /usernames/:username - Release a username, removing its record and disabling its offer
+pub async fn release_username(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(name): Path<String>,
+) -> Result<Json<ReleaseResponse>> {
+    let username_repo = UsernameRepository::new(state.db_pool.clone());
+    let username = owned_username(&state, &username_repo, user_id, &name).await?;
+
+    let mut tx = username_repo.begin().await?;
+    if !username_repo.delete_username(&mut tx, username.id, user_id).await? {
+        return Err(changed_owner());
+    }
+    username_repo
+        .record_history(&mut tx, &username.username, UsernameEvent::Released, Some(user_id), None, Some(&username.offer))
+        .await?;
+
+    if let Some(dns_provider) = &state.dns_provider {
+        dns_provider
+            .delete_txt_record(&bip353_record_name(&username.username, &state.config.domain))
+            .await?;
+    }
+
+    tx.commit().await?;
+
+    // The name no longer resolves, so a node that is unreachable right now
+    // must not block the release
+    let offer_disabled = match disable_offer(&state, user_id, &username.offer).await {
+        Ok(disabled) => disabled,
+        Err(e) => {
+            tracing::warn!("Failed to disable offer for released username {}: {}", username.username, e);
+            false
+        }
+    };
+
+    Ok(Json(ReleaseResponse {
+        username: username.username,
+        offer_disabled,
+        available_at: Utc::now() + release_cooldown(&state),
+    }))
+}
+
+/// GET /usernames/:username/history - Owner and offer changes of a username
+pub async fn get_username_history(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(name): Path<String>,
+) -> Result<Json<Vec<UsernameHistory>>> {
+    let username_repo = UsernameRepository::new(state.db_pool.clone());
+    let username = owned_username(&state, &username_repo, user_id, &name).await?;
+
+    Ok(Json(username_repo.history(&username.username).await?))
+}
+
+/// POST /usernames/:username/transfer - Offer a username to another account
+pub async fn request_transfer(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(name): Path<String>,
+    Json(request): Json<TransferUsernameRequest>,
+) -> Result<(StatusCode, Json<UsernameTransfer>)> {
+    let username_repo = UsernameRepository::new(state.db_pool.clone());
+    let user_repo = UserRepository::new(state.db_pool.clone());
+    let username = owned_username(&state, &username_repo, user_id, &name).await?;
+
+    let recipient = user_repo.find_by_public_key(&request.to_public_key).await?
+        .ok_or_else(|| AppError::NotFound("Recipient not found".to_string()))?;
+
+    if recipient.id == user_id {
+        return Err(AppError::BadRequest("Cannot transfer a username to yourself".to_string()));
+    }
+
+    let expires_at = Utc::now() + Duration::hours(state.config.username_transfer_expiry_hours);
+
+    let mut tx = username_repo.begin().await?;
+    let transfer = username_repo
+        .create_transfer(&mut tx, username.id, user_id, recipient.id, expires_at)
+        .await
+        .map_err(|e| match e {
+            AppError::Database(sqlx::Error::Database(db)) if db.is_unique_violation() => {
+                AppError::BadRequest("A transfer is already pending for this username".to_string())
+            }
+            e => e,
+        })?;
+    username_repo
+        .record_history(&mut tx, &username.username, UsernameEvent::TransferRequested, Some(user_id), Some(recipient.id), None)
+        .await?;
+    tx.commit().await?;
+
+    Ok((StatusCode::CREATED, Json(transfer)))
+}
+
+/// GET /usernames/transfers - Transfers waiting for the authenticated user to accept
+pub async fn list_incoming_transfers(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<Vec<UsernameTransfer>>> {
+    let username_repo = UsernameRepository::new(state.db_pool.clone());
+    Ok(Json(username_repo.incoming_transfers(user_id).await?))
+}
+
+/// POST /usernames/:username/transfer/accept - Recipient confirms a transfer with their own offer
+pub async fn accept_transfer(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(name): Path<String>,
+    Json(request): Json<AcceptTransferRequest>,
+) -> Result<Json<UsernameResponse>> {
+    let username_repo = UsernameRepository::new(state.db_pool.clone());
+    let label = state.username_policy.normalize(&name)?;
+    let username = username_repo.find_by_username(&label).await?
+        .ok_or_else(|| AppError::NotFound("Username not found".to_string()))?;
+
+    validate_offer(&request.offer)?;
+
+    let mut tx = username_repo.begin().await?;
+    check_quota(&state, &username_repo, &mut tx, user_id).await?;
+    let transfer = username_repo.lock_pending_transfer(&mut tx, username.id).await?
+        .filter(|transfer| transfer.to_user_id == user_id)
+        .ok_or_else(|| AppError::NotFound("No pending transfer for this username".to_string()))?;
+
+    // The owner may have released or moved the name since requesting the transfer
+    let username = username_repo
+        .update_owner(&mut tx, username.id, transfer.from_user_id, user_id, &request.offer)
+        .await?
+        .ok_or_else(changed_owner)?;
+    if !username_repo.set_transfer_status(&mut tx, transfer.id, "completed").await? {
+        return Err(AppError::Conflict("Transfer is no longer pending".to_string()));
+    }
+    username_repo
+        .record_history(&mut tx, &username.username, UsernameEvent::Transferred, Some(transfer.from_user_id), Some(user_id), Some(&username.offer))
+        .await?;
+
+    publish_record(&state, &username).await?;
+    tx.commit().await?;
+
+    Ok(Json(UsernameResponse::new(username, &state.config.domain)))
+}
+
+/// DELETE /usernames/:username/transfer - Owner withdraws or recipient declines a transfer
+pub async fn cancel_transfer(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(name): Path<String>,
+) -> Result<StatusCode> {
+    let username_repo = UsernameRepository::new(state.db_pool.clone());
+    let label = state.username_policy.normalize(&name)?;
+    let username = username_repo.find_by_username(&label).await?
+        .ok_or_else(|| AppError::NotFound("Username not found".to_string()))?;
+
+    let mut tx = username_repo.begin().await?;
+    let transfer = username_repo.lock_pending_transfer(&mut tx, username.id).await?
+        .filter(|transfer| transfer.from_user_id == user_id || transfer.to_user_id == user_id)
+        .ok_or_else(|| AppError::NotFound("No pending transfer for this username".to_string()))?;
+
+    if !username_repo.set_transfer_status(&mut tx, transfer.id, "cancelled").await? {
+        return Err(AppError::Conflict("Transfer is no longer pending".to_string()));
+    }
+    username_repo
+        .record_history(&mut tx, &username.username, UsernameEvent::TransferCancelled, Some(transfer.from_user_id), Some(transfer.to_user_id), None)
+        .await?;
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Look up `name` and make sure it belongs to `user_id`
+async fn owned_username(
+    state: &AppState,
+    username_repo: &UsernameRepository,
+    user_id: Uuid,
+    name: &str,
+) -> Result<Username> {
+    let label = state.username_policy.normalize(name)?;
+    let username = username_repo.find_by_username(&label).await?
+        .ok_or_else(|| AppError::NotFound("Username not found".to_string()))?;
+
+    if username.user_id != user_id {
+        return Err(AppError::Authorization("Username belongs to another account".to_string()));
+    }
+
+    Ok(username)
+}
+
+/// Enforce the per-user quota; the count holds a lock on the user until `tx` ends
+async fn check_quota(
+    state: &AppState,
+    username_repo: &UsernameRepository,
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: Uuid,
+) -> Result<()> {
+    if username_repo.count_for_user(tx, user_id).await? >= state.username_policy.max_per_user {
+        return Err(AppError::BadRequest(format!(
+            "Username limit of {} reached",
+            state.username_policy.max_per_user
+        )));
+    }
+    Ok(())
+}
+
+/// A username changed hands between reading it and writing to it
+fn changed_owner() -> AppError {
+    AppError::Conflict("Username changed owner; reload and try again".to_string())
+}
+
+fn validate_offer(offer: &str) -> Result<()> {
+    if !offer.starts_with("lno1") {
+        return Err(AppError::Validation(
+            "Offer must be a valid BOLT12 offer starting with \"lno1\"".to_string()
+        ));
+    }
+    Ok(())
+}
+
+fn release_cooldown(state: &AppState) -> Duration {
+    Duration::days(state.config.username_release_cooldown_days)
+}
+
+/// Replace the published record with the username's current offer
+async fn publish_record(state: &AppState, username: &Username) -> Result<()> {
+    if let Some(dns_provider) = &state.dns_provider {
+        dns_provider
+            .update_txt_record(
+                &bip353_record_name(&username.username, &state.config.domain),
+                &bip353_record_value(&username.offer),
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+async fn disable_offer(state: &AppState, user_id: Uuid, offer: &str) -> Result<bool> {
+    let user_repo = UserRepository::new(state.db_pool.clone());
+    let user = user_repo.find_by_id(user_id).await?
+        .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+
+    if user.encrypted_device_creds.is_none() {
+        return Ok(false);
+    }
+    let device_creds = state.credential_vault.open_for(&user).await?;
+
+    let greenlight_service = GreenlightService::new(state.config.clone());
+    greenlight_service.disable_offer(&device_creds, offer).await
+}
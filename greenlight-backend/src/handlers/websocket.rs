@@ -1,22 +1,23 @@
 use axum::{
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        State, Query,
+        State,
     },
+    http::{header::AUTHORIZATION, HeaderMap},
     response::Response,
 };
-use futures_util::{SinkExt, StreamExt};
+use futures_util::{stream::SplitSink, SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::time::Duration;
 use uuid::Uuid;
-use crate::{AppState, error::AppError};
+use crate::{AppState, error::{AppError, Result}};
+use crate::models::SessionRepository;
 use crate::services::GreenlightService;
-use crate::handlers::node::CreateOfferRequest;
+use crate::handlers::node::{user_device_creds, CreateOfferRequest};
 
-#[derive(Deserialize)]
-pub struct WebSocketAuth {
-    pub token: String,
-}
+/// How long an unauthenticated connection may wait before sending its token
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Deserialize)]
 pub struct WebSocketMessage {
@@ -32,102 +33,124 @@ pub struct WebSocketResponse {
     pub error: Option<String>,
 }
 
+/// Payload of the `auth` command for clients that can't set headers on the
+/// upgrade request, such as browsers
 #[derive(Deserialize)]
-pub struct AuthMessage {
-    #[serde(rename = "encryptedDeviceCreds")]
-    pub encrypted_device_creds: String,
-    pub password: String,
+pub struct AuthPayload {
+    pub token: String,
+}
+
+/// The user and session a connection acts for
+#[derive(Clone, Copy)]
+struct WebSocketSession {
+    user_id: Uuid,
+    session_id: Uuid,
 }
 
-/// GET /api/v1/ws/connect
+/// GET /ws - Authenticate with `Authorization: Bearer <jwt>` on the upgrade
+/// request, or with `{"command": "auth", "payload": {"token": "<jwt>"}}` as
+/// the first message
 pub async fn websocket_handler(
     ws: WebSocketUpgrade,
     State(state): State<AppState>,
-    Query(auth): Query<WebSocketAuth>,
-) -> Result<Response, AppError> {
-    // Validate the token first
-    let user_id = state.jwt_service.get_user_id_from_token(&auth.token)?;
-    
-    Ok(ws.on_upgrade(move |socket| websocket_connection(socket, state, user_id)))
+    headers: HeaderMap,
+) -> Result<Response> {
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "));
+
+    // A bad header fails the upgrade with a normal HTTP error
+    let session = match token {
+        Some(token) => Some(authenticate(&state, token).await?),
+        None => None,
+    };
+
+    Ok(ws.on_upgrade(move |socket| websocket_connection(socket, state, session)))
 }
 
-async fn websocket_connection(socket: WebSocket, state: AppState, _user_id: Uuid) {
+/// Check an access token the way `auth_middleware` does: valid signature,
+/// live session, and a user that still exists
+async fn authenticate(state: &AppState, token: &str) -> Result<WebSocketSession> {
+    let (user_id, session_id) = state.jwt_service.get_session_from_token(token)?;
+    check_session(state, WebSocketSession { user_id, session_id }).await?;
+
+    Ok(WebSocketSession { user_id, session_id })
+}
+
+/// Sessions are deleted with their user, so this also verifies the user exists
+async fn check_session(state: &AppState, session: WebSocketSession) -> Result<()> {
+    let session_repo = SessionRepository::new(state.db_pool.clone());
+    if !session_repo.is_active(session.session_id, session.user_id).await? {
+        return Err(AppError::Authentication("Session has been revoked or has expired".to_string()));
+    }
+
+    Ok(())
+}
+
+async fn websocket_connection(socket: WebSocket, state: AppState, session: Option<WebSocketSession>) {
     let (mut sender, mut receiver) = socket.split();
+
+    let session = match session {
+        Some(session) => session,
+        None => {
+            let auth_message = match tokio::time::timeout(AUTH_TIMEOUT, receiver.next()).await {
+                Ok(Some(Ok(Message::Text(text)))) => text,
+                _ => {
+                    send_response(&mut sender, error_response("auth", "Authentication timed out")).await;
+                    return;
+                }
+            };
+
+            let token = serde_json::from_str::<WebSocketMessage>(&auth_message)
+                .ok()
+                .filter(|message| message.command == "auth")
+                .and_then(|message| message.payload)
+                .and_then(|payload| serde_json::from_value::<AuthPayload>(payload).ok());
+
+            let result = match token {
+                Some(AuthPayload { token }) => authenticate(&state, &token).await,
+                None => Err(AppError::BadRequest("Expected an auth command with a token".to_string())),
+            };
+
+            match result {
+                Ok(session) => session,
+                Err(error) => {
+                    send_response(&mut sender, error_response("auth", &error.to_string())).await;
+                    return;
+                }
+            }
+        }
+    };
+
+    let authenticated = WebSocketResponse {
+        command: "auth".to_string(),
+        success: true,
+        data: Some(serde_json::json!({"message": "Authenticated successfully"})),
+        error: None,
+    };
+    if !send_response(&mut sender, authenticated).await {
+        return;
+    }
+
     let greenlight_service = GreenlightService::new(state.config.clone());
-    
-    // Wait for authentication message
-    let mut authenticated = false;
-    let mut encrypted_creds: Option<String> = None;
-    let mut password: Option<String> = None;
 
     while let Some(msg) = receiver.next().await {
         match msg {
             Ok(Message::Text(text)) => {
-                if !authenticated {
-                    // First message should be authentication
-                    match serde_json::from_str::<AuthMessage>(&text) {
-                        Ok(auth_msg) => {
-                            encrypted_creds = Some(auth_msg.encrypted_device_creds);
-                            password = Some(auth_msg.password);
-                            authenticated = true;
-
-                            let response = WebSocketResponse {
-                                command: "auth".to_string(),
-                                success: true,
-                                data: Some(serde_json::json!({"message": "Authenticated successfully"})),
-                                error: None,
-                            };
-
-                            if let Ok(response_text) = serde_json::to_string(&response) {
-                                if sender.send(Message::Text(response_text)).await.is_err() {
-                                    break;
-                                }
-                            }
-                        }
-                        Err(_) => {
-                            let response = WebSocketResponse {
-                                command: "auth".to_string(),
-                                success: false,
-                                data: None,
-                                error: Some("Invalid authentication message format".to_string()),
-                            };
-
-                            if let Ok(response_text) = serde_json::to_string(&response) {
-                                let _ = sender.send(Message::Text(response_text)).await;
-                            }
-                            break;
-                        }
-                    }
-                } else {
-                    // Handle commands after authentication
-                    match serde_json::from_str::<WebSocketMessage>(&text) {
-                        Ok(ws_msg) => {
-                            let response = handle_websocket_command(
-                                &greenlight_service,
-                                &ws_msg,
-                                encrypted_creds.as_ref().unwrap(),
-                                password.as_ref().unwrap(),
-                            ).await;
-
-                            if let Ok(response_text) = serde_json::to_string(&response) {
-                                if sender.send(Message::Text(response_text)).await.is_err() {
-                                    break;
-                                }
-                            }
-                        }
-                        Err(_) => {
-                            let response = WebSocketResponse {
-                                command: "error".to_string(),
-                                success: false,
-                                data: None,
-                                error: Some("Invalid message format".to_string()),
-                            };
-
-                            if let Ok(response_text) = serde_json::to_string(&response) {
-                                let _ = sender.send(Message::Text(response_text)).await;
-                            }
-                        }
-                    }
+                // Logging out or deleting the account ends open connections too
+                if let Err(error) = check_session(&state, session).await {
+                    send_response(&mut sender, error_response("auth", &error.to_string())).await;
+                    break;
+                }
+
+                let response = match serde_json::from_str::<WebSocketMessage>(&text) {
+                    Ok(ws_msg) => handle_websocket_command(&state, &greenlight_service, session.user_id, &ws_msg).await,
+                    Err(_) => error_response("error", "Invalid message format"),
+                };
+
+                if !send_response(&mut sender, response).await {
+                    break;
                 }
             }
             Ok(Message::Close(_)) => {
@@ -138,21 +161,42 @@ async fn websocket_connection(socket: WebSocket, state: AppState, _user_id: Uuid
     }
 }
 
+/// Send a response, returning false once the client is gone
+async fn send_response(sender: &mut SplitSink<WebSocket, Message>, response: WebSocketResponse) -> bool {
+    match serde_json::to_string(&response) {
+        Ok(response_text) => sender.send(Message::Text(response_text)).await.is_ok(),
+        Err(_) => true,
+    }
+}
+
+fn error_response(command: &str, error: &str) -> WebSocketResponse {
+    WebSocketResponse {
+        command: command.to_string(),
+        success: false,
+        data: None,
+        error: Some(error.to_string()),
+    }
+}
+
 async fn handle_websocket_command(
+    state: &AppState,
     greenlight_service: &GreenlightService,
+    user_id: Uuid,
     message: &WebSocketMessage,
-    _encrypted_creds: &str,
-    _password: &str,
 ) -> WebSocketResponse {
     let result = match message.command.as_str() {
         "get_info" => {
-            greenlight_service.get_node_info(&[0u8; 32]).await
+            match user_device_creds(state, user_id).await {
+                Ok(device_creds) => greenlight_service.get_node_info(&device_creds).await,
+                Err(error) => Err(error),
+            }
         }
         "create_offer" => {
             match message.payload.as_ref().and_then(|p| serde_json::from_value::<CreateOfferRequest>(p.clone()).ok()) {
-                Some(request) => {
-                    greenlight_service.create_offer(&[0u8; 32], request).await
-                }
+                Some(request) => match user_device_creds(state, user_id).await {
+                    Ok(device_creds) => greenlight_service.create_offer(&device_creds, request).await,
+                    Err(error) => Err(error),
+                },
                 None => Err(AppError::BadRequest("Invalid create_offer payload".to_string()))
             }
         }
@@ -171,11 +215,6 @@ async fn handle_websocket_command(
             data: Some(data),
             error: None,
         },
-        Err(error) => WebSocketResponse {
-            command: message.command.clone(),
-            success: false,
-            data: None,
-            error: Some(error.to_string()),
-        }
+        Err(error) => error_response(&message.command, &error.to_string()),
     }
 }
@@ -7,22 +7,29 @@ mod middleware;
 
 use axum::{
     middleware::{from_fn_with_state},
-    routing::{get, post},
+    routing::{delete, get, post, put},
     Router,
 };
 use sqlx::postgres::PgPoolOptions;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use tower::ServiceBuilder;
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use config::Config;
-use services::JwtService;
+use services::{CredentialVault, DnsProvider, JwtService, LoginThrottle, ReconciliationService, TotpService, UsernamePolicy};
 
 #[derive(Clone)]
 pub struct AppState {
     pub db_pool: sqlx::PgPool,
     pub jwt_service: Arc<JwtService>,
+    pub dns_provider: Option<Arc<dyn DnsProvider>>,
+    pub username_policy: Arc<UsernamePolicy>,
+    pub reconciliation_service: Arc<ReconciliationService>,
+    pub login_throttle: Arc<LoginThrottle>,
+    pub totp_service: Arc<TotpService>,
+    pub credential_vault: Arc<CredentialVault>,
     pub config: Config,
 }
 
@@ -52,12 +59,50 @@ async fn main() -> anyhow::Result<()> {
     tracing::info!("Database migrations completed");
 
     // Initialize services
-    let jwt_service = Arc::new(JwtService::new(&config.jwt_secret));
+    services::CryptoService::configure_password_kdf(&config)?;
+    let jwt_service = Arc::new(JwtService::from_config(&config).await?);
+    let dns_provider = services::dns::provider_from_config(&config)?;
+    if dns_provider.is_some() {
+        tracing::info!("Publishing BIP353 records via {} ({})", config.dns_provider, config.dns_server);
+    }
+
+    let credential_vault = Arc::new(CredentialVault::from_config(&config).await?);
+    let converted = credential_vault.convert_legacy_rows(&db_pool).await?;
+    if converted > 0 {
+        tracing::info!("Encrypted {} stored device credentials with the server key", converted);
+    }
+
+    let username_policy = Arc::new(UsernamePolicy::from_config(&config)?);
+    let reconciliation_service = Arc::new(ReconciliationService::new(
+        db_pool.clone(),
+        dns_provider.clone(),
+        credential_vault.clone(),
+        config.clone(),
+    ));
+    reconciliation_service.clone().spawn();
+
+    let login_throttle = Arc::new(LoginThrottle::from_config(&config, db_pool.clone())?);
+    let totp_service = Arc::new(TotpService::from_config(
+        &config,
+        db_pool.clone(),
+        login_throttle.clone(),
+        credential_vault.clone(),
+    )?);
+    let (rebound, unreadable) = totp_service.reencrypt_secrets().await?;
+    if rebound > 0 || unreadable > 0 {
+        tracing::info!("Re-encrypted {} TOTP secrets, {} could not be decrypted", rebound, unreadable);
+    }
 
     // Create application state
     let state = AppState {
         db_pool,
         jwt_service,
+        dns_provider,
+        username_policy,
+        reconciliation_service,
+        login_throttle,
+        totp_service,
+        credential_vault,
         config: config.clone(),
     };
 
@@ -66,21 +111,70 @@ async fn main() -> anyhow::Result<()> {
         // Public routes (no authentication required)
         .route("/auth/register", post(handlers::auth::signup))
         .route("/auth/login", post(handlers::auth::login))
+        .route("/auth/challenge", post(handlers::auth::challenge))
+        .route("/auth/login/signature", post(handlers::auth::login_with_signature))
+        .route("/auth/refresh", post(handlers::auth::refresh))
+        .route("/auth/recover", post(handlers::auth::recover_account))
+        .route("/auth/logout", post(handlers::auth::logout))
+        .route("/.well-known/jwks.json", get(handlers::auth::jwks))
         .route("/health", get(health_check))
+        .route("/usernames/availability", get(handlers::username::check_availability))
+        .route("/.well-known/lnurlp/:username", get(handlers::lnurl::pay_request))
+        .route("/lnurlp/:username/callback", get(handlers::lnurl::pay_callback))
+        .route("/.well-known/nostr.json", get(handlers::nostr::nip05))
+        // Authenticates itself, so browsers can send the token as the first message
+        .route("/ws", get(handlers::websocket::websocket_handler))
         
         // Protected routes (authentication required)
         .nest("/", Router::new()
+            .route("/auth/logout/all", post(handlers::auth::logout_all))
+            .route("/auth/sessions", get(handlers::auth::list_sessions))
+            .route("/auth/password", post(handlers::auth::change_password))
+            .route("/auth/totp", get(handlers::totp::get_status).delete(handlers::totp::disable))
+            .route("/auth/totp/enroll", post(handlers::totp::enroll))
+            .route("/auth/totp/confirm", post(handlers::totp::confirm))
+            .route("/auth/totp/backup-codes", post(handlers::totp::regenerate_backup_codes))
+            .route("/auth/totp/policy", put(handlers::totp::update_policy))
+            .route("/api-keys", get(handlers::api_keys::list_api_keys).post(handlers::api_keys::create_api_key))
+            .route("/api-keys/:id", delete(handlers::api_keys::revoke_api_key))
             .route("/node/register", post(handlers::node::register_node))
             .route("/node/recover", post(handlers::node::recover_node))
             .route("/node/info", get(handlers::node::get_node_info))
             .route("/node/balance", get(handlers::node::get_balance))
             .route("/node/offer", post(handlers::node::create_offer))
-            .route("/ws", get(handlers::websocket::websocket_handler))
+            .route("/node/payment-uri", get(handlers::node::get_payment_uri))
+            .route("/node/pay", post(handlers::payments::pay))
+            .route("/node/withdraw", post(handlers::payments::withdraw))
+            .route("/node/payments", get(handlers::payments::list_payments))
+            .route("/spending/policy", get(handlers::spending::get_policy).put(handlers::spending::update_policy))
+            .route("/spending/policy/history", get(handlers::spending::get_policy_history))
+            .route("/spending/destinations", get(handlers::spending::list_destinations).post(handlers::spending::add_destination))
+            .route("/spending/destinations/:id", delete(handlers::spending::remove_destination))
+            .route("/usernames", get(handlers::username::list_usernames).post(handlers::username::create_username))
+            .route("/lnurl/invoices", get(handlers::lnurl::list_invoices))
+            .route("/nostr/relays", get(handlers::nostr::get_relays).put(handlers::nostr::update_relays))
+            .route("/usernames/transfers", get(handlers::username::list_incoming_transfers))
+            .route("/usernames/:username", put(handlers::username::update_username).delete(handlers::username::release_username))
+            .route("/usernames/:username/history", get(handlers::username::get_username_history))
+            .route("/usernames/:username/transfer", post(handlers::username::request_transfer).delete(handlers::username::cancel_transfer))
+            .route("/usernames/:username/transfer/accept", post(handlers::username::accept_transfer))
             .route_layer(from_fn_with_state(
                 state.clone(),
                 middleware::auth::auth_middleware,
             ))
         )
+
+        // Operator routes (admin API key required)
+        .nest("/admin", Router::new()
+            .route("/reconciliation", get(handlers::admin::get_reconciliation_report))
+            .route("/reconciliation/run", post(handlers::admin::run_reconciliation))
+            .route("/secrets/reencrypt", post(handlers::admin::reencrypt_secrets))
+            .route("/usernames/history", get(handlers::admin::get_username_history))
+            .route_layer(from_fn_with_state(
+                state.clone(),
+                middleware::auth::admin_auth_middleware,
+            ))
+        )
         
         // Add middleware
         .layer(
@@ -94,7 +188,7 @@ async fn main() -> anyhow::Result<()> {
     let listener = tokio::net::TcpListener::bind(format!("{}:{}", config.server_host, config.server_port)).await?;
     tracing::info!("Server starting on {}:{}", config.server_host, config.server_port);
     
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
 
     Ok(())
 }
@@ -1,12 +1,27 @@
 use axum::{
-    extract::{Request, State},
-    http::header::AUTHORIZATION,
+    body::{self, Body},
+    extract::{ConnectInfo, MatchedPath, OriginalUri, Request, State},
+    http::{header::AUTHORIZATION, Method},
     middleware::Next,
     response::Response,
 };
+use base64::{engine::general_purpose, Engine as _};
+use chrono::Utc;
+use std::net::{IpAddr, SocketAddr};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
 use crate::AppState;
 use crate::error::AppError;
-use crate::models::UserRepository;
+use crate::handlers::api_keys::{hash_api_key, API_KEY_PREFIX};
+use crate::middleware::client_ip::{client_ip, ip_in_range};
+use crate::models::{ApiKeyRepository, ApiScope, CurrentSession, ProtectedOperation, SessionRepository, UserRepository};
+use crate::services::nostr::{NostrEvent, KIND_HTTP_AUTH};
+
+/// Header carrying the authenticator code for protected operations
+pub const TOTP_CODE_HEADER: &str = "x-totp-code";
+
+/// Largest request body hashed for a NIP-98 `payload` check
+const NIP98_MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
 
 pub async fn auth_middleware(
     State(state): State<AppState>,
@@ -17,21 +32,62 @@ pub async fn auth_middleware(
         .headers()
         .get(AUTHORIZATION)
         .and_then(|header| header.to_str().ok())
-        .and_then(|header| header.strip_prefix("Bearer "));
+        .map(str::to_string);
+
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| request.uri().path().to_string());
+
+    let code = request
+        .headers()
+        .get(TOTP_CODE_HEADER)
+        .and_then(|header| header.to_str().ok())
+        .map(str::to_string);
+
+    // NIP-98 events are logins of their own; their second factor also
+    // covers the operation, as the code can't be used twice
+    let mut second_factor_checked = false;
+
+    let user_id = match auth_header.as_deref() {
+        Some(header) if header.starts_with(&format!("Bearer {}", API_KEY_PREFIX)) => {
+            let peer = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| *addr);
+            let ip = client_ip(request.headers(), peer, &state.config);
+            let user_id = api_key_user(&state, &header["Bearer ".len()..], ip, request.method().clone(), &path).await?;
 
-    let token = match auth_header {
-        Some(token) => token,
-        None => return Err(AppError::Authentication("Missing authorization header".to_string())),
+            // Keys are for unattended servers, which can't answer a second
+            // factor; see `create_api_key`
+            request.extensions_mut().insert(user_id);
+            return Ok(next.run(request).await);
+        }
+        Some(header) if header.starts_with("Bearer ") => {
+            let (user_id, session_id) = state.jwt_service.get_session_from_token(&header["Bearer ".len()..])?;
+
+            // Sessions are deleted with their user, so this also verifies the user exists
+            let session_repo = SessionRepository::new(state.db_pool.clone());
+            if !session_repo.is_active(session_id, user_id).await? {
+                return Err(AppError::Authentication("Session has been revoked or has expired".to_string()));
+            }
+
+            request.extensions_mut().insert(CurrentSession(session_id));
+            user_id
+        }
+        Some(header) if header.starts_with("Nostr ") => {
+            let (user_id, verified_request) = nip98_user(&state, request, &header["Nostr ".len()..]).await?;
+            state.totp_service.check_login(user_id, code.as_deref()).await?;
+            second_factor_checked = true;
+            request = verified_request;
+            user_id
+        }
+        _ => return Err(AppError::Authentication("Missing authorization header".to_string())),
     };
 
-    let user_id = state.jwt_service.get_user_id_from_token(token)?;
-    
-    // Verify user exists
-    let user_repo = UserRepository::new(state.db_pool.clone());
-    let user = user_repo.find_by_id(user_id).await?;
-    
-    if user.is_none() {
-        return Err(AppError::Authentication("User not found".to_string()));
+    match protected_operation(request.method(), &path) {
+        Some(operation) if !second_factor_checked => {
+            state.totp_service.check_operation(user_id, operation, code.as_deref()).await?;
+        }
+        _ => {}
     }
 
     // Add user ID to request extensions for use in handlers
@@ -39,3 +95,145 @@ pub async fn auth_middleware(
     
     Ok(next.run(request).await)
 }
+
+/// Authenticate a request made with an API key, enforcing its IP allowlist
+/// and the scope the route requires
+async fn api_key_user(
+    state: &AppState,
+    key: &str,
+    ip: Option<IpAddr>,
+    method: Method,
+    path: &str,
+) -> Result<Uuid, AppError> {
+    let api_key_repo = ApiKeyRepository::new(state.db_pool.clone());
+    let api_key = api_key_repo.find_active_by_hash(&hash_api_key(key)).await?
+        .ok_or_else(|| AppError::Authentication("Invalid, revoked or expired API key".to_string()))?;
+
+    if !api_key.allowed_ips.is_empty()
+        && !ip.is_some_and(|ip| api_key.allowed_ips.iter().any(|entry| ip_in_range(ip, entry)))
+    {
+        return Err(AppError::Authorization("API key is not allowed from this address".to_string()));
+    }
+
+    let scope = required_scope(&method, path)
+        .ok_or_else(|| AppError::Authorization("This endpoint cannot be used with an API key".to_string()))?;
+    if !api_key.scopes.iter().any(|granted| granted == scope.as_str()) {
+        return Err(AppError::Authorization(format!("API key lacks the {} scope", scope.as_str())));
+    }
+
+    api_key_repo.record_use(api_key.id, ip.map(|ip| ip.to_string()).as_deref()).await?;
+
+    Ok(api_key.user_id)
+}
+
+/// The scope an API key needs for a route, or None for routes that need a
+/// user session (account, session and API key management, node setup)
+fn required_scope(method: &Method, path: &str) -> Option<ApiScope> {
+    match (method.as_str(), path) {
+        ("GET", "/node/info" | "/node/balance" | "/node/payments" | "/lnurl/invoices") => Some(ApiScope::NodeRead),
+        ("POST", "/node/pay" | "/node/withdraw") => Some(ApiScope::PaymentsSend),
+        ("POST", "/node/offer") | ("GET", "/node/payment-uri") => Some(ApiScope::OffersWrite),
+        ("GET", "/usernames" | "/usernames/transfers" | "/usernames/:username/history") => Some(ApiScope::UsernamesRead),
+        (_, path) if path == "/usernames" || path.starts_with("/usernames/") => Some(ApiScope::UsernamesWrite),
+        _ => None,
+    }
+}
+
+/// The operation a route performs, if users can require a second factor for
+/// it. API key creation is checked by its handler, which also covers what
+/// the requested scopes can do.
+fn protected_operation(method: &Method, path: &str) -> Option<ProtectedOperation> {
+    match (method.as_str(), path) {
+        ("POST", "/node/pay") => Some(ProtectedOperation::Payments),
+        ("POST", "/node/withdraw") => Some(ProtectedOperation::Withdrawals),
+        ("POST", "/usernames/:username/transfer") => Some(ProtectedOperation::UsernameTransfer),
+        _ => None,
+    }
+}
+
+/// Authenticate a request by its NIP-98 `Authorization: Nostr <base64 event>`
+/// header and map the signing key to a user. The body is buffered to check
+/// the `payload` hash, so the request is handed back rebuilt.
+async fn nip98_user(state: &AppState, request: Request, encoded_event: &str) -> Result<(Uuid, Request), AppError> {
+    let invalid = |reason: &str| AppError::Authentication(format!("Invalid NIP-98 authorization: {}", reason));
+
+    let event = general_purpose::STANDARD
+        .decode(encoded_event.trim())
+        .ok()
+        .and_then(|json| serde_json::from_slice::<NostrEvent>(&json).ok())
+        .ok_or_else(|| invalid("malformed event"))?;
+
+    if event.kind != KIND_HTTP_AUTH {
+        return Err(invalid("wrong event kind"));
+    }
+    if (Utc::now().timestamp() - event.created_at).abs() > state.config.nip98_time_window_secs {
+        return Err(invalid("event is outside the allowed time window"));
+    }
+    event.verify()?;
+
+    // `u` must be the absolute URL that was requested, as seen from outside
+    let uri = request
+        .extensions()
+        .get::<OriginalUri>()
+        .map(|original| original.0.clone())
+        .unwrap_or_else(|| request.uri().clone());
+    let path_and_query = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+    let expected_url = format!("{}{}", state.config.public_url.trim_end_matches('/'), path_and_query);
+    if event.tag("u") != Some(expected_url.as_str()) {
+        return Err(invalid("URL does not match"));
+    }
+
+    if !event.tag("method").is_some_and(|method| method.eq_ignore_ascii_case(request.method().as_str())) {
+        return Err(invalid("method does not match"));
+    }
+
+    let (parts, request_body) = request.into_parts();
+    let bytes = body::to_bytes(request_body, NIP98_MAX_BODY_BYTES)
+        .await
+        .map_err(|_| AppError::BadRequest("Request body too large".to_string()))?;
+
+    // A request with a body must commit to it, so a signed event can't be
+    // replayed with a different payload inside the time window
+    match event.tag("payload") {
+        Some(payload) if !payload.eq_ignore_ascii_case(&hex::encode(Sha256::digest(&bytes))) => {
+            return Err(invalid("payload hash does not match"));
+        }
+        None if !bytes.is_empty() => return Err(invalid("missing payload hash")),
+        _ => {}
+    }
+
+    let user_repo = UserRepository::new(state.db_pool.clone());
+    let user = user_repo.find_by_public_key(&event.pubkey.to_lowercase()).await?
+        .ok_or_else(|| AppError::Authentication("User not found".to_string()))?;
+
+    Ok((user.id, Request::from_parts(parts, Body::from(bytes))))
+}
+
+/// Guard for operator endpoints: `Authorization: Bearer <ADMIN_API_KEY>`
+pub async fn admin_auth_middleware(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> std::result::Result<Response, AppError> {
+    let admin_api_key = state.config.admin_api_key.as_deref()
+        .ok_or_else(|| AppError::Authorization("Admin API is disabled".to_string()))?;
+
+    let provided = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .ok_or_else(|| AppError::Authentication("Missing authorization header".to_string()))?;
+
+    let matches = provided.len() == admin_api_key.len()
+        && provided
+            .bytes()
+            .zip(admin_api_key.bytes())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0;
+
+    if !matches {
+        return Err(AppError::Authentication("Invalid admin API key".to_string()));
+    }
+
+    Ok(next.run(request).await)
+}
@@ -0,0 +1,209 @@
+use axum::http::HeaderMap;
+use std::net::{IpAddr, SocketAddr};
+use crate::config::Config;
+
+/// The caller's address. Behind a reverse proxy (`TRUST_PROXY_HEADERS`) this
+/// is the rightmost `X-Forwarded-For` hop that isn't one of `TRUSTED_PROXIES`;
+/// hops further left were written by the client and prove nothing. With no
+/// proxies configured, the one proxy in front of us added the rightmost hop.
+/// Otherwise, or if the peer isn't a trusted proxy, it is the TCP peer.
+pub fn client_ip(headers: &HeaderMap, peer: Option<SocketAddr>, config: &Config) -> Option<IpAddr> {
+    let peer_ip = peer.map(|addr| addr.ip());
+    if !config.trust_proxy_headers {
+        return peer_ip;
+    }
+
+    let is_trusted = |ip: IpAddr| config.trusted_proxies.iter().any(|proxy| ip_in_range(ip, proxy));
+    if !config.trusted_proxies.is_empty() && !peer_ip.is_some_and(is_trusted) {
+        return peer_ip;
+    }
+
+    // Proxies append to the last header line, so read the lines in order
+    let hops: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+
+    let mut client = None;
+    for hop in hops.iter().rev() {
+        let ip = match hop.parse::<IpAddr>() {
+            Ok(ip) => ip,
+            // Whatever the client wrote; stop at the last hop we could read
+            Err(_) => break,
+        };
+        client = Some(ip);
+        if config.trusted_proxies.is_empty() || !is_trusted(ip) {
+            break;
+        }
+    }
+
+    client.or(peer_ip)
+}
+
+/// Whether `entry` is a valid IP address or CIDR range
+pub fn is_valid_ip_range(entry: &str) -> bool {
+    parse_range(entry).is_some()
+}
+
+/// Whether `ip` is `entry`, or inside `entry` when it is a CIDR range
+pub fn ip_in_range(ip: IpAddr, entry: &str) -> bool {
+    let (network, prefix) = match parse_range(entry) {
+        Some(range) => range,
+        None => return false,
+    };
+
+    // Compare IPv4-mapped IPv6 callers against IPv4 ranges
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    };
+
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+fn parse_range(entry: &str) -> Option<(IpAddr, u32)> {
+    let (address, prefix) = match entry.split_once('/') {
+        Some((address, prefix)) => (address, Some(prefix)),
+        None => (entry, None),
+    };
+
+    let address: IpAddr = address.trim().parse().ok()?;
+    let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix.trim().parse::<u32>().ok().filter(|prefix| *prefix <= max_prefix)?,
+        None => max_prefix,
+    };
+
+    Some((address, prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(trust_proxy_headers: bool, trusted_proxies: &[&str]) -> Config {
+        Config {
+            trust_proxy_headers,
+            trusted_proxies: trusted_proxies.iter().map(|proxy| proxy.to_string()).collect(),
+            ..Config::from_env().unwrap()
+        }
+    }
+
+    fn forwarded_for(lines: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for line in lines {
+            headers.append("x-forwarded-for", line.parse().unwrap());
+        }
+        headers
+    }
+
+    fn peer(ip: &str) -> Option<SocketAddr> {
+        Some(SocketAddr::new(ip.parse().unwrap(), 443))
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn ignores_headers_unless_trusted() {
+        let headers = forwarded_for(&["203.0.113.7"]);
+        assert_eq!(client_ip(&headers, peer("10.0.0.1"), &config(false, &[])), ip("10.0.0.1"));
+        assert_eq!(client_ip(&headers, None, &config(false, &[])), None);
+    }
+
+    #[test]
+    fn uses_rightmost_hop_without_trusted_proxies() {
+        let headers = forwarded_for(&["1.2.3.4, 203.0.113.7"]);
+        assert_eq!(client_ip(&headers, peer("10.0.0.1"), &config(true, &[])), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn skips_trusted_proxies_from_the_right() {
+        let config = config(true, &["10.0.0.0/8", "2001:db8::/32"]);
+
+        // The client prepended a spoofed hop; the first untrusted hop from the right wins
+        let headers = forwarded_for(&["1.2.3.4, 203.0.113.7, 10.1.2.3"]);
+        assert_eq!(client_ip(&headers, peer("10.0.0.1"), &config), ip("203.0.113.7"));
+
+        // Trusted ranges spoofed by the client are skipped past too
+        let headers = forwarded_for(&["10.9.9.9", "198.51.100.2, 2001:db8::1"]);
+        assert_eq!(client_ip(&headers, peer("10.0.0.1"), &config), ip("198.51.100.2"));
+
+        // Only proxies in the chain: the leftmost of them
+        let headers = forwarded_for(&["10.2.2.2, 10.1.1.1"]);
+        assert_eq!(client_ip(&headers, peer("10.0.0.1"), &config), ip("10.2.2.2"));
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let headers = forwarded_for(&["203.0.113.7"]);
+        let config = config(true, &["10.0.0.0/8"]);
+        assert_eq!(client_ip(&headers, peer("198.51.100.9"), &config), ip("198.51.100.9"));
+        assert_eq!(client_ip(&headers, None, &config), None);
+    }
+
+    #[test]
+    fn stops_at_malformed_hops() {
+        let config = config(true, &["10.0.0.0/8"]);
+
+        let headers = forwarded_for(&["203.0.113.7, garbage, 10.1.1.1"]);
+        assert_eq!(client_ip(&headers, peer("10.0.0.1"), &config), ip("10.1.1.1"));
+
+        let headers = forwarded_for(&["203.0.113.7, unknown"]);
+        assert_eq!(client_ip(&headers, peer("10.0.0.1"), &config), ip("10.0.0.1"));
+
+        let headers = forwarded_for(&[]);
+        assert_eq!(client_ip(&headers, peer("10.0.0.1"), &config), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn matches_ipv4_ranges() {
+        let addr: IpAddr = "192.168.1.77".parse().unwrap();
+        assert!(ip_in_range(addr, "192.168.1.77"));
+        assert!(ip_in_range(addr, "192.168.1.0/24"));
+        assert!(ip_in_range(addr, "192.168.0.0/16"));
+        assert!(ip_in_range(addr, "0.0.0.0/0"));
+        assert!(!ip_in_range(addr, "192.168.2.0/24"));
+        assert!(!ip_in_range(addr, "192.168.1.76/32"));
+
+        // IPv4-mapped IPv6 callers match IPv4 ranges
+        assert!(ip_in_range("::ffff:192.168.1.77".parse().unwrap(), "192.168.1.0/24"));
+    }
+
+    #[test]
+    fn matches_ipv6_ranges() {
+        let addr: IpAddr = "2001:db8:abcd::1".parse().unwrap();
+        assert!(ip_in_range(addr, "2001:db8::/32"));
+        assert!(ip_in_range(addr, "2001:db8:abcd::1/128"));
+        assert!(ip_in_range(addr, "::/0"));
+        assert!(!ip_in_range(addr, "2001:db9::/32"));
+
+        // Address families never match each other
+        assert!(!ip_in_range(addr, "0.0.0.0/0"));
+        assert!(!ip_in_range("10.0.0.1".parse().unwrap(), "::/0"));
+    }
+
+    #[test]
+    fn rejects_malformed_ranges() {
+        let addr: IpAddr = "10.0.0.1".parse().unwrap();
+        for entry in ["", "10.0.0.0/33", "10.0.0.0/", "10.0.0.0/-1", "10.0.0/8", "::/129", "localhost"] {
+            assert!(!is_valid_ip_range(entry), "{:?} should be invalid", entry);
+            assert!(!ip_in_range(addr, entry));
+        }
+        assert!(is_valid_ip_range(" 10.0.0.0 / 8 "));
+    }
+}
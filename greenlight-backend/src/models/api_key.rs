@@ -0,0 +1,177 @@
+use sqlx::PgPool;
+use uuid::Uuid;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::error::Result;
+use crate::models::ProtectedOperation;
+
+#[derive(Debug, Clone, sqlx::FromRow, Serialize)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub allowed_ips: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub last_used_ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub allowed_ips: Option<Vec<String>>,
+    pub expires_in_days: Option<i64>,
+}
+
+/// What an API key may do; routes outside a key's scopes reject it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiScope {
+    /// Node info, balance and issued invoices
+    NodeRead,
+    /// Create offers and payment requests
+    OffersWrite,
+    /// Send payments from the node
+    PaymentsSend,
+    /// List usernames, their history and incoming transfers
+    UsernamesRead,
+    /// Claim, update, release and transfer usernames
+    UsernamesWrite,
+}
+
+impl ApiScope {
+    pub const ALL: [ApiScope; 5] = [
+        ApiScope::NodeRead,
+        ApiScope::OffersWrite,
+        ApiScope::PaymentsSend,
+        ApiScope::UsernamesRead,
+        ApiScope::UsernamesWrite,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiScope::NodeRead => "node:read",
+            ApiScope::OffersWrite => "offers:write",
+            ApiScope::PaymentsSend => "payments:send",
+            ApiScope::UsernamesRead => "usernames:read",
+            ApiScope::UsernamesWrite => "usernames:write",
+        }
+    }
+
+    pub fn parse(scope: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|candidate| candidate.as_str() == scope)
+    }
+
+    /// The operations a user can protect with a second factor that a key
+    /// with this scope performs without one
+    pub fn protected_operations(&self) -> &'static [ProtectedOperation] {
+        match self {
+            ApiScope::PaymentsSend => &[ProtectedOperation::Payments, ProtectedOperation::Withdrawals],
+            ApiScope::UsernamesWrite => &[ProtectedOperation::UsernameTransfer],
+            ApiScope::NodeRead | ApiScope::OffersWrite | ApiScope::UsernamesRead => &[],
+        }
+    }
+}
+
+pub struct ApiKeyRepository {
+    pool: PgPool,
+}
+
+impl ApiKeyRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn create_api_key(
+        &self,
+        user_id: Uuid,
+        name: &str,
+        prefix: &str,
+        key_hash: &str,
+        scopes: &[String],
+        allowed_ips: &[String],
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<ApiKey> {
+        let api_key = sqlx::query_as::<_, ApiKey>(
+            r#"
+            INSERT INTO api_keys (id, user_id, name, prefix, key_hash, scopes, allowed_ips, expires_at, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            RETURNING id, user_id, name, prefix, scopes, allowed_ips, expires_at, last_used_at, last_used_ip, created_at, revoked_at
+            "#
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(name)
+        .bind(prefix)
+        .bind(key_hash)
+        .bind(scopes)
+        .bind(allowed_ips)
+        .bind(expires_at)
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(api_key)
+    }
+
+    /// The unrevoked, unexpired key with this hash
+    pub async fn find_active_by_hash(&self, key_hash: &str) -> Result<Option<ApiKey>> {
+        let api_key = sqlx::query_as::<_, ApiKey>(
+            r#"
+            SELECT id, user_id, name, prefix, scopes, allowed_ips, expires_at, last_used_at, last_used_ip, created_at, revoked_at
+            FROM api_keys
+            WHERE key_hash = $1 AND revoked_at IS NULL AND (expires_at IS NULL OR expires_at > $2)
+            "#
+        )
+        .bind(key_hash)
+        .bind(Utc::now())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(api_key)
+    }
+
+    pub async fn find_by_user(&self, user_id: Uuid) -> Result<Vec<ApiKey>> {
+        let api_keys = sqlx::query_as::<_, ApiKey>(
+            r#"
+            SELECT id, user_id, name, prefix, scopes, allowed_ips, expires_at, last_used_at, last_used_ip, created_at, revoked_at
+            FROM api_keys WHERE user_id = $1 AND revoked_at IS NULL ORDER BY created_at
+            "#
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(api_keys)
+    }
+
+    /// Revoke one of `user_id`'s keys, returning false if there is no such key
+    pub async fn revoke_api_key(&self, id: Uuid, user_id: Uuid) -> Result<bool> {
+        let result = sqlx::query(
+            "UPDATE api_keys SET revoked_at = $1 WHERE id = $2 AND user_id = $3 AND revoked_at IS NULL"
+        )
+        .bind(Utc::now())
+        .bind(id)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn record_use(&self, id: Uuid, ip: Option<&str>) -> Result<()> {
+        sqlx::query("UPDATE api_keys SET last_used_at = $1, last_used_ip = $2 WHERE id = $3")
+            .bind(Utc::now())
+            .bind(ip)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,91 @@
+use sqlx::PgPool;
+use uuid::Uuid;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use crate::error::Result;
+use crate::services::nostr::NostrEvent;
+
+#[derive(Debug, Clone, sqlx::FromRow, Serialize)]
+pub struct AuthChallenge {
+    pub id: Uuid,
+    pub public_key: String,
+    pub nonce: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChallengeRequest {
+    pub public_key: String,
+}
+
+/// Either a BIP340 `signature` over the challenge message by `public_key`,
+/// or a signed Nostr `event` carrying the nonce in a `challenge` tag
+#[derive(Debug, Deserialize)]
+pub struct SignatureLoginRequest {
+    pub public_key: Option<String>,
+    pub nonce: Option<String>,
+    pub signature: Option<String>,
+    pub event: Option<NostrEvent>,
+    /// Authenticator or backup code, required once TOTP is enabled
+    pub totp_code: Option<String>,
+}
+
+pub struct AuthChallengeRepository {
+    pool: PgPool,
+}
+
+impl AuthChallengeRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create_challenge(&self, public_key: &str, nonce: &str, ttl: Duration) -> Result<AuthChallenge> {
+        let now = Utc::now();
+
+        // Nonces are only useful until they expire
+        sqlx::query("DELETE FROM auth_challenges WHERE expires_at <= $1")
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+
+        let challenge = sqlx::query_as::<_, AuthChallenge>(
+            r#"
+            INSERT INTO auth_challenges (id, public_key, nonce, created_at, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id, public_key, nonce, created_at, expires_at, used_at
+            "#
+        )
+        .bind(Uuid::new_v4())
+        .bind(public_key)
+        .bind(nonce)
+        .bind(now)
+        .bind(now + ttl)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(challenge)
+    }
+
+    /// Mark an unexpired, unused challenge for `public_key` as used. Returns
+    /// None if there is no such challenge, so each nonce logs in at most once.
+    pub async fn consume_challenge(&self, public_key: &str, nonce: &str) -> Result<Option<AuthChallenge>> {
+        let now = Utc::now();
+
+        let challenge = sqlx::query_as::<_, AuthChallenge>(
+            r#"
+            UPDATE auth_challenges SET used_at = $1
+            WHERE nonce = $2 AND public_key = $3 AND used_at IS NULL AND expires_at > $1
+            RETURNING id, public_key, nonce, created_at, expires_at, used_at
+            "#
+        )
+        .bind(now)
+        .bind(nonce)
+        .bind(public_key)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(challenge)
+    }
+}
@@ -0,0 +1,77 @@
+use sqlx::PgPool;
+use uuid::Uuid;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::error::Result;
+
+#[derive(Debug, Clone, sqlx::FromRow, Serialize)]
+pub struct LnurlInvoice {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub username: String,
+    pub amount_msat: i64,
+    pub payment_hash: String,
+    pub bolt11: String,
+    pub comment: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LnurlCallbackQuery {
+    pub amount: u64,
+    pub comment: Option<String>,
+}
+
+pub struct LnurlInvoiceRepository {
+    pool: PgPool,
+}
+
+impl LnurlInvoiceRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create_invoice(
+        &self,
+        user_id: Uuid,
+        username: &str,
+        amount_msat: i64,
+        payment_hash: &str,
+        bolt11: &str,
+        comment: Option<&str>,
+    ) -> Result<LnurlInvoice> {
+        let invoice = sqlx::query_as::<_, LnurlInvoice>(
+            r#"
+            INSERT INTO lnurl_invoices (id, user_id, username, amount_msat, payment_hash, bolt11, comment, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING id, user_id, username, amount_msat, payment_hash, bolt11, comment, created_at
+            "#
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(username)
+        .bind(amount_msat)
+        .bind(payment_hash)
+        .bind(bolt11)
+        .bind(comment)
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(invoice)
+    }
+
+    pub async fn find_by_user(&self, user_id: Uuid) -> Result<Vec<LnurlInvoice>> {
+        let invoices = sqlx::query_as::<_, LnurlInvoice>(
+            r#"
+            SELECT id, user_id, username, amount_msat, payment_hash, bolt11, comment, created_at
+            FROM lnurl_invoices WHERE user_id = $1 ORDER BY created_at DESC
+            "#
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(invoices)
+    }
+}
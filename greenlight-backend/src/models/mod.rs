@@ -1,3 +1,19 @@
+pub mod api_key;
+pub mod auth_challenge;
+pub mod lnurl;
+pub mod reconciliation;
+pub mod session;
+pub mod spending;
+pub mod totp;
 pub mod user;
+pub mod username;
 
+pub use api_key::*;
+pub use auth_challenge::*;
+pub use lnurl::*;
+pub use reconciliation::*;
+pub use session::*;
+pub use spending::*;
+pub use totp::*;
 pub use user::*;
+pub use username::*;
@@ -0,0 +1,171 @@
+use sqlx::PgPool;
+use uuid::Uuid;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use crate::error::Result;
+
+#[derive(Debug, Clone, sqlx::FromRow, Serialize)]
+pub struct ReconciliationRun {
+    pub id: Uuid,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub usernames_checked: i32,
+    pub drift_found: i32,
+    pub repaired: i32,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow, Serialize)]
+pub struct ReconciliationFinding {
+    pub id: Uuid,
+    pub run_id: Uuid,
+    pub username: String,
+    pub user_id: Option<Uuid>,
+    pub issue: String,
+    pub detail: Option<String>,
+    pub repaired: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Kinds of drift the reconciliation job reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriftIssue {
+    /// No TXT record is published for the username
+    DnsMissing,
+    /// The published record points at a different offer
+    DnsMismatch,
+    /// The record could not be resolved
+    DnsLookupFailed,
+    /// The owner has no node to serve the offer
+    NoNode,
+    /// The owner's node could not be queried
+    NodeUnavailable,
+    /// The stored offer is not known to the owner's node
+    OfferNotFound,
+    /// The stored offer exists but has been disabled
+    OfferDisabled,
+}
+
+impl DriftIssue {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DriftIssue::DnsMissing => "dns_missing",
+            DriftIssue::DnsMismatch => "dns_mismatch",
+            DriftIssue::DnsLookupFailed => "dns_lookup_failed",
+            DriftIssue::NoNode => "no_node",
+            DriftIssue::NodeUnavailable => "node_unavailable",
+            DriftIssue::OfferNotFound => "offer_not_found",
+            DriftIssue::OfferDisabled => "offer_disabled",
+        }
+    }
+}
+
+pub struct ReconciliationRepository {
+    pool: PgPool,
+}
+
+impl ReconciliationRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create_run(&self) -> Result<ReconciliationRun> {
+        let run = sqlx::query_as::<_, ReconciliationRun>(
+            r#"
+            INSERT INTO reconciliation_runs (id, started_at)
+            VALUES ($1, $2)
+            RETURNING id, started_at, finished_at, usernames_checked, drift_found, repaired
+            "#
+        )
+        .bind(Uuid::new_v4())
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(run)
+    }
+
+    pub async fn finish_run(
+        &self,
+        id: Uuid,
+        usernames_checked: i32,
+        drift_found: i32,
+        repaired: i32,
+    ) -> Result<ReconciliationRun> {
+        let run = sqlx::query_as::<_, ReconciliationRun>(
+            r#"
+            UPDATE reconciliation_runs
+            SET finished_at = $1, usernames_checked = $2, drift_found = $3, repaired = $4
+            WHERE id = $5
+            RETURNING id, started_at, finished_at, usernames_checked, drift_found, repaired
+            "#
+        )
+        .bind(Utc::now())
+        .bind(usernames_checked)
+        .bind(drift_found)
+        .bind(repaired)
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(run)
+    }
+
+    pub async fn add_finding(
+        &self,
+        run_id: Uuid,
+        username: &str,
+        user_id: Uuid,
+        issue: DriftIssue,
+        detail: Option<&str>,
+        repaired: bool,
+    ) -> Result<ReconciliationFinding> {
+        let finding = sqlx::query_as::<_, ReconciliationFinding>(
+            r#"
+            INSERT INTO reconciliation_findings (id, run_id, username, user_id, issue, detail, repaired, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING id, run_id, username, user_id, issue, detail, repaired, created_at
+            "#
+        )
+        .bind(Uuid::new_v4())
+        .bind(run_id)
+        .bind(username)
+        .bind(user_id)
+        .bind(issue.as_str())
+        .bind(detail)
+        .bind(repaired)
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(finding)
+    }
+
+    /// The most recent completed run
+    pub async fn latest_run(&self) -> Result<Option<ReconciliationRun>> {
+        let run = sqlx::query_as::<_, ReconciliationRun>(
+            r#"
+            SELECT id, started_at, finished_at, usernames_checked, drift_found, repaired
+            FROM reconciliation_runs WHERE finished_at IS NOT NULL
+            ORDER BY started_at DESC LIMIT 1
+            "#
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(run)
+    }
+
+    pub async fn findings_for_run(&self, run_id: Uuid) -> Result<Vec<ReconciliationFinding>> {
+        let findings = sqlx::query_as::<_, ReconciliationFinding>(
+            r#"
+            SELECT id, run_id, username, user_id, issue, detail, repaired, created_at
+            FROM reconciliation_findings WHERE run_id = $1 ORDER BY username, created_at
+            "#
+        )
+        .bind(run_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(findings)
+    }
+}
@@ -0,0 +1,259 @@
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::error::Result;
+
+#[derive(Debug, Clone, sqlx::FromRow, Serialize)]
+pub struct Session {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub user_agent: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub revoked_reason: Option<String>,
+}
+
+/// A refresh token row joined with the state of its session
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct RefreshTokenRecord {
+    pub id: Uuid,
+    pub session_id: Uuid,
+    pub user_id: Uuid,
+    pub used_at: Option<DateTime<Utc>>,
+    pub session_expires_at: DateTime<Utc>,
+    pub session_revoked_at: Option<DateTime<Utc>>,
+}
+
+/// Session behind the access token of the current request, set by the auth
+/// middleware for JWT-authenticated requests
+#[derive(Debug, Clone, Copy)]
+pub struct CurrentSession(pub Uuid);
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshTokenRequest {
+    pub refresh_token: String,
+}
+
+/// Why a session was revoked, stored in `sessions.revoked_reason`
+#[derive(Debug, Clone, Copy)]
+pub enum RevokeReason {
+    Logout,
+    LogoutAll,
+    RefreshTokenReuse,
+    PasswordChanged,
+    AccountRecovered,
+}
+
+impl RevokeReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RevokeReason::Logout => "logout",
+            RevokeReason::LogoutAll => "logout_all",
+            RevokeReason::RefreshTokenReuse => "refresh_token_reuse",
+            RevokeReason::PasswordChanged => "password_changed",
+            RevokeReason::AccountRecovered => "account_recovered",
+        }
+    }
+}
+
+pub struct SessionRepository {
+    pool: PgPool,
+}
+
+impl SessionRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create_session(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        user_id: Uuid,
+        user_agent: Option<&str>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<Session> {
+        let now = Utc::now();
+
+        let session = sqlx::query_as::<_, Session>(
+            r#"
+            INSERT INTO sessions (id, user_id, user_agent, created_at, last_used_at, expires_at)
+            VALUES ($1, $2, $3, $4, $4, $5)
+            RETURNING id, user_id, user_agent, created_at, last_used_at, expires_at, revoked_at, revoked_reason
+            "#
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(user_agent)
+        .bind(now)
+        .bind(expires_at)
+        .fetch_one(&mut **tx)
+        .await?;
+
+        Ok(session)
+    }
+
+    /// Whether `session_id` belongs to `user_id` and is neither revoked nor expired
+    pub async fn is_active(&self, session_id: Uuid, user_id: Uuid) -> Result<bool> {
+        let active = sqlx::query_scalar::<_, bool>(
+            r#"
+            SELECT EXISTS(
+                SELECT 1 FROM sessions
+                WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL AND expires_at > $3
+            )
+            "#
+        )
+        .bind(session_id)
+        .bind(user_id)
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(active)
+    }
+
+    pub async fn find_by_user(&self, user_id: Uuid) -> Result<Vec<Session>> {
+        let sessions = sqlx::query_as::<_, Session>(
+            r#"
+            SELECT id, user_id, user_agent, created_at, last_used_at, expires_at, revoked_at, revoked_reason
+            FROM sessions WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > $2
+            ORDER BY last_used_at DESC
+            "#
+        )
+        .bind(user_id)
+        .bind(Utc::now())
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(sessions)
+    }
+
+    /// Extend a session after a successful refresh
+    pub async fn touch_session(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        session_id: Uuid,
+        expires_at: DateTime<Utc>,
+    ) -> Result<()> {
+        sqlx::query("UPDATE sessions SET last_used_at = $1, expires_at = $2 WHERE id = $3")
+            .bind(Utc::now())
+            .bind(expires_at)
+            .bind(session_id)
+            .execute(&mut **tx)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn revoke_session(&self, session_id: Uuid, reason: RevokeReason) -> Result<()> {
+        sqlx::query(
+            "UPDATE sessions SET revoked_at = $1, revoked_reason = $2 WHERE id = $3 AND revoked_at IS NULL"
+        )
+        .bind(Utc::now())
+        .bind(reason.as_str())
+        .bind(session_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Revoke every open session of a user, returning how many were revoked
+    pub async fn revoke_all_for_user(&self, user_id: Uuid, reason: RevokeReason) -> Result<u64> {
+        let result = sqlx::query(
+            "UPDATE sessions SET revoked_at = $1, revoked_reason = $2 WHERE user_id = $3 AND revoked_at IS NULL"
+        )
+        .bind(Utc::now())
+        .bind(reason.as_str())
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Revoke all of a user's open sessions except `keep`
+    pub async fn revoke_other_sessions(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        user_id: Uuid,
+        keep: Option<Uuid>,
+        reason: RevokeReason,
+    ) -> Result<u64> {
+        let result = sqlx::query(
+            r#"
+            UPDATE sessions SET revoked_at = $1, revoked_reason = $2
+            WHERE user_id = $3 AND revoked_at IS NULL AND id IS DISTINCT FROM $4
+            "#
+        )
+        .bind(Utc::now())
+        .bind(reason.as_str())
+        .bind(user_id)
+        .bind(keep)
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn create_refresh_token(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        session_id: Uuid,
+        token_hash: &str,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO refresh_tokens (id, session_id, token_hash, created_at)
+            VALUES ($1, $2, $3, $4)
+            "#
+        )
+        .bind(Uuid::new_v4())
+        .bind(session_id)
+        .bind(token_hash)
+        .bind(Utc::now())
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Look up a refresh token and lock it, so concurrent refreshes with the
+    /// same token are serialized and the second one sees it as used
+    pub async fn find_refresh_token_for_update(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        token_hash: &str,
+    ) -> Result<Option<RefreshTokenRecord>> {
+        let record = sqlx::query_as::<_, RefreshTokenRecord>(
+            r#"
+            SELECT r.id, r.session_id, s.user_id, r.used_at,
+                   s.expires_at AS session_expires_at, s.revoked_at AS session_revoked_at
+            FROM refresh_tokens r JOIN sessions s ON s.id = r.session_id
+            WHERE r.token_hash = $1
+            FOR UPDATE OF r
+            "#
+        )
+        .bind(token_hash)
+        .fetch_optional(&mut **tx)
+        .await?;
+
+        Ok(record)
+    }
+
+    pub async fn mark_refresh_token_used(&self, tx: &mut Transaction<'_, Postgres>, id: Uuid) -> Result<()> {
+        sqlx::query("UPDATE refresh_tokens SET used_at = $1 WHERE id = $2")
+            .bind(Utc::now())
+            .bind(id)
+            .execute(&mut **tx)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn begin(&self) -> Result<Transaction<'static, Postgres>> {
+        Ok(self.pool.begin().await?)
+    }
+}
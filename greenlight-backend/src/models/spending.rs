@@ -0,0 +1,550 @@
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::error::{AppError, Result};
+
+/// Cooling-off for new destinations when the user set no policy
+pub const DEFAULT_COOLING_OFF_HOURS: i64 = 24;
+
+/// Limits are in satoshis; None means unlimited
+#[derive(Debug, Clone, sqlx::FromRow, Serialize)]
+pub struct SpendingPolicy {
+    pub user_id: Uuid,
+    pub daily_limit_sat: Option<i64>,
+    pub weekly_limit_sat: Option<i64>,
+    pub max_payment_sat: Option<i64>,
+    pub max_fee_sat: Option<i64>,
+    pub max_feerate_sat_vb: Option<i64>,
+    pub allowlist_enabled: bool,
+    pub cooling_off_hours: i64,
+    pub updated_at: DateTime<Utc>,
+    /// A loosening change, applied once `pending_effective_at` has passed
+    pub pending_changes: Option<Value>,
+    pub pending_effective_at: Option<DateTime<Utc>>,
+}
+
+impl SpendingPolicy {
+    /// What applies to users who never set a policy
+    pub fn unrestricted(user_id: Uuid) -> Self {
+        Self {
+            user_id,
+            daily_limit_sat: None,
+            weekly_limit_sat: None,
+            max_payment_sat: None,
+            max_fee_sat: None,
+            max_feerate_sat_vb: None,
+            allowlist_enabled: false,
+            cooling_off_hours: DEFAULT_COOLING_OFF_HOURS,
+            updated_at: Utc::now(),
+            pending_changes: None,
+            pending_effective_at: None,
+        }
+    }
+}
+
+/// Replaces the whole policy; omitted limits become unlimited
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct UpdateSpendingPolicyRequest {
+    pub daily_limit_sat: Option<i64>,
+    pub weekly_limit_sat: Option<i64>,
+    pub max_payment_sat: Option<i64>,
+    pub max_fee_sat: Option<i64>,
+    pub max_feerate_sat_vb: Option<i64>,
+    #[serde(default)]
+    pub allowlist_enabled: bool,
+    pub cooling_off_hours: Option<i64>,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow, Serialize)]
+pub struct SpendingDestination {
+    pub id: Uuid,
+    pub kind: String,
+    pub value: String,
+    pub label: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// End of the cooling-off period
+    pub usable_after: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddDestinationRequest {
+    pub kind: String,
+    pub value: String,
+    pub label: Option<String>,
+}
+
+/// What an allowlist entry identifies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DestinationKind {
+    /// A Lightning node id, covering every invoice and offer it issues
+    NodeId,
+    /// A BIP353 human-readable name, `user@domain`
+    Bip353,
+    /// An on-chain address
+    Address,
+}
+
+impl DestinationKind {
+    pub const ALL: [DestinationKind; 3] = [DestinationKind::NodeId, DestinationKind::Bip353, DestinationKind::Address];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DestinationKind::NodeId => "node_id",
+            DestinationKind::Bip353 => "bip353",
+            DestinationKind::Address => "address",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|candidate| candidate.as_str() == kind)
+    }
+}
+
+#[derive(Debug, Clone, sqlx::FromRow, Serialize)]
+pub struct OutgoingPayment {
+    pub id: Uuid,
+    pub kind: String,
+    pub destination: String,
+    pub amount_msat: i64,
+    pub fee_msat: i64,
+    pub status: String,
+    pub denial_reason: Option<String>,
+    pub payment_hash: Option<String>,
+    pub txid: Option<String>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaymentKind {
+    Lightning,
+    Onchain,
+}
+
+impl PaymentKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaymentKind::Lightning => "lightning",
+            PaymentKind::Onchain => "onchain",
+        }
+    }
+}
+
+#[derive(Debug, Clone, sqlx::FromRow, Serialize)]
+pub struct PolicyAuditEntry {
+    pub id: Uuid,
+    pub action: String,
+    pub details: Value,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Kinds of rows in `spending_policy_audit`
+#[derive(Debug, Clone, Copy)]
+pub enum PolicyAuditAction {
+    PolicyUpdated,
+    DestinationAdded,
+    DestinationRemoved,
+}
+
+impl PolicyAuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PolicyAuditAction::PolicyUpdated => "policy_updated",
+            PolicyAuditAction::DestinationAdded => "destination_added",
+            PolicyAuditAction::DestinationRemoved => "destination_removed",
+        }
+    }
+}
+
+pub struct SpendingRepository {
+    pool: PgPool,
+}
+
+impl SpendingRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn find_policy(&self, user_id: Uuid) -> Result<Option<SpendingPolicy>> {
+        let mut tx = self.begin().await?;
+        self.apply_pending_changes(&mut tx, user_id).await?;
+        tx.commit().await?;
+
+        let policy = sqlx::query_as::<_, SpendingPolicy>(
+            r#"
+            SELECT user_id, daily_limit_sat, weekly_limit_sat, max_payment_sat, max_fee_sat,
+                   max_feerate_sat_vb, allowlist_enabled, cooling_off_hours, updated_at,
+                   pending_changes, pending_effective_at
+            FROM spending_policies WHERE user_id = $1
+            "#
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(policy)
+    }
+
+    /// The policy row locked for the rest of the transaction, so concurrent
+    /// payments of one user are evaluated one after the other. Pending changes
+    /// that are due are applied first.
+    pub async fn lock_policy(&self, tx: &mut Transaction<'_, Postgres>, user_id: Uuid) -> Result<Option<SpendingPolicy>> {
+        self.apply_pending_changes(tx, user_id).await?;
+
+        let policy = sqlx::query_as::<_, SpendingPolicy>(
+            r#"
+            SELECT user_id, daily_limit_sat, weekly_limit_sat, max_payment_sat, max_fee_sat,
+                   max_feerate_sat_vb, allowlist_enabled, cooling_off_hours, updated_at,
+                   pending_changes, pending_effective_at
+            FROM spending_policies WHERE user_id = $1
+            FOR UPDATE
+            "#
+        )
+        .bind(user_id)
+        .fetch_optional(&mut **tx)
+        .await?;
+
+        Ok(policy)
+    }
+
+    /// Write the policy that is in force now, with the requested change that
+    /// has to wait out the cooling-off period, if any
+    pub async fn upsert_policy(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        user_id: Uuid,
+        policy: &UpdateSpendingPolicyRequest,
+        pending: Option<(&UpdateSpendingPolicyRequest, DateTime<Utc>)>,
+    ) -> Result<SpendingPolicy> {
+        let pending_changes = pending.map(|(changes, _)| serde_json::to_value(changes)).transpose()
+            .map_err(|e| AppError::Internal(format!("Failed to serialize pending policy: {}", e)))?;
+
+        let policy = sqlx::query_as::<_, SpendingPolicy>(
+            r#"
+            INSERT INTO spending_policies (user_id, daily_limit_sat, weekly_limit_sat, max_payment_sat, max_fee_sat,
+                                           max_feerate_sat_vb, allowlist_enabled, cooling_off_hours, updated_at,
+                                           pending_changes, pending_effective_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            ON CONFLICT (user_id) DO UPDATE
+            SET daily_limit_sat = EXCLUDED.daily_limit_sat,
+                weekly_limit_sat = EXCLUDED.weekly_limit_sat,
+                max_payment_sat = EXCLUDED.max_payment_sat,
+                max_fee_sat = EXCLUDED.max_fee_sat,
+                max_feerate_sat_vb = EXCLUDED.max_feerate_sat_vb,
+                allowlist_enabled = EXCLUDED.allowlist_enabled,
+                cooling_off_hours = EXCLUDED.cooling_off_hours,
+                updated_at = EXCLUDED.updated_at,
+                pending_changes = EXCLUDED.pending_changes,
+                pending_effective_at = EXCLUDED.pending_effective_at
+            RETURNING user_id, daily_limit_sat, weekly_limit_sat, max_payment_sat, max_fee_sat,
+                      max_feerate_sat_vb, allowlist_enabled, cooling_off_hours, updated_at,
+                      pending_changes, pending_effective_at
+            "#
+        )
+        .bind(user_id)
+        .bind(policy.daily_limit_sat)
+        .bind(policy.weekly_limit_sat)
+        .bind(policy.max_payment_sat)
+        .bind(policy.max_fee_sat)
+        .bind(policy.max_feerate_sat_vb)
+        .bind(policy.allowlist_enabled)
+        .bind(policy.cooling_off_hours.unwrap_or(DEFAULT_COOLING_OFF_HOURS))
+        .bind(Utc::now())
+        .bind(pending_changes)
+        .bind(pending.map(|(_, effective_at)| effective_at))
+        .fetch_one(&mut **tx)
+        .await?;
+
+        Ok(policy)
+    }
+
+    /// Swap in a pending change whose cooling-off period has passed
+    async fn apply_pending_changes(&self, tx: &mut Transaction<'_, Postgres>, user_id: Uuid) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE spending_policies
+            SET daily_limit_sat = (pending_changes->>'daily_limit_sat')::BIGINT,
+                weekly_limit_sat = (pending_changes->>'weekly_limit_sat')::BIGINT,
+                max_payment_sat = (pending_changes->>'max_payment_sat')::BIGINT,
+                max_fee_sat = (pending_changes->>'max_fee_sat')::BIGINT,
+                max_feerate_sat_vb = (pending_changes->>'max_feerate_sat_vb')::BIGINT,
+                allowlist_enabled = (pending_changes->>'allowlist_enabled')::BOOLEAN,
+                cooling_off_hours = COALESCE((pending_changes->>'cooling_off_hours')::BIGINT, cooling_off_hours),
+                updated_at = pending_effective_at,
+                pending_changes = NULL,
+                pending_effective_at = NULL
+            WHERE user_id = $1 AND pending_effective_at <= $2
+            "#
+        )
+        .bind(user_id)
+        .bind(Utc::now())
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn find_destinations(&self, user_id: Uuid) -> Result<Vec<SpendingDestination>> {
+        let destinations = sqlx::query_as::<_, SpendingDestination>(
+            r#"
+            SELECT id, kind, value, label, created_at, usable_after
+            FROM spending_destinations WHERE user_id = $1 AND removed_at IS NULL ORDER BY created_at
+            "#
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(destinations)
+    }
+
+    /// Allowlist entries matching any of the `(kind, value)` identities of a payment
+    pub async fn find_matching_destinations(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        user_id: Uuid,
+        identities: &[(DestinationKind, String)],
+    ) -> Result<Vec<SpendingDestination>> {
+        let kinds: Vec<&str> = identities.iter().map(|(kind, _)| kind.as_str()).collect();
+        let values: Vec<&str> = identities.iter().map(|(_, value)| value.as_str()).collect();
+
+        let destinations = sqlx::query_as::<_, SpendingDestination>(
+            r#"
+            SELECT d.id, d.kind, d.value, d.label, d.created_at, d.usable_after
+            FROM spending_destinations d
+            JOIN UNNEST($2::text[], $3::text[]) AS identity(kind, value)
+              ON d.kind = identity.kind AND d.value = identity.value
+            WHERE d.user_id = $1 AND d.removed_at IS NULL
+            "#
+        )
+        .bind(user_id)
+        .bind(&kinds)
+        .bind(&values)
+        .fetch_all(&mut **tx)
+        .await?;
+
+        Ok(destinations)
+    }
+
+    pub async fn add_destination(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        user_id: Uuid,
+        kind: DestinationKind,
+        value: &str,
+        label: Option<&str>,
+        usable_after: DateTime<Utc>,
+    ) -> Result<SpendingDestination> {
+        let destination = sqlx::query_as::<_, SpendingDestination>(
+            r#"
+            INSERT INTO spending_destinations (id, user_id, kind, value, label, created_at, usable_after)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING id, kind, value, label, created_at, usable_after
+            "#
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(kind.as_str())
+        .bind(value)
+        .bind(label)
+        .bind(Utc::now())
+        .bind(usable_after)
+        .fetch_one(&mut **tx)
+        .await?;
+
+        Ok(destination)
+    }
+
+    pub async fn destination_exists(&self, user_id: Uuid, kind: DestinationKind, value: &str) -> Result<bool> {
+        let exists: (bool,) = sqlx::query_as(
+            r#"
+            SELECT EXISTS(
+                SELECT 1 FROM spending_destinations
+                WHERE user_id = $1 AND kind = $2 AND value = $3 AND removed_at IS NULL
+            )
+            "#
+        )
+        .bind(user_id)
+        .bind(kind.as_str())
+        .bind(value)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(exists.0)
+    }
+
+    /// Take a destination off the allowlist, returning it if it was there
+    pub async fn remove_destination(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        id: Uuid,
+        user_id: Uuid,
+    ) -> Result<Option<SpendingDestination>> {
+        let destination = sqlx::query_as::<_, SpendingDestination>(
+            r#"
+            UPDATE spending_destinations SET removed_at = $1
+            WHERE id = $2 AND user_id = $3 AND removed_at IS NULL
+            RETURNING id, kind, value, label, created_at, usable_after
+            "#
+        )
+        .bind(Utc::now())
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(&mut **tx)
+        .await?;
+
+        Ok(destination)
+    }
+
+    /// Amount plus fees of pending and completed payments since `since`
+    pub async fn spent_since_msat(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        user_id: Uuid,
+        since: DateTime<Utc>,
+    ) -> Result<i64> {
+        let spent: (Option<i64>,) = sqlx::query_as(
+            r#"
+            SELECT SUM(amount_msat + fee_msat)::BIGINT FROM outgoing_payments
+            WHERE user_id = $1 AND created_at > $2 AND status IN ('pending', 'complete')
+            "#
+        )
+        .bind(user_id)
+        .bind(since)
+        .fetch_one(&mut **tx)
+        .await?;
+
+        Ok(spent.0.unwrap_or(0))
+    }
+
+    /// Record a payment attempt; `denial_reason` is set for attempts the policy refused
+    #[allow(clippy::too_many_arguments)]
+    pub async fn create_payment(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        user_id: Uuid,
+        kind: PaymentKind,
+        destination: &str,
+        amount_msat: i64,
+        fee_msat: i64,
+        denial_reason: Option<&str>,
+    ) -> Result<OutgoingPayment> {
+        let status = if denial_reason.is_some() { "denied" } else { "pending" };
+
+        let payment = sqlx::query_as::<_, OutgoingPayment>(
+            r#"
+            INSERT INTO outgoing_payments (id, user_id, kind, destination, amount_msat, fee_msat, status, denial_reason, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            RETURNING id, kind, destination, amount_msat, fee_msat, status, denial_reason, payment_hash, txid, error, created_at, completed_at
+            "#
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(kind.as_str())
+        .bind(destination)
+        .bind(amount_msat)
+        .bind(fee_msat)
+        .bind(status)
+        .bind(denial_reason)
+        .bind(Utc::now())
+        .fetch_one(&mut **tx)
+        .await?;
+
+        Ok(payment)
+    }
+
+    pub async fn complete_payment(
+        &self,
+        id: Uuid,
+        fee_msat: i64,
+        payment_hash: Option<&str>,
+        txid: Option<&str>,
+    ) -> Result<OutgoingPayment> {
+        let payment = sqlx::query_as::<_, OutgoingPayment>(
+            r#"
+            UPDATE outgoing_payments
+            SET status = 'complete', fee_msat = $1, payment_hash = $2, txid = $3, completed_at = $4
+            WHERE id = $5
+            RETURNING id, kind, destination, amount_msat, fee_msat, status, denial_reason, payment_hash, txid, error, created_at, completed_at
+            "#
+        )
+        .bind(fee_msat)
+        .bind(payment_hash)
+        .bind(txid)
+        .bind(Utc::now())
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(payment)
+    }
+
+    /// A failed payment stops counting towards the limits
+    pub async fn fail_payment(&self, id: Uuid, error: &str) -> Result<()> {
+        sqlx::query("UPDATE outgoing_payments SET status = 'failed', error = $1, completed_at = $2 WHERE id = $3")
+            .bind(error)
+            .bind(Utc::now())
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn find_payments(&self, user_id: Uuid, limit: i64) -> Result<Vec<OutgoingPayment>> {
+        let payments = sqlx::query_as::<_, OutgoingPayment>(
+            r#"
+            SELECT id, kind, destination, amount_msat, fee_msat, status, denial_reason, payment_hash, txid, error, created_at, completed_at
+            FROM outgoing_payments WHERE user_id = $1 ORDER BY created_at DESC LIMIT $2
+            "#
+        )
+        .bind(user_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(payments)
+    }
+
+    pub async fn record_audit(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        user_id: Uuid,
+        action: PolicyAuditAction,
+        details: Value,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO spending_policy_audit (id, user_id, action, details, created_at) VALUES ($1, $2, $3, $4, $5)"
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(action.as_str())
+        .bind(details)
+        .bind(Utc::now())
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn find_audit(&self, user_id: Uuid) -> Result<Vec<PolicyAuditEntry>> {
+        let entries = sqlx::query_as::<_, PolicyAuditEntry>(
+            r#"
+            SELECT id, action, details, created_at
+            FROM spending_policy_audit WHERE user_id = $1 ORDER BY created_at DESC
+            "#
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(entries)
+    }
+
+    pub async fn begin(&self) -> Result<Transaction<'static, Postgres>> {
+        Ok(self.pool.begin().await?)
+    }
+}
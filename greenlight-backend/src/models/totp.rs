@@ -0,0 +1,283 @@
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::error::Result;
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct UserTotp {
+    pub user_id: Uuid,
+    pub encrypted_secret: String,
+    /// Credential vault key version; None for secrets from before the vault
+    pub secret_key_version: Option<i32>,
+    pub enabled_at: Option<DateTime<Utc>>,
+    pub protected_operations: Vec<String>,
+}
+
+impl UserTotp {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled_at.is_some()
+    }
+
+    pub fn protects(&self, operation: ProtectedOperation) -> bool {
+        self.protected_operations.iter().any(|protected| protected == operation.as_str())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TotpCodeRequest {
+    /// A 6-digit authenticator code or an unused backup code
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TotpPolicyRequest {
+    pub protected_operations: Vec<String>,
+    /// Current code, so a stolen session can't lower the protection
+    pub code: String,
+}
+
+/// Sensitive operations a user can require a fresh second factor for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtectedOperation {
+    /// Paying invoices and offers
+    Payments,
+    /// Sending funds on-chain
+    Withdrawals,
+    /// Handing a username to another account
+    UsernameTransfer,
+    /// Creating API keys
+    ApiKeyCreation,
+}
+
+impl ProtectedOperation {
+    pub const ALL: [ProtectedOperation; 4] = [
+        ProtectedOperation::Payments,
+        ProtectedOperation::Withdrawals,
+        ProtectedOperation::UsernameTransfer,
+        ProtectedOperation::ApiKeyCreation,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProtectedOperation::Payments => "payments",
+            ProtectedOperation::Withdrawals => "withdrawals",
+            ProtectedOperation::UsernameTransfer => "username_transfer",
+            ProtectedOperation::ApiKeyCreation => "api_key_creation",
+        }
+    }
+
+    pub fn parse(operation: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|candidate| candidate.as_str() == operation)
+    }
+}
+
+/// What the API shows about a user's second factor; the secret never leaves
+/// the server after enrollment
+#[derive(Debug, Serialize)]
+pub struct TotpStatus {
+    pub enabled: bool,
+    pub enabled_at: Option<DateTime<Utc>>,
+    pub protected_operations: Vec<String>,
+    pub backup_codes_remaining: i64,
+}
+
+pub struct TotpRepository {
+    pool: PgPool,
+}
+
+impl TotpRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn find_by_user(&self, user_id: Uuid) -> Result<Option<UserTotp>> {
+        let totp = sqlx::query_as::<_, UserTotp>(
+            r#"
+            SELECT user_id, encrypted_secret, secret_key_version, enabled_at, protected_operations
+            FROM user_totp WHERE user_id = $1
+            "#
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(totp)
+    }
+
+    /// Every stored secret, enabled or not
+    pub async fn find_all(&self) -> Result<Vec<UserTotp>> {
+        let totps = sqlx::query_as::<_, UserTotp>(
+            "SELECT user_id, encrypted_secret, secret_key_version, enabled_at, protected_operations FROM user_totp"
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(totps)
+    }
+
+    /// Swap in a re-encrypted secret, unless the secret changed in the meantime
+    pub async fn replace_secret(
+        &self,
+        user_id: Uuid,
+        current_secret: &str,
+        encrypted_secret: &str,
+        key_version: i32,
+    ) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE user_totp SET encrypted_secret = $1, secret_key_version = $2, updated_at = $3
+            WHERE user_id = $4 AND encrypted_secret = $5
+            "#
+        )
+        .bind(encrypted_secret)
+        .bind(key_version)
+        .bind(Utc::now())
+        .bind(user_id)
+        .bind(current_secret)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Store a new, not yet confirmed secret. Returns false if the user
+    /// already has an enabled authenticator, which is never overwritten.
+    pub async fn start_enrollment(&self, user_id: Uuid, encrypted_secret: &str, key_version: i32) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            INSERT INTO user_totp (user_id, encrypted_secret, secret_key_version, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $4)
+            ON CONFLICT (user_id) DO UPDATE
+            SET encrypted_secret = EXCLUDED.encrypted_secret, secret_key_version = EXCLUDED.secret_key_version,
+                last_used_step = NULL, updated_at = EXCLUDED.updated_at
+            WHERE user_totp.enabled_at IS NULL
+            "#
+        )
+        .bind(user_id)
+        .bind(encrypted_secret)
+        .bind(key_version)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Turn on a pending enrollment
+    pub async fn enable(&self, tx: &mut Transaction<'_, Postgres>, user_id: Uuid) -> Result<bool> {
+        let result = sqlx::query(
+            "UPDATE user_totp SET enabled_at = $1, updated_at = $1 WHERE user_id = $2 AND enabled_at IS NULL"
+        )
+        .bind(Utc::now())
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn delete(&self, tx: &mut Transaction<'_, Postgres>, user_id: Uuid) -> Result<()> {
+        sqlx::query("DELETE FROM user_totp WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut **tx)
+            .await?;
+        sqlx::query("DELETE FROM totp_backup_codes WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut **tx)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Record the time step of an accepted code. Returns false if that step,
+    /// or a later one, was already used, i.e. the code is a replay.
+    pub async fn record_used_step(&self, user_id: Uuid, step: i64) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE user_totp SET last_used_step = $1
+            WHERE user_id = $2 AND (last_used_step IS NULL OR last_used_step < $1)
+            "#
+        )
+        .bind(step)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn update_protected_operations(&self, user_id: Uuid, operations: &[String]) -> Result<()> {
+        sqlx::query("UPDATE user_totp SET protected_operations = $1, updated_at = $2 WHERE user_id = $3")
+            .bind(operations)
+            .bind(Utc::now())
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Replace all backup codes of a user
+    pub async fn replace_backup_codes(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        user_id: Uuid,
+        code_hashes: &[String],
+    ) -> Result<()> {
+        sqlx::query("DELETE FROM totp_backup_codes WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut **tx)
+            .await?;
+
+        for code_hash in code_hashes {
+            sqlx::query(
+                "INSERT INTO totp_backup_codes (id, user_id, code_hash, created_at) VALUES ($1, $2, $3, $4)"
+            )
+            .bind(Uuid::new_v4())
+            .bind(user_id)
+            .bind(code_hash)
+            .bind(Utc::now())
+            .execute(&mut **tx)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Mark an unused backup code as used, returning false if there is none
+    pub async fn consume_backup_code(&self, user_id: Uuid, code_hash: &str) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE totp_backup_codes SET used_at = $1
+            WHERE id = (
+                SELECT id FROM totp_backup_codes
+                WHERE user_id = $2 AND code_hash = $3 AND used_at IS NULL
+                LIMIT 1
+            ) AND used_at IS NULL
+            "#
+        )
+        .bind(Utc::now())
+        .bind(user_id)
+        .bind(code_hash)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn count_unused_backup_codes(&self, user_id: Uuid) -> Result<i64> {
+        let count: (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM totp_backup_codes WHERE user_id = $1 AND used_at IS NULL"
+        )
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(count.0)
+    }
+
+    pub async fn begin(&self) -> Result<Transaction<'static, Postgres>> {
+        Ok(self.pool.begin().await?)
+    }
+}
@@ -1,8 +1,9 @@
-use sqlx::PgPool;
+use sqlx::{PgPool, Postgres, Transaction};
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use crate::error::Result;
+use crate::error::{AppError, Result};
+use crate::services::{MnemonicOptions, SecretString};
 
 #[derive(Debug, Clone, sqlx::FromRow, Serialize)]
 pub struct User {
@@ -11,20 +12,77 @@ pub struct User {
     pub password_hash: String,
     pub encrypted_seed: Option<String>,
     pub encrypted_device_creds: Option<String>,
+    /// Key that wraps `encrypted_device_creds`; None for the legacy formats
+    pub device_creds_key_version: Option<i32>,
+    pub mnemonic_word_count: i16,
+    pub mnemonic_language: String,
+    /// Whether the seed needs a BIP39 passphrase on top of the mnemonic
+    pub bip39_passphrase: bool,
+    /// BIP32 master key fingerprint of the seed; None for accounts that
+    /// predate it, until their seed is next derived
+    pub seed_fingerprint: Option<String>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
+impl User {
+    /// The BIP39 options the account's mnemonic was created with
+    pub fn mnemonic_options(&self) -> Result<MnemonicOptions> {
+        let language = MnemonicOptions::parse_language(&self.mnemonic_language).ok_or_else(|| {
+            AppError::Internal(format!("Unknown mnemonic language {}", self.mnemonic_language))
+        })?;
+
+        Ok(MnemonicOptions {
+            word_count: self.mnemonic_word_count as usize,
+            language,
+            passphrase: self.bip39_passphrase,
+        })
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct CreateUserRequest {
     pub public_key: String,
-    pub password: String,
+    pub password: SecretString,
+    /// 12 or 24 (default)
+    pub word_count: Option<usize>,
+    /// BIP39 wordlist, `english` by default
+    pub language: Option<String>,
+    /// Optional BIP39 passphrase, separate from the password and never stored
+    pub passphrase: Option<SecretString>,
+    /// Import this mnemonic instead of generating one
+    pub mnemonic: Option<SecretString>,
+    /// Import a mnemonic encrypted with the password (CryptoService format)
+    #[serde(rename = "encryptedSeed")]
+    pub encrypted_seed: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct LoginRequest {
     pub public_key: String,
-    pub password: String,
+    pub password: SecretString,
+    /// Authenticator or backup code, required once TOTP is enabled
+    pub totp_code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RecoverAccountRequest {
+    pub public_key: String,
+    pub mnemonic: SecretString,
+    /// Required if the account's seed was created with a BIP39 passphrase
+    pub passphrase: Option<SecretString>,
+    pub new_password: SecretString,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: SecretString,
+    pub new_password: SecretString,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NostrRelaysRequest {
+    pub relays: Vec<String>,
 }
 
 pub struct UserRepository {
@@ -36,28 +94,40 @@ impl UserRepository {
         Self { pool }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn create_user(
         &self,
+        tx: &mut Transaction<'_, Postgres>,
+        id: Uuid,
         public_key: &str,
         password_hash: &str,
         encrypted_seed: &str,
+        mnemonic_options: &MnemonicOptions,
+        seed_fingerprint: &str,
     ) -> Result<User> {
-        let id = Uuid::new_v4();
         let now = Utc::now();
         
         let user = sqlx::query_as::<_, User>(
             r#"
-            INSERT INTO users (id, public_key, password_hash, encrypted_seed, created_at, updated_at)
-            VALUES ($1, $2, $3, $4, $5, $5)
-            RETURNING id, public_key, password_hash, encrypted_seed, encrypted_device_creds, created_at, updated_at
+            INSERT INTO users (
+                id, public_key, password_hash, encrypted_seed,
+                mnemonic_word_count, mnemonic_language, bip39_passphrase, seed_fingerprint, created_at, updated_at
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $9)
+            RETURNING id, public_key, password_hash, encrypted_seed, encrypted_device_creds, device_creds_key_version,
+                   mnemonic_word_count, mnemonic_language, bip39_passphrase, seed_fingerprint, created_at, updated_at
             "#
         )
         .bind(id)
         .bind(public_key)
         .bind(password_hash)
         .bind(encrypted_seed)
+        .bind(mnemonic_options.word_count as i16)
+        .bind(MnemonicOptions::language_as_str(mnemonic_options.language))
+        .bind(mnemonic_options.passphrase)
+        .bind(seed_fingerprint)
         .bind(now)
-        .fetch_one(&self.pool)
+        .fetch_one(&mut **tx)
         .await?;
 
         Ok(user)
@@ -65,7 +135,11 @@ impl UserRepository {
 
     pub async fn find_by_public_key(&self, public_key: &str) -> Result<Option<User>> {
         let user = sqlx::query_as::<_, User>(
-            "SELECT id, public_key, password_hash, encrypted_seed, encrypted_device_creds, created_at, updated_at FROM users WHERE public_key = $1"
+            r#"
+            SELECT id, public_key, password_hash, encrypted_seed, encrypted_device_creds, device_creds_key_version,
+                   mnemonic_word_count, mnemonic_language, bip39_passphrase, seed_fingerprint, created_at, updated_at
+            FROM users WHERE public_key = $1
+            "#
         )
         .bind(public_key)
         .fetch_optional(&self.pool)
@@ -76,7 +150,11 @@ impl UserRepository {
 
     pub async fn find_by_id(&self, id: Uuid) -> Result<Option<User>> {
         let user = sqlx::query_as::<_, User>(
-            "SELECT id, public_key, password_hash, encrypted_seed, encrypted_device_creds, created_at, updated_at FROM users WHERE id = $1"
+            r#"
+            SELECT id, public_key, password_hash, encrypted_seed, encrypted_device_creds, device_creds_key_version,
+                   mnemonic_word_count, mnemonic_language, bip39_passphrase, seed_fingerprint, created_at, updated_at
+            FROM users WHERE id = $1
+            "#
         )
         .bind(id)
         .fetch_optional(&self.pool)
@@ -85,19 +163,331 @@ impl UserRepository {
         Ok(user)
     }
 
-    pub async fn update_device_credentials(&self, user_id: Uuid, encrypted_creds: &str) -> Result<()> {
+    pub async fn update_device_credentials(&self, user_id: Uuid, encrypted_creds: &str, key_version: i32) -> Result<()> {
         sqlx::query(
-            "UPDATE users SET encrypted_device_creds = $1, updated_at = $2 WHERE id = $3"
+            "UPDATE users SET encrypted_device_creds = $1, device_creds_key_version = $2, updated_at = $3 WHERE id = $4"
+        )
+        .bind(encrypted_creds)
+        .bind(key_version)
+        .bind(Utc::now())
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Device credentials still stored in a legacy format
+    pub async fn find_legacy_device_credentials(&self) -> Result<Vec<(Uuid, String)>> {
+        let rows = sqlx::query_as::<_, (Uuid, String)>(
+            r#"
+            SELECT id, encrypted_device_creds FROM users
+            WHERE encrypted_device_creds IS NOT NULL AND device_creds_key_version IS NULL
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows)
+    }
+
+    /// Replace legacy credentials with their encrypted form, unless the row
+    /// changed since `legacy_creds` was read
+    pub async fn convert_device_credentials(
+        &self,
+        user_id: Uuid,
+        legacy_creds: &str,
+        encrypted_creds: &str,
+        key_version: i32,
+    ) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE users SET encrypted_device_creds = $1, device_creds_key_version = $2
+            WHERE id = $3 AND encrypted_device_creds = $4 AND device_creds_key_version IS NULL
+            "#
         )
         .bind(encrypted_creds)
+        .bind(key_version)
+        .bind(user_id)
+        .bind(legacy_creds)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Every stored seed, for reporting which still need re-encryption
+    pub async fn find_encrypted_seeds(&self) -> Result<Vec<(Uuid, String)>> {
+        let rows = sqlx::query_as::<_, (Uuid, String)>(
+            "SELECT id, encrypted_seed FROM users WHERE encrypted_seed IS NOT NULL"
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows)
+    }
+
+    /// Swap in a re-encrypted seed, unless the seed changed in the meantime
+    pub async fn replace_encrypted_seed(&self, user_id: Uuid, current_seed: &str, encrypted_seed: &str) -> Result<bool> {
+        let result = sqlx::query(
+            "UPDATE users SET encrypted_seed = $1, updated_at = $2 WHERE id = $3 AND encrypted_seed = $4"
+        )
+        .bind(encrypted_seed)
         .bind(Utc::now())
         .bind(user_id)
+        .bind(current_seed)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Device credentials sealed by the credential vault, with their key version
+    pub async fn find_versioned_device_credentials(&self) -> Result<Vec<(Uuid, String, i32)>> {
+        let rows = sqlx::query_as::<_, (Uuid, String, i32)>(
+            r#"
+            SELECT id, encrypted_device_creds, device_creds_key_version FROM users
+            WHERE encrypted_device_creds IS NOT NULL AND device_creds_key_version IS NOT NULL
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows)
+    }
+
+    /// Swap in resealed device credentials, unless they changed in the meantime
+    pub async fn replace_device_credentials(
+        &self,
+        user_id: Uuid,
+        current_creds: &str,
+        current_key_version: i32,
+        encrypted_creds: &str,
+        key_version: i32,
+    ) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE users SET encrypted_device_creds = $1, device_creds_key_version = $2
+            WHERE id = $3 AND encrypted_device_creds = $4 AND device_creds_key_version = $5
+            "#
+        )
+        .bind(encrypted_creds)
+        .bind(key_version)
+        .bind(user_id)
+        .bind(current_creds)
+        .bind(current_key_version)
         .execute(&self.pool)
         .await?;
 
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Record the fingerprint of an account that predates fingerprints
+    pub async fn record_seed_fingerprint(&self, user_id: Uuid, seed_fingerprint: &str) -> Result<()> {
+        sqlx::query("UPDATE users SET seed_fingerprint = $1 WHERE id = $2 AND seed_fingerprint IS NULL")
+            .bind(seed_fingerprint)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
         Ok(())
     }
 
+    pub async fn get_node_id(&self, user_id: Uuid) -> Result<Option<String>> {
+        let node_id = sqlx::query_scalar::<_, Option<String>>(
+            "SELECT node_id FROM users WHERE id = $1"
+        )
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(node_id)
+    }
+
+    /// Whether an account already holds the node `node_id`
+    pub async fn node_id_exists(&self, node_id: &str) -> Result<bool> {
+        let exists = sqlx::query_scalar::<_, bool>(
+            "SELECT EXISTS(SELECT 1 FROM users WHERE node_id = $1)"
+        )
+        .bind(node_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(exists)
+    }
+
+    pub async fn update_node_id(&self, user_id: Uuid, node_id: &str) -> Result<()> {
+        sqlx::query(
+            "UPDATE users SET node_id = $1, updated_at = $2 WHERE id = $3"
+        )
+        .bind(node_id)
+        .bind(Utc::now())
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Record the node of a user created in `tx`, with its sealed credentials
+    pub async fn attach_node(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        user_id: Uuid,
+        node_id: &str,
+        encrypted_creds: &str,
+        key_version: i32,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE users SET node_id = $1, encrypted_device_creds = $2, device_creds_key_version = $3, updated_at = $4
+            WHERE id = $5
+            "#
+        )
+        .bind(node_id)
+        .bind(encrypted_creds)
+        .bind(key_version)
+        .bind(Utc::now())
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Reset the password after mnemonic recovery, with fresh node credentials
+    #[allow(clippy::too_many_arguments)]
+    pub async fn recover_account(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        user_id: Uuid,
+        password_hash: &str,
+        encrypted_seed: &str,
+        encrypted_device_creds: &str,
+        device_creds_key_version: i32,
+        node_id: &str,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE users
+            SET password_hash = $1, encrypted_seed = $2, encrypted_device_creds = $3, device_creds_key_version = $4,
+                node_id = $5, updated_at = $6
+            WHERE id = $7
+            "#
+        )
+        .bind(password_hash)
+        .bind(encrypted_seed)
+        .bind(encrypted_device_creds)
+        .bind(device_creds_key_version)
+        .bind(node_id)
+        .bind(Utc::now())
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(())
+    }
+
+    /// The offer shown in payment URIs of accounts without a username
+    pub async fn get_payment_offer(&self, user_id: Uuid) -> Result<Option<String>> {
+        let offer = sqlx::query_scalar::<_, Option<String>>(
+            "SELECT payment_offer FROM users WHERE id = $1"
+        )
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(offer)
+    }
+
+    /// Store `offer` unless another request stored one first; returns the stored offer
+    pub async fn set_payment_offer(&self, user_id: Uuid, offer: &str) -> Result<String> {
+        let offer = sqlx::query_scalar::<_, String>(
+            r#"
+            UPDATE users SET payment_offer = COALESCE(payment_offer, $1), updated_at = $2
+            WHERE id = $3
+            RETURNING payment_offer
+            "#
+        )
+        .bind(offer)
+        .bind(Utc::now())
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(offer)
+    }
+
+    pub async fn get_nostr_relays(&self, user_id: Uuid) -> Result<Vec<String>> {
+        let relays = sqlx::query_scalar::<_, Vec<String>>(
+            "SELECT nostr_relays FROM users WHERE id = $1"
+        )
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(relays)
+    }
+
+    pub async fn update_nostr_relays(&self, user_id: Uuid, relays: &[String]) -> Result<()> {
+        sqlx::query(
+            "UPDATE users SET nostr_relays = $1, updated_at = $2 WHERE id = $3"
+        )
+        .bind(relays)
+        .bind(Utc::now())
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Replace the password hash and every secret encrypted under the password,
+    /// converting legacy device credentials (`(legacy, encrypted, key version)`)
+    /// in the same statement. Only applies if the hash is still
+    /// `current_password_hash` and the credentials are still `legacy`, so two
+    /// concurrent changes can't leave secrets encrypted under a lost password.
+    pub async fn update_password_secrets(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        user_id: Uuid,
+        current_password_hash: &str,
+        password_hash: &str,
+        encrypted_seed: Option<&str>,
+        converted_device_creds: Option<(&str, &str, i32)>,
+    ) -> Result<bool> {
+        let (legacy_creds, encrypted_creds, key_version) = match converted_device_creds {
+            Some((legacy, encrypted, key_version)) => (Some(legacy), Some(encrypted), Some(key_version)),
+            None => (None, None, None),
+        };
+        let result = sqlx::query(
+            r#"
+            UPDATE users
+            SET password_hash = $1, encrypted_seed = $2, updated_at = $3,
+                encrypted_device_creds = COALESCE($6, encrypted_device_creds),
+                device_creds_key_version = COALESCE($7, device_creds_key_version)
+            WHERE id = $4 AND password_hash = $5
+              AND ($8::TEXT IS NULL OR (encrypted_device_creds = $8 AND device_creds_key_version IS NULL))
+            "#
+        )
+        .bind(password_hash)
+        .bind(encrypted_seed)
+        .bind(Utc::now())
+        .bind(user_id)
+        .bind(current_password_hash)
+        .bind(encrypted_creds)
+        .bind(key_version)
+        .bind(legacy_creds)
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn begin(&self) -> Result<Transaction<'static, Postgres>> {
+        Ok(self.pool.begin().await?)
+    }
+
     pub async fn public_key_exists(&self, public_key: &str) -> Result<bool> {
         let result = sqlx::query_scalar::<_, bool>(
             "SELECT EXISTS(SELECT 1 FROM users WHERE public_key = $1)"
@@ -0,0 +1,453 @@
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use crate::error::Result;
+
+#[derive(Debug, Clone, sqlx::FromRow, Serialize)]
+pub struct Username {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub username: String,
+    pub offer: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateUsernameRequest {
+    pub username: String,
+    pub offer: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AvailabilityQuery {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateUsernameRequest {
+    pub offer: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TransferUsernameRequest {
+    pub to_public_key: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AcceptTransferRequest {
+    pub offer: String,
+}
+
+/// Support's history lookup; at least one of the two is required
+#[derive(Debug, Deserialize)]
+pub struct UsernameHistoryQuery {
+    pub username: Option<String>,
+    pub user_id: Option<Uuid>,
+}
+
+/// Kinds of rows in `username_history`
+#[derive(Debug, Clone, Copy)]
+pub enum UsernameEvent {
+    Claimed,
+    OfferChanged,
+    Released,
+    TransferRequested,
+    TransferCancelled,
+    Transferred,
+}
+
+impl UsernameEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UsernameEvent::Claimed => "claimed",
+            UsernameEvent::OfferChanged => "offer_changed",
+            UsernameEvent::Released => "released",
+            UsernameEvent::TransferRequested => "transfer_requested",
+            UsernameEvent::TransferCancelled => "transfer_cancelled",
+            UsernameEvent::Transferred => "transferred",
+        }
+    }
+}
+
+#[derive(Debug, Clone, sqlx::FromRow, Serialize)]
+pub struct UsernameHistory {
+    pub id: Uuid,
+    pub username: String,
+    pub event: String,
+    pub from_user_id: Option<Uuid>,
+    pub to_user_id: Option<Uuid>,
+    pub offer: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow, Serialize)]
+pub struct UsernameTransfer {
+    pub id: Uuid,
+    pub username_id: Uuid,
+    pub username: String,
+    pub from_user_id: Uuid,
+    pub to_user_id: Uuid,
+    pub status: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+pub struct UsernameRepository {
+    pool: PgPool,
+}
+
+impl UsernameRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create_username(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        user_id: Uuid,
+        username: &str,
+        offer: &str,
+    ) -> Result<Username> {
+        let now = Utc::now();
+
+        let username = sqlx::query_as::<_, Username>(
+            r#"
+            INSERT INTO usernames (id, user_id, username, offer, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $5)
+            RETURNING id, user_id, username, offer, created_at, updated_at
+            "#
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(username)
+        .bind(offer)
+        .bind(now)
+        .fetch_one(&mut **tx)
+        .await?;
+
+        Ok(username)
+    }
+
+    pub async fn find_by_username(&self, username: &str) -> Result<Option<Username>> {
+        let username = sqlx::query_as::<_, Username>(
+            "SELECT id, user_id, username, offer, created_at, updated_at FROM usernames WHERE username = $1"
+        )
+        .bind(username)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(username)
+    }
+
+    pub async fn find_by_user(&self, user_id: Uuid) -> Result<Vec<Username>> {
+        let usernames = sqlx::query_as::<_, Username>(
+            "SELECT id, user_id, username, offer, created_at, updated_at FROM usernames WHERE user_id = $1 ORDER BY created_at"
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(usernames)
+    }
+
+    /// The user's oldest username, which stands for the account where only one fits
+    pub async fn find_primary(&self, user_id: Uuid) -> Result<Option<Username>> {
+        let username = sqlx::query_as::<_, Username>(
+            r#"
+            SELECT id, user_id, username, offer, created_at, updated_at FROM usernames
+            WHERE user_id = $1 ORDER BY created_at, username LIMIT 1
+            "#
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(username)
+    }
+
+    pub async fn find_all(&self) -> Result<Vec<Username>> {
+        let usernames = sqlx::query_as::<_, Username>(
+            "SELECT id, user_id, username, offer, created_at, updated_at FROM usernames ORDER BY user_id, username"
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(usernames)
+    }
+
+    /// How many usernames `user_id` holds. The user row stays locked until
+    /// `tx` ends, so concurrent claims can't all pass the same quota check.
+    pub async fn count_for_user(&self, tx: &mut Transaction<'_, Postgres>, user_id: Uuid) -> Result<i64> {
+        sqlx::query("SELECT id FROM users WHERE id = $1 FOR UPDATE")
+            .bind(user_id)
+            .execute(&mut **tx)
+            .await?;
+
+        let count = sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(*) FROM usernames WHERE user_id = $1"
+        )
+        .bind(user_id)
+        .fetch_one(&mut **tx)
+        .await?;
+
+        Ok(count)
+    }
+
+    /// Return which of `usernames` are already taken
+    pub async fn find_taken(&self, usernames: &[String]) -> Result<Vec<String>> {
+        let taken = sqlx::query_scalar::<_, String>(
+            "SELECT username FROM usernames WHERE username = ANY($1)"
+        )
+        .bind(usernames)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(taken)
+    }
+
+    /// Point a username at a new offer. None if it no longer belongs to `user_id`.
+    pub async fn update_offer(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        id: Uuid,
+        user_id: Uuid,
+        offer: &str,
+    ) -> Result<Option<Username>> {
+        let username = sqlx::query_as::<_, Username>(
+            r#"
+            UPDATE usernames SET offer = $1, updated_at = $2 WHERE id = $3 AND user_id = $4
+            RETURNING id, user_id, username, offer, created_at, updated_at
+            "#
+        )
+        .bind(offer)
+        .bind(Utc::now())
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(&mut **tx)
+        .await?;
+
+        Ok(username)
+    }
+
+    /// Move a username from `from_user_id` to `to_user_id`. None if
+    /// `from_user_id` no longer owns it.
+    pub async fn update_owner(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        id: Uuid,
+        from_user_id: Uuid,
+        to_user_id: Uuid,
+        offer: &str,
+    ) -> Result<Option<Username>> {
+        let username = sqlx::query_as::<_, Username>(
+            r#"
+            UPDATE usernames SET user_id = $1, offer = $2, updated_at = $3 WHERE id = $4 AND user_id = $5
+            RETURNING id, user_id, username, offer, created_at, updated_at
+            "#
+        )
+        .bind(to_user_id)
+        .bind(offer)
+        .bind(Utc::now())
+        .bind(id)
+        .bind(from_user_id)
+        .fetch_optional(&mut **tx)
+        .await?;
+
+        Ok(username)
+    }
+
+    /// Delete a username. False if it no longer belongs to `user_id`.
+    pub async fn delete_username(&self, tx: &mut Transaction<'_, Postgres>, id: Uuid, user_id: Uuid) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM usernames WHERE id = $1 AND user_id = $2")
+            .bind(id)
+            .bind(user_id)
+            .execute(&mut **tx)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn record_history(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        username: &str,
+        event: UsernameEvent,
+        from_user_id: Option<Uuid>,
+        to_user_id: Option<Uuid>,
+        offer: Option<&str>,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO username_history (id, username, event, from_user_id, to_user_id, offer, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#
+        )
+        .bind(Uuid::new_v4())
+        .bind(username)
+        .bind(event.as_str())
+        .bind(from_user_id)
+        .bind(to_user_id)
+        .bind(offer)
+        .bind(Utc::now())
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn history(&self, username: &str) -> Result<Vec<UsernameHistory>> {
+        let history = sqlx::query_as::<_, UsernameHistory>(
+            r#"
+            SELECT id, username, event, from_user_id, to_user_id, offer, created_at
+            FROM username_history WHERE username = $1 ORDER BY created_at
+            "#
+        )
+        .bind(username)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(history)
+    }
+
+    /// History rows for `username`, for names `user_id` gave up or received,
+    /// or both when both are given
+    pub async fn search_history(&self, username: Option<&str>, user_id: Option<Uuid>) -> Result<Vec<UsernameHistory>> {
+        let history = sqlx::query_as::<_, UsernameHistory>(
+            r#"
+            SELECT id, username, event, from_user_id, to_user_id, offer, created_at
+            FROM username_history
+            WHERE ($1::TEXT IS NULL OR username = $1)
+              AND ($2::UUID IS NULL OR from_user_id = $2 OR to_user_id = $2)
+            ORDER BY created_at
+            "#
+        )
+        .bind(username)
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(history)
+    }
+
+    /// When a released name leaves its cooldown, if it is still cooling down
+    pub async fn cooldown_until(&self, username: &str, cooldown: Duration) -> Result<Option<DateTime<Utc>>> {
+        let released_at = sqlx::query_scalar::<_, Option<DateTime<Utc>>>(
+            "SELECT MAX(created_at) FROM username_history WHERE username = $1 AND event = $2"
+        )
+        .bind(username)
+        .bind(UsernameEvent::Released.as_str())
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(released_at
+            .map(|released_at| released_at + cooldown)
+            .filter(|available_at| *available_at > Utc::now()))
+    }
+
+    pub async fn create_transfer(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        username_id: Uuid,
+        from_user_id: Uuid,
+        to_user_id: Uuid,
+        expires_at: DateTime<Utc>,
+    ) -> Result<UsernameTransfer> {
+        // Expired requests no longer block a new one
+        sqlx::query(
+            "UPDATE username_transfers SET status = 'expired' WHERE username_id = $1 AND status = 'pending' AND expires_at <= $2"
+        )
+        .bind(username_id)
+        .bind(Utc::now())
+        .execute(&mut **tx)
+        .await?;
+
+        let transfer = sqlx::query_as::<_, UsernameTransfer>(
+            r#"
+            WITH t AS (
+                INSERT INTO username_transfers (id, username_id, from_user_id, to_user_id, status, created_at, expires_at)
+                VALUES ($1, $2, $3, $4, 'pending', $5, $6)
+                RETURNING *
+            )
+            SELECT t.id, t.username_id, u.username, t.from_user_id, t.to_user_id, t.status, t.created_at, t.expires_at, t.completed_at
+            FROM t JOIN usernames u ON u.id = t.username_id
+            "#
+        )
+        .bind(Uuid::new_v4())
+        .bind(username_id)
+        .bind(from_user_id)
+        .bind(to_user_id)
+        .bind(Utc::now())
+        .bind(expires_at)
+        .fetch_one(&mut **tx)
+        .await?;
+
+        Ok(transfer)
+    }
+
+    /// The username's pending transfer, locked until `tx` ends so a
+    /// concurrent accept and cancel can't both act on it
+    pub async fn lock_pending_transfer(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        username_id: Uuid,
+    ) -> Result<Option<UsernameTransfer>> {
+        let transfer = sqlx::query_as::<_, UsernameTransfer>(
+            r#"
+            SELECT t.id, t.username_id, u.username, t.from_user_id, t.to_user_id, t.status, t.created_at, t.expires_at, t.completed_at
+            FROM username_transfers t JOIN usernames u ON u.id = t.username_id
+            WHERE t.username_id = $1 AND t.status = 'pending' AND t.expires_at > $2
+            FOR UPDATE OF t
+            "#
+        )
+        .bind(username_id)
+        .bind(Utc::now())
+        .fetch_optional(&mut **tx)
+        .await?;
+
+        Ok(transfer)
+    }
+
+    /// Pending transfers waiting for `user_id` to accept them
+    pub async fn incoming_transfers(&self, user_id: Uuid) -> Result<Vec<UsernameTransfer>> {
+        let transfers = sqlx::query_as::<_, UsernameTransfer>(
+            r#"
+            SELECT t.id, t.username_id, u.username, t.from_user_id, t.to_user_id, t.status, t.created_at, t.expires_at, t.completed_at
+            FROM username_transfers t JOIN usernames u ON u.id = t.username_id
+            WHERE t.to_user_id = $1 AND t.status = 'pending' AND t.expires_at > $2
+            ORDER BY t.created_at
+            "#
+        )
+        .bind(user_id)
+        .bind(Utc::now())
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(transfers)
+    }
+
+    /// Complete or cancel a pending transfer. False if it is no longer pending.
+    pub async fn set_transfer_status(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        id: Uuid,
+        status: &str,
+    ) -> Result<bool> {
+        let result = sqlx::query(
+            "UPDATE username_transfers SET status = $1, completed_at = $2 WHERE id = $3 AND status = 'pending'"
+        )
+        .bind(status)
+        .bind(Utc::now())
+        .bind(id)
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn begin(&self) -> Result<Transaction<'static, Postgres>> {
+        Ok(self.pool.begin().await?)
+    }
+}
@@ -0,0 +1,274 @@
+use base64::{engine::general_purpose, Engine as _};
+use rand::RngCore;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+use crate::config::Config;
+use crate::error::{AppError, Result};
+use crate::models::{User, UserRepository};
+use crate::services::greenlight::DeviceCredentials;
+use crate::services::key_provider::{key_from_spec, KeyProvider, LocalKey};
+use crate::services::{CryptoService, SecretKey, SecretString};
+
+/// Every Greenlight device certificate is PEM, which never shows up in a
+/// password-encrypted blob
+const PEM_MARKER: &[u8] = b"-----BEGIN";
+
+const DEVICE_CREDS_PURPOSE: &str = "device-creds";
+
+/// Envelope encryption of the Greenlight device credentials stored in
+/// `users.encrypted_device_creds`.
+///
+/// A stored value is `<wrapped data key>.<sealed credentials>`. The
+/// credentials are sealed under a random data key for the row, as a
+/// CryptoService envelope. The data key is wrapped by the
+/// key-encryption key (CREDENTIALS_KEY, by default SECRETS_ENCRYPTION_KEY),
+/// in whatever form its key provider produces; the key's version is in
+/// `users.device_creds_key_version`. Both parts authenticate the user id
+/// and key version, so a value can't be copied onto another account.
+/// TOTP secrets are sealed the same way through `seal_secret`.
+/// Retired keys listed in CREDENTIALS_PREVIOUS_KEYS still open rows under
+/// their version until `reencrypt_rows` moves them to the current key.
+///
+/// Rows with no key version predate this format: plain base64 from node
+/// registration, converted at startup, or JSON encrypted with the user's
+/// password from node recovery, converted when the password is next given.
+pub struct CredentialVault {
+    key: Arc<dyn KeyProvider>,
+    key_version: i32,
+    previous_keys: HashMap<i32, Arc<dyn KeyProvider>>,
+}
+
+impl CredentialVault {
+    /// Load the key-encryption key and check it can unwrap what it wraps,
+    /// so a misconfigured HSM or Vault fails startup rather than logins
+    pub async fn from_config(config: &Config) -> Result<Self> {
+        let key: Arc<dyn KeyProvider> = match config.credentials_key.as_deref() {
+            Some(spec) => key_from_spec(spec, config)?,
+            None => {
+                let encoded = config.secrets_encryption_key.as_deref().ok_or_else(|| {
+                    AppError::Internal("SECRETS_ENCRYPTION_KEY or CREDENTIALS_KEY is required to encrypt device credentials".to_string())
+                })?;
+                Arc::new(LocalKey::new("SECRETS_ENCRYPTION_KEY", encoded.as_bytes().to_vec()))
+            }
+        };
+
+        Self::probe(key.as_ref()).await?;
+        tracing::info!("Device credentials are encrypted with {}", key.describe());
+
+        let mut previous_keys = HashMap::new();
+        for (version, spec) in &config.credentials_previous_keys {
+            let previous = key_from_spec(spec, config)?;
+            Self::probe(previous.as_ref()).await?;
+            previous_keys.insert(*version, previous);
+        }
+
+        Ok(Self {
+            key,
+            key_version: config.secrets_encryption_key_version,
+            previous_keys,
+        })
+    }
+
+    async fn probe(key: &dyn KeyProvider) -> Result<()> {
+        let mut probe = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut probe);
+        let wrapped = key.wrap_key(&probe, b"probe").await?;
+        if key.unwrap_key(&wrapped, b"probe").await? != probe {
+            return Err(AppError::Internal(format!("Key {} does not unwrap what it wraps", key.describe())));
+        }
+        Ok(())
+    }
+
+    /// Version recorded next to values sealed by `seal`
+    pub fn key_version(&self) -> i32 {
+        self.key_version
+    }
+
+    /// Encrypt `user_id`'s device credentials for storage
+    pub async fn seal(&self, user_id: Uuid, device_creds: &[u8]) -> Result<String> {
+        self.seal_secret(DEVICE_CREDS_PURPOSE, user_id, device_creds).await
+    }
+
+    /// Encrypt another per-user secret the same way, e.g. a TOTP secret. The
+    /// purpose is authenticated too, so values can't move between columns.
+    pub async fn seal_secret(&self, purpose: &str, user_id: Uuid, secret: &[u8]) -> Result<String> {
+        let mut data_key = SecretKey::new([0u8; 32]);
+        rand::thread_rng().fill_bytes(data_key.expose_mut());
+
+        let aad = Self::aad(purpose, user_id, self.key_version);
+        let wrapped_key = self.key.wrap_key(data_key.expose(), &aad).await?;
+        let sealed = CryptoService::encrypt_with_key_aad(secret, data_key.expose(), &aad)?;
+
+        Ok(format!("{}.{}", wrapped_key, sealed))
+    }
+
+    /// Decrypt a stored value. Plain base64 rows that haven't been converted
+    /// yet are still readable; password-encrypted ones are not.
+    pub async fn open(&self, user_id: Uuid, encrypted_device_creds: &str, key_version: Option<i32>) -> Result<Vec<u8>> {
+        match key_version {
+            Some(key_version) => self.open_secret(DEVICE_CREDS_PURPOSE, user_id, encrypted_device_creds, key_version).await,
+            None => Self::legacy_plain(encrypted_device_creds).ok_or_else(|| {
+                AppError::BadRequest(
+                    "Node credentials are still encrypted with your password; log in again to convert them".to_string(),
+                )
+            }),
+        }
+    }
+
+    /// Decrypt a value sealed by `seal_secret` under `key_version`
+    pub async fn open_secret(&self, purpose: &str, user_id: Uuid, sealed: &str, key_version: i32) -> Result<Vec<u8>> {
+        let key = if key_version == self.key_version {
+            &self.key
+        } else {
+            self.previous_keys.get(&key_version).ok_or_else(|| {
+                AppError::Cryptography(format!(
+                    "Secret is encrypted with key version {}, which is neither the current version {} nor in CREDENTIALS_PREVIOUS_KEYS",
+                    key_version, self.key_version
+                ))
+            })?
+        };
+
+        let (wrapped_key, sealed_secret) = sealed
+            .split_once('.')
+            .ok_or_else(|| AppError::Cryptography("Malformed sealed secret".to_string()))?;
+        let aad = Self::aad(purpose, user_id, key_version);
+        let data_key = <[u8; 32]>::try_from(key.unwrap_key(wrapped_key, &aad).await?)
+            .map(SecretKey::new)
+            .map_err(|_| AppError::Cryptography("Malformed sealed secret key".to_string()))?;
+
+        CryptoService::decrypt_with_key_aad(sealed_secret, data_key.expose(), &aad)
+    }
+
+    /// Whether a sealed value should be sealed again: it is under a previous
+    /// key version, or in an older CryptoService format
+    pub fn is_outdated(&self, sealed: &str, key_version: i32) -> bool {
+        key_version != self.key_version
+            || sealed
+                .split_once('.')
+                .is_some_and(|(_, sealed_secret)| CryptoService::needs_reencryption(sealed_secret, None))
+    }
+
+    /// The node credentials of `user`, as the node handlers use them
+    pub async fn open_for(&self, user: &User) -> Result<Vec<u8>> {
+        let encrypted_device_creds = user.encrypted_device_creds.as_deref()
+            .ok_or_else(|| AppError::BadRequest("No node registered for this user".to_string()))?;
+
+        self.open(user.id, encrypted_device_creds, user.device_creds_key_version).await
+    }
+
+    /// Convert every plain base64 row. Password-encrypted rows are left for
+    /// `convert_with_password`.
+    pub async fn convert_legacy_rows(&self, db_pool: &PgPool) -> Result<usize> {
+        let user_repo = UserRepository::new(db_pool.clone());
+        let mut converted = 0;
+        let mut password_bound = 0;
+
+        for (user_id, legacy) in user_repo.find_legacy_device_credentials().await? {
+            let Some(device_creds) = Self::legacy_plain(&legacy) else {
+                password_bound += 1;
+                continue;
+            };
+            let encrypted = self.seal(user_id, &device_creds).await?;
+            if user_repo.convert_device_credentials(user_id, &legacy, &encrypted, self.key_version).await? {
+                converted += 1;
+            }
+        }
+
+        if password_bound > 0 {
+            tracing::info!(
+                "{} users have password-encrypted device credentials, converted at their next login",
+                password_bound
+            );
+        }
+
+        Ok(converted)
+    }
+
+    /// Convert `user`'s password-encrypted credentials now that the password
+    /// is known. Does nothing for rows that are already converted.
+    pub async fn convert_with_password(&self, db_pool: &PgPool, user: &User, password: &SecretString) -> Result<()> {
+        let (Some(legacy), Some(encrypted)) = (
+            user.encrypted_device_creds.as_deref(),
+            self.seal_legacy_with_password(user, password).await?,
+        ) else {
+            return Ok(());
+        };
+
+        let user_repo = UserRepository::new(db_pool.clone());
+        if user_repo.convert_device_credentials(user.id, legacy, &encrypted, self.key_version).await? {
+            tracing::info!("Converted device credentials of user {}", user.id);
+        }
+
+        Ok(())
+    }
+
+    /// `user`'s legacy credentials sealed under the server key, for the caller
+    /// to store; None if they are already converted or there are none
+    pub async fn seal_legacy_with_password(&self, user: &User, password: &SecretString) -> Result<Option<String>> {
+        if user.device_creds_key_version.is_some() {
+            return Ok(None);
+        }
+        let Some(legacy) = user.encrypted_device_creds.as_deref() else {
+            return Ok(None);
+        };
+
+        let device_creds = match Self::legacy_plain(legacy) {
+            Some(device_creds) => device_creds,
+            None => {
+                let creds_json = CryptoService::decrypt(legacy, password, None).await?;
+                serde_json::from_str::<DeviceCredentials>(creds_json.expose())
+                    .map_err(|e| AppError::Cryptography(format!("Invalid legacy device credentials: {}", e)))?
+                    .creds
+            }
+        };
+
+        Ok(Some(self.seal(user.id, &device_creds).await?))
+    }
+
+    /// Reseal rows under a previous key version with the current key, and
+    /// rows whose sealed part is in an older CryptoService format. Returns
+    /// how many were resealed and how many could not be opened.
+    pub async fn reencrypt_rows(&self, db_pool: &PgPool) -> Result<(usize, usize)> {
+        let user_repo = UserRepository::new(db_pool.clone());
+        let mut reencrypted = 0;
+        let mut failed = 0;
+
+        for (user_id, current, key_version) in user_repo.find_versioned_device_credentials().await? {
+            if !self.is_outdated(&current, key_version) {
+                continue;
+            }
+
+            let device_creds = match self.open(user_id, &current, Some(key_version)).await {
+                Ok(device_creds) => device_creds,
+                Err(e) => {
+                    tracing::warn!("Could not open device credentials of user {}: {}", user_id, e);
+                    failed += 1;
+                    continue;
+                }
+            };
+            let encrypted = self.seal(user_id, &device_creds).await?;
+            if user_repo
+                .replace_device_credentials(user_id, &current, key_version, &encrypted, self.key_version)
+                .await?
+            {
+                reencrypted += 1;
+            }
+        }
+
+        Ok((reencrypted, failed))
+    }
+
+    /// Plain base64 credentials as `register_node` used to store them
+    fn legacy_plain(legacy: &str) -> Option<Vec<u8>> {
+        general_purpose::STANDARD
+            .decode(legacy)
+            .ok()
+            .filter(|device_creds| device_creds.windows(PEM_MARKER.len()).any(|window| window == PEM_MARKER))
+    }
+
+    fn aad(purpose: &str, user_id: Uuid, key_version: i32) -> Vec<u8> {
+        format!("{}:{}:{}", purpose, user_id, key_version).into_bytes()
+    }
+}
@@ -1,95 +1,477 @@
 use aes_gcm::{Aes256Gcm, Nonce, KeyInit};
-use aes_gcm::aead::Aead;
+use aes_gcm::aead::{Aead, Payload};
 use pbkdf2::pbkdf2_hmac;
 use sha2::Sha256;
 use rand::{RngCore, rngs::OsRng};
-use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use zeroize::Zeroize;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
 use argon2::password_hash::{rand_core, SaltString};
 use bip39::{Mnemonic, Language};
+use bitcoin::bip32::Xpriv;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::Network;
 use base64::{Engine as _, engine::general_purpose};
+use std::sync::OnceLock;
+use tokio::sync::Semaphore;
+use std::time::Instant;
+use uuid::Uuid;
+use crate::config::Config;
 use crate::error::{AppError, Result};
+use crate::services::{SecretBytes, SecretKey, SecretString};
 
-const PBKDF2_ITERATIONS: u32 = 100_000;
+/// Fixed by the format `encrypt` used before envelopes
+const LEGACY_PBKDF2_ITERATIONS: u32 = 100_000;
+/// Highest Argon2id costs the config may set
+const MAX_ARGON2_MEMORY_KIB: u32 = 1024 * 1024;
+const MAX_ARGON2_ITERATIONS: u32 = 64;
+const MAX_ARGON2_PARALLELISM: u32 = 16;
+
+/// Startup warns when one derivation takes longer than this, as every
+/// password login and node registration pays it
+const SLOW_KDF_MS: u128 = 1000;
+
+/// Argon2id parameters for new password-encrypted ciphertexts, set from the
+/// config at startup
+const DEFAULT_PASSWORD_KDF: Kdf = Kdf::Argon2id { memory_kib: 64 * 1024, iterations: 3, parallelism: 1 };
+static PASSWORD_KDF: OnceLock<Kdf> = OnceLock::new();
+/// Every KDF the server has written envelopes with. Clients hand ciphertexts
+/// back, so an envelope with any other parameters is refused rather than
+/// letting them pick the cost of the derivation.
+static ISSUED_KDFS: OnceLock<Vec<Kdf>> = OnceLock::new();
+/// Derivations running at once; each one holds a blocking thread and, for
+/// Argon2id, its memory cost
+static KDF_PERMITS: OnceLock<Semaphore> = OnceLock::new();
 const AES_KEY_SIZE: usize = 32;
 const NONCE_SIZE: usize = 12;
 const SALT_SIZE: usize = 32;
 
+/// Leading bytes of every envelope. Legacy blobs start with a random salt
+/// or nonce, which is how the two formats are told apart.
+const ENVELOPE_MAGIC: &[u8] = b"GLC";
+const ENVELOPE_VERSION: u8 = 1;
+
+const KDF_NONE: u8 = 0;
+const KDF_PBKDF2_SHA256: u8 = 1;
+const KDF_ARGON2ID: u8 = 2;
+const CIPHER_AES_256_GCM: u8 = 1;
+
+/// BIP39 wordlists by the names the API and database use
+const MNEMONIC_LANGUAGES: [(&str, Language); 10] = [
+    ("english", Language::English),
+    ("chinese_simplified", Language::SimplifiedChinese),
+    ("chinese_traditional", Language::TraditionalChinese),
+    ("czech", Language::Czech),
+    ("french", Language::French),
+    ("italian", Language::Italian),
+    ("japanese", Language::Japanese),
+    ("korean", Language::Korean),
+    ("portuguese", Language::Portuguese),
+    ("spanish", Language::Spanish),
+];
+
+/// How an account's mnemonic turns into its seed. Recorded at signup, since
+/// the same words give a different seed under another wordlist or passphrase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MnemonicOptions {
+    /// 12 or 24
+    pub word_count: usize,
+    pub language: Language,
+    /// Whether the seed needs a BIP39 passphrase, which is never stored
+    pub passphrase: bool,
+}
+
+impl MnemonicOptions {
+    pub const WORD_COUNTS: [usize; 2] = [12, 24];
+
+    pub fn language_as_str(language: Language) -> &'static str {
+        MNEMONIC_LANGUAGES
+            .iter()
+            .find(|(_, candidate)| *candidate == language)
+            .map(|(name, _)| *name)
+            .unwrap_or("english")
+    }
+
+    pub fn parse_language(name: &str) -> Option<Language> {
+        MNEMONIC_LANGUAGES
+            .iter()
+            .find(|(candidate, _)| *candidate == name)
+            .map(|(_, language)| *language)
+    }
+
+    pub fn language_names() -> Vec<&'static str> {
+        MNEMONIC_LANGUAGES.iter().map(|(name, _)| *name).collect()
+    }
+}
+
+impl Default for MnemonicOptions {
+    fn default() -> Self {
+        Self {
+            word_count: 24,
+            language: Language::English,
+            passphrase: false,
+        }
+    }
+}
+
+/// How an envelope's key is obtained
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kdf {
+    /// A server-held key, used as it is
+    None,
+    /// PBKDF2-HMAC-SHA256 over the password and the envelope's salt
+    Pbkdf2Sha256 { iterations: u32 },
+    /// Argon2id (version 0x13) over the password and the envelope's salt
+    Argon2id { memory_kib: u32, iterations: u32, parallelism: u32 },
+}
+
+impl Kdf {
+    /// The Argon2id parameters from the config
+    fn argon2id_from_config(config: &Config) -> Self {
+        Kdf::Argon2id {
+            memory_kib: config.seed_kdf_memory_kib,
+            iterations: config.seed_kdf_iterations,
+            parallelism: config.seed_kdf_parallelism,
+        }
+    }
+
+    /// Within the bounds the config may set, and valid for Argon2id
+    fn is_acceptable(&self) -> bool {
+        match *self {
+            Kdf::Argon2id { memory_kib, iterations, parallelism } => {
+                memory_kib <= MAX_ARGON2_MEMORY_KIB
+                    && iterations <= MAX_ARGON2_ITERATIONS
+                    && parallelism <= MAX_ARGON2_PARALLELISM
+                    && Params::new(memory_kib, iterations, parallelism, Some(AES_KEY_SIZE)).is_ok()
+            }
+            _ => false,
+        }
+    }
+
+    /// One the server writes, or has written, envelopes with
+    fn is_issued(&self) -> bool {
+        match *self {
+            Kdf::None => true,
+            Kdf::Pbkdf2Sha256 { iterations } => iterations == LEGACY_PBKDF2_ITERATIONS,
+            Kdf::Argon2id { .. } => ISSUED_KDFS.get_or_init(|| vec![DEFAULT_PASSWORD_KDF]).contains(self),
+        }
+    }
+}
+
+/// What a password-encrypted secret is for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecretPurpose {
+    /// The BIP39 mnemonic
+    Seed,
+    /// Greenlight device credentials as handed to the client
+    DeviceCredentials,
+}
+
+impl SecretPurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SecretPurpose::Seed => "seed",
+            SecretPurpose::DeviceCredentials => "device_credentials",
+        }
+    }
+}
+
+/// The account and purpose a ciphertext is written for. It is stored in the
+/// envelope and authenticated with it, so the ciphertext can't be passed
+/// off as another user's, or as a different kind of secret.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Binding {
+    pub user_id: Uuid,
+    pub purpose: SecretPurpose,
+}
+
+impl Binding {
+    pub fn new(user_id: Uuid, purpose: SecretPurpose) -> Self {
+        Self { user_id, purpose }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        format!("{}:{}", self.purpose.as_str(), self.user_id).into_bytes()
+    }
+}
+
+/// A parsed ciphertext, base64 encoded as:
+///
+/// ```text
+/// "GLC" | version (1) | KDF id | KDF params | salt len | salt
+///       | cipher id | nonce len | nonce | associated data len (u16) | associated data
+///       | AES-256-GCM ciphertext
+/// ```
+///
+/// KDF ids are 0 for a server-held key (no params, no salt), 1 for
+/// PBKDF2-HMAC-SHA256 (iterations as a u32) and 2 for Argon2id (memory in
+/// KiB, iterations and parallelism as u32s); cipher id 1 is AES-256-GCM.
+/// Integers are big-endian, and everything before the ciphertext is
+/// authenticated with it.
+struct Envelope<'a> {
+    kdf: Kdf,
+    salt: &'a [u8],
+    nonce: &'a [u8],
+    associated_data: &'a [u8],
+    header: &'a [u8],
+    ciphertext: &'a [u8],
+}
+
+impl<'a> Envelope<'a> {
+    /// None if `data` isn't a well-formed envelope of a known version
+    fn parse(data: &'a [u8]) -> Option<Self> {
+        let mut reader = ByteReader { data, pos: 0 };
+        if reader.take(ENVELOPE_MAGIC.len())? != ENVELOPE_MAGIC || reader.u8()? != ENVELOPE_VERSION {
+            return None;
+        }
+
+        let kdf = match reader.u8()? {
+            KDF_NONE => Kdf::None,
+            KDF_PBKDF2_SHA256 => Kdf::Pbkdf2Sha256 { iterations: reader.u32()? },
+            KDF_ARGON2ID => Kdf::Argon2id {
+                memory_kib: reader.u32()?,
+                iterations: reader.u32()?,
+                parallelism: reader.u32()?,
+            },
+            _ => return None,
+        };
+        if !kdf.is_issued() {
+            return None;
+        }
+        let salt_len = reader.u8()? as usize;
+        let salt = reader.take(salt_len)?;
+        if (kdf == Kdf::None) != salt.is_empty() {
+            return None;
+        }
+        if reader.u8()? != CIPHER_AES_256_GCM || reader.u8()? as usize != NONCE_SIZE {
+            return None;
+        }
+        let nonce = reader.take(NONCE_SIZE)?;
+        let associated_data_len = u16::from_be_bytes(reader.take(2)?.try_into().ok()?) as usize;
+        let associated_data = reader.take(associated_data_len)?;
+
+        let (header, ciphertext) = data.split_at(reader.pos);
+        Some(Self { kdf, salt, nonce, associated_data, header, ciphertext })
+    }
+}
+
+struct ByteReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4)?.try_into().ok().map(u32::from_be_bytes)
+    }
+}
+
 pub struct CryptoService;
 
 impl CryptoService {
-    /// Hash a password using Argon2
-    pub fn hash_password(password: &str) -> Result<String> {
+    /// Hash a password using Argon2, on the blocking pool
+    pub async fn hash_password(password: &SecretString) -> Result<String> {
+        let password = password.clone();
+        Self::with_kdf(move || Self::hash_password_blocking(&password)).await
+    }
+
+    fn hash_password_blocking(password: &SecretString) -> Result<String> {
         let salt = SaltString::generate(&mut rand_core::OsRng);
         let argon2 = Argon2::default();
         
         let password_hash = argon2
-            .hash_password(password.as_bytes(), &salt)
+            .hash_password(password.expose().as_bytes(), &salt)
             .map_err(|e| AppError::Cryptography(format!("Failed to hash password: {}", e)))?;
 
         Ok(password_hash.to_string())
     }
 
-    /// Verify a password against its hash
-    pub fn verify_password(password: &str, hash: &str) -> Result<bool> {
+    /// Verify a password against its hash, on the blocking pool
+    pub async fn verify_password(password: &SecretString, hash: &str) -> Result<bool> {
+        let (password, hash) = (password.clone(), hash.to_string());
+        Self::with_kdf(move || Self::verify_password_blocking(&password, &hash)).await
+    }
+
+    fn verify_password_blocking(password: &SecretString, hash: &str) -> Result<bool> {
         let parsed_hash = PasswordHash::new(hash)
             .map_err(|e| AppError::Cryptography(format!("Invalid hash format: {}", e)))?;
 
         let argon2 = Argon2::default();
-        Ok(argon2.verify_password(password.as_bytes(), &parsed_hash).is_ok())
+        Ok(argon2.verify_password(password.expose().as_bytes(), &parsed_hash).is_ok())
     }
 
-    /// Generate a new BIP39 mnemonic
-    pub fn generate_mnemonic() -> Result<String> {
+    /// Generate a new BIP39 mnemonic with the given word count and wordlist
+    pub fn generate_mnemonic(options: &MnemonicOptions) -> Result<SecretString> {
+        // 4 bytes of entropy for every 3 words
         let mut entropy = [0u8; 32];
-        OsRng.fill_bytes(&mut entropy);
-        let mnemonic = Mnemonic::from_entropy(&entropy)
+        let entropy_len = options.word_count / 3 * 4;
+        OsRng.fill_bytes(&mut entropy[..entropy_len]);
+        let mnemonic = Mnemonic::from_entropy_in(options.language, &entropy[..entropy_len]);
+        entropy.zeroize();
+        let mnemonic = mnemonic
             .map_err(|e| AppError::Cryptography(format!("Failed to generate mnemonic: {}", e)))?;
         
-        Ok(mnemonic.to_string())
+        Ok(SecretString::new(mnemonic.to_string()))
     }
 
-    /// Derive an encryption key from a password using PBKDF2
-    fn derive_key(password: &str, salt: &[u8]) -> [u8; AES_KEY_SIZE] {
-        let mut key = [0u8; AES_KEY_SIZE];
-        pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, PBKDF2_ITERATIONS, &mut key);
-        key
+    /// Derive an encryption key from a password
+    fn derive_key(password: &str, salt: &[u8], kdf: Kdf) -> Result<SecretKey> {
+        let mut key = SecretKey::new([0u8; AES_KEY_SIZE]);
+        match kdf {
+            Kdf::Pbkdf2Sha256 { iterations } => {
+                pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, key.expose_mut());
+            }
+            Kdf::Argon2id { memory_kib, iterations, parallelism } => {
+                let params = Params::new(memory_kib, iterations, parallelism, Some(AES_KEY_SIZE))
+                    .map_err(|e| AppError::Cryptography(format!("Invalid Argon2id parameters: {}", e)))?;
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(password.as_bytes(), salt, key.expose_mut())
+                    .map_err(|e| AppError::Cryptography(format!("Key derivation failed: {}", e)))?;
+            }
+            Kdf::None => {
+                return Err(AppError::Cryptography("Ciphertext is not password-encrypted".to_string()));
+            }
+        }
+        Ok(key)
     }
 
-    /// Encrypt data using AES-256-GCM
-    pub fn encrypt(data: &str, password: &str) -> Result<String> {
-        // Generate random salt and nonce
+    /// KDF used for new password-encrypted ciphertexts
+    fn password_kdf() -> Kdf {
+        *PASSWORD_KDF.get_or_init(|| DEFAULT_PASSWORD_KDF)
+    }
+
+    /// Set the Argon2id parameters for new password-encrypted ciphertexts
+    /// and the earlier ones still accepted, and measure one derivation, so
+    /// costs that would stall logins show up at startup
+    pub fn configure_password_kdf(config: &Config) -> Result<()> {
+        let kdf = Kdf::argon2id_from_config(config);
+        let previous = config
+            .seed_kdf_previous_params
+            .iter()
+            .map(|&(memory_kib, iterations, parallelism)| Kdf::Argon2id { memory_kib, iterations, parallelism });
+        let mut issued = vec![kdf, DEFAULT_PASSWORD_KDF];
+        for previous in previous {
+            if !issued.contains(&previous) {
+                issued.push(previous);
+            }
+        }
+        if let Some(invalid) = issued.iter().find(|kdf| !kdf.is_acceptable()) {
+            return Err(AppError::Internal(format!(
+                "Invalid Argon2id parameters {:?}: memory must be at least 8 KiB per lane and at most {} KiB, \
+                 iterations between 1 and {}, parallelism between 1 and {}",
+                invalid, MAX_ARGON2_MEMORY_KIB, MAX_ARGON2_ITERATIONS, MAX_ARGON2_PARALLELISM
+            )));
+        }
+
+        let started = Instant::now();
+        Self::derive_key("benchmark", &[0u8; SALT_SIZE], kdf)?;
+        let elapsed_ms = started.elapsed().as_millis();
+        if elapsed_ms > SLOW_KDF_MS {
+            tracing::warn!("Seed key derivation {:?} takes {} ms; password logins will be slow", kdf, elapsed_ms);
+        } else {
+            tracing::info!("Seed key derivation {:?} takes {} ms", kdf, elapsed_ms);
+        }
+
+        let configured = PASSWORD_KDF.set(kdf).is_ok() && ISSUED_KDFS.set(issued).is_ok();
+        if !configured {
+            return Err(AppError::Internal("Password KDF is already configured".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Run a password-based operation on the blocking pool, at most one per
+    /// CPU at a time, so derivations don't stall the async workers
+    async fn with_kdf<T, F>(operation: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T> + Send + 'static,
+    {
+        let permits = KDF_PERMITS.get_or_init(|| {
+            Semaphore::new(std::thread::available_parallelism().map(|cpus| cpus.get()).unwrap_or(2))
+        });
+        let _permit = permits
+            .acquire()
+            .await
+            .map_err(|_| AppError::Internal("Key derivation limiter closed".to_string()))?;
+
+        tokio::task::spawn_blocking(operation)
+            .await
+            .map_err(|e| AppError::Internal(format!("Key derivation task failed: {}", e)))?
+    }
+
+    /// Encrypt data with AES-256-GCM under a key derived from `password`.
+    /// With a `binding`, the ciphertext only decrypts for that user and purpose.
+    pub async fn encrypt(data: &str, password: &SecretString, binding: Option<&Binding>) -> Result<String> {
+        let (data, password, binding) = (SecretString::new(data.to_string()), password.clone(), binding.copied());
+        Self::with_kdf(move || Self::encrypt_blocking(data.expose(), &password, binding.as_ref())).await
+    }
+
+    fn encrypt_blocking(data: &str, password: &SecretString, binding: Option<&Binding>) -> Result<String> {
         let mut salt = [0u8; SALT_SIZE];
-        let mut nonce_bytes = [0u8; NONCE_SIZE];
         OsRng.fill_bytes(&mut salt);
-        OsRng.fill_bytes(&mut nonce_bytes);
 
-        // Derive key from password
-        let key_bytes = Self::derive_key(password, &salt);
-        let key = aes_gcm::Key::<Aes256Gcm>::from_slice(&key_bytes);
-        let nonce = Nonce::from_slice(&nonce_bytes);
+        let kdf = Self::password_kdf();
+        let key = Self::derive_key(password.expose(), &salt, kdf)?;
+        let associated_data = binding.map(Binding::encode).unwrap_or_default();
 
-        // Encrypt the data
-        let cipher = Aes256Gcm::new(key);
-        let ciphertext = cipher
-            .encrypt(nonce, data.as_bytes())
-            .map_err(|e| AppError::Cryptography(format!("Encryption failed: {}", e)))?;
+        Self::seal(data.as_bytes(), key.expose(), kdf, &salt, &associated_data, &[])
+    }
 
-        // Combine salt + nonce + ciphertext and encode as base64
-        let mut result = Vec::new();
-        result.extend_from_slice(&salt);
-        result.extend_from_slice(&nonce_bytes);
-        result.extend_from_slice(&ciphertext);
+    /// Decrypt data produced by `encrypt`, or by its legacy
+    /// `salt || nonce || ciphertext` format. A `binding` must match the one
+    /// the envelope was written with; legacy blobs have none to check.
+    pub async fn decrypt(encrypted_data: &str, password: &SecretString, binding: Option<&Binding>) -> Result<SecretString> {
+        let (encrypted_data, password, binding) = (encrypted_data.to_string(), password.clone(), binding.copied());
+        Self::with_kdf(move || Self::decrypt_blocking(&encrypted_data, &password, binding.as_ref())).await
+    }
 
-        Ok(general_purpose::STANDARD.encode(result))
+    /// Decrypt a ciphertext a client made before it had an account to bind
+    /// it to. Only unbound envelopes with the current KDF parameters are
+    /// accepted, as anyone can send one.
+    pub async fn decrypt_unbound(encrypted_data: &str, password: &SecretString) -> Result<SecretString> {
+        let data = general_purpose::STANDARD
+            .decode(encrypted_data)
+            .map_err(|e| AppError::Validation(format!("Invalid base64: {}", e)))?;
+        let acceptable = Envelope::parse(&data)
+            .is_some_and(|envelope| envelope.kdf == Self::password_kdf() && envelope.associated_data.is_empty());
+        if !acceptable {
+            return Err(AppError::Validation(
+                "Ciphertext must be an unbound envelope using the server's current Argon2id parameters".to_string(),
+            ));
+        }
+
+        Self::decrypt(encrypted_data, password, None).await
     }
 
-    /// Decrypt data using AES-256-GCM
-    pub fn decrypt(encrypted_data: &str, password: &str) -> Result<String> {
-        // Decode from base64
+    fn decrypt_blocking(encrypted_data: &str, password: &SecretString, binding: Option<&Binding>) -> Result<SecretString> {
         let data = general_purpose::STANDARD
             .decode(encrypted_data)
             .map_err(|e| AppError::Cryptography(format!("Invalid base64: {}", e)))?;
 
+        let plaintext = match Envelope::parse(&data) {
+            Some(envelope) => Self::check_binding(&envelope, binding)
+                .and_then(|_| Self::derive_key(password.expose(), envelope.salt, envelope.kdf))
+                .and_then(|key| Self::open(&envelope, key.expose(), &[]))
+                .or_else(|e| Self::decrypt_legacy(&data, password.expose()).map_err(|_| e))?,
+            None => Self::decrypt_legacy(&data, password.expose())?,
+        };
+
+        String::from_utf8(plaintext)
+            .map(SecretString::new)
+            .map_err(|e| AppError::Cryptography(format!("Invalid UTF-8: {}", e)))
+    }
+
+    /// The format `encrypt` wrote before envelopes
+    fn decrypt_legacy(data: &[u8], password: &str) -> Result<Vec<u8>> {
         if data.len() < SALT_SIZE + NONCE_SIZE {
             return Err(AppError::Cryptography("Invalid encrypted data length".to_string()));
         }
@@ -99,34 +481,322 @@ impl CryptoService {
         let nonce_bytes = &data[SALT_SIZE..SALT_SIZE + NONCE_SIZE];
         let ciphertext = &data[SALT_SIZE + NONCE_SIZE..];
 
-        // Derive key from password
-        let key_bytes = Self::derive_key(password, salt);
-        let key = aes_gcm::Key::<Aes256Gcm>::from_slice(&key_bytes);
-        let nonce = Nonce::from_slice(nonce_bytes);
+        let key_bytes = Self::derive_key(password, salt, Kdf::Pbkdf2Sha256 { iterations: LEGACY_PBKDF2_ITERATIONS })?;
+        let cipher = Aes256Gcm::new(aes_gcm::Key::<Aes256Gcm>::from_slice(key_bytes.expose()));
+        cipher
+            .decrypt(Nonce::from_slice(nonce_bytes), ciphertext)
+            .map_err(|e| AppError::Cryptography(format!("Decryption failed: {}", e)))
+    }
 
-        // Decrypt the data
-        let cipher = Aes256Gcm::new(key);
-        let plaintext = cipher
-            .decrypt(nonce, ciphertext)
-            .map_err(|e| AppError::Cryptography(format!("Decryption failed: {}", e)))?;
+    /// Decrypt data encrypted under a server-held key without associated data
+    pub fn decrypt_with_key(encrypted_data: &str, key: &[u8; AES_KEY_SIZE]) -> Result<Vec<u8>> {
+        Self::decrypt_with_key_aad(encrypted_data, key, &[])
+    }
 
-        String::from_utf8(plaintext)
-            .map_err(|e| AppError::Cryptography(format!("Invalid UTF-8: {}", e)))
+    /// Encrypt data with AES-256-GCM under a server-held key rather than a
+    /// password, also authenticating `aad`, so the ciphertext only decrypts
+    /// in the context it was written for. Unlike a `Binding`, `aad` isn't
+    /// stored; the caller supplies it again to decrypt.
+    pub fn encrypt_with_key_aad(data: &[u8], key: &[u8; AES_KEY_SIZE], aad: &[u8]) -> Result<String> {
+        Self::seal(data, key, Kdf::None, &[], &[], aad)
+    }
+
+    /// Decrypt data produced by `encrypt_with_key_aad` with the same `aad`,
+    /// including the legacy `nonce || ciphertext` format
+    pub fn decrypt_with_key_aad(encrypted_data: &str, key: &[u8; AES_KEY_SIZE], aad: &[u8]) -> Result<Vec<u8>> {
+        let data = general_purpose::STANDARD
+            .decode(encrypted_data)
+            .map_err(|e| AppError::Cryptography(format!("Invalid base64: {}", e)))?;
+
+        match Envelope::parse(&data) {
+            Some(envelope) if envelope.kdf == Kdf::None => Self::open(&envelope, key, aad)
+                .or_else(|e| Self::decrypt_with_key_legacy(&data, key, aad).map_err(|_| e)),
+            Some(_) => Err(AppError::Cryptography("Ciphertext is password-encrypted".to_string())),
+            None => Self::decrypt_with_key_legacy(&data, key, aad),
+        }
+    }
+
+    /// The format `encrypt_with_key_aad` wrote before envelopes
+    fn decrypt_with_key_legacy(data: &[u8], key: &[u8; AES_KEY_SIZE], aad: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < NONCE_SIZE {
+            return Err(AppError::Cryptography("Invalid encrypted data length".to_string()));
+        }
+
+        let cipher = Aes256Gcm::new(aes_gcm::Key::<Aes256Gcm>::from_slice(key));
+        cipher
+            .decrypt(Nonce::from_slice(&data[..NONCE_SIZE]), Payload { msg: &data[NONCE_SIZE..], aad })
+            .map_err(|e| AppError::Cryptography(format!("Decryption failed: {}", e)))
+    }
+
+    /// Whether a ciphertext should be rewritten: it predates envelopes, its
+    /// password KDF parameters are no longer the current ones, or it lacks
+    /// the `binding` it would be written with today
+    pub fn needs_reencryption(encrypted_data: &str, binding: Option<&Binding>) -> bool {
+        let Ok(data) = general_purpose::STANDARD.decode(encrypted_data) else {
+            return false;
+        };
+        let Some(envelope) = Envelope::parse(&data) else {
+            return true;
+        };
+
+        let associated_data = binding.map(Binding::encode).unwrap_or_default();
+        let outdated_kdf = envelope.kdf != Kdf::None && envelope.kdf != Self::password_kdf();
+        outdated_kdf || envelope.associated_data != associated_data.as_slice()
+    }
+
+    /// Decrypt and encrypt again in the current format, if
+    /// `needs_reencryption`. Returns None when the ciphertext is current.
+    pub async fn reencrypt(encrypted_data: &str, password: &SecretString, binding: Option<&Binding>) -> Result<Option<String>> {
+        if !Self::needs_reencryption(encrypted_data, binding) {
+            return Ok(None);
+        }
+        let (encrypted_data, password, binding) = (encrypted_data.to_string(), password.clone(), binding.copied());
+        Self::with_kdf(move || {
+            let data = Self::decrypt_blocking(&encrypted_data, &password, None)?;
+            Self::encrypt_blocking(data.expose(), &password, binding.as_ref()).map(Some)
+        })
+        .await
+    }
+
+    /// Build an envelope; see `Envelope` for the layout
+    fn seal(
+        data: &[u8],
+        key: &[u8; AES_KEY_SIZE],
+        kdf: Kdf,
+        salt: &[u8],
+        associated_data: &[u8],
+        aad: &[u8],
+    ) -> Result<String> {
+        let mut nonce_bytes = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce_bytes);
+
+        let associated_data_len = u16::try_from(associated_data.len())
+            .map_err(|_| AppError::Cryptography("Associated data too long".to_string()))?;
+
+        let mut envelope = Vec::with_capacity(64 + associated_data.len() + data.len());
+        envelope.extend_from_slice(ENVELOPE_MAGIC);
+        envelope.push(ENVELOPE_VERSION);
+        match kdf {
+            Kdf::None => envelope.push(KDF_NONE),
+            Kdf::Pbkdf2Sha256 { iterations } => {
+                envelope.push(KDF_PBKDF2_SHA256);
+                envelope.extend_from_slice(&iterations.to_be_bytes());
+            }
+            Kdf::Argon2id { memory_kib, iterations, parallelism } => {
+                envelope.push(KDF_ARGON2ID);
+                envelope.extend_from_slice(&memory_kib.to_be_bytes());
+                envelope.extend_from_slice(&iterations.to_be_bytes());
+                envelope.extend_from_slice(&parallelism.to_be_bytes());
+            }
+        }
+        envelope.push(salt.len() as u8);
+        envelope.extend_from_slice(salt);
+        envelope.push(CIPHER_AES_256_GCM);
+        envelope.push(NONCE_SIZE as u8);
+        envelope.extend_from_slice(&nonce_bytes);
+        envelope.extend_from_slice(&associated_data_len.to_be_bytes());
+        envelope.extend_from_slice(associated_data);
+
+        // The header is authenticated too, so its parameters can't be swapped
+        let mut full_aad = envelope.clone();
+        full_aad.extend_from_slice(aad);
+
+        let cipher = Aes256Gcm::new(aes_gcm::Key::<Aes256Gcm>::from_slice(key));
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce_bytes), Payload { msg: data, aad: &full_aad })
+            .map_err(|e| AppError::Cryptography(format!("Encryption failed: {}", e)))?;
+        envelope.extend_from_slice(&ciphertext);
+
+        Ok(general_purpose::STANDARD.encode(envelope))
+    }
+
+    /// Decrypt a parsed envelope
+    fn open(envelope: &Envelope<'_>, key: &[u8; AES_KEY_SIZE], aad: &[u8]) -> Result<Vec<u8>> {
+        let mut full_aad = envelope.header.to_vec();
+        full_aad.extend_from_slice(aad);
+
+        let cipher = Aes256Gcm::new(aes_gcm::Key::<Aes256Gcm>::from_slice(key));
+        cipher
+            .decrypt(Nonce::from_slice(envelope.nonce), Payload { msg: envelope.ciphertext, aad: &full_aad })
+            .map_err(|e| AppError::Cryptography(format!("Decryption failed: {}", e)))
+    }
+
+    fn check_binding(envelope: &Envelope<'_>, binding: Option<&Binding>) -> Result<()> {
+        match binding {
+            Some(binding) if envelope.associated_data != binding.encode().as_slice() => Err(
+                AppError::Cryptography("Ciphertext belongs to another account or purpose".to_string()),
+            ),
+            _ => Ok(()),
+        }
     }
 
     /// Validate a BIP39 mnemonic
-    pub fn validate_mnemonic(mnemonic: &str) -> Result<bool> {
-        match Mnemonic::parse_in_normalized(Language::English, mnemonic) {
+    pub fn validate_mnemonic(mnemonic: &SecretString, language: Language) -> Result<bool> {
+        match Mnemonic::parse_in(language, mnemonic.expose().as_str()) {
             Ok(_) => Ok(true),
             Err(_) => Ok(false),
         }
     }
 
-    /// Convert mnemonic to seed bytes
-    pub fn mnemonic_to_seed(mnemonic: &str) -> Result<Vec<u8>> {
-        let mnemonic = Mnemonic::parse_in_normalized(Language::English, mnemonic)
+    /// The BIP39 wordlist a valid mnemonic is written in, if any. Parsing
+    /// normalizes the words, which matters for accented wordlists.
+    pub fn detect_mnemonic_language(mnemonic: &SecretString) -> Option<Language> {
+        MNEMONIC_LANGUAGES
+            .iter()
+            .map(|(_, language)| *language)
+            .find(|language| Mnemonic::parse_in(*language, mnemonic.expose().as_str()).is_ok())
+    }
+
+    /// Convert mnemonic to seed bytes, with the optional BIP39 passphrase
+    pub fn mnemonic_to_seed(
+        mnemonic: &SecretString,
+        language: Language,
+        passphrase: Option<&SecretString>,
+    ) -> Result<SecretBytes> {
+        let mnemonic = Mnemonic::parse_in(language, mnemonic.expose().as_str())
             .map_err(|e| AppError::Cryptography(format!("Invalid mnemonic: {}", e)))?;
-        
-        Ok(mnemonic.to_seed("").to_vec())
+
+        let mut seed = mnemonic.to_seed(passphrase.map(|passphrase| passphrase.expose().as_str()).unwrap_or(""));
+        let secret_seed = SecretBytes::new(seed.to_vec());
+        seed.zeroize();
+        Ok(secret_seed)
+    }
+
+    /// BIP32 master key fingerprint of a seed, as hex. It tells whether a
+    /// mnemonic and passphrase derive the account's seed without revealing it.
+    pub fn seed_fingerprint(seed: &SecretBytes) -> Result<String> {
+        let master = Xpriv::new_master(Network::Bitcoin, seed.expose())
+            .map_err(|e| AppError::Cryptography(format!("Invalid seed: {}", e)))?;
+
+        Ok(master.fingerprint(&Secp256k1::signing_only()).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; AES_KEY_SIZE] = [7u8; AES_KEY_SIZE];
+
+    fn password() -> SecretString {
+        SecretString::new("correct horse battery staple".to_string())
+    }
+
+    fn tamper(encrypted: &str, index: usize) -> String {
+        let mut data = general_purpose::STANDARD.decode(encrypted).unwrap();
+        data[index] ^= 1;
+        general_purpose::STANDARD.encode(data)
+    }
+
+    #[tokio::test]
+    async fn password_hash_verifies_only_its_password() {
+        let hash = CryptoService::hash_password(&password()).await.unwrap();
+        assert!(CryptoService::verify_password(&password(), &hash).await.unwrap());
+        assert!(!CryptoService::verify_password(&SecretString::new("wrong".to_string()), &hash).await.unwrap());
+        assert!(CryptoService::verify_password(&password(), "not a hash").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn password_envelope_round_trips_only_for_its_binding() {
+        let user_id = Uuid::new_v4();
+        let binding = Binding::new(user_id, SecretPurpose::Seed);
+        let encrypted = CryptoService::encrypt("abandon ability", &password(), Some(&binding)).await.unwrap();
+
+        let decrypted = CryptoService::decrypt(&encrypted, &password(), Some(&binding)).await.unwrap();
+        assert_eq!(decrypted.expose(), "abandon ability");
+        assert!(!CryptoService::needs_reencryption(&encrypted, Some(&binding)));
+
+        let other_user = Binding::new(Uuid::new_v4(), SecretPurpose::Seed);
+        let other_purpose = Binding::new(user_id, SecretPurpose::DeviceCredentials);
+        assert!(CryptoService::decrypt(&encrypted, &password(), Some(&other_user)).await.is_err());
+        assert!(CryptoService::decrypt(&encrypted, &password(), Some(&other_purpose)).await.is_err());
+
+        let wrong_password = SecretString::new("wrong".to_string());
+        assert!(CryptoService::decrypt(&encrypted, &wrong_password, Some(&binding)).await.is_err());
+    }
+
+    #[test]
+    fn key_envelope_round_trips_only_with_its_aad() {
+        let encrypted = CryptoService::encrypt_with_key_aad(b"device creds", &KEY, b"device-creds:1").unwrap();
+
+        assert_eq!(CryptoService::decrypt_with_key_aad(&encrypted, &KEY, b"device-creds:1").unwrap(), b"device creds");
+        assert!(CryptoService::decrypt_with_key_aad(&encrypted, &KEY, b"device-creds:2").is_err());
+        assert!(CryptoService::decrypt_with_key_aad(&encrypted, &[8u8; AES_KEY_SIZE], b"device-creds:1").is_err());
+        assert!(CryptoService::decrypt_with_key(&encrypted, &KEY).is_err());
+    }
+
+    #[test]
+    fn authenticates_the_envelope_header_and_ciphertext() {
+        let encrypted = CryptoService::seal(b"secret", &KEY, Kdf::None, &[], b"seed:user", &[]).unwrap();
+        let length = general_purpose::STANDARD.decode(&encrypted).unwrap().len();
+        assert!(CryptoService::decrypt_with_key(&encrypted, &KEY).is_ok());
+
+        // The nonce, the associated data and the last ciphertext byte
+        for index in [10, 24, length - 1] {
+            assert!(CryptoService::decrypt_with_key(&tamper(&encrypted, index), &KEY).is_err());
+        }
+    }
+
+    #[test]
+    fn parses_the_envelope_it_writes() {
+        let encrypted = CryptoService::seal(b"secret", &KEY, DEFAULT_PASSWORD_KDF, &[1u8; SALT_SIZE], b"seed:user", &[]).unwrap();
+        let data = general_purpose::STANDARD.decode(&encrypted).unwrap();
+        let envelope = Envelope::parse(&data).unwrap();
+
+        assert_eq!(envelope.kdf, DEFAULT_PASSWORD_KDF);
+        assert_eq!(envelope.salt, &[1u8; SALT_SIZE]);
+        assert_eq!(envelope.associated_data, b"seed:user");
+        assert_eq!(envelope.ciphertext.len(), b"secret".len() + 16);
+        assert_eq!(CryptoService::open(&envelope, &KEY, &[]).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn rejects_kdf_parameters_the_server_did_not_issue() {
+        let weak = [
+            Kdf::Argon2id { memory_kib: 8, iterations: 1, parallelism: 1 },
+            Kdf::Pbkdf2Sha256 { iterations: 1 },
+        ];
+        for kdf in weak {
+            let encrypted = CryptoService::seal(b"secret", &KEY, kdf, &[1u8; SALT_SIZE], &[], &[]).unwrap();
+            let data = general_purpose::STANDARD.decode(&encrypted).unwrap();
+            assert!(Envelope::parse(&data).is_none());
+        }
+
+        let issued = CryptoService::seal(b"secret", &KEY, DEFAULT_PASSWORD_KDF, &[1u8; SALT_SIZE], &[], &[]).unwrap();
+        assert!(Envelope::parse(&general_purpose::STANDARD.decode(&issued).unwrap()).is_some());
+    }
+
+    #[tokio::test]
+    async fn decrypts_legacy_ciphertexts() {
+        let salt = [3u8; SALT_SIZE];
+        let nonce = [4u8; NONCE_SIZE];
+        let key = CryptoService::derive_key(password().expose(), &salt, Kdf::Pbkdf2Sha256 { iterations: LEGACY_PBKDF2_ITERATIONS }).unwrap();
+        let ciphertext = Aes256Gcm::new(aes_gcm::Key::<Aes256Gcm>::from_slice(key.expose()))
+            .encrypt(Nonce::from_slice(&nonce), b"legacy seed".as_slice())
+            .unwrap();
+        let legacy = general_purpose::STANDARD.encode([salt.as_slice(), &nonce, &ciphertext].concat());
+
+        let decrypted = CryptoService::decrypt(&legacy, &password(), None).await.unwrap();
+        assert_eq!(decrypted.expose(), "legacy seed");
+        assert!(CryptoService::needs_reencryption(&legacy, None));
+    }
+
+    #[tokio::test]
+    async fn decrypt_unbound_accepts_only_current_unbound_envelopes() {
+        let unbound = CryptoService::encrypt("abandon ability", &password(), None).await.unwrap();
+        assert_eq!(CryptoService::decrypt_unbound(&unbound, &password()).await.unwrap().expose(), "abandon ability");
+
+        let binding = Binding::new(Uuid::new_v4(), SecretPurpose::Seed);
+        let bound = CryptoService::seal(b"abandon ability", &KEY, DEFAULT_PASSWORD_KDF, &[1u8; SALT_SIZE], &binding.encode(), &[]).unwrap();
+        assert!(matches!(
+            CryptoService::decrypt_unbound(&bound, &password()).await,
+            Err(AppError::Validation(_))
+        ));
+
+        let keyed = CryptoService::encrypt_with_key_aad(b"abandon ability", &KEY, &[]).unwrap();
+        assert!(matches!(
+            CryptoService::decrypt_unbound(&keyed, &password()).await,
+            Err(AppError::Validation(_))
+        ));
     }
 }
@@ -0,0 +1,958 @@
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine as _};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::{Sha256, Sha512};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use crate::config::Config;
+use crate::error::{AppError, Result};
+
+const TYPE_SOA: u16 = 6;
+const TYPE_CNAME: u16 = 5;
+const TYPE_TXT: u16 = 16;
+const TYPE_OPT: u16 = 41;
+const TYPE_TSIG: u16 = 250;
+const CLASS_IN: u16 = 1;
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+const OPCODE_UPDATE: u16 = 5;
+const HEADER_SIZE: usize = 12;
+const FLAG_AD: u16 = 0x0020;
+const EDNS_FLAG_DO: u32 = 0x8000;
+const EDNS_PAYLOAD_SIZE: u16 = 1232;
+const MAX_CNAME_CHAIN: usize = 8;
+const TSIG_FUDGE: u16 = 300;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A backend able to publish the BIP353 TXT records for our usernames.
+#[async_trait]
+pub trait DnsProvider: Send + Sync {
+    /// Create a TXT record, failing if one already exists at `name`
+    async fn create_txt_record(&self, name: &str, value: &str) -> Result<()>;
+
+    /// Replace whatever TXT records exist at `name` with `value`
+    async fn update_txt_record(&self, name: &str, value: &str) -> Result<()>;
+
+    /// Remove all TXT records at `name`
+    async fn delete_txt_record(&self, name: &str) -> Result<()>;
+}
+
+/// Fully qualified BIP353 record name for `username` under `domain`
+pub fn bip353_record_name(username: &str, domain: &str) -> String {
+    format!("{}.user._bitcoin-payment.{}", username, domain.trim_end_matches('.'))
+}
+
+/// BIP353 TXT record content for a BOLT12 offer
+pub fn bip353_record_value(offer: &str) -> String {
+    format!("bitcoin:?lno={}", offer)
+}
+
+/// Split a BIP353 name, `₿user@domain` or `user@domain`, into its parts
+pub fn parse_bip353_name(name: &str) -> Option<(String, String)> {
+    let name = name.trim().trim_start_matches('₿');
+    let (user, domain) = name.split_once('@')?;
+    if user.is_empty() || domain.is_empty() || domain.contains('@') {
+        return None;
+    }
+    Some((user.to_lowercase(), domain.to_lowercase()))
+}
+
+/// A BOLT12 offer resolved from a BIP353 name
+pub struct Bip353Offer {
+    pub offer: String,
+    /// Whether the resolver vouched for the answer with the AD flag
+    pub authenticated: bool,
+}
+
+/// Resolve a BIP353 name to the BOLT12 offer it publishes. DNSSEC is checked
+/// by the resolver at `server`, which reports it through the AD flag, so only
+/// trust `authenticated` when DNS_RESOLVER is a validating resolver reached
+/// over a trusted path.
+pub async fn resolve_bip353_offer(server: &str, name: &str) -> Result<Bip353Offer> {
+    let (user, domain) = parse_bip353_name(name)
+        .ok_or_else(|| AppError::BadRequest(format!("Invalid BIP353 name: {}", name)))?;
+
+    let lookup = resolve_txt(server, &bip353_record_name(&user, &domain)).await?;
+    let records: Vec<&String> = lookup
+        .records
+        .iter()
+        .filter(|record| record.to_lowercase().starts_with("bitcoin:"))
+        .collect();
+
+    // BIP353 requires exactly one payment record
+    let record = match records.as_slice() {
+        [record] => record,
+        [] => return Err(AppError::NotFound(format!("{} publishes no payment instructions", name))),
+        _ => return Err(AppError::BadRequest(format!("{} publishes more than one payment record", name))),
+    };
+
+    let offer = record
+        .split_once('?')
+        .map(|(_, query)| query)
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|param| {
+            let (key, value) = param.split_once('=')?;
+            key.eq_ignore_ascii_case("lno").then(|| value.to_string())
+        })
+        .ok_or_else(|| AppError::BadRequest(format!("{} has no BOLT12 offer", name)))?;
+
+    Ok(Bip353Offer {
+        offer,
+        authenticated: lookup.authenticated,
+    })
+}
+
+/// Build the DNS provider selected by `DNS_PROVIDER`, if any
+pub fn provider_from_config(config: &Config) -> Result<Option<Arc<dyn DnsProvider>>> {
+    match config.dns_provider.as_str() {
+        "" | "none" => Ok(None),
+        "rfc2136" => Ok(Some(Arc::new(Rfc2136Provider::from_config(config)?))),
+        other => Err(AppError::Internal(format!("Unknown DNS provider: {}", other))),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum TsigAlgorithm {
+    HmacSha256,
+    HmacSha512,
+}
+
+impl TsigAlgorithm {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name.trim_end_matches('.').to_ascii_lowercase().as_str() {
+            "hmac-sha256" => Ok(TsigAlgorithm::HmacSha256),
+            "hmac-sha512" => Ok(TsigAlgorithm::HmacSha512),
+            other => Err(AppError::Internal(format!("Unsupported TSIG algorithm: {}", other))),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            TsigAlgorithm::HmacSha256 => "hmac-sha256",
+            TsigAlgorithm::HmacSha512 => "hmac-sha512",
+        }
+    }
+
+    fn mac(&self, key: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+        match self {
+            TsigAlgorithm::HmacSha256 => {
+                let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key)
+                    .expect("HMAC accepts keys of any length");
+                parts.iter().for_each(|part| mac.update(part));
+                mac.finalize().into_bytes().to_vec()
+            }
+            TsigAlgorithm::HmacSha512 => {
+                let mut mac = <Hmac<Sha512> as Mac>::new_from_slice(key)
+                    .expect("HMAC accepts keys of any length");
+                parts.iter().for_each(|part| mac.update(part));
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+}
+
+/// RFC 2136 dynamic updates against our own authoritative servers (BIND, Knot),
+/// authenticated with an RFC 8945 TSIG key.
+pub struct Rfc2136Provider {
+    server: String,
+    zone: String,
+    key_name: String,
+    key_secret: Vec<u8>,
+    algorithm: TsigAlgorithm,
+    ttl: u32,
+}
+
+struct ResourceRecord {
+    name: Vec<u8>,
+    rtype: u16,
+    class: u16,
+    ttl: u32,
+    rdata: Vec<u8>,
+}
+
+impl Rfc2136Provider {
+    pub fn new(
+        server: &str,
+        zone: &str,
+        key_name: &str,
+        key_secret_base64: &str,
+        algorithm: TsigAlgorithm,
+        ttl: u32,
+    ) -> Result<Self> {
+        let key_secret = general_purpose::STANDARD
+            .decode(key_secret_base64.trim())
+            .map_err(|e| AppError::Internal(format!("Invalid TSIG secret: {}", e)))?;
+
+        if key_name.is_empty() || key_secret.is_empty() {
+            return Err(AppError::Internal("TSIG key name and secret are required".to_string()));
+        }
+
+        Ok(Self {
+            server: server.to_string(),
+            zone: zone.trim_end_matches('.').to_ascii_lowercase(),
+            key_name: key_name.to_string(),
+            key_secret,
+            algorithm,
+            ttl,
+        })
+    }
+
+    pub fn from_config(config: &Config) -> Result<Self> {
+        Self::new(
+            &config.dns_server,
+            &config.dns_zone,
+            &config.dns_tsig_key_name,
+            &config.dns_tsig_secret,
+            TsigAlgorithm::parse(&config.dns_tsig_algorithm)?,
+            config.dns_record_ttl,
+        )
+    }
+
+    fn txt_record(&self, name: &str, value: &str) -> Result<ResourceRecord> {
+        Ok(ResourceRecord {
+            name: encode_name(name)?,
+            rtype: TYPE_TXT,
+            class: CLASS_IN,
+            ttl: self.ttl,
+            rdata: encode_txt_rdata(value),
+        })
+    }
+
+    /// An empty TXT RR of the given class, used for "RRset exists/does not
+    /// exist" prerequisites (RFC 2136 2.4) and "delete RRset" updates (2.5.2)
+    fn empty_txt_rrset(&self, name: &str, class: u16) -> Result<ResourceRecord> {
+        Ok(ResourceRecord {
+            name: encode_name(name)?,
+            rtype: TYPE_TXT,
+            class,
+            ttl: 0,
+            rdata: Vec::new(),
+        })
+    }
+
+    fn check_in_zone(&self, name: &str) -> Result<()> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if name == self.zone || name.ends_with(&format!(".{}", self.zone)) {
+            Ok(())
+        } else {
+            Err(AppError::Validation(format!("{} is not inside zone {}", name, self.zone)))
+        }
+    }
+
+    async fn send_update(&self, prerequisites: &[ResourceRecord], updates: &[ResourceRecord]) -> Result<()> {
+        let id: u16 = rand::thread_rng().gen();
+        let mut message = build_update(id, &encode_name(&self.zone)?, prerequisites, updates);
+        let time_signed = chrono::Utc::now().timestamp() as u64;
+        let request_mac = self.sign(&mut message, id, time_signed)?;
+
+        let response = exchange(&self.server, &message).await?;
+
+        self.check_response(id, &request_mac, &response)
+    }
+
+    /// Append a TSIG record to `message` and return its MAC
+    fn sign(&self, message: &mut Vec<u8>, id: u16, time_signed: u64) -> Result<Vec<u8>> {
+        let key_name = encode_name(&self.key_name.to_ascii_lowercase())?;
+        let algorithm_name = encode_name(self.algorithm.name())?;
+        let variables = tsig_variables(&key_name, &algorithm_name, time_signed, TSIG_FUDGE, 0, &[]);
+        let mac = self.algorithm.mac(&self.key_secret, &[message, &variables]);
+
+        let mut rdata = algorithm_name;
+        rdata.extend_from_slice(&time_signed.to_be_bytes()[2..]);
+        rdata.extend_from_slice(&TSIG_FUDGE.to_be_bytes());
+        rdata.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+        rdata.extend_from_slice(&mac);
+        rdata.extend_from_slice(&id.to_be_bytes());
+        rdata.extend_from_slice(&0u16.to_be_bytes()); // error
+        rdata.extend_from_slice(&0u16.to_be_bytes()); // other len
+
+        write_record(message, &ResourceRecord {
+            name: key_name,
+            rtype: TYPE_TSIG,
+            class: CLASS_ANY,
+            ttl: 0,
+            rdata,
+        });
+        let arcount = read_u16(message, 10)? + 1;
+        message[10..12].copy_from_slice(&arcount.to_be_bytes());
+
+        Ok(mac)
+    }
+
+    fn check_response(&self, id: u16, request_mac: &[u8], response: &[u8]) -> Result<()> {
+        if read_u16(response, 0)? != id {
+            return Err(AppError::Dns("Response ID does not match request".to_string()));
+        }
+
+        let rcode = read_u16(response, 2)? & 0x000f;
+        let tsig = find_tsig(response)?;
+
+        if let Some(tsig) = &tsig {
+            if tsig.error != 0 {
+                return Err(AppError::Dns(format!("Server rejected TSIG: {}", tsig_error_name(tsig.error))));
+            }
+        }
+
+        if rcode != 0 {
+            return Err(AppError::Dns(format!("Update failed: {}", rcode_name(rcode))));
+        }
+
+        let tsig = tsig.ok_or_else(|| AppError::Dns("Response is not signed".to_string()))?;
+
+        // RFC 8945 5.3.1: the response MAC covers the request MAC, the response
+        // without its TSIG record (and with the original ID), and the TSIG variables
+        let mut unsigned = response[..tsig.offset].to_vec();
+        unsigned[0..2].copy_from_slice(&tsig.original_id.to_be_bytes());
+        let arcount = read_u16(&unsigned, 10)? - 1;
+        unsigned[10..12].copy_from_slice(&arcount.to_be_bytes());
+
+        let key_name = encode_name(&self.key_name.to_ascii_lowercase())?;
+        let algorithm_name = encode_name(self.algorithm.name())?;
+        let variables = tsig_variables(&key_name, &algorithm_name, tsig.time_signed, tsig.fudge, tsig.error, &tsig.other);
+        let expected = self.algorithm.mac(
+            &self.key_secret,
+            &[&(request_mac.len() as u16).to_be_bytes(), request_mac, &unsigned, &variables],
+        );
+
+        if !constant_time_eq(&expected, &tsig.mac) {
+            return Err(AppError::Dns("Response TSIG signature is invalid".to_string()));
+        }
+
+        let now = chrono::Utc::now().timestamp() as u64;
+        if now.abs_diff(tsig.time_signed) > tsig.fudge as u64 {
+            return Err(AppError::Dns("Response TSIG is outside the allowed time window".to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DnsProvider for Rfc2136Provider {
+    async fn create_txt_record(&self, name: &str, value: &str) -> Result<()> {
+        self.check_in_zone(name)?;
+        let prerequisite = self.empty_txt_rrset(name, CLASS_NONE)?;
+        self.send_update(&[prerequisite], &[self.txt_record(name, value)?]).await
+    }
+
+    async fn update_txt_record(&self, name: &str, value: &str) -> Result<()> {
+        self.check_in_zone(name)?;
+        let delete = self.empty_txt_rrset(name, CLASS_ANY)?;
+        self.send_update(&[], &[delete, self.txt_record(name, value)?]).await
+    }
+
+    async fn delete_txt_record(&self, name: &str) -> Result<()> {
+        self.check_in_zone(name)?;
+        let delete = self.empty_txt_rrset(name, CLASS_ANY)?;
+        self.send_update(&[], &[delete]).await
+    }
+}
+
+/// TXT records found by [`resolve_txt`]
+pub struct TxtLookup {
+    pub records: Vec<String>,
+    /// The resolver set the AD flag, i.e. it validated the answer with DNSSEC
+    pub authenticated: bool,
+}
+
+/// Look up the TXT records at `name` through `server`, asking for DNSSEC
+/// validation. Each record's strings are concatenated and only records owned
+/// by `name`, or by a CNAME it points to, are returned; a name that does not
+/// exist yields no records.
+pub async fn resolve_txt(server: &str, name: &str) -> Result<TxtLookup> {
+    let id: u16 = rand::thread_rng().gen();
+    let qname = encode_name(name)?;
+    let mut query = Vec::with_capacity(HEADER_SIZE + qname.len() + 15);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&0x0100u16.to_be_bytes()); // RD
+    query.extend_from_slice(&1u16.to_be_bytes()); // QDCOUNT
+    query.extend_from_slice(&[0u8; 4]);
+    query.extend_from_slice(&1u16.to_be_bytes()); // ARCOUNT
+    query.extend_from_slice(&qname);
+    query.extend_from_slice(&TYPE_TXT.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    // EDNS0 OPT record (RFC 6891) with the DO bit set (RFC 3225)
+    query.push(0);
+    query.extend_from_slice(&TYPE_OPT.to_be_bytes());
+    query.extend_from_slice(&EDNS_PAYLOAD_SIZE.to_be_bytes());
+    query.extend_from_slice(&EDNS_FLAG_DO.to_be_bytes());
+    query.extend_from_slice(&0u16.to_be_bytes());
+
+    let response = exchange(server, &query).await?;
+    parse_txt_response(id, name, &response)
+}
+
+fn parse_txt_response(id: u16, name: &str, response: &[u8]) -> Result<TxtLookup> {
+    if read_u16(response, 0)? != id {
+        return Err(AppError::Dns("Response ID does not match request".to_string()));
+    }
+
+    let flags = read_u16(response, 2)?;
+    let authenticated = flags & FLAG_AD != 0;
+    match flags & 0x000f {
+        0 => {}
+        3 => return Ok(TxtLookup { records: Vec::new(), authenticated }),
+        rcode => return Err(AppError::Dns(format!("Lookup failed: {}", rcode_name(rcode)))),
+    }
+
+    let qname = normalize_name(name);
+    if read_u16(response, 4)? != 1 {
+        return Err(AppError::Dns("Response does not echo the question".to_string()));
+    }
+    let (question, pos) = read_name(response, HEADER_SIZE)?;
+    if question != qname || read_u16(response, pos)? != TYPE_TXT || read_u16(response, pos + 2)? != CLASS_IN {
+        return Err(AppError::Dns("Response answers a different question".to_string()));
+    }
+
+    let ancount = read_u16(response, 6)? as usize;
+    let mut pos = pos + 4;
+    let mut txt = Vec::new();
+    let mut cnames = Vec::new();
+    for _ in 0..ancount {
+        let (owner, next) = read_name(response, pos)?;
+        let rdata_start = next + 10;
+        let rtype = read_u16(response, next)?;
+        let class = read_u16(response, next + 2)?;
+        let rdlength = read_u16(response, next + 8)? as usize;
+        let rdata = read_bytes(response, rdata_start, rdlength)?;
+        pos = rdata_start + rdlength;
+
+        if class != CLASS_IN {
+            continue;
+        }
+        match rtype {
+            TYPE_TXT => {
+                let mut value = Vec::with_capacity(rdlength);
+                let mut i = 0;
+                while i < rdata.len() {
+                    let length = rdata[i] as usize;
+                    value.extend_from_slice(read_bytes(rdata, i + 1, length)?);
+                    i += length + 1;
+                }
+                txt.push((owner, String::from_utf8_lossy(&value).into_owned()));
+            }
+            TYPE_CNAME => cnames.push((owner, read_name(response, rdata_start)?.0)),
+            _ => {}
+        }
+    }
+
+    // Follow CNAMEs from the question; records for any other name are
+    // unsolicited and ignored
+    let mut target = qname;
+    for _ in 0..MAX_CNAME_CHAIN {
+        match cnames.iter().find(|(owner, _)| *owner == target) {
+            Some((_, alias)) => target = alias.clone(),
+            None => break,
+        }
+    }
+
+    let records = txt
+        .into_iter()
+        .filter(|(owner, _)| *owner == target)
+        .map(|(_, value)| value)
+        .collect();
+
+    Ok(TxtLookup { records, authenticated })
+}
+
+/// Send a message over TCP (RFC 1035 4.2.2) and wait for the response;
+/// signed updates carrying a BOLT12 offer routinely exceed the 512 byte UDP limit
+async fn exchange(server: &str, message: &[u8]) -> Result<Vec<u8>> {
+    let request = async {
+        let mut stream = TcpStream::connect(server)
+            .await
+            .map_err(|e| AppError::Dns(format!("Failed to connect to {}: {}", server, e)))?;
+
+        let mut framed = Vec::with_capacity(message.len() + 2);
+        framed.extend_from_slice(&(message.len() as u16).to_be_bytes());
+        framed.extend_from_slice(message);
+        stream
+            .write_all(&framed)
+            .await
+            .map_err(|e| AppError::Dns(format!("Failed to send request: {}", e)))?;
+
+        let length = stream
+            .read_u16()
+            .await
+            .map_err(|e| AppError::Dns(format!("Failed to read response: {}", e)))?;
+        let mut response = vec![0u8; length as usize];
+        stream
+            .read_exact(&mut response)
+            .await
+            .map_err(|e| AppError::Dns(format!("Failed to read response: {}", e)))?;
+
+        Ok(response)
+    };
+
+    tokio::time::timeout(REQUEST_TIMEOUT, request)
+        .await
+        .map_err(|_| AppError::Dns(format!("Timed out talking to {}", server)))?
+}
+
+fn build_update(id: u16, zone: &[u8], prerequisites: &[ResourceRecord], updates: &[ResourceRecord]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(512);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&(OPCODE_UPDATE << 11).to_be_bytes());
+    message.extend_from_slice(&1u16.to_be_bytes()); // ZOCOUNT
+    message.extend_from_slice(&(prerequisites.len() as u16).to_be_bytes());
+    message.extend_from_slice(&(updates.len() as u16).to_be_bytes());
+    message.extend_from_slice(&0u16.to_be_bytes()); // ADCOUNT
+
+    message.extend_from_slice(zone);
+    message.extend_from_slice(&TYPE_SOA.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    for record in prerequisites.iter().chain(updates) {
+        write_record(&mut message, record);
+    }
+
+    message
+}
+
+fn write_record(message: &mut Vec<u8>, record: &ResourceRecord) {
+    message.extend_from_slice(&record.name);
+    message.extend_from_slice(&record.rtype.to_be_bytes());
+    message.extend_from_slice(&record.class.to_be_bytes());
+    message.extend_from_slice(&record.ttl.to_be_bytes());
+    message.extend_from_slice(&(record.rdata.len() as u16).to_be_bytes());
+    message.extend_from_slice(&record.rdata);
+}
+
+fn tsig_variables(key_name: &[u8], algorithm_name: &[u8], time_signed: u64, fudge: u16, error: u16, other: &[u8]) -> Vec<u8> {
+    let mut variables = Vec::new();
+    variables.extend_from_slice(key_name);
+    variables.extend_from_slice(&CLASS_ANY.to_be_bytes());
+    variables.extend_from_slice(&0u32.to_be_bytes());
+    variables.extend_from_slice(algorithm_name);
+    variables.extend_from_slice(&time_signed.to_be_bytes()[2..]);
+    variables.extend_from_slice(&fudge.to_be_bytes());
+    variables.extend_from_slice(&error.to_be_bytes());
+    variables.extend_from_slice(&(other.len() as u16).to_be_bytes());
+    variables.extend_from_slice(other);
+    variables
+}
+
+/// Encode a domain name in uncompressed wire format
+fn encode_name(name: &str) -> Result<Vec<u8>> {
+    let mut encoded = Vec::with_capacity(name.len() + 2);
+    for label in name.trim_end_matches('.').split('.').filter(|label| !label.is_empty()) {
+        if label.len() > 63 {
+            return Err(AppError::Validation(format!("DNS label too long: {}", label)));
+        }
+        encoded.push(label.len() as u8);
+        encoded.extend_from_slice(label.as_bytes());
+    }
+    encoded.push(0);
+
+    if encoded.len() > 255 {
+        return Err(AppError::Validation(format!("DNS name too long: {}", name)));
+    }
+
+    Ok(encoded)
+}
+
+/// TXT RDATA is a sequence of length-prefixed strings of at most 255 bytes
+fn encode_txt_rdata(value: &str) -> Vec<u8> {
+    let mut rdata = Vec::with_capacity(value.len() + value.len() / 255 + 1);
+    for chunk in value.as_bytes().chunks(255) {
+        rdata.push(chunk.len() as u8);
+        rdata.extend_from_slice(chunk);
+    }
+    if value.is_empty() {
+        rdata.push(0);
+    }
+    rdata
+}
+
+struct ResponseTsig {
+    offset: usize,
+    time_signed: u64,
+    fudge: u16,
+    mac: Vec<u8>,
+    original_id: u16,
+    error: u16,
+    other: Vec<u8>,
+}
+
+/// Locate the TSIG record, which must be the last additional record
+fn find_tsig(response: &[u8]) -> Result<Option<ResponseTsig>> {
+    let qdcount = read_u16(response, 4)? as usize;
+    let records = read_u16(response, 6)? as usize + read_u16(response, 8)? as usize;
+    let arcount = read_u16(response, 10)? as usize;
+    if arcount == 0 {
+        return Ok(None);
+    }
+
+    let mut pos = HEADER_SIZE;
+    for _ in 0..qdcount {
+        pos = skip_name(response, pos)? + 4;
+    }
+    for _ in 0..records + arcount - 1 {
+        pos = skip_record(response, pos)?;
+    }
+
+    let offset = pos;
+    pos = skip_name(response, pos)?;
+    if read_u16(response, pos)? != TYPE_TSIG {
+        return Ok(None);
+    }
+    pos += 10; // type, class, ttl, rdlength
+
+    pos = skip_name(response, pos)?;
+    let time_signed = (read_u16(response, pos)? as u64) << 32 | read_u32(response, pos + 2)? as u64;
+    let fudge = read_u16(response, pos + 6)?;
+    let mac_size = read_u16(response, pos + 8)? as usize;
+    pos += 10;
+    let mac = read_bytes(response, pos, mac_size)?.to_vec();
+    pos += mac_size;
+    let original_id = read_u16(response, pos)?;
+    let error = read_u16(response, pos + 2)?;
+    let other_len = read_u16(response, pos + 4)? as usize;
+    let other = read_bytes(response, pos + 6, other_len)?.to_vec();
+
+    Ok(Some(ResponseTsig {
+        offset,
+        time_signed,
+        fudge,
+        mac,
+        original_id,
+        error,
+        other,
+    }))
+}
+
+fn skip_name(buf: &[u8], mut pos: usize) -> Result<usize> {
+    loop {
+        let length = *buf.get(pos).ok_or_else(truncated)? as usize;
+        match length {
+            0 => return Ok(pos + 1),
+            l if l & 0xc0 == 0xc0 => return Ok(pos + 2),
+            l => pos += l + 1,
+        }
+    }
+}
+
+/// Read a possibly compressed name, returning it lowercased without the
+/// trailing dot along with the position just past it
+fn read_name(buf: &[u8], mut pos: usize) -> Result<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let length = *buf.get(pos).ok_or_else(truncated)? as usize;
+        match length {
+            0 => break,
+            l if l & 0xc0 == 0xc0 => {
+                jumps += 1;
+                if jumps > 64 {
+                    return Err(AppError::Dns("Compression loop in DNS response".to_string()));
+                }
+                end.get_or_insert(pos + 2);
+                pos = (read_u16(buf, pos)? & 0x3fff) as usize;
+            }
+            l if l & 0xc0 != 0 => return Err(AppError::Dns("Unsupported DNS label type".to_string())),
+            l => {
+                labels.push(String::from_utf8_lossy(read_bytes(buf, pos + 1, l)?).to_ascii_lowercase());
+                pos += l + 1;
+            }
+        }
+    }
+    Ok((labels.join("."), end.unwrap_or(pos + 1)))
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn skip_record(buf: &[u8], pos: usize) -> Result<usize> {
+    let pos = skip_name(buf, pos)?;
+    let rdlength = read_u16(buf, pos + 8)? as usize;
+    Ok(pos + 10 + rdlength)
+}
+
+fn read_bytes(buf: &[u8], pos: usize, len: usize) -> Result<&[u8]> {
+    buf.get(pos..pos + len).ok_or_else(truncated)
+}
+
+fn read_u16(buf: &[u8], pos: usize) -> Result<u16> {
+    let bytes = read_bytes(buf, pos, 2)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> Result<u32> {
+    let bytes = read_bytes(buf, pos, 4)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn truncated() -> AppError {
+    AppError::Dns("Truncated DNS response".to_string())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn rcode_name(rcode: u16) -> &'static str {
+    match rcode {
+        1 => "FORMERR",
+        2 => "SERVFAIL",
+        3 => "NXDOMAIN",
+        4 => "NOTIMP",
+        5 => "REFUSED",
+        6 => "YXDOMAIN",
+        7 => "YXRRSET (record already exists)",
+        8 => "NXRRSET (record does not exist)",
+        9 => "NOTAUTH",
+        10 => "NOTZONE",
+        _ => "unknown error",
+    }
+}
+
+fn tsig_error_name(error: u16) -> &'static str {
+    match error {
+        16 => "BADSIG",
+        17 => "BADKEY",
+        18 => "BADTIME",
+        22 => "BADTRUNC",
+        _ => "unknown TSIG error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "c2VjcmV0IHRzaWcga2V5IGZvciB0ZXN0cw==";
+
+    fn provider() -> Rfc2136Provider {
+        Rfc2136Provider::new("127.0.0.1:53", "Example.com.", "Update-Key", SECRET, TsigAlgorithm::HmacSha256, 300).unwrap()
+    }
+
+    fn now() -> u64 {
+        chrono::Utc::now().timestamp() as u64
+    }
+
+    /// An update response as the server would sign it (RFC 8945 5.3)
+    fn signed_response(provider: &Rfc2136Provider, id: u16, rcode: u16, request_mac: &[u8], time_signed: u64, error: u16) -> Vec<u8> {
+        let mut response = build_update(id, &encode_name(&provider.zone).unwrap(), &[], &[]);
+        response[2..4].copy_from_slice(&(0x8000 | OPCODE_UPDATE << 11 | rcode).to_be_bytes());
+
+        let key_name = encode_name("update-key").unwrap();
+        let algorithm_name = encode_name("hmac-sha256").unwrap();
+        let variables = tsig_variables(&key_name, &algorithm_name, time_signed, TSIG_FUDGE, error, &[]);
+        let mac = provider.algorithm.mac(
+            &provider.key_secret,
+            &[&(request_mac.len() as u16).to_be_bytes(), request_mac, &response, &variables],
+        );
+
+        let mut rdata = algorithm_name;
+        rdata.extend_from_slice(&time_signed.to_be_bytes()[2..]);
+        rdata.extend_from_slice(&TSIG_FUDGE.to_be_bytes());
+        rdata.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+        rdata.extend_from_slice(&mac);
+        rdata.extend_from_slice(&id.to_be_bytes());
+        rdata.extend_from_slice(&error.to_be_bytes());
+        rdata.extend_from_slice(&0u16.to_be_bytes());
+        write_record(&mut response, &ResourceRecord { name: key_name, rtype: TYPE_TSIG, class: CLASS_ANY, ttl: 0, rdata });
+        response[10..12].copy_from_slice(&1u16.to_be_bytes());
+        response
+    }
+
+    /// A TXT response to `name` with the given answer records
+    fn txt_response(id: u16, flags: u16, name: &str, answers: &[ResourceRecord]) -> Vec<u8> {
+        let mut response = Vec::new();
+        response.extend_from_slice(&id.to_be_bytes());
+        response.extend_from_slice(&flags.to_be_bytes());
+        response.extend_from_slice(&1u16.to_be_bytes());
+        response.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+        response.extend_from_slice(&[0u8; 4]);
+        response.extend_from_slice(&encode_name(name).unwrap());
+        response.extend_from_slice(&TYPE_TXT.to_be_bytes());
+        response.extend_from_slice(&CLASS_IN.to_be_bytes());
+        for answer in answers {
+            write_record(&mut response, answer);
+        }
+        response
+    }
+
+    fn txt(name: Vec<u8>, value: &str) -> ResourceRecord {
+        ResourceRecord { name, rtype: TYPE_TXT, class: CLASS_IN, ttl: 300, rdata: encode_txt_rdata(value) }
+    }
+
+    #[test]
+    fn builds_bip353_records() {
+        assert_eq!(bip353_record_name("alice", "example.com."), "alice.user._bitcoin-payment.example.com");
+        assert_eq!(bip353_record_value("lno1qq"), "bitcoin:?lno=lno1qq");
+        assert_eq!(parse_bip353_name("₿Alice@Example.com"), Some(("alice".to_string(), "example.com".to_string())));
+        assert_eq!(parse_bip353_name("alice@"), None);
+        assert_eq!(parse_bip353_name("alice@a@b"), None);
+    }
+
+    #[test]
+    fn encodes_names_in_wire_format() {
+        assert_eq!(encode_name("Example.com.").unwrap(), b"\x07Example\x03com\x00");
+        assert_eq!(encode_name(".").unwrap(), b"\x00");
+        assert!(encode_name(&"a".repeat(64)).is_err());
+        assert!(encode_name(&vec!["a".repeat(63); 4].join(".")).is_err());
+    }
+
+    #[test]
+    fn splits_txt_rdata_into_255_byte_strings() {
+        let value = "x".repeat(300);
+        let rdata = encode_txt_rdata(&value);
+        assert_eq!(rdata.len(), 302);
+        assert_eq!(rdata[0], 255);
+        assert_eq!(rdata[256], 45);
+        assert_eq!(encode_txt_rdata(""), vec![0]);
+    }
+
+    #[test]
+    fn lays_out_tsig_variables() {
+        let variables = tsig_variables(b"\x01k\x00", b"\x01a\x00", 0x0102_0304_0506, 300, 16, b"xy");
+        let expected: &[u8] = b"\x01k\x00\x00\xff\x00\x00\x00\x00\x01a\x00\x01\x02\x03\x04\x05\x06\x01\x2c\x00\x10\x00\x02xy";
+        assert_eq!(variables, expected);
+    }
+
+    #[test]
+    fn signs_updates_with_a_trailing_tsig_record() {
+        let provider = provider();
+        let record = provider.txt_record("alice.user._bitcoin-payment.example.com", "bitcoin:?lno=lno1qq").unwrap();
+        let mut message = build_update(0x1234, &encode_name(&provider.zone).unwrap(), &[], &[record]);
+        let unsigned = message.clone();
+        let time_signed = now();
+
+        let mac = provider.sign(&mut message, 0x1234, time_signed).unwrap();
+
+        assert_eq!(read_u16(&message, 10).unwrap(), 1);
+        let tsig = find_tsig(&message).unwrap().unwrap();
+        assert_eq!(tsig.offset, unsigned.len());
+        assert_eq!(tsig.mac, mac);
+        assert_eq!(tsig.time_signed, time_signed);
+        assert_eq!(tsig.fudge, TSIG_FUDGE);
+        assert_eq!(tsig.original_id, 0x1234);
+        assert_eq!(tsig.error, 0);
+
+        // The key name is canonicalized to lowercase before signing
+        let variables = tsig_variables(b"\x0aupdate-key\x00", b"\x0bhmac-sha256\x00", time_signed, TSIG_FUDGE, 0, &[]);
+        let mut expected = <Hmac<Sha256> as Mac>::new_from_slice(b"secret tsig key for tests").unwrap();
+        expected.update(&unsigned);
+        expected.update(&variables);
+        assert_eq!(mac, expected.finalize().into_bytes().to_vec());
+    }
+
+    #[test]
+    fn accepts_a_correctly_signed_response() {
+        let provider = provider();
+        let request_mac = [9u8; 32];
+        let response = signed_response(&provider, 0x1234, 0, &request_mac, now(), 0);
+        assert!(provider.check_response(0x1234, &request_mac, &response).is_ok());
+    }
+
+    #[test]
+    fn rejects_responses_that_fail_verification() {
+        let provider = provider();
+        let request_mac = [9u8; 32];
+        let response = signed_response(&provider, 0x1234, 0, &request_mac, now(), 0);
+
+        assert!(provider.check_response(0x4321, &request_mac, &response).is_err());
+        assert!(provider.check_response(0x1234, &[8u8; 32], &response).is_err());
+
+        let mut tampered = response.clone();
+        tampered[14] ^= 1; // inside the zone name
+        assert!(provider.check_response(0x1234, &request_mac, &tampered).is_err());
+
+        let other_key = Rfc2136Provider::new("127.0.0.1:53", "example.com", "update-key", "b3RoZXI=", TsigAlgorithm::HmacSha256, 300).unwrap();
+        assert!(other_key.check_response(0x1234, &request_mac, &response).is_err());
+
+        let stale = signed_response(&provider, 0x1234, 0, &request_mac, now() - 3600, 0);
+        assert!(provider.check_response(0x1234, &request_mac, &stale).is_err());
+
+        let mut unsigned = build_update(0x1234, &encode_name(&provider.zone).unwrap(), &[], &[]);
+        unsigned[2] |= 0x80;
+        assert!(provider.check_response(0x1234, &request_mac, &unsigned).is_err());
+    }
+
+    #[test]
+    fn reports_server_errors() {
+        let provider = provider();
+        let request_mac = [9u8; 32];
+
+        let refused = signed_response(&provider, 1, 5, &request_mac, now(), 0);
+        match provider.check_response(1, &request_mac, &refused) {
+            Err(AppError::Dns(message)) => assert!(message.contains("REFUSED"), "{}", message),
+            other => panic!("expected a DNS error, got {:?}", other),
+        }
+
+        let badsig = signed_response(&provider, 1, 9, &request_mac, now(), 16);
+        match provider.check_response(1, &request_mac, &badsig) {
+            Err(AppError::Dns(message)) => assert!(message.contains("BADSIG"), "{}", message),
+            other => panic!("expected a DNS error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn keeps_update_names_inside_the_zone() {
+        let provider = provider();
+        assert!(provider.check_in_zone("alice.user._bitcoin-payment.example.com.").is_ok());
+        assert!(provider.check_in_zone("EXAMPLE.COM").is_ok());
+        assert!(provider.check_in_zone("alice.badexample.com").is_err());
+        assert!(provider.check_in_zone("example.com.evil.net").is_err());
+    }
+
+    #[test]
+    fn follows_cnames_and_ignores_unsolicited_records() {
+        let name = "alice.user._bitcoin-payment.example.com";
+        // The CNAME owner points back at the question; the alias is uncompressed
+        let answers = [
+            ResourceRecord {
+                name: vec![0xc0, 12],
+                rtype: TYPE_CNAME,
+                class: CLASS_IN,
+                ttl: 300,
+                rdata: encode_name("pay.example.net").unwrap(),
+            },
+            txt(encode_name("Pay.Example.Net.").unwrap(), &format!("bitcoin:?lno={}", "q".repeat(300))),
+            txt(encode_name("evil.example.org").unwrap(), "bitcoin:?lno=evil"),
+            txt(vec![0xc0, 12], "bitcoin:?lno=stale"),
+        ];
+        let response = txt_response(7, 0x81a0, name, &answers);
+
+        let lookup = parse_txt_response(7, &name.to_uppercase(), &response).unwrap();
+        assert!(lookup.authenticated);
+        assert_eq!(lookup.records, vec![format!("bitcoin:?lno={}", "q".repeat(300))]);
+    }
+
+    #[test]
+    fn parses_plain_and_missing_txt_answers() {
+        let name = "alice.user._bitcoin-payment.example.com";
+        let response = txt_response(7, 0x8180, name, &[txt(vec![0xc0, 12], "bitcoin:?lno=lno1qq")]);
+        let lookup = parse_txt_response(7, name, &response).unwrap();
+        assert!(!lookup.authenticated);
+        assert_eq!(lookup.records, vec!["bitcoin:?lno=lno1qq".to_string()]);
+
+        let nxdomain = txt_response(7, 0x8183, name, &[]);
+        assert!(parse_txt_response(7, name, &nxdomain).unwrap().records.is_empty());
+
+        let servfail = txt_response(7, 0x8182, name, &[]);
+        assert!(parse_txt_response(7, name, &servfail).is_err());
+    }
+
+    #[test]
+    fn rejects_mismatched_or_malformed_txt_responses() {
+        let name = "alice.user._bitcoin-payment.example.com";
+        let response = txt_response(7, 0x8180, name, &[txt(vec![0xc0, 12], "bitcoin:?lno=lno1qq")]);
+
+        assert!(parse_txt_response(8, name, &response).is_err());
+        assert!(parse_txt_response(7, "bob.user._bitcoin-payment.example.com", &response).is_err());
+        assert!(parse_txt_response(7, name, &response[..response.len() - 3]).is_err());
+
+        let mut looping = txt_response(7, 0x8180, name, &[txt(vec![0xc0, 12], "x")]);
+        looping[12..14].copy_from_slice(&[0xc0, 12]);
+        assert!(parse_txt_response(7, name, &looping).is_err());
+    }
+}
@@ -1,6 +1,7 @@
 use crate::config::Config;
 use crate::error::{AppError, Result};
 use crate::handlers::node::CreateOfferRequest;
+use crate::services::SecretBytes;
 
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -12,7 +13,11 @@ use gl_client::{
     scheduler::Scheduler,
     signer::Signer,
     node::ClnClient,
-    pb::cln::OfferRequest,
+    pb::cln::{
+        amount_or_all, amount_or_any, decode_response::DecodeType, feerate, newaddr_request::NewaddrAddresstype,
+        Amount, AmountOrAll, AmountOrAny, DecodeRequest, DisableofferRequest, Feerate, FetchinvoiceRequest,
+        InvoiceRequest, ListoffersRequest, NewaddrRequest, OfferRequest, PayRequest, WithdrawRequest,
+    },
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -20,6 +25,48 @@ pub struct DeviceCredentials {
     pub creds: Vec<u8>,
 }
 
+/// An offer as reported by the node's `listoffers`
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeOffer {
+    pub offer_id: String,
+    pub bolt12: String,
+    pub active: bool,
+    pub used: bool,
+}
+
+/// A BOLT11 invoice created on the user's node
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeInvoice {
+    pub bolt11: String,
+    pub payment_hash: String,
+    pub expires_at: u64,
+}
+
+/// What a payment string turned out to be
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaymentRequestKind {
+    Bolt11,
+    Bolt12Offer,
+}
+
+/// A BOLT11 invoice or BOLT12 offer as decoded by the node
+#[derive(Debug, Clone)]
+pub struct DecodedPaymentRequest {
+    pub kind: PaymentRequestKind,
+    /// Payee of the invoice, or issuer of the offer
+    pub node_id: String,
+    pub amount_msat: Option<u64>,
+}
+
+/// A completed Lightning payment
+#[derive(Debug, Clone, Serialize)]
+pub struct NodePayment {
+    pub payment_hash: String,
+    pub amount_msat: u64,
+    /// Amount plus routing fees
+    pub amount_sent_msat: u64,
+}
+
 pub struct GreenlightService {
     #[allow(dead_code)]
     config: Config,
@@ -30,7 +77,7 @@ impl GreenlightService {
         Self { config }
     }
 
-    pub async fn register_node(&self, seed: &[u8]) -> Result<DeviceCredentials> {
+    pub async fn register_node(&self, seed: &SecretBytes) -> Result<DeviceCredentials> {
         // Load developer credentials from files
         let developer_cert = fs::read(&self.config.gl_cert_path)
             .map_err(|e| AppError::Greenlight(format!("Failed to read cert file: {}", e)))?;
@@ -51,7 +98,7 @@ impl GreenlightService {
             .map_err(|e| AppError::Greenlight(format!("Failed to create scheduler: {}", e)))?;
 
         // Create a signer from the seed
-        let signer = Signer::new(seed.to_vec(), Network::Bitcoin, developer_creds)
+        let signer = Signer::new(seed.expose().clone(), Network::Bitcoin, developer_creds)
             .map_err(|e| AppError::Greenlight(format!("Failed to create signer: {}", e)))?;
 
         // Register the node
@@ -66,7 +113,7 @@ impl GreenlightService {
         })
     }
 
-    pub async fn recover_node(&self, seed: &[u8]) -> Result<DeviceCredentials> {
+    pub async fn recover_node(&self, seed: &SecretBytes) -> Result<DeviceCredentials> {
         // Load developer credentials from files
         let developer_cert = fs::read(&self.config.gl_cert_path)
             .map_err(|e| AppError::Greenlight(format!("Failed to read cert file: {}", e)))?;
@@ -87,14 +134,23 @@ impl GreenlightService {
             .map_err(|e| AppError::Greenlight(format!("Failed to create scheduler: {}", e)))?;
 
         // Create a signer from the seed
-        let signer = Signer::new(seed.to_vec(), Network::Bitcoin, developer_creds)
+        let signer = Signer::new(seed.expose().clone(), Network::Bitcoin, developer_creds)
             .map_err(|e| AppError::Greenlight(format!("Failed to create signer: {}", e)))?;
 
-        // Recover the node
+        // Recover the node; NotFound means Greenlight has none for this seed
         let recovery_response = scheduler
             .recover(&signer)
             .await
-            .map_err(|e| AppError::Greenlight(format!("Failed to recover node: {}", e)))?;
+            .map_err(|e| {
+                let not_found = e
+                    .chain()
+                    .any(|cause| cause.downcast_ref::<tonic::Status>().is_some_and(|status| status.code() == tonic::Code::NotFound));
+                if not_found {
+                    AppError::NotFound("Greenlight has no node for this seed".to_string())
+                } else {
+                    AppError::Greenlight(format!("Failed to recover node: {}", e))
+                }
+            })?;
 
         // Return device credentials as bytes
         Ok(DeviceCredentials {
@@ -102,6 +158,26 @@ impl GreenlightService {
         })
     }
 
+    /// Hex id of the node that `seed` signs for, without contacting Greenlight
+    pub fn node_id(&self, seed: &SecretBytes) -> Result<String> {
+        let developer_cert = fs::read(&self.config.gl_cert_path)
+            .map_err(|e| AppError::Greenlight(format!("Failed to read cert file: {}", e)))?;
+
+        let developer_key = fs::read(&self.config.gl_key_path)
+            .map_err(|e| AppError::Greenlight(format!("Failed to read key file: {}", e)))?;
+
+        let developer_creds = Nobody {
+            cert: developer_cert,
+            key: developer_key,
+            ..Nobody::default()
+        };
+
+        let signer = Signer::new(seed.expose().clone(), Network::Bitcoin, developer_creds)
+            .map_err(|e| AppError::Greenlight(format!("Failed to create signer: {}", e)))?;
+
+        Ok(hex::encode(signer.node_id()))
+    }
+
     #[allow(dead_code)]
     pub async fn connect_to_node(&self, device_creds: &[u8]) -> Result<()> {
         // Load device credentials from the stored bytes  
@@ -310,6 +386,265 @@ impl GreenlightService {
         }))
     }
 
+    pub async fn list_offers(&self, device_creds: &[u8]) -> Result<Vec<NodeOffer>> {
+        let mut node = self.node_client(device_creds).await?;
+
+        let offers_response = node
+            .list_offers(ListoffersRequest {
+                offer_id: None,
+                active_only: None,
+            })
+            .await
+            .map_err(|e| AppError::Greenlight(format!("Failed to list offers: {}", e)))?;
+
+        Ok(offers_response
+            .get_ref()
+            .offers
+            .iter()
+            .map(|offer| NodeOffer {
+                offer_id: hex::encode(&offer.offer_id),
+                bolt12: offer.bolt12.clone(),
+                active: offer.active,
+                used: offer.used,
+            })
+            .collect())
+    }
+
+    /// Create a BOLT11 invoice, for any amount if `amount_msat` is None. With
+    /// `description_hash_only` the invoice commits to sha256(description)
+    /// instead of carrying it (LNURL-pay, LUD-06).
+    pub async fn create_invoice(
+        &self,
+        device_creds: &[u8],
+        amount_msat: Option<u64>,
+        description: &str,
+        label: &str,
+        description_hash_only: bool,
+    ) -> Result<NodeInvoice> {
+        let mut node = self.node_client(device_creds).await?;
+
+        let invoice_response = node
+            .invoice(InvoiceRequest {
+                amount_msat: Some(AmountOrAny {
+                    value: Some(match amount_msat {
+                        Some(msat) => amount_or_any::Value::Amount(Amount { msat }),
+                        None => amount_or_any::Value::Any(true),
+                    }),
+                }),
+                description: description.to_string(),
+                label: label.to_string(),
+                deschashonly: Some(description_hash_only),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| AppError::Greenlight(format!("Failed to create invoice: {}", e)))?;
+
+        let invoice = invoice_response.get_ref();
+        Ok(NodeInvoice {
+            bolt11: invoice.bolt11.clone(),
+            payment_hash: hex::encode(&invoice.payment_hash),
+            expires_at: invoice.expires_at,
+        })
+    }
+
+    /// Get a fresh bech32 (P2WPKH) address from the node's on-chain wallet
+    pub async fn new_address(&self, device_creds: &[u8]) -> Result<String> {
+        let mut node = self.node_client(device_creds).await?;
+
+        let address_response = node
+            .new_addr(NewaddrRequest {
+                addresstype: Some(NewaddrAddresstype::Bech32 as i32),
+            })
+            .await
+            .map_err(|e| AppError::Greenlight(format!("Failed to create address: {}", e)))?;
+
+        address_response
+            .get_ref()
+            .bech32
+            .clone()
+            .ok_or_else(|| AppError::Greenlight("Node returned no bech32 address".to_string()))
+    }
+
+    /// Decode a BOLT11 invoice or BOLT12 offer, rejecting anything else
+    pub async fn decode_payment_request(&self, device_creds: &[u8], request: &str) -> Result<DecodedPaymentRequest> {
+        let mut node = self.node_client(device_creds).await?;
+
+        let decode_response = node
+            .decode(DecodeRequest { string: request.to_string() })
+            .await
+            .map_err(|e| AppError::BadRequest(format!("Could not decode payment request: {}", e)))?;
+        let decoded = decode_response.get_ref();
+
+        if !decoded.valid {
+            return Err(AppError::BadRequest("Invalid payment request".to_string()));
+        }
+
+        let (kind, node_id, amount) = if decoded.item_type == DecodeType::Bolt11Invoice as i32 {
+            (PaymentRequestKind::Bolt11, decoded.payee.as_ref(), decoded.amount_msat.as_ref())
+        } else if decoded.item_type == DecodeType::Bolt12Offer as i32 {
+            (PaymentRequestKind::Bolt12Offer, decoded.offer_node_id.as_ref(), decoded.offer_amount_msat.as_ref())
+        } else {
+            return Err(AppError::BadRequest("Expected a BOLT11 invoice or a BOLT12 offer".to_string()));
+        };
+
+        let node_id = node_id
+            .ok_or_else(|| AppError::BadRequest("Payment request does not name its node".to_string()))?;
+
+        Ok(DecodedPaymentRequest {
+            kind,
+            node_id: hex::encode(node_id),
+            amount_msat: amount.map(|amount| amount.msat),
+        })
+    }
+
+    /// Request an invoice for `amount_msat` from a BOLT12 offer
+    pub async fn fetch_invoice(
+        &self,
+        device_creds: &[u8],
+        offer: &str,
+        amount_msat: Option<u64>,
+        payer_note: Option<&str>,
+    ) -> Result<String> {
+        let mut node = self.node_client(device_creds).await?;
+
+        let fetch_response = node
+            .fetch_invoice(FetchinvoiceRequest {
+                offer: offer.to_string(),
+                amount_msat: amount_msat.map(|msat| Amount { msat }),
+                payer_note: payer_note.map(str::to_string),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| AppError::Greenlight(format!("Failed to fetch invoice from offer: {}", e)))?;
+
+        Ok(fetch_response.get_ref().invoice.clone())
+    }
+
+    /// Pay a BOLT11 or BOLT12 invoice. `amount_msat` is only for invoices
+    /// without an amount; the node refuses routes costing more than `max_fee_msat`.
+    pub async fn pay(
+        &self,
+        device_creds: &[u8],
+        invoice: &str,
+        amount_msat: Option<u64>,
+        max_fee_msat: Option<u64>,
+    ) -> Result<NodePayment> {
+        let mut node = self.node_client(device_creds).await?;
+
+        let pay_response = node
+            .pay(PayRequest {
+                bolt11: invoice.to_string(),
+                amount_msat: amount_msat.map(|msat| Amount { msat }),
+                maxfee: max_fee_msat.map(|msat| Amount { msat }),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| AppError::Greenlight(format!("Payment failed: {}", e)))?;
+
+        let payment = pay_response.get_ref();
+        Ok(NodePayment {
+            payment_hash: hex::encode(&payment.payment_hash),
+            amount_msat: payment.amount_msat.as_ref().map(|amount| amount.msat).unwrap_or_default(),
+            amount_sent_msat: payment.amount_sent_msat.as_ref().map(|amount| amount.msat).unwrap_or_default(),
+        })
+    }
+
+    /// Send `amount_sat` on-chain to `address`, returning the txid
+    pub async fn withdraw(
+        &self,
+        device_creds: &[u8],
+        address: &str,
+        amount_sat: u64,
+        feerate_sat_vb: Option<u64>,
+    ) -> Result<String> {
+        let amount_msat = amount_sat
+            .checked_mul(1000)
+            .ok_or_else(|| AppError::Validation("amount_sat is too large".to_string()))?;
+        // The node takes sat per 1000 vbytes as a u32
+        let feerate_perkb = feerate_sat_vb
+            .map(|sat_vb| {
+                sat_vb
+                    .checked_mul(1000)
+                    .and_then(|perkb| u32::try_from(perkb).ok())
+                    .ok_or_else(|| AppError::Validation("feerate_sat_vb is too large".to_string()))
+            })
+            .transpose()?;
+
+        let mut node = self.node_client(device_creds).await?;
+
+        let withdraw_response = node
+            .withdraw(WithdrawRequest {
+                destination: address.to_string(),
+                satoshi: Some(AmountOrAll {
+                    value: Some(amount_or_all::Value::Amount(Amount { msat: amount_msat })),
+                }),
+                feerate: feerate_perkb.map(|perkb| Feerate {
+                    style: Some(feerate::Style::Perkb(perkb)),
+                }),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| AppError::Greenlight(format!("Withdrawal failed: {}", e)))?;
+
+        Ok(hex::encode(&withdraw_response.get_ref().txid))
+    }
+
+    /// Disable the offer whose bolt12 string is `bolt12`, so it stops
+    /// producing invoices. Returns false if the node has no such active offer.
+    pub async fn disable_offer(&self, device_creds: &[u8], bolt12: &str) -> Result<bool> {
+        let mut node = self.node_client(device_creds).await?;
+
+        let offers_response = node
+            .list_offers(ListoffersRequest {
+                offer_id: None,
+                active_only: Some(true),
+            })
+            .await
+            .map_err(|e| AppError::Greenlight(format!("Failed to list offers: {}", e)))?;
+
+        let offer_id = match offers_response.get_ref().offers.iter().find(|offer| offer.bolt12 == bolt12) {
+            Some(offer) => offer.offer_id.clone(),
+            None => return Ok(false),
+        };
+
+        node.disable_offer(DisableofferRequest { offer_id })
+            .await
+            .map_err(|e| AppError::Greenlight(format!("Failed to disable offer: {}", e)))?;
+
+        Ok(true)
+    }
+
+    /// Connect to the user's node with their device credentials
+    async fn node_client(&self, device_creds: &[u8]) -> Result<ClnClient> {
+        let device = Device::from_bytes(device_creds);
+
+        let developer_cert = fs::read(&self.config.gl_cert_path)
+            .map_err(|e| AppError::Greenlight(format!("Failed to read cert file: {}", e)))?;
+
+        let developer_key = fs::read(&self.config.gl_key_path)
+            .map_err(|e| AppError::Greenlight(format!("Failed to read key file: {}", e)))?;
+
+        let developer_creds = Nobody {
+            cert: developer_cert,
+            key: developer_key,
+            ..Nobody::default()
+        };
+
+        let scheduler = Scheduler::new(Network::Bitcoin, developer_creds)
+            .await
+            .map_err(|e| AppError::Greenlight(format!("Failed to create scheduler: {}", e)))?;
+
+        let scheduler = scheduler
+            .authenticate(device)
+            .await
+            .map_err(|e| AppError::Greenlight(format!("Failed to authenticate: {}", e)))?;
+
+        scheduler
+            .node()
+            .await
+            .map_err(|e| AppError::Greenlight(format!("Failed to get node: {}", e)))
+    }
+
     #[allow(dead_code)]
     pub fn load_credentials(&self) -> Result<Device> {
         let cert_path = &self.config.gl_cert_path;
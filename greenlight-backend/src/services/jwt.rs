@@ -1,60 +1,273 @@
-use jsonwebtoken::{encode, decode, Header, Algorithm, Validation, EncodingKey, DecodingKey};
+use base64::{engine::general_purpose, Engine as _};
+use jsonwebtoken::{decode, decode_header, Header, Algorithm, Validation, DecodingKey};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use chrono::{Utc, Duration};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
 use uuid::Uuid;
+use crate::config::Config;
 use crate::error::{AppError, Result};
+use crate::services::key_provider::{key_from_spec, KeyProvider, LocalKey};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String, // User ID
+    pub sid: String, // Session ID
+    pub iss: String, // Issuer
+    pub aud: String, // Audience
     pub exp: i64,    // Expiration time
     pub iat: i64,    // Issued at
 }
 
+/// Issues and verifies access tokens. Tokens are signed with one active key
+/// and verified against every configured key, picked by the `kid` header, so
+/// a new key can be rolled out before the old one stops being accepted. The
+/// signing key can live in an HSM or Vault (JWT_SIGNING_KEY); verification
+/// always happens locally.
 pub struct JwtService {
-    encoding_key: EncodingKey,
-    decoding_key: DecodingKey,
+    algorithm: Algorithm,
+    signing_kid: String,
+    signing_key: Arc<dyn KeyProvider>,
+    decoding_keys: HashMap<String, DecodingKey>,
+    /// Public keys as JWKs, empty for HMAC
+    jwks: Vec<Value>,
+    issuer: String,
+    audience: String,
+    expiry: Duration,
 }
 
 impl JwtService {
-    pub fn new(secret: &str) -> Self {
-        let encoding_key = EncodingKey::from_secret(secret.as_bytes());
-        let decoding_key = DecodingKey::from_secret(secret.as_bytes());
-        
-        Self {
-            encoding_key,
-            decoding_key,
+    pub async fn from_config(config: &Config) -> Result<Self> {
+        let algorithm = match config.jwt_algorithm.as_str() {
+            "HS256" => Algorithm::HS256,
+            "EdDSA" => Algorithm::EdDSA,
+            "ES256" => Algorithm::ES256,
+            "RS256" => Algorithm::RS256,
+            other => {
+                return Err(AppError::Internal(format!(
+                    "Unsupported JWT_ALGORITHM '{}', expected HS256, EdDSA, ES256 or RS256",
+                    other
+                )))
+            }
+        };
+
+        let signing_key: Arc<dyn KeyProvider> = match config.jwt_signing_key.as_deref() {
+            Some(spec) => key_from_spec(spec, config)?,
+            None if algorithm == Algorithm::HS256 => {
+                Arc::new(LocalKey::new("JWT_SECRET", config.jwt_secret.as_bytes().to_vec()))
+            }
+            None => {
+                let path = config.jwt_signing_key_path.as_deref()
+                    .ok_or_else(|| AppError::Internal(format!("JWT_SIGNING_KEY_PATH is required for {:?}", algorithm)))?;
+                Arc::new(LocalKey::new(path, read_key(path)?))
+            }
+        };
+
+        let mut decoding_keys = HashMap::new();
+        let mut jwks = Vec::new();
+
+        if algorithm == Algorithm::HS256 {
+            // An HMAC key verifies too, so it has to be at hand for every request
+            let secret = signing_key.secret().ok_or_else(|| {
+                AppError::Internal(format!(
+                    "HS256 needs a local key, {} can only sign; use EdDSA, ES256 or RS256",
+                    signing_key.describe()
+                ))
+            })?;
+            decoding_keys.insert(config.jwt_signing_kid.clone(), DecodingKey::from_secret(secret));
+        } else {
+            for (kid, path) in &config.jwt_public_keys {
+                let pem = read_key(path)?;
+                let invalid = |e: String| AppError::Internal(format!("Invalid JWT public key {}: {}", path, e));
+
+                let decoding_key = match algorithm {
+                    Algorithm::EdDSA => DecodingKey::from_ed_pem(&pem),
+                    Algorithm::ES256 => DecodingKey::from_ec_pem(&pem),
+                    _ => DecodingKey::from_rsa_pem(&pem),
+                }
+                .map_err(|e| invalid(e.to_string()))?;
+                let jwk = public_jwk(algorithm, kid, &pem).ok_or_else(|| invalid("unsupported key encoding".to_string()))?;
+
+                decoding_keys.insert(kid.clone(), decoding_key);
+                jwks.push(jwk);
+            }
+        }
+
+        if !decoding_keys.contains_key(&config.jwt_signing_kid) {
+            return Err(AppError::Internal(format!(
+                "JWT_PUBLIC_KEYS has no public key for the signing key '{}'",
+                config.jwt_signing_kid
+            )));
+        }
+
+        // Catch a signing key that doesn't match its public key, or a
+        // provider that can't sign, before the first login does
+        let probe = b"greenlight-backend.probe";
+        let signature = signing_key.sign(algorithm, probe).await?;
+        let verified = jsonwebtoken::crypto::verify(&signature, probe, &decoding_keys[&config.jwt_signing_kid], algorithm)
+            .unwrap_or(false);
+        if !verified {
+            return Err(AppError::Internal(format!(
+                "JWT signing key {} does not match the public key '{}'",
+                signing_key.describe(),
+                config.jwt_signing_kid
+            )));
         }
+        tracing::info!("Access tokens are signed with {}", signing_key.describe());
+
+        Ok(Self {
+            algorithm,
+            signing_kid: config.jwt_signing_kid.clone(),
+            signing_key,
+            decoding_keys,
+            jwks,
+            issuer: config.jwt_issuer.clone(),
+            audience: config.jwt_audience.clone(),
+            expiry: Duration::minutes(config.jwt_expiry_minutes),
+        })
+    }
+
+    /// Access token lifetime in seconds
+    pub fn expires_in(&self) -> i64 {
+        self.expiry.num_seconds()
+    }
+
+    /// JSON Web Key Set of the verification keys, for `/.well-known/jwks.json`
+    pub fn jwks(&self) -> Value {
+        json!({ "keys": self.jwks })
     }
 
-    /// Generate a JWT token for a user
-    pub fn generate_token(&self, user_id: Uuid) -> Result<String> {
+    /// Generate an access token for a user's session
+    pub async fn generate_token(&self, user_id: Uuid, session_id: Uuid) -> Result<String> {
         let now = Utc::now();
-        let expiration = now + Duration::hours(24); // Token expires in 24 hours
+        let expiration = now + self.expiry;
 
         let claims = Claims {
             sub: user_id.to_string(),
+            sid: session_id.to_string(),
+            iss: self.issuer.clone(),
+            aud: self.audience.clone(),
             exp: expiration.timestamp(),
             iat: now.timestamp(),
         };
 
-        encode(&Header::default(), &claims, &self.encoding_key)
-            .map_err(|e| AppError::Authentication(format!("Failed to generate token: {}", e)))
+        let mut header = Header::new(self.algorithm);
+        header.kid = Some(self.signing_kid.clone());
+
+        // The JWS compact serialization, signed by the key provider
+        let message = format!("{}.{}", token_segment(&header)?, token_segment(&claims)?);
+        let signature = self.signing_key.sign(self.algorithm, message.as_bytes()).await?;
+
+        Ok(format!("{}.{}", message, signature))
     }
 
     /// Validate and decode a JWT token
     pub fn validate_token(&self, token: &str) -> Result<Claims> {
-        let validation = Validation::new(Algorithm::HS256);
-        
-        decode::<Claims>(token, &self.decoding_key, &validation)
+        let header = decode_header(token)
+            .map_err(|e| AppError::Authentication(format!("Invalid token: {}", e)))?;
+
+        // Tokens from before key ids were issued are checked against the signing key
+        let kid = header.kid.unwrap_or_else(|| self.signing_kid.clone());
+        let decoding_key = self.decoding_keys.get(&kid)
+            .ok_or_else(|| AppError::Authentication("Invalid token: unknown key id".to_string()))?;
+
+        let mut validation = Validation::new(self.algorithm);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.set_required_spec_claims(&["exp", "sub", "iss", "aud"]);
+
+        decode::<Claims>(token, decoding_key, &validation)
             .map(|data| data.claims)
             .map_err(|e| AppError::Authentication(format!("Invalid token: {}", e)))
     }
 
-    /// Extract user ID from token
-    pub fn get_user_id_from_token(&self, token: &str) -> Result<Uuid> {
+    /// Extract user ID and session ID from token
+    pub fn get_session_from_token(&self, token: &str) -> Result<(Uuid, Uuid)> {
         let claims = self.validate_token(token)?;
-        Uuid::parse_str(&claims.sub)
-            .map_err(|e| AppError::Authentication(format!("Invalid user ID in token: {}", e)))
+        let user_id = Uuid::parse_str(&claims.sub)
+            .map_err(|e| AppError::Authentication(format!("Invalid user ID in token: {}", e)))?;
+        let session_id = Uuid::parse_str(&claims.sid)
+            .map_err(|e| AppError::Authentication(format!("Invalid session ID in token: {}", e)))?;
+
+        Ok((user_id, session_id))
+    }
+}
+
+fn token_segment<T: Serialize>(value: &T) -> Result<String> {
+    serde_json::to_vec(value)
+        .map(|json| general_purpose::URL_SAFE_NO_PAD.encode(json))
+        .map_err(|e| AppError::Authentication(format!("Failed to generate token: {}", e)))
+}
+
+fn read_key(path: &str) -> Result<Vec<u8>> {
+    fs::read(path).map_err(|e| AppError::Internal(format!("Failed to read JWT key {}: {}", path, e)))
+}
+
+/// Build the JWK for a PEM `PUBLIC KEY` (SubjectPublicKeyInfo)
+fn public_jwk(algorithm: Algorithm, kid: &str, pem: &[u8]) -> Option<Value> {
+    let b64 = |bytes: &[u8]| general_purpose::URL_SAFE_NO_PAD.encode(bytes);
+
+    let body: String = std::str::from_utf8(pem)
+        .ok()?
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    let der = general_purpose::STANDARD.decode(body.trim()).ok()?;
+
+    // SEQUENCE { AlgorithmIdentifier, BIT STRING }
+    let (spki, _) = der_element(&der, 0x30)?;
+    let (_, rest) = der_element(spki, 0x30)?;
+    let (bits, _) = der_element(rest, 0x03)?;
+    let key = bits.strip_prefix(&[0])?;
+
+    let mut jwk = match algorithm {
+        Algorithm::EdDSA if key.len() == 32 => json!({ "kty": "OKP", "crv": "Ed25519", "x": b64(key) }),
+        Algorithm::ES256 if key.len() == 65 && key[0] == 0x04 => {
+            json!({ "kty": "EC", "crv": "P-256", "x": b64(&key[1..33]), "y": b64(&key[33..]) })
+        }
+        Algorithm::RS256 => {
+            // RSAPublicKey ::= SEQUENCE { modulus INTEGER, publicExponent INTEGER }
+            let (rsa, _) = der_element(key, 0x30)?;
+            let (n, rest) = der_element(rsa, 0x02)?;
+            let (e, _) = der_element(rest, 0x02)?;
+            let unsigned = |int: &'_ [u8]| -> Vec<u8> {
+                int.iter().skip_while(|byte| **byte == 0).copied().collect()
+            };
+            json!({ "kty": "RSA", "n": b64(&unsigned(n)), "e": b64(&unsigned(e)) })
+        }
+        _ => return None,
+    };
+
+    jwk["kid"] = json!(kid);
+    jwk["alg"] = json!(format!("{:?}", algorithm));
+    jwk["use"] = json!("sig");
+    Some(jwk)
+}
+
+/// Read one DER element with the expected tag: (contents, remaining input)
+fn der_element(input: &[u8], expected_tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    if tag != expected_tag {
+        return None;
+    }
+
+    let (&first, mut input) = input.split_first()?;
+    let length = if first < 0x80 {
+        first as usize
+    } else {
+        let length_bytes = (first & 0x7f) as usize;
+        if length_bytes == 0 || length_bytes > 4 || input.len() < length_bytes {
+            return None;
+        }
+        let length = input[..length_bytes].iter().fold(0usize, |acc, byte| (acc << 8) | *byte as usize);
+        input = &input[length_bytes..];
+        length
+    };
+
+    if input.len() < length {
+        return None;
     }
+    Some((&input[..length], &input[length..]))
 }
@@ -0,0 +1,786 @@
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine as _};
+use jsonwebtoken::{Algorithm, EncodingKey};
+use libloading::Library;
+use rand::RngCore;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::os::raw::{c_ulong, c_void};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{env, fs};
+use crate::config::Config;
+use crate::error::{AppError, Result};
+use crate::services::{CryptoService, SecretBytes, SecretKey};
+
+/// A server-side master key, used to wrap the data keys of stored secrets
+/// and to sign access tokens. Keys are configured as `<provider>:<name>`:
+///
+/// - `env:VAR` - held in an environment variable
+/// - `file:/path` - held in a file
+/// - `pkcs11:label` - an object on a PKCS#11 token (PKCS11_MODULE, PKCS11_SLOT, PKCS11_PIN)
+/// - `vault:name` - a HashiCorp Vault transit key (VAULT_ADDR, VAULT_TOKEN, VAULT_TRANSIT_MOUNT)
+///
+/// Local keys are base64 of 32 bytes for wrapping, and the HMAC secret or a
+/// PEM private key for signing. PKCS#11 and Vault keys never leave their
+/// store: wrapping and signing happen there.
+#[async_trait]
+pub trait KeyProvider: Send + Sync {
+    /// Where the key lives, for logs and errors
+    fn describe(&self) -> String;
+
+    /// Encrypt a data key, authenticating `aad` along with it
+    async fn wrap_key(&self, data_key: &[u8], aad: &[u8]) -> Result<String>;
+
+    /// Decrypt the output of `wrap_key`, given the same `aad`
+    async fn unwrap_key(&self, wrapped_key: &str, aad: &[u8]) -> Result<Vec<u8>>;
+
+    /// Sign a JWS signing input, returning the base64url signature
+    async fn sign(&self, algorithm: Algorithm, message: &[u8]) -> Result<String>;
+
+    /// The key material, for local keys only. HS256 needs it to verify tokens.
+    fn secret(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+/// Load the key named by a `<provider>:<name>` spec
+pub fn key_from_spec(spec: &str, config: &Config) -> Result<Arc<dyn KeyProvider>> {
+    let (provider, name) = spec
+        .split_once(':')
+        .ok_or_else(|| AppError::Internal(format!("Key '{}' must be written as <provider>:<name>", spec)))?;
+
+    match provider {
+        "env" => {
+            let value = env::var(name)
+                .map_err(|_| AppError::Internal(format!("Environment variable {} for key '{}' is not set", name, spec)))?;
+            Ok(Arc::new(LocalKey::new(spec, value.into_bytes())))
+        }
+        "file" => {
+            let value = fs::read(name)
+                .map_err(|e| AppError::Internal(format!("Failed to read key file {}: {}", name, e)))?;
+            Ok(Arc::new(LocalKey::new(spec, value)))
+        }
+        "pkcs11" => Ok(Arc::new(Pkcs11Key::from_config(name, config)?)),
+        "vault" => Ok(Arc::new(VaultTransitKey::from_config(name, config)?)),
+        other => Err(AppError::Internal(format!(
+            "Unknown key provider '{}', expected env, file, pkcs11 or vault",
+            other
+        ))),
+    }
+}
+
+/// A key held in the server's memory
+pub struct LocalKey {
+    source: String,
+    material: SecretBytes,
+}
+
+impl LocalKey {
+    pub fn new(source: impl Into<String>, material: Vec<u8>) -> Self {
+        Self {
+            source: source.into(),
+            material: SecretBytes::new(material),
+        }
+    }
+
+    fn wrapping_key(&self) -> Result<SecretKey> {
+        std::str::from_utf8(self.material.expose())
+            .ok()
+            .and_then(|encoded| general_purpose::STANDARD.decode(encoded.trim()).ok())
+            .map(SecretBytes::new)
+            .and_then(|key| <[u8; 32]>::try_from(key.expose().as_slice()).ok())
+            .map(SecretKey::new)
+            .ok_or_else(|| AppError::Internal(format!("{} must be 32 bytes of base64", self.source)))
+    }
+}
+
+#[async_trait]
+impl KeyProvider for LocalKey {
+    fn describe(&self) -> String {
+        self.source.clone()
+    }
+
+    async fn wrap_key(&self, data_key: &[u8], aad: &[u8]) -> Result<String> {
+        CryptoService::encrypt_with_key_aad(data_key, self.wrapping_key()?.expose(), aad)
+    }
+
+    async fn unwrap_key(&self, wrapped_key: &str, aad: &[u8]) -> Result<Vec<u8>> {
+        CryptoService::decrypt_with_key_aad(wrapped_key, self.wrapping_key()?.expose(), aad)
+    }
+
+    async fn sign(&self, algorithm: Algorithm, message: &[u8]) -> Result<String> {
+        let key = match algorithm {
+            Algorithm::HS256 => Ok(EncodingKey::from_secret(self.material.expose())),
+            Algorithm::EdDSA => EncodingKey::from_ed_pem(self.material.expose()),
+            Algorithm::ES256 => EncodingKey::from_ec_pem(self.material.expose()),
+            Algorithm::RS256 => EncodingKey::from_rsa_pem(self.material.expose()),
+            other => return Err(AppError::Internal(format!("Unsupported signing algorithm {:?}", other))),
+        }
+        .map_err(|e| AppError::Internal(format!("Invalid signing key {}: {}", self.source, e)))?;
+
+        jsonwebtoken::crypto::sign(message, &key, algorithm)
+            .map_err(|e| AppError::Cryptography(format!("Signing failed: {}", e)))
+    }
+
+    fn secret(&self) -> Option<&[u8]> {
+        Some(self.material.expose())
+    }
+}
+
+// PKCS#11 (Cryptoki) as far as this server uses it. Modules export every
+// C_* function by name, so they are resolved one by one.
+type CkUlong = c_ulong;
+type CkRv = CkUlong;
+type InitFn = unsafe extern "C" fn(CkUlong, *mut CkMechanism, CkUlong) -> CkRv;
+type CryptFn = unsafe extern "C" fn(CkUlong, *const u8, CkUlong, *mut u8, *mut CkUlong) -> CkRv;
+
+const CKR_OK: CkRv = 0x000;
+const CKR_USER_ALREADY_LOGGED_IN: CkRv = 0x100;
+const CKR_CRYPTOKI_ALREADY_INITIALIZED: CkRv = 0x191;
+const CKF_RW_SESSION: CkUlong = 0x2;
+const CKF_SERIAL_SESSION: CkUlong = 0x4;
+const CKU_USER: CkUlong = 1;
+const CKA_CLASS: CkUlong = 0x000;
+const CKA_LABEL: CkUlong = 0x003;
+const CKO_PRIVATE_KEY: CkUlong = 3;
+const CKO_SECRET_KEY: CkUlong = 4;
+const CKM_SHA256_RSA_PKCS: CkUlong = 0x040;
+const CKM_ECDSA: CkUlong = 0x1041;
+const CKM_EDDSA: CkUlong = 0x1057;
+const CKM_AES_GCM: CkUlong = 0x1087;
+
+const GCM_IV_SIZE: usize = 12;
+
+#[repr(C)]
+struct CkAttribute {
+    attribute_type: CkUlong,
+    value: *mut c_void,
+    value_len: CkUlong,
+}
+
+#[repr(C)]
+struct CkMechanism {
+    mechanism: CkUlong,
+    parameter: *mut c_void,
+    parameter_len: CkUlong,
+}
+
+#[repr(C)]
+struct CkGcmParams {
+    iv: *mut u8,
+    iv_len: CkUlong,
+    iv_bits: CkUlong,
+    aad: *mut u8,
+    aad_len: CkUlong,
+    tag_bits: CkUlong,
+}
+
+/// A logged-in session on a PKCS#11 token
+struct Pkcs11Session {
+    // Keeps the function pointers below valid
+    _library: Library,
+    session: CkUlong,
+    find_objects_init: unsafe extern "C" fn(CkUlong, *mut CkAttribute, CkUlong) -> CkRv,
+    find_objects: unsafe extern "C" fn(CkUlong, *mut CkUlong, CkUlong, *mut CkUlong) -> CkRv,
+    find_objects_final: unsafe extern "C" fn(CkUlong) -> CkRv,
+    encrypt_init: InitFn,
+    encrypt: CryptFn,
+    decrypt_init: InitFn,
+    decrypt: CryptFn,
+    sign_init: InitFn,
+    sign: CryptFn,
+}
+
+impl Pkcs11Session {
+    fn open(module: &str, slot: Option<u64>, pin: Option<&str>) -> Result<Self> {
+        let failed = |e: libloading::Error| AppError::Internal(format!("Failed to load PKCS#11 module {}: {}", module, e));
+
+        // SAFETY: loading a PKCS#11 module runs its initializers; the module
+        // is the operator's choice, like any other shared library
+        let library = unsafe { Library::new(module) }.map_err(failed)?;
+        // SAFETY: the signatures match the PKCS#11 v2.40 specification
+        unsafe {
+            let initialize = *library
+                .get::<unsafe extern "C" fn(*mut c_void) -> CkRv>(b"C_Initialize\0")
+                .map_err(failed)?;
+            let get_slot_list = *library
+                .get::<unsafe extern "C" fn(u8, *mut CkUlong, *mut CkUlong) -> CkRv>(b"C_GetSlotList\0")
+                .map_err(failed)?;
+            let open_session = *library
+                .get::<unsafe extern "C" fn(CkUlong, CkUlong, *mut c_void, *mut c_void, *mut CkUlong) -> CkRv>(
+                    b"C_OpenSession\0",
+                )
+                .map_err(failed)?;
+            let login = *library
+                .get::<unsafe extern "C" fn(CkUlong, CkUlong, *const u8, CkUlong) -> CkRv>(b"C_Login\0")
+                .map_err(failed)?;
+
+            // Calls are serialized by the caller, so the module needs no locking of its own
+            let rv = initialize(ptr::null_mut());
+            if rv != CKR_OK && rv != CKR_CRYPTOKI_ALREADY_INITIALIZED {
+                return Err(pkcs11_error("C_Initialize", rv));
+            }
+
+            let slot = match slot {
+                Some(slot) => slot as CkUlong,
+                None => {
+                    let mut count: CkUlong = 0;
+                    check(get_slot_list(1, ptr::null_mut(), &mut count), "C_GetSlotList")?;
+                    let mut slots = vec![0 as CkUlong; count as usize];
+                    check(get_slot_list(1, slots.as_mut_ptr(), &mut count), "C_GetSlotList")?;
+                    *slots.first().ok_or_else(|| AppError::Internal("No PKCS#11 token present".to_string()))?
+                }
+            };
+
+            let mut session: CkUlong = 0;
+            check(
+                open_session(slot, CKF_SERIAL_SESSION | CKF_RW_SESSION, ptr::null_mut(), ptr::null_mut(), &mut session),
+                "C_OpenSession",
+            )?;
+
+            if let Some(pin) = pin {
+                let rv = login(session, CKU_USER, pin.as_ptr(), pin.len() as CkUlong);
+                if rv != CKR_OK && rv != CKR_USER_ALREADY_LOGGED_IN {
+                    return Err(pkcs11_error("C_Login", rv));
+                }
+            }
+
+            Ok(Self {
+                session,
+                find_objects_init: *library.get(b"C_FindObjectsInit\0").map_err(failed)?,
+                find_objects: *library.get(b"C_FindObjects\0").map_err(failed)?,
+                find_objects_final: *library.get(b"C_FindObjectsFinal\0").map_err(failed)?,
+                encrypt_init: *library.get(b"C_EncryptInit\0").map_err(failed)?,
+                encrypt: *library.get(b"C_Encrypt\0").map_err(failed)?,
+                decrypt_init: *library.get(b"C_DecryptInit\0").map_err(failed)?,
+                decrypt: *library.get(b"C_Decrypt\0").map_err(failed)?,
+                sign_init: *library.get(b"C_SignInit\0").map_err(failed)?,
+                sign: *library.get(b"C_Sign\0").map_err(failed)?,
+                _library: library,
+            })
+        }
+    }
+
+    /// The first object of `class` labelled `label`
+    fn find_key(&self, class: CkUlong, label: &str) -> Result<Option<CkUlong>> {
+        let mut class = class;
+        let mut template = [
+            CkAttribute {
+                attribute_type: CKA_CLASS,
+                value: &mut class as *mut CkUlong as *mut c_void,
+                value_len: std::mem::size_of::<CkUlong>() as CkUlong,
+            },
+            CkAttribute {
+                attribute_type: CKA_LABEL,
+                value: label.as_ptr() as *mut c_void,
+                value_len: label.len() as CkUlong,
+            },
+        ];
+
+        let mut object: CkUlong = 0;
+        let mut count: CkUlong = 0;
+        // SAFETY: the template points at locals that outlive the search
+        unsafe {
+            check(
+                (self.find_objects_init)(self.session, template.as_mut_ptr(), template.len() as CkUlong),
+                "C_FindObjectsInit",
+            )?;
+            let found = check((self.find_objects)(self.session, &mut object, 1, &mut count), "C_FindObjects");
+            check((self.find_objects_final)(self.session), "C_FindObjectsFinal")?;
+            found?;
+        }
+
+        Ok((count > 0).then_some(object))
+    }
+
+    /// Run a single-part operation, asking the module for the output size first
+    fn run(&self, init: InitFn, operation: CryptFn, mechanism: &mut CkMechanism, key: CkUlong, input: &[u8]) -> Result<Vec<u8>> {
+        let mut output_len: CkUlong = 0;
+        // SAFETY: `mechanism` and its parameters outlive the operation, and
+        // the output buffer has the size the module asked for
+        unsafe {
+            check(init(self.session, mechanism, key), "operation init")?;
+            check(
+                operation(self.session, input.as_ptr(), input.len() as CkUlong, ptr::null_mut(), &mut output_len),
+                "operation",
+            )?;
+            let mut output = vec![0u8; output_len as usize];
+            check(
+                operation(self.session, input.as_ptr(), input.len() as CkUlong, output.as_mut_ptr(), &mut output_len),
+                "operation",
+            )?;
+            output.truncate(output_len as usize);
+            Ok(output)
+        }
+    }
+
+    fn aes_gcm(&self, encrypt: bool, key: CkUlong, iv: &[u8], aad: &[u8], input: &[u8]) -> Result<Vec<u8>> {
+        let mut iv = iv.to_vec();
+        let mut aad = aad.to_vec();
+        let mut params = CkGcmParams {
+            iv: iv.as_mut_ptr(),
+            iv_len: iv.len() as CkUlong,
+            iv_bits: (iv.len() * 8) as CkUlong,
+            aad: aad.as_mut_ptr(),
+            aad_len: aad.len() as CkUlong,
+            tag_bits: 128,
+        };
+        let mut mechanism = CkMechanism {
+            mechanism: CKM_AES_GCM,
+            parameter: &mut params as *mut CkGcmParams as *mut c_void,
+            parameter_len: std::mem::size_of::<CkGcmParams>() as CkUlong,
+        };
+
+        if encrypt {
+            self.run(self.encrypt_init, self.encrypt, &mut mechanism, key, input)
+        } else {
+            self.run(self.decrypt_init, self.decrypt, &mut mechanism, key, input)
+        }
+    }
+}
+
+fn check(rv: CkRv, function: &str) -> Result<()> {
+    if rv == CKR_OK {
+        Ok(())
+    } else {
+        Err(pkcs11_error(function, rv))
+    }
+}
+
+fn pkcs11_error(function: &str, rv: CkRv) -> AppError {
+    AppError::Cryptography(format!("PKCS#11 {} failed with 0x{:x}", function, rv))
+}
+
+/// The module is initialized and logged in once per process; every
+/// `Pkcs11Key` shares that session, since a second C_Initialize or C_Login
+/// on the same module fails
+static PKCS11_SESSION: Mutex<Option<Arc<Mutex<Pkcs11Session>>>> = Mutex::new(None);
+
+fn shared_pkcs11_session(config: &Config) -> Result<Arc<Mutex<Pkcs11Session>>> {
+    let mut shared = PKCS11_SESSION
+        .lock()
+        .map_err(|_| AppError::Internal("PKCS#11 session poisoned".to_string()))?;
+    if let Some(session) = shared.as_ref() {
+        return Ok(session.clone());
+    }
+
+    let module = config.pkcs11_module.as_deref()
+        .ok_or_else(|| AppError::Internal("PKCS11_MODULE is required for pkcs11 keys".to_string()))?;
+    let session = Arc::new(Mutex::new(Pkcs11Session::open(module, config.pkcs11_slot, config.pkcs11_pin.as_deref())?));
+    *shared = Some(session.clone());
+    Ok(session)
+}
+
+/// A key on a PKCS#11 token: an AES secret key for wrapping, or a private
+/// key for signing, found by label
+pub struct Pkcs11Key {
+    session: Arc<Mutex<Pkcs11Session>>,
+    label: String,
+    secret_key: Option<CkUlong>,
+    private_key: Option<CkUlong>,
+}
+
+impl Pkcs11Key {
+    pub fn from_config(label: &str, config: &Config) -> Result<Self> {
+        let session = shared_pkcs11_session(config)?;
+
+        let (secret_key, private_key) = {
+            let session = session.lock().map_err(|_| AppError::Internal("PKCS#11 session poisoned".to_string()))?;
+            (session.find_key(CKO_SECRET_KEY, label)?, session.find_key(CKO_PRIVATE_KEY, label)?)
+        };
+        if secret_key.is_none() && private_key.is_none() {
+            return Err(AppError::Internal(format!("No PKCS#11 key labelled '{}' on the token", label)));
+        }
+
+        Ok(Self {
+            session,
+            label: label.to_string(),
+            secret_key,
+            private_key,
+        })
+    }
+
+    /// Module calls block, so they run off the async workers
+    async fn with_session<T, F>(&self, operation: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Pkcs11Session) -> Result<T> + Send + 'static,
+    {
+        let session = self.session.clone();
+        tokio::task::spawn_blocking(move || {
+            let session = session.lock().map_err(|_| AppError::Internal("PKCS#11 session poisoned".to_string()))?;
+            operation(&session)
+        })
+        .await
+        .map_err(|e| AppError::Internal(format!("PKCS#11 task failed: {}", e)))?
+    }
+
+    fn secret_key(&self) -> Result<CkUlong> {
+        self.secret_key
+            .ok_or_else(|| AppError::Internal(format!("PKCS#11 key '{}' is not a secret key", self.label)))
+    }
+}
+
+#[async_trait]
+impl KeyProvider for Pkcs11Key {
+    fn describe(&self) -> String {
+        format!("pkcs11:{}", self.label)
+    }
+
+    async fn wrap_key(&self, data_key: &[u8], aad: &[u8]) -> Result<String> {
+        let key = self.secret_key()?;
+        let mut iv = [0u8; GCM_IV_SIZE];
+        rand::thread_rng().fill_bytes(&mut iv);
+        let (data_key, aad) = (data_key.to_vec(), aad.to_vec());
+
+        let ciphertext = self.with_session(move |session| session.aes_gcm(true, key, &iv, &aad, &data_key)).await?;
+
+        let mut wrapped = iv.to_vec();
+        wrapped.extend_from_slice(&ciphertext);
+        Ok(general_purpose::STANDARD.encode(wrapped))
+    }
+
+    async fn unwrap_key(&self, wrapped_key: &str, aad: &[u8]) -> Result<Vec<u8>> {
+        let key = self.secret_key()?;
+        let wrapped = general_purpose::STANDARD
+            .decode(wrapped_key)
+            .map_err(|e| AppError::Cryptography(format!("Invalid base64: {}", e)))?;
+        if wrapped.len() < GCM_IV_SIZE {
+            return Err(AppError::Cryptography("Invalid wrapped key length".to_string()));
+        }
+        let aad = aad.to_vec();
+
+        self.with_session(move |session| {
+            let (iv, ciphertext) = wrapped.split_at(GCM_IV_SIZE);
+            session.aes_gcm(false, key, iv, &aad, ciphertext)
+        })
+        .await
+    }
+
+    async fn sign(&self, algorithm: Algorithm, message: &[u8]) -> Result<String> {
+        let key = self.private_key
+            .ok_or_else(|| AppError::Internal(format!("PKCS#11 key '{}' is not a private key", self.label)))?;
+
+        // ECDSA signs a digest; the JWS form is r || s, which is what the token returns
+        let (mechanism, input) = match algorithm {
+            Algorithm::EdDSA => (CKM_EDDSA, message.to_vec()),
+            Algorithm::ES256 => (CKM_ECDSA, Sha256::digest(message).to_vec()),
+            Algorithm::RS256 => (CKM_SHA256_RSA_PKCS, message.to_vec()),
+            other => return Err(AppError::Internal(format!("{:?} can't be signed with a PKCS#11 key", other))),
+        };
+
+        let signature = self
+            .with_session(move |session| {
+                let mut mechanism = CkMechanism {
+                    mechanism,
+                    parameter: ptr::null_mut(),
+                    parameter_len: 0,
+                };
+                session.run(session.sign_init, session.sign, &mut mechanism, key, &input)
+            })
+            .await?;
+
+        Ok(general_purpose::URL_SAFE_NO_PAD.encode(signature))
+    }
+}
+
+/// A key in a HashiCorp Vault transit secrets engine, or anything speaking
+/// its HTTP API
+pub struct VaultTransitKey {
+    http: reqwest::Client,
+    addr: String,
+    token: String,
+    namespace: Option<String>,
+    mount: String,
+    name: String,
+}
+
+impl VaultTransitKey {
+    pub fn from_config(name: &str, config: &Config) -> Result<Self> {
+        let token = config.vault_token.clone()
+            .ok_or_else(|| AppError::Internal("VAULT_TOKEN is required for vault keys".to_string()))?;
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .map_err(|e| AppError::Internal(format!("Failed to create Vault client: {}", e)))?;
+
+        Ok(Self {
+            http,
+            addr: config.vault_addr.trim_end_matches('/').to_string(),
+            token,
+            namespace: config.vault_namespace.clone(),
+            mount: config.vault_transit_mount.trim_matches('/').to_string(),
+            name: name.to_string(),
+        })
+    }
+
+    /// POST to `/v1/<mount>/<path>` and return the response's `data`
+    async fn transit(&self, path: &str, body: Value) -> Result<Value> {
+        let url = format!("{}/v1/{}/{}", self.addr, self.mount, path);
+        let mut request = self.http.post(&url).header("X-Vault-Token", &self.token).json(&body);
+        if let Some(namespace) = &self.namespace {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| AppError::Internal(format!("Vault request to {} failed: {}", url, e)))?;
+        let status = response.status();
+        let body: Value = response
+            .json()
+            .await
+            .map_err(|e| AppError::Internal(format!("Invalid Vault response from {}: {}", url, e)))?;
+
+        if !status.is_success() {
+            let errors = body.get("errors").map(Value::to_string).unwrap_or_default();
+            return Err(AppError::Cryptography(format!("Vault {} returned {}: {}", path, status, errors)));
+        }
+
+        body.get("data")
+            .cloned()
+            .ok_or_else(|| AppError::Internal(format!("Vault response from {} has no data", url)))
+    }
+
+    fn response_field(data: &Value, field: &str) -> Result<String> {
+        data.get(field)
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| AppError::Internal(format!("Vault response has no {}", field)))
+    }
+}
+
+#[async_trait]
+impl KeyProvider for VaultTransitKey {
+    fn describe(&self) -> String {
+        format!("vault:{}", self.name)
+    }
+
+    async fn wrap_key(&self, data_key: &[u8], aad: &[u8]) -> Result<String> {
+        let data = self
+            .transit(
+                &format!("encrypt/{}", self.name),
+                json!({
+                    "plaintext": general_purpose::STANDARD.encode(data_key),
+                    "associated_data": general_purpose::STANDARD.encode(aad),
+                }),
+            )
+            .await?;
+
+        // `vault:v<n>:<base64>`
+        Self::response_field(&data, "ciphertext")
+    }
+
+    async fn unwrap_key(&self, wrapped_key: &str, aad: &[u8]) -> Result<Vec<u8>> {
+        let data = self
+            .transit(
+                &format!("decrypt/{}", self.name),
+                json!({
+                    "ciphertext": wrapped_key,
+                    "associated_data": general_purpose::STANDARD.encode(aad),
+                }),
+            )
+            .await?;
+
+        general_purpose::STANDARD
+            .decode(Self::response_field(&data, "plaintext")?)
+            .map_err(|e| AppError::Cryptography(format!("Invalid base64 from Vault: {}", e)))
+    }
+
+    async fn sign(&self, algorithm: Algorithm, message: &[u8]) -> Result<String> {
+        let mut body = json!({ "input": general_purpose::STANDARD.encode(message) });
+        match algorithm {
+            Algorithm::EdDSA => {}
+            // JWS marshaling gives r || s in base64url instead of DER
+            Algorithm::ES256 => body["marshaling_algorithm"] = json!("jws"),
+            Algorithm::RS256 => body["signature_algorithm"] = json!("pkcs1v15"),
+            other => return Err(AppError::Internal(format!("{:?} can't be signed with a Vault key", other))),
+        }
+
+        let data = self.transit(&format!("sign/{}/sha2-256", self.name), body).await?;
+        let signature = Self::response_field(&data, "signature")?;
+        let encoded = signature.rsplit(':').next().unwrap_or_default();
+
+        if algorithm == Algorithm::ES256 {
+            return Ok(encoded.to_string());
+        }
+        let signature = general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(|e| AppError::Cryptography(format!("Invalid signature from Vault: {}", e)))?;
+        Ok(general_purpose::URL_SAFE_NO_PAD.encode(signature))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::Path, http::{HeaderMap, StatusCode}, routing::post, Json, Router};
+
+    const KEY: [u8; 32] = [5u8; 32];
+    const TOKEN: &str = "test-token";
+
+    fn local_key(key: &[u8; 32]) -> LocalKey {
+        LocalKey::new("env:TEST_KEY", general_purpose::STANDARD.encode(key).into_bytes())
+    }
+
+    fn config() -> Config {
+        Config {
+            pkcs11_module: None,
+            vault_token: Some(TOKEN.to_string()),
+            ..Config::from_env().unwrap()
+        }
+    }
+
+    fn error_message(result: Result<Arc<dyn KeyProvider>>) -> String {
+        match result {
+            Ok(key) => panic!("expected an error, got {}", key.describe()),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn local_key_wraps_and_unwraps() {
+        let key = local_key(&KEY);
+        let wrapped = key.wrap_key(b"data key", b"device-creds:user:1").await.unwrap();
+
+        assert_eq!(key.unwrap_key(&wrapped, b"device-creds:user:1").await.unwrap(), b"data key");
+        assert_ne!(wrapped, key.wrap_key(b"data key", b"device-creds:user:1").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn local_key_rejects_wrong_aad_and_key() {
+        let key = local_key(&KEY);
+        let wrapped = key.wrap_key(b"data key", b"device-creds:user:1").await.unwrap();
+
+        assert!(key.unwrap_key(&wrapped, b"device-creds:user:2").await.is_err());
+        assert!(key.unwrap_key(&wrapped, b"").await.is_err());
+        assert!(local_key(&[6u8; 32]).unwrap_key(&wrapped, b"device-creds:user:1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn local_key_needs_32_bytes_of_base64() {
+        let short = LocalKey::new("env:SHORT", general_purpose::STANDARD.encode([1u8; 16]).into_bytes());
+        assert!(short.wrap_key(b"data key", b"").await.is_err());
+
+        let not_base64 = LocalKey::new("env:TEXT", b"not base64!".to_vec());
+        assert!(not_base64.wrap_key(b"data key", b"").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn parses_env_and_file_specs() {
+        env::set_var("KEY_PROVIDER_TEST_KEY", general_purpose::STANDARD.encode(KEY));
+        let from_env = key_from_spec("env:KEY_PROVIDER_TEST_KEY", &config()).unwrap();
+        assert_eq!(from_env.describe(), "env:KEY_PROVIDER_TEST_KEY");
+
+        let path = env::temp_dir().join(format!("key-provider-test-{}", std::process::id()));
+        fs::write(&path, format!("{}\n", general_purpose::STANDARD.encode(KEY))).unwrap();
+        let spec = format!("file:{}", path.display());
+        let from_file = key_from_spec(&spec, &config()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(from_file.describe(), spec);
+
+        // Both hold the same key, so each opens what the other wraps
+        let wrapped = from_env.wrap_key(b"data key", b"aad").await.unwrap();
+        assert_eq!(from_file.unwrap_key(&wrapped, b"aad").await.unwrap(), b"data key");
+    }
+
+    #[test]
+    fn rejects_malformed_and_unavailable_specs() {
+        assert!(error_message(key_from_spec("KEY_WITHOUT_PROVIDER", &config())).contains("<provider>:<name>"));
+        assert!(error_message(key_from_spec("kms:alias/key", &config())).contains("Unknown key provider 'kms'"));
+        assert!(error_message(key_from_spec("env:KEY_PROVIDER_TEST_UNSET", &config())).contains("is not set"));
+        assert!(error_message(key_from_spec("file:/nonexistent/key", &config())).contains("Failed to read key file"));
+        assert!(error_message(key_from_spec("pkcs11:credentials", &config())).contains("PKCS11_MODULE is required"));
+
+        let no_token = Config { vault_token: None, ..config() };
+        assert!(error_message(key_from_spec("vault:credentials", &no_token)).contains("VAULT_TOKEN is required"));
+        assert_eq!(key_from_spec("vault:credentials", &config()).unwrap().describe(), "vault:credentials");
+    }
+
+    /// Enough of Vault's transit engine for the provider: ciphertexts carry
+    /// the plaintext and associated data, and decrypt checks the latter
+    async fn transit_stand_in() -> String {
+        async fn encrypt(Path(name): Path<String>, headers: HeaderMap, Json(body): Json<Value>) -> (StatusCode, Json<Value>) {
+            if headers.get("X-Vault-Token").and_then(|token| token.to_str().ok()) != Some(TOKEN) {
+                return (StatusCode::FORBIDDEN, Json(json!({ "errors": ["permission denied"] })));
+            }
+            let sealed = general_purpose::STANDARD.encode(json!({ "key": name, "body": body }).to_string());
+            (StatusCode::OK, Json(json!({ "data": { "ciphertext": format!("vault:v1:{}", sealed) } })))
+        }
+
+        async fn decrypt(Path(name): Path<String>, headers: HeaderMap, Json(body): Json<Value>) -> (StatusCode, Json<Value>) {
+            if headers.get("X-Vault-Token").and_then(|token| token.to_str().ok()) != Some(TOKEN) {
+                return (StatusCode::FORBIDDEN, Json(json!({ "errors": ["permission denied"] })));
+            }
+            let sealed: Value = body["ciphertext"]
+                .as_str()
+                .and_then(|ciphertext| ciphertext.strip_prefix("vault:v1:"))
+                .and_then(|sealed| general_purpose::STANDARD.decode(sealed).ok())
+                .and_then(|sealed| serde_json::from_slice(&sealed).ok())
+                .unwrap_or_default();
+            if sealed["key"] != json!(name) || sealed["body"]["associated_data"] != body["associated_data"] {
+                return (StatusCode::BAD_REQUEST, Json(json!({ "errors": ["cipher: message authentication failed"] })));
+            }
+            (StatusCode::OK, Json(json!({ "data": { "plaintext": sealed["body"]["plaintext"] } })))
+        }
+
+        async fn sign(Path(name): Path<String>, Json(body): Json<Value>) -> Json<Value> {
+            let signature = match body["marshaling_algorithm"].as_str() {
+                Some("jws") => format!("vault:v1:{}-_", name),
+                _ => format!("vault:v1:{}", general_purpose::STANDARD.encode([0xfbu8, 0xff, 0xfe])),
+            };
+            Json(json!({ "data": { "signature": signature } }))
+        }
+
+        let app = Router::new()
+            .route("/v1/transit/encrypt/:name", post(encrypt))
+            .route("/v1/transit/decrypt/:name", post(decrypt))
+            .route("/v1/transit/sign/:name/sha2-256", post(sign));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}/", addr)
+    }
+
+    async fn vault_key(name: &str, token: &str) -> VaultTransitKey {
+        let config = Config {
+            vault_addr: transit_stand_in().await,
+            vault_token: Some(token.to_string()),
+            vault_transit_mount: "/transit/".to_string(),
+            ..config()
+        };
+        VaultTransitKey::from_config(name, &config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn vault_key_wraps_and_unwraps_through_transit() {
+        let key = vault_key("credentials", TOKEN).await;
+        let wrapped = key.wrap_key(b"data key", b"device-creds:user:1").await.unwrap();
+
+        assert!(wrapped.starts_with("vault:v1:"));
+        assert_eq!(key.unwrap_key(&wrapped, b"device-creds:user:1").await.unwrap(), b"data key");
+        assert!(matches!(
+            key.unwrap_key(&wrapped, b"device-creds:user:2").await,
+            Err(AppError::Cryptography(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn vault_key_reports_rejected_tokens() {
+        let key = vault_key("credentials", "wrong-token").await;
+        match key.wrap_key(b"data key", b"").await {
+            Err(AppError::Cryptography(message)) => assert!(message.contains("403"), "{}", message),
+            other => panic!("expected a Vault error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn vault_key_returns_base64url_signatures() {
+        let key = vault_key("jwt", TOKEN).await;
+        assert_eq!(key.sign(Algorithm::RS256, b"header.claims").await.unwrap(), "-__-");
+        assert_eq!(key.sign(Algorithm::ES256, b"header.claims").await.unwrap(), "jwt-_");
+        assert!(key.sign(Algorithm::HS256, b"header.claims").await.is_err());
+    }
+}
@@ -0,0 +1,577 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+use crate::config::Config;
+use crate::error::{AppError, Result};
+
+/// Past this many in-memory keys, expired ones are pruned and then the least
+/// recently failed are evicted, so a flood of distinct keys can't grow the map
+const MAX_MEMORY_ENTRIES: usize = 10_000;
+
+/// Failure counters for one throttling key
+#[derive(Debug, Clone, Copy)]
+pub struct ThrottleState {
+    pub failures: u32,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+/// Outcome of asking to make an attempt
+#[derive(Debug, Clone, Copy)]
+pub enum Reservation {
+    /// The attempt may go ahead and already counts as a failure
+    Reserved,
+    /// The key is locked until then
+    Locked(DateTime<Utc>),
+}
+
+/// Where failure counters live: in-process for a single instance, or in
+/// Postgres so that every replica sees the same counts.
+#[async_trait]
+pub trait ThrottleStore: Send + Sync {
+    /// Atomically refuse the attempt if `key` is locked, or count it as a
+    /// failure up front (starting over if the last one is older than
+    /// `window`) and apply the lock `policy` sets for the new count. Parallel
+    /// attempts therefore can't all slip in before the first one fails.
+    async fn reserve(&self, key: &str, window: Duration, policy: &ThrottlePolicy) -> Result<Reservation>;
+
+    /// Take back a reserved failure once the attempt succeeded, and unlock the
+    /// key if that brings it back under the free attempts
+    async fn release(&self, key: &str, policy: &ThrottlePolicy) -> Result<()>;
+
+    async fn clear(&self, key: &str) -> Result<()>;
+}
+
+/// Backoff and lockout thresholds for one kind of key
+#[derive(Debug, Clone, Copy)]
+pub struct ThrottlePolicy {
+    /// Failures allowed before any delay applies
+    free_attempts: u32,
+    /// Failures after which the key is locked for `lockout`
+    lockout_threshold: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    lockout: Duration,
+}
+
+impl ThrottlePolicy {
+    /// How long to block the key after its `failures`-th failure
+    fn delay(&self, failures: u32) -> Option<Duration> {
+        if failures >= self.lockout_threshold {
+            return Some(self.lockout);
+        }
+        if failures < self.free_attempts {
+            return None;
+        }
+
+        let exponent = (failures - self.free_attempts).min(20);
+        let delay = self.base_delay * 2i32.pow(exponent);
+        Some(delay.min(self.max_delay))
+    }
+}
+
+/// Slows down password guessing: every failed login against an account and
+/// from an IP address adds exponential backoff, and repeated failures lock
+/// the key for a while. Checked before the (expensive) password hash.
+pub struct LoginThrottle {
+    store: Arc<dyn ThrottleStore>,
+    account_policy: ThrottlePolicy,
+    ip_policy: ThrottlePolicy,
+    window: Duration,
+}
+
+impl LoginThrottle {
+    pub fn from_config(config: &Config, db_pool: PgPool) -> Result<Self> {
+        let store: Arc<dyn ThrottleStore> = match config.login_throttle_store.as_str() {
+            "memory" => Arc::new(MemoryThrottleStore::default()),
+            "postgres" => Arc::new(PostgresThrottleStore::new(db_pool)),
+            other => {
+                return Err(AppError::Internal(format!(
+                    "Unknown LOGIN_THROTTLE_STORE '{}', expected memory or postgres",
+                    other
+                )))
+            }
+        };
+
+        let account_policy = ThrottlePolicy {
+            free_attempts: config.login_free_attempts,
+            lockout_threshold: config.login_lockout_threshold,
+            base_delay: Duration::seconds(config.login_backoff_base_secs),
+            max_delay: Duration::seconds(config.login_backoff_max_secs),
+            lockout: Duration::seconds(config.login_lockout_secs),
+        };
+
+        // Many users can share an address behind NAT, so IPs get more room
+        let ip_policy = ThrottlePolicy {
+            free_attempts: account_policy.free_attempts * config.login_ip_multiplier,
+            lockout_threshold: account_policy.lockout_threshold * config.login_ip_multiplier,
+            ..account_policy
+        };
+
+        Ok(Self {
+            store,
+            account_policy,
+            ip_policy,
+            window: Duration::seconds(config.login_failure_window_secs),
+        })
+    }
+
+    /// Reserve an attempt for the account and IP, or reject it with
+    /// `AppError::RateLimited` if either is blocked. The attempt counts as a
+    /// failure until `record_success`, so callers don't record failures.
+    pub async fn reserve(&self, public_key: &str, ip: Option<IpAddr>) -> Result<()> {
+        self.reserve_keys(self.keys(public_key, ip)).await
+    }
+
+    /// Reserve a second-factor attempt for `user_id`, wherever the code is
+    /// entered, so a stolen session can't brute-force six digits
+    pub async fn reserve_second_factor(&self, user_id: Uuid) -> Result<()> {
+        self.reserve_keys(vec![(second_factor_key(user_id), &self.account_policy)]).await
+    }
+
+    /// Forget `user_id`'s second-factor failures after a valid code
+    pub async fn record_second_factor_success(&self, user_id: Uuid) -> Result<()> {
+        self.store.clear(&second_factor_key(user_id)).await
+    }
+
+    async fn reserve_keys(&self, keys: Vec<(String, &ThrottlePolicy)>) -> Result<()> {
+        let now = Utc::now();
+        let mut reserved = Vec::new();
+        let mut retry_after: Option<Duration> = None;
+
+        for (key, policy) in keys {
+            match self.store.reserve(&key, self.window, policy).await? {
+                Reservation::Reserved => reserved.push((key, policy)),
+                Reservation::Locked(locked_until) => retry_after = retry_after.max(Some(locked_until - now)),
+            }
+        }
+
+        let Some(wait) = retry_after else {
+            return Ok(());
+        };
+
+        // A refused attempt isn't a failure of the keys that weren't locked
+        for (key, policy) in reserved {
+            self.store.release(&key, policy).await?;
+        }
+        Err(AppError::RateLimited(wait.num_seconds().max(1) as u64))
+    }
+
+    /// Forget the account's failures after a successful attempt. The IP only
+    /// gets this attempt back, so one valid account can't be used to reset
+    /// guessing on others.
+    pub async fn record_success(&self, public_key: &str, ip: Option<IpAddr>) -> Result<()> {
+        self.store.clear(&account_key(public_key)).await?;
+        if let Some(ip) = ip {
+            self.store.release(&ip_key(ip), &self.ip_policy).await?;
+        }
+        Ok(())
+    }
+
+    fn keys(&self, public_key: &str, ip: Option<IpAddr>) -> Vec<(String, &ThrottlePolicy)> {
+        let mut keys = vec![(account_key(public_key), &self.account_policy)];
+        if let Some(ip) = ip {
+            keys.push((ip_key(ip), &self.ip_policy));
+        }
+        keys
+    }
+}
+
+fn account_key(public_key: &str) -> String {
+    format!("account:{}", public_key.to_lowercase())
+}
+
+fn ip_key(ip: IpAddr) -> String {
+    format!("ip:{}", ip)
+}
+
+fn second_factor_key(user_id: Uuid) -> String {
+    format!("totp:{}", user_id)
+}
+
+fn log_lockout(key: &str, failures: u32, policy: &ThrottlePolicy, delay: Duration) {
+    if failures >= policy.lockout_threshold {
+        tracing::warn!("Locking {} for {}s after {} failed attempts", key, delay.num_seconds(), failures);
+    }
+}
+
+/// Counters in process memory; each replica throttles independently
+#[derive(Default)]
+pub struct MemoryThrottleStore {
+    entries: Mutex<HashMap<String, (ThrottleState, DateTime<Utc>)>>,
+}
+
+impl MemoryThrottleStore {
+    /// Drop expired entries, then the least recently failed unlocked ones
+    /// (locked ones last, so a flood can't lift a lockout), down to 90% of the cap
+    fn evict(entries: &mut HashMap<String, (ThrottleState, DateTime<Utc>)>, now: DateTime<Utc>, window: Duration) {
+        let is_locked = |state: &ThrottleState| state.locked_until.is_some_and(|until| until > now);
+        entries.retain(|_, (state, last_failure_at)| *last_failure_at > now - window || is_locked(state));
+
+        let target = MAX_MEMORY_ENTRIES - MAX_MEMORY_ENTRIES / 10;
+        if entries.len() < MAX_MEMORY_ENTRIES {
+            return;
+        }
+
+        let mut candidates: Vec<(bool, DateTime<Utc>, String)> = entries
+            .iter()
+            .map(|(key, (state, last_failure_at))| (is_locked(state), *last_failure_at, key.clone()))
+            .collect();
+        candidates.sort();
+        for (_, _, key) in candidates.into_iter().take(entries.len() - target) {
+            entries.remove(&key);
+        }
+    }
+}
+
+#[async_trait]
+impl ThrottleStore for MemoryThrottleStore {
+    async fn reserve(&self, key: &str, window: Duration, policy: &ThrottlePolicy) -> Result<Reservation> {
+        let now = Utc::now();
+        let mut entries = self.entries.lock().await;
+
+        if let Some(locked_until) = entries.get(key).and_then(|(state, _)| state.locked_until) {
+            if locked_until > now {
+                return Ok(Reservation::Locked(locked_until));
+            }
+        }
+
+        if !entries.contains_key(key) && entries.len() >= MAX_MEMORY_ENTRIES {
+            Self::evict(&mut entries, now, window);
+        }
+
+        let (state, last_failure_at) = entries
+            .entry(key.to_string())
+            .or_insert((ThrottleState { failures: 0, locked_until: None }, now));
+        if *last_failure_at <= now - window {
+            state.failures = 0;
+        }
+        state.failures += 1;
+        *last_failure_at = now;
+        state.locked_until = policy.delay(state.failures).map(|delay| {
+            log_lockout(key, state.failures, policy, delay);
+            now + delay
+        });
+
+        Ok(Reservation::Reserved)
+    }
+
+    async fn release(&self, key: &str, policy: &ThrottlePolicy) -> Result<()> {
+        if let Some((state, _)) = self.entries.lock().await.get_mut(key) {
+            state.failures = state.failures.saturating_sub(1);
+            if state.failures < policy.free_attempts {
+                state.locked_until = None;
+            }
+        }
+        Ok(())
+    }
+
+    async fn clear(&self, key: &str) -> Result<()> {
+        self.entries.lock().await.remove(key);
+        Ok(())
+    }
+}
+
+/// Counters in the `login_throttle` table, shared by all replicas
+pub struct PostgresThrottleStore {
+    pool: PgPool,
+}
+
+impl PostgresThrottleStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl ThrottleStore for PostgresThrottleStore {
+    async fn reserve(&self, key: &str, window: Duration, policy: &ThrottlePolicy) -> Result<Reservation> {
+        let now = Utc::now();
+        let mut tx = self.pool.begin().await?;
+
+        // The row lock serializes concurrent attempts on the key across replicas
+        sqlx::query(
+            "INSERT INTO login_throttle (key, failures, last_failure_at) VALUES ($1, 0, $2) ON CONFLICT (key) DO NOTHING"
+        )
+        .bind(key)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+        let (failures, last_failure_at, locked_until) = sqlx::query_as::<_, (i32, DateTime<Utc>, Option<DateTime<Utc>>)>(
+            "SELECT failures, last_failure_at, locked_until FROM login_throttle WHERE key = $1 FOR UPDATE"
+        )
+        .bind(key)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        if let Some(locked_until) = locked_until.filter(|until| *until > now) {
+            return Ok(Reservation::Locked(locked_until));
+        }
+
+        let failures = if last_failure_at <= now - window { 1 } else { failures as u32 + 1 };
+        let locked_until = policy.delay(failures).map(|delay| {
+            log_lockout(key, failures, policy, delay);
+            now + delay
+        });
+
+        sqlx::query("UPDATE login_throttle SET failures = $1, last_failure_at = $2, locked_until = $3 WHERE key = $4")
+            .bind(failures as i32)
+            .bind(now)
+            .bind(locked_until)
+            .bind(key)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        // Rows nobody has failed on for a while are dead weight
+        sqlx::query(
+            "DELETE FROM login_throttle WHERE last_failure_at <= $1 AND (locked_until IS NULL OR locked_until <= $2)"
+        )
+        .bind(now - window)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(Reservation::Reserved)
+    }
+
+    async fn release(&self, key: &str, policy: &ThrottlePolicy) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE login_throttle SET
+                failures = GREATEST(failures - 1, 0),
+                locked_until = CASE WHEN failures - 1 < $1 THEN NULL ELSE locked_until END
+            WHERE key = $2
+            "#
+        )
+        .bind(policy.free_attempts as i32)
+        .bind(key)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn clear(&self, key: &str) -> Result<()> {
+        sqlx::query("DELETE FROM login_throttle WHERE key = $1")
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::hours(1);
+
+    fn policy() -> ThrottlePolicy {
+        ThrottlePolicy {
+            free_attempts: 3,
+            lockout_threshold: 10,
+            base_delay: Duration::seconds(1),
+            max_delay: Duration::seconds(60),
+            lockout: Duration::minutes(15),
+        }
+    }
+
+    async fn state(store: &MemoryThrottleStore, key: &str) -> ThrottleState {
+        store.entries.lock().await[key].0
+    }
+
+    /// Reserve `key`, then lift its lock so the next attempt isn't refused
+    async fn fail_and_unlock(store: &MemoryThrottleStore, key: &str) -> Option<Duration> {
+        let now = Utc::now();
+        assert!(matches!(store.reserve(key, WINDOW, &policy()).await.unwrap(), Reservation::Reserved));
+        let mut entries = store.entries.lock().await;
+        let state = &mut entries.get_mut(key).unwrap().0;
+        state.locked_until.take().map(|until| until - now)
+    }
+
+    fn seconds(delay: Option<Duration>) -> Option<i64> {
+        // Rounded, as the lock was set a moment after `now`
+        delay.map(|delay| (delay.num_milliseconds() as f64 / 1000.0).round() as i64)
+    }
+
+    #[test]
+    fn delay_grows_exponentially_up_to_the_cap() {
+        let policy = policy();
+        let delays: Vec<Option<i64>> = (1..=10).map(|failures| policy.delay(failures).map(|d| d.num_seconds())).collect();
+        assert_eq!(delays, vec![
+            None, None, Some(1), Some(2), Some(4), Some(8), Some(16), Some(32), Some(60), Some(900),
+        ]);
+        assert_eq!(policy.delay(u32::MAX), Some(Duration::minutes(15)));
+
+        let no_lockout = ThrottlePolicy { lockout_threshold: u32::MAX, ..policy };
+        assert_eq!(no_lockout.delay(1000), Some(Duration::seconds(60)));
+    }
+
+    #[tokio::test]
+    async fn memory_store_backs_off_after_the_free_attempts() {
+        let store = MemoryThrottleStore::default();
+        let mut delays = Vec::new();
+        for _ in 0..6 {
+            delays.push(seconds(fail_and_unlock(&store, "account:a").await));
+        }
+        assert_eq!(delays, vec![None, None, Some(1), Some(2), Some(4), Some(8)]);
+        assert_eq!(state(&store, "account:a").await.failures, 6);
+    }
+
+    #[tokio::test]
+    async fn memory_store_refuses_locked_keys_without_counting() {
+        let store = MemoryThrottleStore::default();
+        for _ in 0..3 {
+            store.reserve("account:a", WINDOW, &policy()).await.unwrap();
+        }
+
+        let locked_until = state(&store, "account:a").await.locked_until.unwrap();
+        match store.reserve("account:a", WINDOW, &policy()).await.unwrap() {
+            Reservation::Locked(until) => assert_eq!(until, locked_until),
+            Reservation::Reserved => panic!("a locked key was reserved"),
+        }
+        assert_eq!(state(&store, "account:a").await.failures, 3);
+
+        // Other keys are unaffected
+        assert!(matches!(store.reserve("account:b", WINDOW, &policy()).await.unwrap(), Reservation::Reserved));
+    }
+
+    #[tokio::test]
+    async fn memory_store_locks_out_at_the_threshold() {
+        let store = MemoryThrottleStore::default();
+        for _ in 0..9 {
+            fail_and_unlock(&store, "account:a").await;
+        }
+        assert_eq!(seconds(fail_and_unlock(&store, "account:a").await), Some(900));
+    }
+
+    #[tokio::test]
+    async fn release_unlocks_below_the_free_attempts() {
+        let store = MemoryThrottleStore::default();
+        for _ in 0..3 {
+            store.reserve("account:a", WINDOW, &policy()).await.unwrap();
+        }
+        assert!(state(&store, "account:a").await.locked_until.is_some());
+
+        store.release("account:a", &policy()).await.unwrap();
+        let released = state(&store, "account:a").await;
+        assert_eq!(released.failures, 2);
+        assert!(released.locked_until.is_none());
+
+        // Still past the free attempts: a release keeps the lock
+        for _ in 0..3 {
+            fail_and_unlock(&store, "account:b").await;
+        }
+        store.reserve("account:b", WINDOW, &policy()).await.unwrap();
+        store.release("account:b", &policy()).await.unwrap();
+        assert_eq!(state(&store, "account:b").await.failures, 3);
+        assert!(state(&store, "account:b").await.locked_until.is_some());
+
+        // Releasing an unknown key is a no-op
+        store.release("account:c", &policy()).await.unwrap();
+        assert!(!store.entries.lock().await.contains_key("account:c"));
+    }
+
+    #[tokio::test]
+    async fn failures_reset_after_the_window() {
+        let store = MemoryThrottleStore::default();
+        for _ in 0..2 {
+            store.reserve("account:a", WINDOW, &policy()).await.unwrap();
+        }
+        store.entries.lock().await.get_mut("account:a").unwrap().1 = Utc::now() - WINDOW - Duration::seconds(1);
+
+        store.reserve("account:a", WINDOW, &policy()).await.unwrap();
+        let state = state(&store, "account:a").await;
+        assert_eq!(state.failures, 1);
+        assert!(state.locked_until.is_none());
+    }
+
+    #[tokio::test]
+    async fn clear_forgets_the_key() {
+        let store = MemoryThrottleStore::default();
+        for _ in 0..3 {
+            store.reserve("account:a", WINDOW, &policy()).await.unwrap();
+        }
+        store.clear("account:a").await.unwrap();
+        assert!(matches!(store.reserve("account:a", WINDOW, &policy()).await.unwrap(), Reservation::Reserved));
+        assert_eq!(state(&store, "account:a").await.failures, 1);
+    }
+
+    fn entry(failures: u32, locked_until: Option<DateTime<Utc>>, last_failure_at: DateTime<Utc>) -> (ThrottleState, DateTime<Utc>) {
+        (ThrottleState { failures, locked_until }, last_failure_at)
+    }
+
+    #[tokio::test]
+    async fn eviction_drops_expired_entries_first() {
+        let store = MemoryThrottleStore::default();
+        let now = Utc::now();
+        {
+            let mut entries = store.entries.lock().await;
+            for i in 0..MAX_MEMORY_ENTRIES - 1 {
+                entries.insert(format!("ip:{}", i), entry(1, None, now - WINDOW * 2));
+            }
+            // Expired failures, but a lockout still running
+            entries.insert("account:locked".to_string(), entry(10, Some(now + Duration::minutes(5)), now - WINDOW * 2));
+        }
+
+        store.reserve("account:new", WINDOW, &policy()).await.unwrap();
+
+        let entries = store.entries.lock().await;
+        assert_eq!(entries.len(), 2);
+        assert!(entries.contains_key("account:locked") && entries.contains_key("account:new"));
+    }
+
+    #[tokio::test]
+    async fn eviction_drops_the_least_recent_unlocked_entries() {
+        let store = MemoryThrottleStore::default();
+        let now = Utc::now();
+        {
+            let mut entries = store.entries.lock().await;
+            for i in 0..MAX_MEMORY_ENTRIES - 1 {
+                entries.insert(format!("ip:{}", i), entry(1, None, now - Duration::milliseconds(i as i64)));
+            }
+            // The oldest failure of all, but locked
+            entries.insert("account:locked".to_string(), entry(10, Some(now + Duration::minutes(5)), now - WINDOW / 2));
+        }
+
+        store.reserve("account:new", WINDOW, &policy()).await.unwrap();
+
+        let entries = store.entries.lock().await;
+        let target = MAX_MEMORY_ENTRIES - MAX_MEMORY_ENTRIES / 10;
+        assert_eq!(entries.len(), target + 1);
+        assert!(entries.contains_key("account:locked") && entries.contains_key("account:new"));
+        assert!(entries.contains_key("ip:0"));
+        assert!(!entries.contains_key(&format!("ip:{}", MAX_MEMORY_ENTRIES - 2)));
+    }
+
+    #[tokio::test]
+    async fn refused_attempts_are_given_back_to_unlocked_keys() {
+        let store = Arc::new(MemoryThrottleStore::default());
+        let throttle = LoginThrottle {
+            store: store.clone(),
+            account_policy: policy(),
+            ip_policy: ThrottlePolicy { free_attempts: 30, lockout_threshold: 100, ..policy() },
+            window: WINDOW,
+        };
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        for _ in 0..3 {
+            throttle.reserve("npub1alice", Some(ip)).await.unwrap();
+        }
+        assert!(matches!(throttle.reserve("NPUB1ALICE", Some(ip)).await, Err(AppError::RateLimited(_))));
+        assert_eq!(state(&store, "ip:192.0.2.1").await.failures, 3);
+
+        // A success clears the account and gives the IP its attempt back
+        store.entries.lock().await.get_mut("account:npub1alice").unwrap().0.locked_until = None;
+        throttle.reserve("npub1alice", Some(ip)).await.unwrap();
+        throttle.record_success("npub1alice", Some(ip)).await.unwrap();
+        assert!(!store.entries.lock().await.contains_key("account:npub1alice"));
+        assert_eq!(state(&store, "ip:192.0.2.1").await.failures, 3);
+    }
+}
@@ -1,7 +1,25 @@
 pub mod crypto;
+pub mod credential_vault;
 pub mod jwt;
+pub mod key_provider;
 pub mod greenlight;
+pub mod dns;
+pub mod username_policy;
+pub mod reconciliation;
+pub mod payment_uri;
+pub mod nostr;
+pub mod login_throttle;
+pub mod spending_policy;
+pub mod totp;
+pub mod secret;
 
-pub use crypto::CryptoService;
+pub use crypto::{Binding, CryptoService, MnemonicOptions, SecretPurpose};
+pub use credential_vault::CredentialVault;
 pub use jwt::JwtService;
 pub use greenlight::GreenlightService;
+pub use dns::DnsProvider;
+pub use username_policy::UsernamePolicy;
+pub use reconciliation::ReconciliationService;
+pub use login_throttle::LoginThrottle;
+pub use totp::TotpService;
+pub use secret::{SecretBytes, SecretKey, SecretString};
@@ -0,0 +1,197 @@
+use bitcoin::secp256k1::{schnorr::Signature, Message, Secp256k1, XOnlyPublicKey};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use crate::error::{AppError, Result};
+
+/// NIP-42 client authentication event, also used for challenge login
+pub const KIND_CLIENT_AUTH: u32 = 22242;
+
+/// NIP-98 HTTP authentication event
+pub const KIND_HTTP_AUTH: u32 = 27235;
+
+/// A signed Nostr event (NIP-01)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct NostrEvent {
+    pub id: String,
+    pub pubkey: String,
+    pub created_at: i64,
+    pub kind: u32,
+    pub tags: Vec<Vec<String>>,
+    pub content: String,
+    pub sig: String,
+}
+
+impl NostrEvent {
+    /// sha256 of the canonical `[0, pubkey, created_at, kind, tags, content]` serialization
+    pub fn compute_id(&self) -> [u8; 32] {
+        let serialized = json!([0, self.pubkey, self.created_at, self.kind, self.tags, self.content]).to_string();
+        Sha256::digest(serialized.as_bytes()).into()
+    }
+
+    /// Check that `id` matches the event contents and `sig` is a valid BIP340
+    /// signature over it by `pubkey`
+    pub fn verify(&self) -> Result<()> {
+        let id = self.compute_id();
+        if !hex::encode(id).eq_ignore_ascii_case(&self.id) {
+            return Err(AppError::Authentication("Event id does not match its contents".to_string()));
+        }
+
+        verify_signature(&self.pubkey, &id, &self.sig)
+    }
+
+    /// Value of the first tag named `name`
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|tag| tag.first().map(String::as_str) == Some(name))
+            .and_then(|tag| tag.get(1))
+            .map(String::as_str)
+    }
+}
+
+/// Lowercase a hex x-only public key, or None if it is not one
+pub fn normalize_pubkey(public_key: &str) -> Option<String> {
+    let public_key = public_key.trim().to_lowercase();
+    (public_key.len() == 64 && public_key.chars().all(|c| c.is_ascii_hexdigit())).then_some(public_key)
+}
+
+/// Verify a hex BIP340 Schnorr signature over a 32-byte message
+pub fn verify_signature(public_key: &str, message: &[u8; 32], signature: &str) -> Result<()> {
+    let invalid = || AppError::Authentication("Invalid signature".to_string());
+
+    let public_key = hex::decode(public_key)
+        .ok()
+        .and_then(|bytes| XOnlyPublicKey::from_slice(&bytes).ok())
+        .ok_or_else(|| AppError::Authentication("Invalid public key".to_string()))?;
+    let signature = hex::decode(signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .ok_or_else(invalid)?;
+    let message = Message::from_digest_slice(message).map_err(|_| invalid())?;
+
+    Secp256k1::verification_only()
+        .verify_schnorr(&signature, &message, &public_key)
+        .map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A NIP-42 auth event signed with the secret key 0x11..11
+    fn signed_event() -> NostrEvent {
+        serde_json::from_value(json!({
+            "id": "54dfa555315008add8037bd7e6fabca9415f0750394134aa04ac3f24dd36b243",
+            "pubkey": "4f355bdcb7cc0af728ef3cceb9615d90684bb5b2ca5f859ab0f0b704075871aa",
+            "created_at": 1700000000,
+            "kind": 22242,
+            "tags": [["relay", "wss://relay.example.com"], ["challenge", "5f8c0b5e2a1d4c3b"]],
+            "content": "",
+            "sig": "edfe0115cdd6797cb0cc10e1e4291de74e125b6fd2fdfeba67f55f08bda3fdd7c8db790b33144e4de60386931acbe9d5d36c76faea5384d81e633894ab3c2c87"
+        }))
+        .unwrap()
+    }
+
+    fn rejected(event: &NostrEvent) -> String {
+        match event.verify() {
+            Err(AppError::Authentication(message)) => message,
+            other => panic!("expected an authentication error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn verifies_a_signed_event() {
+        let event = signed_event();
+        event.verify().unwrap();
+        assert_eq!(event.tag("challenge"), Some("5f8c0b5e2a1d4c3b"));
+        assert_eq!(event.tag("method"), None);
+
+        // Hex case doesn't matter for the id and sig; the pubkey is hashed as written
+        let event = NostrEvent { id: event.id.to_uppercase(), sig: event.sig.to_uppercase(), ..event };
+        event.verify().unwrap();
+        let event = NostrEvent { pubkey: event.pubkey.to_uppercase(), ..event };
+        assert_eq!(rejected(&event), "Event id does not match its contents");
+    }
+
+    #[test]
+    fn id_is_the_hash_of_the_canonical_serialization() {
+        let event = signed_event();
+        let serialized = r#"[0,"4f355bdcb7cc0af728ef3cceb9615d90684bb5b2ca5f859ab0f0b704075871aa",1700000000,22242,[["relay","wss://relay.example.com"],["challenge","5f8c0b5e2a1d4c3b"]],""]"#;
+        assert_eq!(event.compute_id(), <[u8; 32]>::from(Sha256::digest(serialized.as_bytes())));
+    }
+
+    #[test]
+    fn rejects_tampered_content() {
+        let event = NostrEvent { content: "hello".to_string(), ..signed_event() };
+        assert_eq!(rejected(&event), "Event id does not match its contents");
+
+        let mut event = signed_event();
+        event.tags[1][1] = "another-challenge".to_string();
+        assert_eq!(rejected(&event), "Event id does not match its contents");
+
+        let event = NostrEvent { created_at: 1700000001, ..signed_event() };
+        assert_eq!(rejected(&event), "Event id does not match its contents");
+    }
+
+    #[test]
+    fn rejects_a_wrong_id() {
+        let event = NostrEvent { id: "00".repeat(32), ..signed_event() };
+        assert_eq!(rejected(&event), "Event id does not match its contents");
+
+        // A correct id for tampered content still needs a signature over it
+        let mut event = NostrEvent { content: "hello".to_string(), ..signed_event() };
+        event.id = hex::encode(event.compute_id());
+        assert_eq!(rejected(&event), "Invalid signature");
+    }
+
+    #[test]
+    fn rejects_a_wrong_sig() {
+        let mut sig = hex::decode(signed_event().sig).unwrap();
+        sig[63] ^= 1;
+        let event = NostrEvent { sig: hex::encode(sig), ..signed_event() };
+        assert_eq!(rejected(&event), "Invalid signature");
+
+        for sig in ["", "zz", &"00".repeat(63)] {
+            let event = NostrEvent { sig: sig.to_string(), ..signed_event() };
+            assert_eq!(rejected(&event), "Invalid signature");
+        }
+
+        // Signed by someone else: the BIP340 generator point's x coordinate
+        let event = NostrEvent {
+            pubkey: "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798".to_string(),
+            ..signed_event()
+        };
+        assert_eq!(rejected(&event), "Event id does not match its contents");
+    }
+
+    #[test]
+    fn rejects_a_malformed_pubkey() {
+        let event = signed_event();
+        let id = event.compute_id();
+        assert!(matches!(verify_signature("not-hex", &id, &event.sig), Err(AppError::Authentication(m)) if m == "Invalid public key"));
+        assert!(matches!(verify_signature(&"ff".repeat(32), &id, &event.sig), Err(AppError::Authentication(m)) if m == "Invalid public key"));
+    }
+
+    #[test]
+    fn verifies_bip340_vectors() {
+        // Test vector 1 from BIP340
+        let public_key = "DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659";
+        let message: [u8; 32] = hex::decode("243F6A8885A308D313198A2E03707344A4093822299F31D0082EFA98EC4E6C89").unwrap().try_into().unwrap();
+        let signature = "6896BD60EEAE296DB48A229FF71DFE071BDE413E6D43F917DC8DCF8C78DE33418906D11AC976ABCCB20B091292BFF4EA897EFCB639EA871CFA95F6DE339E4B0A";
+        verify_signature(public_key, &message, signature).unwrap();
+
+        let mut other = message;
+        other[0] ^= 1;
+        assert!(verify_signature(public_key, &other, signature).is_err());
+    }
+
+    #[test]
+    fn normalizes_pubkeys() {
+        let pubkey = "4f355bdcb7cc0af728ef3cceb9615d90684bb5b2ca5f859ab0f0b704075871aa";
+        assert_eq!(normalize_pubkey(&format!(" {} ", pubkey.to_uppercase())).as_deref(), Some(pubkey));
+        assert_eq!(normalize_pubkey(&pubkey[1..]), None);
+        assert_eq!(normalize_pubkey(&format!("{}g", &pubkey[1..])), None);
+        assert_eq!(normalize_pubkey("npub1..."), None);
+    }
+}
@@ -0,0 +1,102 @@
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use qrcode::{render::svg, Color, EcLevel, QrCode};
+use crate::error::{AppError, Result};
+
+/// Modules of blank border around the code, as required by the QR spec
+const QUIET_ZONE: usize = 4;
+
+/// Parts of a BIP21 URI that any wallet can pay: on-chain, BOLT11 or BOLT12
+#[derive(Debug, Default)]
+pub struct PaymentUri {
+    pub address: String,
+    pub amount_sat: Option<u64>,
+    pub label: Option<String>,
+    pub message: Option<String>,
+    pub bolt11: Option<String>,
+    pub bolt12: Option<String>,
+}
+
+impl PaymentUri {
+    /// `bitcoin:<address>?amount=..&label=..&message=..&lightning=<bolt11>&lno=<bolt12>`
+    pub fn to_uri(&self) -> String {
+        let mut params = Vec::new();
+
+        if let Some(amount_sat) = self.amount_sat {
+            params.push(format!("amount={}", format_btc(amount_sat)));
+        }
+        if let Some(label) = &self.label {
+            params.push(format!("label={}", utf8_percent_encode(label, NON_ALPHANUMERIC)));
+        }
+        if let Some(message) = &self.message {
+            params.push(format!("message={}", utf8_percent_encode(message, NON_ALPHANUMERIC)));
+        }
+        if let Some(bolt11) = &self.bolt11 {
+            params.push(format!("lightning={}", bolt11));
+        }
+        if let Some(bolt12) = &self.bolt12 {
+            params.push(format!("lno={}", bolt12));
+        }
+
+        if params.is_empty() {
+            format!("bitcoin:{}", self.address)
+        } else {
+            format!("bitcoin:{}?{}", self.address, params.join("&"))
+        }
+    }
+}
+
+/// BIP21 amounts are decimal BTC without trailing zeros
+fn format_btc(amount_sat: u64) -> String {
+    let formatted = format!("{}.{:08}", amount_sat / 100_000_000, amount_sat % 100_000_000);
+    formatted.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+fn encode(data: &str) -> Result<QrCode> {
+    QrCode::with_error_correction_level(data.as_bytes(), EcLevel::M)
+        .map_err(|e| AppError::Internal(format!("Failed to encode QR code: {}", e)))
+}
+
+/// Render `data` as an SVG QR code
+pub fn render_svg(data: &str) -> Result<String> {
+    let code = encode(data)?;
+    Ok(code
+        .render::<svg::Color>()
+        .min_dimensions(256, 256)
+        .quiet_zone(true)
+        .build())
+}
+
+/// Render `data` as a grayscale PNG QR code with `scale` pixels per module
+pub fn render_png(data: &str, scale: usize) -> Result<Vec<u8>> {
+    let code = encode(data)?;
+    let modules = code.width();
+    let colors = code.to_colors();
+    let size = (modules + 2 * QUIET_ZONE) * scale;
+
+    let mut pixels = vec![255u8; size * size];
+    for (i, color) in colors.iter().enumerate() {
+        if *color != Color::Dark {
+            continue;
+        }
+        let (x, y) = ((i % modules + QUIET_ZONE) * scale, (i / modules + QUIET_ZONE) * scale);
+        for row in y..y + scale {
+            pixels[row * size + x..row * size + x + scale].fill(0);
+        }
+    }
+
+    let mut png_bytes = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png_bytes, size as u32, size as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder
+        .write_header()
+        .map_err(|e| AppError::Internal(format!("Failed to write PNG: {}", e)))?;
+    writer
+        .write_image_data(&pixels)
+        .map_err(|e| AppError::Internal(format!("Failed to write PNG: {}", e)))?;
+    writer
+        .finish()
+        .map_err(|e| AppError::Internal(format!("Failed to write PNG: {}", e)))?;
+
+    Ok(png_bytes)
+}
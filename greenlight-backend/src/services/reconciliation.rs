@@ -0,0 +1,203 @@
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::{hash_map::Entry, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+use crate::config::Config;
+use crate::error::{AppError, Result};
+use crate::models::{
+    DriftIssue, ReconciliationFinding, ReconciliationRepository, ReconciliationRun, Username,
+    UsernameRepository, UserRepository,
+};
+use crate::services::credential_vault::CredentialVault;
+use crate::services::dns::{self, bip353_record_name, bip353_record_value, DnsProvider};
+use crate::services::greenlight::{GreenlightService, NodeOffer};
+
+#[derive(Debug, Serialize)]
+pub struct ReconciliationReport {
+    pub run: ReconciliationRun,
+    pub findings: Vec<ReconciliationFinding>,
+}
+
+/// Compares every username's published TXT record with the offer in the
+/// database and with the owner's node, and repairs DNS drift when allowed.
+pub struct ReconciliationService {
+    db_pool: PgPool,
+    dns_provider: Option<Arc<dyn DnsProvider>>,
+    credential_vault: Arc<CredentialVault>,
+    config: Config,
+    running: Mutex<()>,
+}
+
+impl ReconciliationService {
+    pub fn new(
+        db_pool: PgPool,
+        dns_provider: Option<Arc<dyn DnsProvider>>,
+        credential_vault: Arc<CredentialVault>,
+        config: Config,
+    ) -> Self {
+        Self {
+            db_pool,
+            dns_provider,
+            credential_vault,
+            config,
+            running: Mutex::new(()),
+        }
+    }
+
+    /// Run the job every `RECONCILE_INTERVAL_SECS` in the background
+    pub fn spawn(self: Arc<Self>) {
+        if self.config.reconcile_interval_secs == 0 {
+            tracing::info!("Username reconciliation job disabled");
+            return;
+        }
+
+        let period = Duration::from_secs(self.config.reconcile_interval_secs);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                match self.run().await {
+                    Ok(report) => tracing::info!(
+                        "Reconciliation checked {} usernames, {} drifted, {} repaired",
+                        report.run.usernames_checked,
+                        report.run.drift_found,
+                        report.run.repaired
+                    ),
+                    Err(e) => tracing::error!("Reconciliation run failed: {}", e),
+                }
+            }
+        });
+    }
+
+    pub async fn run(&self) -> Result<ReconciliationReport> {
+        let _guard = self.running.try_lock()
+            .map_err(|_| AppError::BadRequest("A reconciliation run is already in progress".to_string()))?;
+
+        let reconciliation_repo = ReconciliationRepository::new(self.db_pool.clone());
+        let username_repo = UsernameRepository::new(self.db_pool.clone());
+
+        let run = reconciliation_repo.create_run().await?;
+        let usernames = username_repo.find_all().await?;
+
+        // Offers per owner, fetched once per node
+        let mut node_offers: HashMap<Uuid, std::result::Result<Vec<NodeOffer>, DriftIssue>> = HashMap::new();
+        let mut findings = Vec::new();
+        let mut drifted = 0;
+        let mut repaired = 0;
+
+        for username in &usernames {
+            if let Entry::Vacant(entry) = node_offers.entry(username.user_id) {
+                entry.insert(self.load_offers(username.user_id).await);
+            }
+
+            // (issue, detail, repaired)
+            let mut issues: Vec<(DriftIssue, Option<String>, bool)> = Vec::new();
+            let offer_ok = match &node_offers[&username.user_id] {
+                Ok(offers) => match offers.iter().find(|offer| offer.bolt12 == username.offer) {
+                    Some(offer) if offer.active => true,
+                    Some(offer) => {
+                        issues.push((DriftIssue::OfferDisabled, Some(format!("offer_id: {}", offer.offer_id)), false));
+                        false
+                    }
+                    None => {
+                        issues.push((DriftIssue::OfferNotFound, None, false));
+                        false
+                    }
+                },
+                Err(issue) => {
+                    issues.push((*issue, None, false));
+                    false
+                }
+            };
+
+            if let Some((issue, detail)) = self.check_record(username).await {
+                // Only point DNS at offers the node will actually pay out on
+                let fixed = issue != DriftIssue::DnsLookupFailed
+                    && offer_ok
+                    && self.config.reconcile_auto_repair
+                    && self.repair_record(username).await;
+                issues.push((issue, detail, fixed));
+            }
+
+            if !issues.is_empty() {
+                drifted += 1;
+            }
+
+            for (issue, detail, fixed) in issues {
+                if fixed {
+                    repaired += 1;
+                }
+                findings.push(
+                    reconciliation_repo
+                        .add_finding(run.id, &username.username, username.user_id, issue, detail.as_deref(), fixed)
+                        .await?,
+                );
+            }
+        }
+
+        let run = reconciliation_repo
+            .finish_run(run.id, usernames.len() as i32, drifted, repaired)
+            .await?;
+
+        Ok(ReconciliationReport { run, findings })
+    }
+
+    /// Compare the published record against the stored offer
+    async fn check_record(&self, username: &Username) -> Option<(DriftIssue, Option<String>)> {
+        let name = bip353_record_name(&username.username, &self.config.domain);
+        let expected = bip353_record_value(&username.offer);
+
+        match dns::resolve_txt(&self.config.dns_resolver, &name).await {
+            Ok(lookup) if lookup.records.contains(&expected) => None,
+            Ok(lookup) if lookup.records.is_empty() => Some((DriftIssue::DnsMissing, None)),
+            Ok(lookup) => Some((DriftIssue::DnsMismatch, Some(format!("published: {}", lookup.records.join(" | "))))),
+            Err(e) => Some((DriftIssue::DnsLookupFailed, Some(e.to_string()))),
+        }
+    }
+
+    async fn repair_record(&self, username: &Username) -> bool {
+        let dns_provider = match &self.dns_provider {
+            Some(dns_provider) => dns_provider,
+            None => return false,
+        };
+
+        match dns_provider
+            .update_txt_record(
+                &bip353_record_name(&username.username, &self.config.domain),
+                &bip353_record_value(&username.offer),
+            )
+            .await
+        {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::warn!("Failed to repair record for {}: {}", username.username, e);
+                false
+            }
+        }
+    }
+
+    async fn load_offers(&self, user_id: Uuid) -> std::result::Result<Vec<NodeOffer>, DriftIssue> {
+        let user_repo = UserRepository::new(self.db_pool.clone());
+        let user = match user_repo.find_by_id(user_id).await {
+            Ok(Some(user)) => user,
+            _ => return Err(DriftIssue::NodeUnavailable),
+        };
+
+        if user.encrypted_device_creds.is_none() {
+            return Err(DriftIssue::NoNode);
+        }
+        let device_creds = self.credential_vault.open_for(&user).await.map_err(|e| {
+            tracing::warn!("Failed to read device credentials of user {}: {}", user_id, e);
+            DriftIssue::NodeUnavailable
+        })?;
+
+        let greenlight_service = GreenlightService::new(self.config.clone());
+        greenlight_service.list_offers(&device_creds).await.map_err(|e| {
+            tracing::warn!("Failed to list offers for user {}: {}", user_id, e);
+            DriftIssue::NodeUnavailable
+        })
+    }
+}
@@ -0,0 +1,447 @@
+use bitcoin::{Address, Network};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::str::FromStr;
+use crate::error::AppError;
+use crate::models::{DestinationKind, PaymentKind, SpendingDestination, SpendingPolicy, UpdateSpendingPolicyRequest};
+use crate::services::dns::parse_bip353_name;
+
+/// An outgoing payment as the policy sees it
+#[derive(Debug)]
+pub struct SpendRequest {
+    pub kind: PaymentKind,
+    pub amount_msat: u64,
+    /// Lightning: the most the route may cost
+    pub max_fee_msat: Option<u64>,
+    /// On-chain: the feerate the withdrawal will use
+    pub feerate_sat_vb: Option<u64>,
+    /// Everything the destination is known as: node id, BIP353 name, address
+    pub identities: Vec<(DestinationKind, String)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DenialReason {
+    ExceedsMaxPayment,
+    ExceedsDailyLimit,
+    ExceedsWeeklyLimit,
+    FeeCapExceeded,
+    DestinationNotAllowlisted,
+    DestinationCoolingOff,
+}
+
+impl DenialReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DenialReason::ExceedsMaxPayment => "exceeds_max_payment",
+            DenialReason::ExceedsDailyLimit => "exceeds_daily_limit",
+            DenialReason::ExceedsWeeklyLimit => "exceeds_weekly_limit",
+            DenialReason::FeeCapExceeded => "fee_cap_exceeded",
+            DenialReason::DestinationNotAllowlisted => "destination_not_allowlisted",
+            DenialReason::DestinationCoolingOff => "destination_cooling_off",
+        }
+    }
+}
+
+/// Why a payment was refused, returned to the client as-is
+#[derive(Debug, Clone, Serialize)]
+pub struct PolicyDenial {
+    pub reason: DenialReason,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit_sat: Option<i64>,
+    /// What is left of a daily or weekly limit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining_sat: Option<i64>,
+    /// When a destination in its cooling-off period becomes payable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usable_after: Option<DateTime<Utc>>,
+}
+
+impl PolicyDenial {
+    fn new(reason: DenialReason, message: String) -> Self {
+        Self {
+            reason,
+            message,
+            limit_sat: None,
+            remaining_sat: None,
+            usable_after: None,
+        }
+    }
+}
+
+/// `value` as the signed amounts the database stores, or a validation error
+/// naming `field`. Casting would wrap huge values negative and slip them
+/// under every limit.
+pub fn to_i64(value: u64, field: &str) -> Result<i64, AppError> {
+    i64::try_from(value).map_err(|_| AppError::Validation(format!("{} is too large", field)))
+}
+
+/// Check a payment against the user's policy. `spent_day_msat` and
+/// `spent_week_msat` cover pending and completed payments in the rolling
+/// windows; `allowlisted` are the allowlist entries matching the request's
+/// identities. Lightning payments count with their maximum fee. Refusals are
+/// `AppError::PolicyDenied`; amounts out of range are `AppError::Validation`.
+pub fn evaluate(
+    policy: &SpendingPolicy,
+    request: &SpendRequest,
+    spent_day_msat: i64,
+    spent_week_msat: i64,
+    allowlisted: &[SpendingDestination],
+    now: DateTime<Utc>,
+) -> Result<(), AppError> {
+    let amount_msat = to_i64(request.amount_msat, "amount_msat")?;
+    let max_fee_msat = request.max_fee_msat.map(|fee| to_i64(fee, "max_fee_msat")).transpose()?;
+    let amounts = Amounts {
+        amount_msat,
+        max_fee_msat,
+        feerate_sat_vb: request.feerate_sat_vb.map(|rate| to_i64(rate, "feerate_sat_vb")).transpose()?,
+        charged_msat: amount_msat
+            .checked_add(max_fee_msat.unwrap_or(0))
+            .ok_or_else(|| AppError::Validation("amount_msat plus max_fee_msat is too large".to_string()))?,
+    };
+
+    check_policy(policy, request.kind, &amounts, spent_day_msat, spent_week_msat, allowlisted, now)
+        .map_err(AppError::PolicyDenied)
+}
+
+/// A request's amounts as signed integers, like the limits they meet
+struct Amounts {
+    amount_msat: i64,
+    max_fee_msat: Option<i64>,
+    feerate_sat_vb: Option<i64>,
+    /// Amount plus maximum fee
+    charged_msat: i64,
+}
+
+fn check_policy(
+    policy: &SpendingPolicy,
+    kind: PaymentKind,
+    amounts: &Amounts,
+    spent_day_msat: i64,
+    spent_week_msat: i64,
+    allowlisted: &[SpendingDestination],
+    now: DateTime<Utc>,
+) -> Result<(), PolicyDenial> {
+    if policy.allowlist_enabled {
+        if allowlisted.is_empty() {
+            return Err(PolicyDenial::new(
+                DenialReason::DestinationNotAllowlisted,
+                "Destination is not on the allowlist".to_string(),
+            ));
+        }
+        if !allowlisted.iter().any(|destination| destination.usable_after <= now) {
+            let usable_after = allowlisted.iter().map(|destination| destination.usable_after).min();
+            return Err(PolicyDenial {
+                usable_after,
+                ..PolicyDenial::new(
+                    DenialReason::DestinationCoolingOff,
+                    "Destination was added recently and is still in its cooling-off period".to_string(),
+                )
+            });
+        }
+    }
+
+    // Limits too large to express in msat can't be exceeded
+    if let Some(max_payment_sat) = policy.max_payment_sat {
+        if amounts.amount_msat > max_payment_sat.saturating_mul(1000) {
+            return Err(PolicyDenial {
+                limit_sat: Some(max_payment_sat),
+                ..PolicyDenial::new(
+                    DenialReason::ExceedsMaxPayment,
+                    format!("Payments are limited to {} sat each", max_payment_sat),
+                )
+            });
+        }
+    }
+
+    match kind {
+        PaymentKind::Lightning => {
+            if let (Some(max_fee_sat), Some(max_fee_msat)) = (policy.max_fee_sat, amounts.max_fee_msat) {
+                if max_fee_msat > max_fee_sat.saturating_mul(1000) {
+                    return Err(PolicyDenial {
+                        limit_sat: Some(max_fee_sat),
+                        ..PolicyDenial::new(
+                            DenialReason::FeeCapExceeded,
+                            format!("Routing fees are capped at {} sat", max_fee_sat),
+                        )
+                    });
+                }
+            }
+        }
+        PaymentKind::Onchain => {
+            if let (Some(max_feerate), Some(feerate)) = (policy.max_feerate_sat_vb, amounts.feerate_sat_vb) {
+                if feerate > max_feerate {
+                    return Err(PolicyDenial::new(
+                        DenialReason::FeeCapExceeded,
+                        format!("Withdrawal feerates are capped at {} sat/vB", max_feerate),
+                    ));
+                }
+            }
+        }
+    }
+
+    let windows = [
+        (policy.daily_limit_sat, spent_day_msat, DenialReason::ExceedsDailyLimit, "daily"),
+        (policy.weekly_limit_sat, spent_week_msat, DenialReason::ExceedsWeeklyLimit, "weekly"),
+    ];
+    for (limit_sat, spent_msat, reason, period) in windows {
+        if let Some(limit_sat) = limit_sat {
+            let limit_msat = limit_sat.saturating_mul(1000);
+            if spent_msat.saturating_add(amounts.charged_msat) > limit_msat {
+                return Err(PolicyDenial {
+                    limit_sat: Some(limit_sat),
+                    remaining_sat: Some(limit_msat.saturating_sub(spent_msat).max(0) / 1000),
+                    ..PolicyDenial::new(reason, format!("Payment would exceed the {} limit of {} sat", period, limit_sat))
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The part of a requested policy that can apply right away: each field
+/// keeps whichever of the current and requested values is stricter, so the
+/// result never lets a session spend more than `current` does
+pub fn stricter_policy(current: &SpendingPolicy, requested: &UpdateSpendingPolicyRequest) -> UpdateSpendingPolicyRequest {
+    let stricter_limit = |current: Option<i64>, requested: Option<i64>| match (current, requested) {
+        (Some(current), Some(requested)) => Some(current.min(requested)),
+        (limit, None) | (None, limit) => limit,
+    };
+
+    UpdateSpendingPolicyRequest {
+        daily_limit_sat: stricter_limit(current.daily_limit_sat, requested.daily_limit_sat),
+        weekly_limit_sat: stricter_limit(current.weekly_limit_sat, requested.weekly_limit_sat),
+        max_payment_sat: stricter_limit(current.max_payment_sat, requested.max_payment_sat),
+        max_fee_sat: stricter_limit(current.max_fee_sat, requested.max_fee_sat),
+        max_feerate_sat_vb: stricter_limit(current.max_feerate_sat_vb, requested.max_feerate_sat_vb),
+        allowlist_enabled: current.allowlist_enabled || requested.allowlist_enabled,
+        cooling_off_hours: Some(requested.cooling_off_hours.map_or(current.cooling_off_hours, |hours| hours.max(current.cooling_off_hours))),
+    }
+}
+
+/// Canonical form of a destination, so allowlist entries and payments
+/// compare equal: lowercase node ids and names, addresses as the network
+/// formats them. None if the value is not valid for its kind.
+pub fn normalize_destination(kind: DestinationKind, value: &str, network: Network) -> Option<String> {
+    let value = value.trim();
+    match kind {
+        DestinationKind::NodeId => {
+            let node_id = value.to_lowercase();
+            (node_id.len() == 66 && hex::decode(&node_id).is_ok()).then_some(node_id)
+        }
+        DestinationKind::Bip353 => parse_bip353_name(value).map(|(user, domain)| format!("{}@{}", user, domain)),
+        DestinationKind::Address => {
+            Address::from_str(value)
+                .ok()?
+                .require_network(network)
+                .ok()
+                .map(|address| address.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use uuid::Uuid;
+
+    fn policy() -> SpendingPolicy {
+        SpendingPolicy {
+            daily_limit_sat: Some(10_000),
+            weekly_limit_sat: Some(50_000),
+            max_payment_sat: Some(5_000),
+            max_fee_sat: Some(100),
+            max_feerate_sat_vb: Some(50),
+            ..SpendingPolicy::unrestricted(Uuid::nil())
+        }
+    }
+
+    fn lightning(amount_msat: u64, max_fee_msat: u64) -> SpendRequest {
+        SpendRequest {
+            kind: PaymentKind::Lightning,
+            amount_msat,
+            max_fee_msat: Some(max_fee_msat),
+            feerate_sat_vb: None,
+            identities: Vec::new(),
+        }
+    }
+
+    fn destination(usable_after: DateTime<Utc>) -> SpendingDestination {
+        SpendingDestination {
+            id: Uuid::nil(),
+            kind: DestinationKind::NodeId.as_str().to_string(),
+            value: "02".repeat(33),
+            label: None,
+            created_at: usable_after,
+            usable_after,
+        }
+    }
+
+    fn denial(result: Result<(), AppError>) -> PolicyDenial {
+        match result {
+            Err(AppError::PolicyDenied(denial)) => denial,
+            other => panic!("expected a policy denial, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn normalizes_addresses_for_the_configured_network() {
+        let mainnet = "BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4";
+        let testnet = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+        assert_eq!(
+            normalize_destination(DestinationKind::Address, mainnet, Network::Bitcoin).as_deref(),
+            Some("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4")
+        );
+        assert_eq!(normalize_destination(DestinationKind::Address, mainnet, Network::Testnet), None);
+        assert_eq!(normalize_destination(DestinationKind::Address, testnet, Network::Testnet).as_deref(), Some(testnet));
+        assert_eq!(normalize_destination(DestinationKind::Address, testnet, Network::Bitcoin), None);
+        assert_eq!(normalize_destination(DestinationKind::Address, testnet, Network::Regtest), None);
+    }
+
+    #[test]
+    fn allows_payments_within_limits() {
+        let result = evaluate(&policy(), &lightning(5_000_000, 100_000), 4_000_000, 4_000_000, &[], Utc::now());
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn unrestricted_policy_allows_anything() {
+        let policy = SpendingPolicy::unrestricted(Uuid::nil());
+        let result = evaluate(&policy, &lightning(i64::MAX as u64 - 1, 1), i64::MAX, i64::MAX, &[], Utc::now());
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn refuses_payments_over_the_maximum() {
+        let denial = denial(evaluate(&policy(), &lightning(5_000_001, 0), 0, 0, &[], Utc::now()));
+        assert_eq!(denial.reason, DenialReason::ExceedsMaxPayment);
+        assert_eq!(denial.limit_sat, Some(5_000));
+    }
+
+    #[test]
+    fn caps_lightning_fees() {
+        let denial = denial(evaluate(&policy(), &lightning(1_000, 100_001), 0, 0, &[], Utc::now()));
+        assert_eq!(denial.reason, DenialReason::FeeCapExceeded);
+    }
+
+    #[test]
+    fn caps_onchain_feerates() {
+        let request = SpendRequest {
+            kind: PaymentKind::Onchain,
+            amount_msat: 1_000_000,
+            max_fee_msat: None,
+            feerate_sat_vb: Some(51),
+            identities: Vec::new(),
+        };
+        let denial = denial(evaluate(&policy(), &request, 0, 0, &[], Utc::now()));
+        assert_eq!(denial.reason, DenialReason::FeeCapExceeded);
+    }
+
+    #[test]
+    fn counts_the_maximum_fee_against_the_daily_limit() {
+        let denial = denial(evaluate(&policy(), &lightning(5_000_000, 1_000), 5_000_000, 5_000_000, &[], Utc::now()));
+        assert_eq!(denial.reason, DenialReason::ExceedsDailyLimit);
+        assert_eq!(denial.limit_sat, Some(10_000));
+        assert_eq!(denial.remaining_sat, Some(5_000));
+    }
+
+    #[test]
+    fn refuses_payments_over_the_weekly_limit() {
+        let denial = denial(evaluate(&policy(), &lightning(1_000_000, 0), 0, 49_500_000, &[], Utc::now()));
+        assert_eq!(denial.reason, DenialReason::ExceedsWeeklyLimit);
+        assert_eq!(denial.remaining_sat, Some(500));
+    }
+
+    #[test]
+    fn reports_no_remaining_allowance_when_already_over() {
+        let denial = denial(evaluate(&policy(), &lightning(1_000, 0), 11_000_000, 11_000_000, &[], Utc::now()));
+        assert_eq!(denial.remaining_sat, Some(0));
+    }
+
+    #[test]
+    fn rejects_amounts_that_do_not_fit_in_i64() {
+        let result = evaluate(&policy(), &lightning(u64::MAX, 0), 0, 0, &[], Utc::now());
+        assert!(matches!(result, Err(AppError::Validation(_))));
+
+        let result = evaluate(&policy(), &lightning(i64::MAX as u64, 1), 0, 0, &[], Utc::now());
+        assert!(matches!(result, Err(AppError::Validation(_))));
+    }
+
+    #[test]
+    fn huge_limits_do_not_overflow() {
+        let policy = SpendingPolicy {
+            daily_limit_sat: Some(i64::MAX),
+            max_payment_sat: Some(i64::MAX),
+            ..SpendingPolicy::unrestricted(Uuid::nil())
+        };
+        assert!(evaluate(&policy, &lightning(i64::MAX as u64 / 2, 0), i64::MAX / 2, 0, &[], Utc::now()).is_ok());
+    }
+
+    #[test]
+    fn allowlist_refuses_unknown_destinations() {
+        let policy = SpendingPolicy { allowlist_enabled: true, ..policy() };
+        let denial = denial(evaluate(&policy, &lightning(1_000, 0), 0, 0, &[], Utc::now()));
+        assert_eq!(denial.reason, DenialReason::DestinationNotAllowlisted);
+    }
+
+    #[test]
+    fn allowlist_refuses_destinations_still_cooling_off() {
+        let policy = SpendingPolicy { allowlist_enabled: true, ..policy() };
+        let now = Utc::now();
+        let allowlisted = [destination(now + Duration::hours(2)), destination(now + Duration::hours(1))];
+
+        let denial = denial(evaluate(&policy, &lightning(1_000, 0), 0, 0, &allowlisted, now));
+        assert_eq!(denial.reason, DenialReason::DestinationCoolingOff);
+        assert_eq!(denial.usable_after, Some(now + Duration::hours(1)));
+
+        let allowlisted = [destination(now + Duration::hours(1)), destination(now)];
+        assert!(evaluate(&policy, &lightning(1_000, 0), 0, 0, &allowlisted, now).is_ok());
+    }
+
+    #[test]
+    fn stricter_policy_keeps_the_tighter_value_of_each_field() {
+        let current = SpendingPolicy {
+            allowlist_enabled: true,
+            cooling_off_hours: 48,
+            weekly_limit_sat: None,
+            ..policy()
+        };
+        let requested = UpdateSpendingPolicyRequest {
+            daily_limit_sat: Some(20_000),
+            weekly_limit_sat: Some(40_000),
+            max_payment_sat: None,
+            max_fee_sat: Some(10),
+            max_feerate_sat_vb: None,
+            allowlist_enabled: false,
+            cooling_off_hours: Some(1),
+        };
+
+        let stricter = stricter_policy(&current, &requested);
+        assert_eq!(stricter, UpdateSpendingPolicyRequest {
+            daily_limit_sat: Some(10_000),
+            weekly_limit_sat: Some(40_000),
+            max_payment_sat: Some(5_000),
+            max_fee_sat: Some(10),
+            max_feerate_sat_vb: Some(50),
+            allowlist_enabled: true,
+            cooling_off_hours: Some(48),
+        });
+    }
+
+    #[test]
+    fn stricter_policy_of_a_tightening_request_is_the_request() {
+        let requested = UpdateSpendingPolicyRequest {
+            daily_limit_sat: Some(1_000),
+            weekly_limit_sat: Some(2_000),
+            max_payment_sat: Some(500),
+            max_fee_sat: Some(5),
+            max_feerate_sat_vb: Some(10),
+            allowlist_enabled: true,
+            cooling_off_hours: Some(72),
+        };
+        assert_eq!(stricter_policy(&policy(), &requested), requested);
+    }
+}
@@ -0,0 +1,532 @@
+use base64::{engine::general_purpose, Engine as _};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rand::{Rng, RngCore};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+use crate::config::Config;
+use crate::error::{AppError, Result};
+use crate::models::{ProtectedOperation, TotpRepository, TotpStatus, UserTotp};
+use crate::services::{CredentialVault, CryptoService, LoginThrottle, SecretKey};
+
+/// RFC 6238 defaults, which is what every authenticator app supports
+const SECRET_BYTES: usize = 20;
+const STEP_SECS: i64 = 30;
+const DIGITS: u32 = 6;
+
+/// Credential vault purpose for TOTP secrets
+const SECRET_PURPOSE: &str = "totp-secret";
+
+const BACKUP_CODE_COUNT: usize = 10;
+const BACKUP_CODE_LENGTH: usize = 10;
+
+/// RFC 4648 base32, the encoding authenticator apps expect for secrets
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// A pending enrollment, shown to the user once
+pub struct TotpEnrollment {
+    /// Base32 secret for manual entry
+    pub secret: String,
+    /// `otpauth://` provisioning URI, usually shown as a QR code
+    pub provisioning_uri: String,
+}
+
+/// TOTP second factor: enrollment, verification with replay protection and
+/// the login throttle, and single-use backup codes. Secrets are sealed by the
+/// credential vault, so they are protected by CREDENTIALS_KEY and bound to
+/// their user.
+pub struct TotpService {
+    db_pool: PgPool,
+    login_throttle: Arc<LoginThrottle>,
+    credential_vault: Arc<CredentialVault>,
+    /// SECRETS_ENCRYPTION_KEY, to convert secrets from before the vault
+    legacy_key: Option<SecretKey>,
+    issuer: String,
+    allowed_skew_steps: i64,
+}
+
+impl TotpService {
+    pub fn from_config(
+        config: &Config,
+        db_pool: PgPool,
+        login_throttle: Arc<LoginThrottle>,
+        credential_vault: Arc<CredentialVault>,
+    ) -> Result<Self> {
+        let legacy_key = config
+            .secrets_encryption_key
+            .as_deref()
+            .map(|encoded| {
+                general_purpose::STANDARD
+                    .decode(encoded.trim())
+                    .ok()
+                    .and_then(|key| <[u8; 32]>::try_from(key).ok())
+                    .map(SecretKey::new)
+                    .ok_or_else(|| AppError::Internal("SECRETS_ENCRYPTION_KEY must be 32 bytes of base64".to_string()))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            db_pool,
+            login_throttle,
+            credential_vault,
+            legacy_key,
+            issuer: config.totp_issuer.clone(),
+            allowed_skew_steps: config.totp_allowed_skew_steps,
+        })
+    }
+
+    async fn decrypt_secret(&self, totp: &UserTotp) -> Result<Vec<u8>> {
+        match totp.secret_key_version {
+            Some(key_version) => {
+                self.credential_vault
+                    .open_secret(SECRET_PURPOSE, totp.user_id, &totp.encrypted_secret, key_version)
+                    .await
+            }
+            None => {
+                let key = self.legacy_key.as_ref().map(SecretKey::expose).ok_or_else(|| {
+                    AppError::Cryptography("TOTP secret predates the credential vault and SECRETS_ENCRYPTION_KEY is not set".to_string())
+                })?;
+                // Bound to the user, or from before secrets were
+                CryptoService::decrypt_with_key_aad(&totp.encrypted_secret, key, &secret_aad(totp.user_id))
+                    .or_else(|_| CryptoService::decrypt_with_key(&totp.encrypted_secret, key))
+            }
+        }
+    }
+
+    /// Create a new secret for `user_id`, replacing any unconfirmed one
+    pub async fn begin_enrollment(&self, user_id: Uuid, account_name: &str) -> Result<TotpEnrollment> {
+        let mut secret = [0u8; SECRET_BYTES];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let encrypted_secret = self.credential_vault.seal_secret(SECRET_PURPOSE, user_id, &secret).await?;
+
+        let totp_repo = TotpRepository::new(self.db_pool.clone());
+        if !totp_repo
+            .start_enrollment(user_id, &encrypted_secret, self.credential_vault.key_version())
+            .await?
+        {
+            return Err(AppError::BadRequest("Two-factor authentication is already enabled".to_string()));
+        }
+
+        let secret = base32_encode(&secret);
+        Ok(TotpEnrollment {
+            provisioning_uri: self.provisioning_uri(&secret, account_name),
+            secret,
+        })
+    }
+
+    /// `otpauth://totp/<issuer>:<account>?secret=..&issuer=..`
+    fn provisioning_uri(&self, secret: &str, account_name: &str) -> String {
+        let issuer = utf8_percent_encode(&self.issuer, NON_ALPHANUMERIC).to_string();
+        format!(
+            "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+            issuer,
+            utf8_percent_encode(account_name, NON_ALPHANUMERIC),
+            secret,
+            issuer,
+            DIGITS,
+            STEP_SECS,
+        )
+    }
+
+    /// Enable a pending enrollment once the user proves their app has the
+    /// secret, returning a fresh set of backup codes
+    pub async fn confirm_enrollment(&self, user_id: Uuid, code: &str) -> Result<Vec<String>> {
+        let totp_repo = TotpRepository::new(self.db_pool.clone());
+        let totp = totp_repo.find_by_user(user_id).await?
+            .ok_or_else(|| AppError::BadRequest("Start enrollment first".to_string()))?;
+        if totp.is_enabled() {
+            return Err(AppError::BadRequest("Two-factor authentication is already enabled".to_string()));
+        }
+
+        self.login_throttle.reserve_second_factor(user_id).await?;
+        self.verify_totp_code(&totp, code).await?;
+        self.login_throttle.record_second_factor_success(user_id).await?;
+
+        let backup_codes = generate_backup_codes();
+        let code_hashes: Vec<String> = backup_codes.iter().map(|code| hash_backup_code(code)).collect();
+
+        let mut tx = totp_repo.begin().await?;
+        if !totp_repo.enable(&mut tx, user_id).await? {
+            return Err(AppError::BadRequest("Two-factor authentication is already enabled".to_string()));
+        }
+        totp_repo.replace_backup_codes(&mut tx, user_id, &code_hashes).await?;
+        tx.commit().await?;
+
+        Ok(backup_codes)
+    }
+
+    /// Turn off the second factor; needs a valid code
+    pub async fn disable(&self, user_id: Uuid, code: &str) -> Result<()> {
+        let totp = self.enabled_totp(user_id).await?;
+        self.verify(&totp, code).await?;
+
+        let totp_repo = TotpRepository::new(self.db_pool.clone());
+        let mut tx = totp_repo.begin().await?;
+        totp_repo.delete(&mut tx, user_id).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Invalidate all backup codes and issue new ones; needs a valid code
+    pub async fn regenerate_backup_codes(&self, user_id: Uuid, code: &str) -> Result<Vec<String>> {
+        let totp = self.enabled_totp(user_id).await?;
+        self.verify(&totp, code).await?;
+
+        let backup_codes = generate_backup_codes();
+        let code_hashes: Vec<String> = backup_codes.iter().map(|code| hash_backup_code(code)).collect();
+
+        let totp_repo = TotpRepository::new(self.db_pool.clone());
+        let mut tx = totp_repo.begin().await?;
+        totp_repo.replace_backup_codes(&mut tx, user_id, &code_hashes).await?;
+        tx.commit().await?;
+
+        Ok(backup_codes)
+    }
+
+    /// Choose which operations need a code; needs a valid code, so a stolen
+    /// session can't switch the protection off
+    pub async fn update_protected_operations(
+        &self,
+        user_id: Uuid,
+        operations: &[ProtectedOperation],
+        code: &str,
+    ) -> Result<()> {
+        let totp = self.enabled_totp(user_id).await?;
+        self.verify(&totp, code).await?;
+
+        let operations: Vec<String> = operations.iter().map(|operation| operation.as_str().to_string()).collect();
+        TotpRepository::new(self.db_pool.clone())
+            .update_protected_operations(user_id, &operations)
+            .await
+    }
+
+    pub async fn status(&self, user_id: Uuid) -> Result<TotpStatus> {
+        let totp_repo = TotpRepository::new(self.db_pool.clone());
+        let totp = totp_repo.find_by_user(user_id).await?.filter(UserTotp::is_enabled);
+
+        Ok(TotpStatus {
+            enabled: totp.is_some(),
+            enabled_at: totp.as_ref().and_then(|totp| totp.enabled_at),
+            protected_operations: totp.map(|totp| totp.protected_operations).unwrap_or_default(),
+            backup_codes_remaining: totp_repo.count_unused_backup_codes(user_id).await?,
+        })
+    }
+
+    /// Second factor at login: required whenever the user has it enabled
+    pub async fn check_login(&self, user_id: Uuid, code: Option<&str>) -> Result<()> {
+        let totp_repo = TotpRepository::new(self.db_pool.clone());
+        match totp_repo.find_by_user(user_id).await? {
+            Some(totp) if totp.is_enabled() => {
+                self.verify(&totp, code.ok_or(AppError::SecondFactorRequired)?).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Second factor for a sensitive operation: required if the user has it
+    /// enabled and protects that operation
+    pub async fn check_operation(&self, user_id: Uuid, operation: ProtectedOperation, code: Option<&str>) -> Result<()> {
+        self.check_operations(user_id, &[operation], code).await
+    }
+
+    /// Second factor for several operations at once: one code is required
+    /// if the user protects any of them
+    pub async fn check_operations(&self, user_id: Uuid, operations: &[ProtectedOperation], code: Option<&str>) -> Result<()> {
+        let totp_repo = TotpRepository::new(self.db_pool.clone());
+        match totp_repo.find_by_user(user_id).await? {
+            Some(totp) if totp.is_enabled() && operations.iter().any(|operation| totp.protects(*operation)) => {
+                self.verify(&totp, code.ok_or(AppError::SecondFactorRequired)?).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Seal secrets from before the credential vault with it, and reseal
+    /// ones under a previous key version or an older CryptoService format.
+    /// Returns how many were re-encrypted and how many could not be decrypted.
+    pub async fn reencrypt_secrets(&self) -> Result<(usize, usize)> {
+        let totp_repo = TotpRepository::new(self.db_pool.clone());
+        let mut reencrypted = 0;
+        let mut failed = 0;
+
+        for totp in totp_repo.find_all().await? {
+            let current = totp
+                .secret_key_version
+                .is_some_and(|key_version| !self.credential_vault.is_outdated(&totp.encrypted_secret, key_version));
+            if current {
+                continue;
+            }
+
+            let secret = match self.decrypt_secret(&totp).await {
+                Ok(secret) => secret,
+                Err(e) => {
+                    tracing::warn!("Could not decrypt the TOTP secret of user {}: {}", totp.user_id, e);
+                    failed += 1;
+                    continue;
+                }
+            };
+            let encrypted_secret = self.credential_vault.seal_secret(SECRET_PURPOSE, totp.user_id, &secret).await?;
+            if totp_repo
+                .replace_secret(totp.user_id, &totp.encrypted_secret, &encrypted_secret, self.credential_vault.key_version())
+                .await?
+            {
+                reencrypted += 1;
+            }
+        }
+
+        Ok((reencrypted, failed))
+    }
+
+    async fn enabled_totp(&self, user_id: Uuid) -> Result<UserTotp> {
+        TotpRepository::new(self.db_pool.clone())
+            .find_by_user(user_id)
+            .await?
+            .filter(UserTotp::is_enabled)
+            .ok_or_else(|| AppError::BadRequest("Two-factor authentication is not enabled".to_string()))
+    }
+
+    /// Accept either an authenticator code or an unused backup code. Every
+    /// attempt goes through the login throttle, which locks the user's
+    /// second factor after repeated misses.
+    async fn verify(&self, totp: &UserTotp, code: &str) -> Result<()> {
+        self.login_throttle.reserve_second_factor(totp.user_id).await?;
+        self.check_code(totp, code).await?;
+        self.login_throttle.record_second_factor_success(totp.user_id).await
+    }
+
+    async fn check_code(&self, totp: &UserTotp, code: &str) -> Result<()> {
+        let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+        if code.len() == DIGITS as usize && code.chars().all(|c| c.is_ascii_digit()) {
+            return self.verify_totp_code(totp, &code).await;
+        }
+
+        let totp_repo = TotpRepository::new(self.db_pool.clone());
+        if totp_repo.consume_backup_code(totp.user_id, &hash_backup_code(&code)).await? {
+            tracing::info!("Backup code used by user {}", totp.user_id);
+            return Ok(());
+        }
+
+        Err(AppError::Authentication("Invalid two-factor code".to_string()))
+    }
+
+    async fn verify_totp_code(&self, totp: &UserTotp, code: &str) -> Result<()> {
+        let secret = self.decrypt_secret(totp).await?;
+        let step = matching_step(&secret, code.trim(), Utc::now().timestamp(), self.allowed_skew_steps)
+            .ok_or_else(|| AppError::Authentication("Invalid two-factor code".to_string()))?;
+
+        // Each code works once, even within its 30 seconds
+        let totp_repo = TotpRepository::new(self.db_pool.clone());
+        if !totp_repo.record_used_step(totp.user_id, step).await? {
+            return Err(AppError::Authentication("Two-factor code was already used".to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Authenticated with secrets from before the credential vault
+fn secret_aad(user_id: Uuid) -> Vec<u8> {
+    format!("totp-secret:{}", user_id).into_bytes()
+}
+
+/// The time step within `skew` steps of `now` whose code is `code`
+fn matching_step(secret: &[u8], code: &str, now: i64, skew: i64) -> Option<i64> {
+    let current = now / STEP_SECS;
+    (current - skew..=current + skew)
+        .filter(|step| *step >= 0)
+        .find(|step| constant_time_eq(hotp(secret, *step as u64).as_bytes(), code.as_bytes()))
+}
+
+/// RFC 4226 HOTP with HMAC-SHA1 and dynamic truncation
+fn hotp(secret: &[u8], counter: u64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([digest[offset], digest[offset + 1], digest[offset + 2], digest[offset + 3]])
+        & 0x7fff_ffff;
+
+    format!("{:0width$}", binary % 10u32.pow(DIGITS), width = DIGITS as usize)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn base32_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(5) * 8);
+    let (mut buffer, mut bits) = (0u32, 0u32);
+
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+
+    encoded
+}
+
+/// Codes like `k7xq2-m9fpa`, from an alphabet without look-alike characters
+fn generate_backup_codes() -> Vec<String> {
+    const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+    let mut rng = rand::thread_rng();
+
+    (0..BACKUP_CODE_COUNT)
+        .map(|_| {
+            let code: String = (0..BACKUP_CODE_LENGTH)
+                .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+                .collect();
+            format!("{}-{}", &code[..BACKUP_CODE_LENGTH / 2], &code[BACKUP_CODE_LENGTH / 2..])
+        })
+        .collect()
+}
+
+/// Hash of a backup code, ignoring case and the separator
+fn hash_backup_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hex::encode(Sha256::digest(normalized.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The SHA-1 seed of RFC 4226 Appendix D and RFC 6238 Appendix B
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn hotp_matches_rfc_4226_vectors() {
+        let expected = [
+            "755224", "287082", "359152", "969429", "338314",
+            "254676", "287922", "162583", "399871", "520489",
+        ];
+        for (counter, code) in expected.iter().enumerate() {
+            assert_eq!(hotp(RFC_SECRET, counter as u64), *code, "counter {}", counter);
+        }
+    }
+
+    #[test]
+    fn totp_matches_rfc_6238_sha1_vectors() {
+        // Appendix B lists 8 digits; 6-digit codes are their last six
+        let expected = [
+            (59, "94287082"),
+            (1111111109, "07081804"),
+            (1111111111, "14050471"),
+            (1234567890, "89005924"),
+            (2000000000, "69279037"),
+            (20000000000, "65353130"),
+        ];
+        for (time, code) in expected {
+            assert_eq!(hotp(RFC_SECRET, (time / STEP_SECS) as u64), code[2..], "time {}", time);
+            assert_eq!(matching_step(RFC_SECRET, &code[2..], time, 0), Some(time / STEP_SECS));
+        }
+    }
+
+    #[test]
+    fn accepts_codes_within_the_skew_window() {
+        let now = 1111111111;
+        let step = now / STEP_SECS;
+        let code_at = |step: i64| hotp(RFC_SECRET, step as u64);
+
+        assert_eq!(matching_step(RFC_SECRET, &code_at(step - 1), now, 1), Some(step - 1));
+        assert_eq!(matching_step(RFC_SECRET, &code_at(step + 1), now, 1), Some(step + 1));
+        assert_eq!(matching_step(RFC_SECRET, &code_at(step - 2), now, 1), None);
+        assert_eq!(matching_step(RFC_SECRET, &code_at(step + 2), now, 1), None);
+        assert_eq!(matching_step(RFC_SECRET, &code_at(step + 1), now, 0), None);
+        assert_eq!(matching_step(b"another secret", &code_at(step), now, 1), None);
+        assert_eq!(matching_step(RFC_SECRET, "", now, 1), None);
+    }
+
+    #[test]
+    fn skew_window_stops_at_step_zero() {
+        assert_eq!(matching_step(RFC_SECRET, &hotp(RFC_SECRET, 0), 10, 1), Some(0));
+    }
+
+    #[test]
+    fn base32_matches_rfc_4648_vectors() {
+        let expected = [
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ];
+        for (data, encoded) in expected {
+            assert_eq!(base32_encode(data.as_bytes()), encoded);
+        }
+        assert_eq!(base32_encode(RFC_SECRET), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    }
+
+    #[test]
+    fn backup_codes_are_distinct_and_hash_loosely() {
+        let codes = generate_backup_codes();
+        assert_eq!(codes.len(), BACKUP_CODE_COUNT);
+        assert!(codes.iter().all(|code| code.len() == BACKUP_CODE_LENGTH + 1 && code.as_bytes()[5] == b'-'));
+        let unique: std::collections::HashSet<_> = codes.iter().collect();
+        assert_eq!(unique.len(), codes.len());
+
+        assert_eq!(hash_backup_code("k7xq2-m9fpa"), hash_backup_code(" K7XQ2M9FPA "));
+        assert_ne!(hash_backup_code("k7xq2-m9fpa"), hash_backup_code("k7xq2-m9fpb"));
+    }
+
+    async fn user_with_totp(pool: &PgPool) -> Uuid {
+        let user_id = Uuid::new_v4();
+        sqlx::query("INSERT INTO users (id, public_key, password_hash) VALUES ($1, $2, 'x')")
+            .bind(user_id)
+            .bind(user_id.to_string())
+            .execute(pool)
+            .await
+            .unwrap();
+        let totp_repo = TotpRepository::new(pool.clone());
+        assert!(totp_repo.start_enrollment(user_id, "sealed", 1).await.unwrap());
+        user_id
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a PostgreSQL server in DATABASE_URL"]
+    async fn codes_are_single_use(pool: PgPool) {
+        let user_id = user_with_totp(&pool).await;
+        let totp_repo = TotpRepository::new(pool);
+
+        assert!(totp_repo.record_used_step(user_id, 100).await.unwrap());
+        // The same code again, or an older one still inside the window
+        assert!(!totp_repo.record_used_step(user_id, 100).await.unwrap());
+        assert!(!totp_repo.record_used_step(user_id, 99).await.unwrap());
+        assert!(totp_repo.record_used_step(user_id, 101).await.unwrap());
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a PostgreSQL server in DATABASE_URL"]
+    async fn backup_codes_are_single_use(pool: PgPool) {
+        let user_id = user_with_totp(&pool).await;
+        let totp_repo = TotpRepository::new(pool);
+        let hashes: Vec<String> = ["aaaaa-bbbbb", "ccccc-ddddd"].iter().map(|code| hash_backup_code(code)).collect();
+
+        let mut tx = totp_repo.begin().await.unwrap();
+        totp_repo.replace_backup_codes(&mut tx, user_id, &hashes).await.unwrap();
+        tx.commit().await.unwrap();
+
+        assert!(totp_repo.consume_backup_code(user_id, &hash_backup_code("AAAAA BBBBB")).await.unwrap());
+        assert!(!totp_repo.consume_backup_code(user_id, &hashes[0]).await.unwrap());
+        assert!(!totp_repo.consume_backup_code(Uuid::new_v4(), &hashes[1]).await.unwrap());
+        assert_eq!(totp_repo.count_unused_backup_codes(user_id).await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::HashSet;
+use std::fs;
+use crate::config::Config;
+use crate::error::{AppError, Result};
+
+/// Longest possible DNS label
+const MAX_LABEL_LENGTH: usize = 63;
+
+/// Names nobody may claim, on top of `USERNAME_RESERVED`
+const BUILTIN_RESERVED: &[&str] = &[
+    "abuse", "admin", "administrator", "api", "billing", "bitcoin", "greenlight",
+    "help", "hostmaster", "info", "lightning", "mail", "moderator", "no-reply",
+    "noreply", "nostr", "official", "postmaster", "root", "security", "shopstr",
+    "staff", "support", "system", "webmaster", "www",
+];
+
+/// Greek and Cyrillic letters that render like Latin ones. A name made only of
+/// these would impersonate an ASCII name (e.g. Cyrillic "рау" for "pay").
+const LATIN_LOOKALIKES: &str = "аеорсхуіјѕһԁԛԝӏвкмнтαεικνορτυχ";
+
+const SUGGESTION_SUFFIXES: &[&str] = &["shop", "store", "pay", "market"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Script {
+    Common,
+    Latin,
+    Greek,
+    Cyrillic,
+    Other,
+}
+
+fn script_of(c: char) -> Script {
+    match c {
+        '0'..='9' | '-' => Script::Common,
+        'a'..='z' | '\u{00c0}'..='\u{024f}' | '\u{1e00}'..='\u{1eff}' => Script::Latin,
+        '\u{0370}'..='\u{03ff}' | '\u{1f00}'..='\u{1fff}' => Script::Greek,
+        '\u{0400}'..='\u{052f}' => Script::Cyrillic,
+        _ => Script::Other,
+    }
+}
+
+/// Rules every username must satisfy before it can be published as a BIP353 name
+pub struct UsernamePolicy {
+    min_length: usize,
+    max_length: usize,
+    reserved: HashSet<String>,
+    blocked_words: Vec<String>,
+    pub max_per_user: i64,
+}
+
+impl UsernamePolicy {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let mut reserved: HashSet<String> = BUILTIN_RESERVED.iter().map(|name| name.to_string()).collect();
+        reserved.extend(config.username_reserved.iter().map(|name| name.to_lowercase()));
+
+        let blocked_words = match &config.username_blocklist_path {
+            Some(path) => fs::read_to_string(path)
+                .map_err(|e| AppError::Internal(format!("Failed to read username blocklist {}: {}", path, e)))?
+                .lines()
+                .map(|line| line.trim().to_lowercase())
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .collect(),
+            None => Vec::new(),
+        };
+
+        Ok(Self {
+            min_length: config.username_min_length,
+            max_length: config.username_max_length.min(MAX_LABEL_LENGTH),
+            reserved,
+            blocked_words,
+            max_per_user: config.username_max_per_user,
+        })
+    }
+
+    /// Normalize a requested name to the ASCII (punycode) DNS label we publish.
+    /// Case folding and width/compatibility mapping follow UTS #46.
+    pub fn normalize(&self, name: &str) -> Result<String> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(AppError::Validation("Username cannot be empty".to_string()));
+        }
+        if name.contains(['.', '@']) {
+            return Err(AppError::Validation("Username cannot contain '.' or '@'".to_string()));
+        }
+
+        let label = idna::domain_to_ascii(name)
+            .map_err(|_| AppError::Validation("Username is not a valid internationalized name".to_string()))?;
+
+        if !label.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-') {
+            return Err(AppError::Validation(
+                "Username may only contain letters, digits and hyphens".to_string()
+            ));
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err(AppError::Validation("Username cannot start or end with a hyphen".to_string()));
+        }
+        if label.get(2..4) == Some("--") && !label.starts_with("xn--") {
+            return Err(AppError::Validation("Username cannot contain '--' in the third and fourth position".to_string()));
+        }
+
+        let length = label.chars().count();
+        if length < self.min_length || length > self.max_length {
+            return Err(AppError::Validation(format!(
+                "Username must be between {} and {} characters long",
+                self.min_length, self.max_length
+            )));
+        }
+
+        if label.starts_with("xn--") {
+            let (unicode, result) = idna::domain_to_unicode(&label);
+            if result.is_err() {
+                return Err(AppError::Validation("Username is not a valid internationalized name".to_string()));
+            }
+            Self::check_confusables(&unicode)?;
+        }
+
+        Ok(label)
+    }
+
+    /// Normalize `name` and apply the reserved-name and blocklist rules
+    pub fn check(&self, name: &str) -> Result<String> {
+        let label = self.normalize(name)?;
+
+        let unhyphenated = label.replace('-', "");
+        if self.reserved.contains(&label) || self.reserved.contains(&unhyphenated) {
+            return Err(AppError::Validation("Username is reserved".to_string()));
+        }
+
+        let unicode = idna::domain_to_unicode(&label).0;
+        if self.blocked_words.iter().any(|word| {
+            unhyphenated.contains(word.as_str()) || unicode.contains(word.as_str())
+        }) {
+            return Err(AppError::Validation("Username is not allowed".to_string()));
+        }
+
+        Ok(label)
+    }
+
+    /// Candidate alternatives for `name` that pass the policy. The caller still
+    /// has to filter out the ones already taken.
+    pub fn suggestions(&self, name: &str) -> Vec<String> {
+        let base = match self.normalize(name) {
+            Ok(label) => label,
+            Err(_) => name
+                .to_lowercase()
+                .chars()
+                .filter(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || *c == '-')
+                .collect::<String>()
+                .trim_matches('-')
+                .to_string(),
+        };
+        if base.is_empty() {
+            return Vec::new();
+        }
+
+        let mut candidates: Vec<String> = SUGGESTION_SUFFIXES
+            .iter()
+            .map(|suffix| format!("{}-{}", base, suffix))
+            .collect();
+        candidates.extend((1..=9).map(|n| format!("{}{}", base, n)));
+
+        candidates
+            .into_iter()
+            .filter_map(|candidate| self.check(&candidate).ok())
+            .filter(|candidate| *candidate != base)
+            .collect()
+    }
+
+    fn check_confusables(unicode: &str) -> Result<()> {
+        let scripts: HashSet<Script> = unicode
+            .chars()
+            .map(script_of)
+            .filter(|script| *script != Script::Common)
+            .collect();
+
+        let european = [Script::Latin, Script::Greek, Script::Cyrillic];
+        if scripts.iter().filter(|script| european.contains(script)).count() > 1 {
+            return Err(AppError::Validation("Username cannot mix Latin, Greek or Cyrillic letters".to_string()));
+        }
+
+        let letters: Vec<char> = unicode.chars().filter(|c| script_of(*c) != Script::Common).collect();
+        if !scripts.contains(&Script::Latin)
+            && !letters.is_empty()
+            && letters.iter().all(|c| LATIN_LOOKALIKES.contains(*c))
+        {
+            return Err(AppError::Validation("Username is confusable with a Latin name".to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> UsernamePolicy {
+        UsernamePolicy {
+            min_length: 3,
+            max_length: 20,
+            reserved: BUILTIN_RESERVED.iter().map(|name| name.to_string()).collect(),
+            blocked_words: vec!["scam".to_string()],
+            max_per_user: 3,
+        }
+    }
+
+    fn rejects(name: &str) -> bool {
+        matches!(policy().normalize(name), Err(AppError::Validation(_)))
+    }
+
+    #[test]
+    fn folds_case_and_trims() {
+        assert_eq!(policy().normalize("  Alice ").unwrap(), "alice");
+        assert_eq!(policy().normalize("bob-42").unwrap(), "bob-42");
+    }
+
+    #[test]
+    fn maps_fullwidth_characters() {
+        assert_eq!(policy().normalize("ＡＬＩＣＥ").unwrap(), "alice");
+    }
+
+    #[test]
+    fn encodes_internationalized_names_as_punycode() {
+        assert_eq!(policy().normalize("Bücher").unwrap(), "xn--bcher-kva");
+        assert_eq!(policy().normalize("магазин").unwrap(), "xn--80aairftm");
+    }
+
+    #[test]
+    fn rejects_malformed_names() {
+        assert!(rejects(""));
+        assert!(rejects("   "));
+        assert!(rejects("alice.shop"));
+        assert!(rejects("alice@example.com"));
+        assert!(rejects("alice_shop"));
+        assert!(rejects("alice shop"));
+        assert!(rejects("-alice"));
+        assert!(rejects("alice-"));
+        assert!(rejects("ab--cd"));
+    }
+
+    #[test]
+    fn enforces_length_after_encoding() {
+        assert!(rejects("ab"));
+        assert!(!rejects("abc"));
+        assert!(!rejects(&"a".repeat(20)));
+        assert!(rejects(&"a".repeat(21)));
+        // 15 characters, but 22 once punycoded
+        assert!(rejects("bücher-für-alle"));
+    }
+
+    #[test]
+    fn rejects_mixed_scripts_and_lookalikes() {
+        // Latin "p" and "y" with a Cyrillic "а"
+        assert!(rejects("pаy"));
+        // Cyrillic letters only, but spelling the Latin "pay"
+        assert!(rejects("рау"));
+        // Greek "ο" among Latin letters
+        assert!(rejects("shοp"));
+    }
+
+    #[test]
+    fn rejects_invalid_punycode() {
+        assert!(rejects("xn--a"));
+    }
+
+    #[test]
+    fn check_applies_reserved_names_and_blocklist() {
+        let policy = policy();
+        assert!(policy.check("Admin").is_err());
+        assert!(policy.check("no-reply").is_err());
+        assert!(policy.check("post-master").is_err());
+        assert!(policy.check("notascammer").is_err());
+        assert_eq!(policy.check("alice").unwrap(), "alice");
+    }
+}